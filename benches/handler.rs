@@ -0,0 +1,116 @@
+//! Compares the per-request overhead of the handler APIs of servers:
+//! `Action::Reply` and `Action::FutureReply` of `HandleMessage`, and `HandleSharedMessage`.
+//!
+//! The servers and clients exchange messages via a `SimulatedNetwork`,
+//! thus the results are not affected by the cost of real sockets.
+//!
+//! ```console
+//! $ cargo bench --bench handler
+//! ```
+use bytecodec::marker::Never;
+use criterion::{BenchmarkId, Criterion, Throughput};
+use futures::future::{self, FutureResult};
+use futures::Future;
+use rustun::channel::Channel;
+use rustun::client::Client;
+use rustun::message::{Request, Response, SuccessResponse};
+use rustun::server::{
+    Action, Dispatch, HandleMessage, HandleSharedMessage, RequestContext, SharedHandler, UdpServer,
+};
+use rustun::transport::{MemoryTransporter, SimulatedNetwork};
+use std::net::SocketAddr;
+use stun_codec::rfc5389;
+
+type Attribute = rfc5389::Attribute;
+
+const REQUESTS: usize = 256;
+
+#[derive(Debug)]
+struct ImmediateHandler;
+impl HandleMessage for ImmediateHandler {
+    type Attribute = Attribute;
+
+    fn handle_call(
+        &mut self,
+        _context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Action<Response<Self::Attribute>> {
+        Action::Reply(Ok(SuccessResponse::new(&request)))
+    }
+}
+
+#[derive(Debug)]
+struct BoxedFutureHandler;
+impl HandleMessage for BoxedFutureHandler {
+    type Attribute = Attribute;
+
+    fn handle_call(
+        &mut self,
+        _context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Action<Response<Self::Attribute>> {
+        Action::FutureReply(Box::new(future::ok(Ok(SuccessResponse::new(&request)))))
+    }
+}
+
+#[derive(Debug)]
+struct SharedFutureHandler;
+impl HandleSharedMessage for SharedFutureHandler {
+    type Attribute = Attribute;
+    type Reply = FutureResult<Option<Response<Self::Attribute>>, Never>;
+
+    fn handle_call(
+        &self,
+        _context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Self::Reply {
+        future::ok(Some(Ok(SuccessResponse::new(&request))))
+    }
+}
+
+fn start_server<H>(network: &SimulatedNetwork, handler: H) -> SocketAddr
+where
+    H: Dispatch<Attribute = Attribute> + Send + 'static,
+{
+    let transporter = network.bind_any().expect("Cannot bind a server endpoint");
+    let server = UdpServer::with_transporter(fibers_global::handle(), transporter, handler);
+    let addr = server.local_addr();
+    fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+    addr
+}
+
+fn run_bindings(client: &Client<Attribute, MemoryTransporter<Attribute>>, server_addr: SocketAddr) {
+    let calls = (0..REQUESTS)
+        .map(|_| client.call(server_addr, Request::new(rfc5389::methods::BINDING)))
+        .collect::<Vec<_>>();
+    fibers_global::execute(future::join_all(calls)).expect("Binding failed");
+}
+
+fn handlers(c: &mut Criterion) {
+    let network = SimulatedNetwork::new();
+    let transporter = network.bind_any().expect("Cannot bind a client endpoint");
+    let client = Client::new(&fibers_global::handle(), Channel::new(transporter));
+
+    let mut group = c.benchmark_group("handler");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+
+    let server_addr = start_server(&network, ImmediateHandler);
+    group.bench_function(BenchmarkId::new("binding", "Reply"), |b| {
+        b.iter(|| run_bindings(&client, server_addr))
+    });
+
+    let server_addr = start_server(&network, BoxedFutureHandler);
+    group.bench_function(BenchmarkId::new("binding", "FutureReply"), |b| {
+        b.iter(|| run_bindings(&client, server_addr))
+    });
+
+    let server_addr = start_server(&network, SharedHandler::new(SharedFutureHandler));
+    group.bench_function(BenchmarkId::new("binding", "SharedHandler"), |b| {
+        b.iter(|| run_bindings(&client, server_addr))
+    });
+
+    group.finish();
+}
+
+criterion::criterion_group!(benches, handlers);
+criterion::criterion_main!(benches);
@@ -0,0 +1,100 @@
+//! Compares the throughput of UDP servers using `fibers_transport::UdpTransporter`
+//! and `rustun::transport::BatchUdpTransporter`.
+//!
+//! ```console
+//! $ cargo bench --bench udp_transport
+//! ```
+#[cfg(target_os = "linux")]
+mod bench {
+    use criterion::{BenchmarkId, Criterion, Throughput};
+    use fibers_transport::{UdpTransport, UdpTransporter};
+    use futures::{future, Future};
+    use rustun::channel::Channel;
+    use rustun::client::Client;
+    use rustun::message::Request;
+    use rustun::server::{BindingHandler, UdpServer};
+    use rustun::transport::{
+        BatchUdpTransporter, StunTransport, StunUdpTransporter, StunUdpTransporterBuilder,
+    };
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use stun_codec::rfc5389;
+    use stun_codec::{MessageDecoder, MessageEncoder};
+
+    type Attribute = rfc5389::Attribute;
+    type PlainUdpTransporter = UdpTransporter<MessageEncoder<Attribute>, MessageDecoder<Attribute>>;
+    type ClientTransporter = StunUdpTransporter<Attribute, PlainUdpTransporter>;
+
+    const CLIENTS: usize = 8;
+    const REQUESTS_PER_CLIENT: usize = 32;
+
+    fn start_server<T>(transporter: T) -> SocketAddr
+    where
+        T: UdpTransport + StunTransport<Attribute> + Send + 'static,
+    {
+        let server =
+            UdpServer::with_transporter(fibers_global::handle(), transporter, BindingHandler);
+        let addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        addr
+    }
+
+    fn start_clients() -> Vec<Client<Attribute, ClientTransporter>> {
+        (0..CLIENTS)
+            .map(|_| {
+                let transporter = fibers_global::execute(PlainUdpTransporter::bind(
+                    "127.0.0.1:0".parse().unwrap(),
+                ))
+                .expect("Cannot bind a client socket");
+                let transporter = StunUdpTransporterBuilder::new()
+                    .min_transaction_interval(Duration::from_millis(0))
+                    .max_outstanding_transactions(REQUESTS_PER_CLIENT)
+                    .finish(transporter);
+                Client::new(&fibers_global::handle(), Channel::new(transporter))
+            })
+            .collect()
+    }
+
+    fn run_bindings(clients: &[Client<Attribute, ClientTransporter>], server_addr: SocketAddr) {
+        let calls = clients.iter().flat_map(|client| {
+            (0..REQUESTS_PER_CLIENT).map(move |_| {
+                let request = Request::<Attribute>::new(rfc5389::methods::BINDING);
+                client.call(server_addr, request)
+            })
+        });
+        fibers_global::execute(future::join_all(calls.collect::<Vec<_>>()))
+            .expect("Binding failed");
+    }
+
+    pub fn udp_servers(c: &mut Criterion) {
+        let clients = start_clients();
+        let mut group = c.benchmark_group("udp_server");
+        group.throughput(Throughput::Elements((CLIENTS * REQUESTS_PER_CLIENT) as u64));
+
+        let transporter =
+            fibers_global::execute(PlainUdpTransporter::bind("127.0.0.1:0".parse().unwrap()))
+                .expect("Cannot bind a server socket");
+        let server_addr = start_server(transporter);
+        group.bench_function(BenchmarkId::new("binding", "UdpTransporter"), |b| {
+            b.iter(|| run_bindings(&clients, server_addr))
+        });
+
+        let transporter =
+            fibers_global::execute(BatchUdpTransporter::bind("127.0.0.1:0".parse().unwrap()))
+                .expect("Cannot bind a server socket");
+        let server_addr = start_server(transporter);
+        group.bench_function(BenchmarkId::new("binding", "BatchUdpTransporter"), |b| {
+            b.iter(|| run_bindings(&clients, server_addr))
+        });
+
+        group.finish();
+    }
+}
+
+#[cfg(target_os = "linux")]
+criterion::criterion_group!(benches, bench::udp_servers);
+#[cfg(target_os = "linux")]
+criterion::criterion_main!(benches);
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
@@ -2,47 +2,78 @@
 extern crate trackable;
 
 use clap::Parser;
-use fibers_transport::UdpTransporter;
 use futures::Future;
 use rustun::channel::Channel;
-use rustun::client::Client;
-use rustun::message::Request;
-use rustun::transport::StunUdpTransporter;
+use rustun::client::{AddressFamily, Client, DnsResolver, ServerResolver, ServerTransport};
+use rustun::message::{Pretty, Request};
+use rustun::server::canonical_peer_addr;
+use rustun::transport::{StdUdpTransporter, StunUdpTransporter};
 use rustun::Error;
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr};
 use stun_codec::rfc5389;
-use stun_codec::{MessageDecoder, MessageEncoder};
-use trackable::error::Failed;
 use trackable::error::MainError;
 
 #[derive(Debug, Parser)]
 struct Args {
     host: String,
 
-    #[clap(short, long, default_value_t = 3478)]
-    port: usize,
+    /// If omitted, the server is looked up by using DNS SRV records (`_stun._udp`).
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// Uses IPv4 only.
+    #[clap(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Uses IPv6 only.
+    #[clap(short = '6')]
+    ipv6: bool,
 }
 
 fn main() -> Result<(), MainError> {
     let args = Args::parse();
-    let peer_addr = track_assert_some!(
-        track_any_err!(format!("{}:{}", args.host, args.port).to_socket_addrs())?
-            .filter(|x| x.is_ipv4())
-            .nth(0),
-        Failed
-    );
+    let family = if args.ipv4 {
+        AddressFamily::Ipv4
+    } else if args.ipv6 {
+        AddressFamily::Ipv6
+    } else {
+        AddressFamily::Any
+    };
+    let mut resolver = ServerResolver::new(track!(DnsResolver::from_system_conf())?);
+    resolver.address_family(family);
+    let candidates = track!(fibers_global::execute(resolver.resolve(
+        &args.host,
+        args.port,
+        ServerTransport::Udp
+    )))?;
 
-    let local_addr = "0.0.0.0:0".parse().unwrap();
-    let response = UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(local_addr)
+    // A dual-stack socket reaches IPv4 servers via IPv4-mapped IPv6 addresses
+    let candidates = candidates
+        .into_iter()
+        .map(|addr| match addr.ip() {
+            IpAddr::V4(ip) if family == AddressFamily::Any => {
+                SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port())
+            }
+            _ => addr,
+        })
+        .collect::<Vec<_>>();
+
+    let local_addr = family.unspecified_addr();
+    let response = StdUdpTransporter::bind(local_addr)
         .map_err(Error::from)
         .map(StunUdpTransporter::new)
         .map(Channel::new)
         .and_then(move |channel| {
             let client = Client::new(&fibers_global::handle(), channel);
-            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
-            client.call(peer_addr, request)
+            client.call_with_failover(candidates, || {
+                Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING)
+            })
         });
-    let response = track!(fibers_global::execute(response))?;
-    println!("{:?}", response);
+    let (peer_addr, response) = track!(fibers_global::execute(response))?;
+    println!(
+        "{}: {:#}",
+        canonical_peer_addr(peer_addr),
+        Pretty::new(&response)
+    );
     Ok(())
 }
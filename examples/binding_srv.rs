@@ -2,24 +2,72 @@
 extern crate trackable;
 
 use clap::Parser;
-use rustun::server::{BindingHandler, UdpServer};
+use factory::{CloneFactory, DefaultFactory};
+use futures::Future;
+use rustun::server::{BindingHandler, CompositeServerBuilder, MultiSocketUdpServer, UdpServer};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use trackable::error::MainError;
 
 #[derive(Debug, Parser)]
 struct Args {
     #[clap(short, long, default_value_t = 3478)]
     port: u16,
+
+    /// If omitted, the server accepts both IPv4 and IPv6 clients (dual-stack).
+    #[clap(short, long)]
+    addr: Option<IpAddr>,
+
+    /// Number of `SO_REUSEPORT` sockets (each socket is driven by a dedicated fiber).
+    #[clap(short, long, default_value_t = 1)]
+    sockets: usize,
+
+    /// Also accepts TCP clients on the same port.
+    #[clap(long)]
+    tcp: bool,
 }
 
 fn main() -> Result<(), MainError> {
     let args = Args::parse();
-    let addr = track_any_err!(format!("0.0.0.0:{}", args.port).parse())?;
+    if args.tcp {
+        let addr = SocketAddr::new(
+            args.addr.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            args.port,
+        );
+        let server = track!(fibers_global::execute(
+            CompositeServerBuilder::new()
+                .udp(addr)
+                .tcp(addr)
+                .start(fibers_global::handle(), CloneFactory::new(BindingHandler))
+        ))?;
+        track!(fibers_global::execute(server))?;
+        return Ok(());
+    }
+    if args.sockets > 1 {
+        let ip = args.addr.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let server = track!(fibers_global::execute(MultiSocketUdpServer::start(
+            fibers_global::handle(),
+            SocketAddr::new(ip, args.port),
+            args.sockets,
+            DefaultFactory::<BindingHandler>::new()
+        )))?;
+        track!(fibers_global::execute(server))?;
+        return Ok(());
+    }
 
-    let server = track!(fibers_global::execute(UdpServer::start(
-        fibers_global::handle(),
-        addr,
-        BindingHandler
-    )))?;
-    track!(fibers_global::execute(server))?;
+    if let Some(ip) = args.addr {
+        let server = track!(fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            SocketAddr::new(ip, args.port),
+            BindingHandler
+        )))?;
+        track!(fibers_global::execute(server.map(|_| ())))?;
+    } else {
+        let server = track!(fibers_global::execute(UdpServer::start_dual_stack(
+            fibers_global::handle(),
+            args.port,
+            BindingHandler
+        )))?;
+        track!(fibers_global::execute(server.map(|_| ())))?;
+    }
     Ok(())
 }
@@ -1,20 +1,25 @@
 //! Channel for sending and receiving STUN messages.
+use crate::clock::{Clock, SystemClock};
 use crate::message::{
     ErrorResponse, Indication, InvalidMessage, MessageError, MessageErrorKind, MessageResult,
     Request, Response, SuccessResponse,
 };
+use crate::timeout_queue::TimeoutQueue;
+use crate::trace::{self, Span};
 use crate::transport::StunTransport;
-use crate::{Error, Result};
-use fibers::sync::oneshot;
-use fibers_timeout_queue::TimeoutQueue;
-use futures::{Async, Future, Poll};
+use crate::{Error, ErrorKind, Result};
+use fibers::sync::{mpsc, oneshot};
+use fibers_transport::Result as TransportResult;
+use futures::{Async, Future, Poll, Stream};
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
 use std::time::Duration;
 use stun_codec::{Attribute, BrokenMessage, Message, MessageClass, Method, TransactionId};
 use trackable::error::ErrorKindExt;
 
 type Reply<A> = oneshot::Monitored<Response<A>, MessageError>;
+type Transaction<A> = (Method, Reply<A>, Span);
 
 /// [`Channel`] builder.
 ///
@@ -22,6 +27,7 @@ type Reply<A> = oneshot::Monitored<Response<A>, MessageError>;
 #[derive(Debug, Clone)]
 pub struct ChannelBuilder {
     request_timeout: Duration,
+    clock: Arc<dyn Clock>,
 }
 impl ChannelBuilder {
     /// The default value of `request_timeout`.
@@ -51,17 +57,29 @@ impl ChannelBuilder {
         self
     }
 
+    /// Sets the clock used for request timeouts of the channel.
+    ///
+    /// The default value is `SystemClock`.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     /// Makes a new `Channel` instance with the given settings.
     pub fn finish<A, T>(&self, transporter: T) -> Channel<A, T>
     where
         A: Attribute,
         T: StunTransport<A>,
     {
+        let (cancel_tx, cancel_rx) = mpsc::channel();
         Channel {
             transporter,
-            timeout_queue: TimeoutQueue::new(),
+            timeout_queue: TimeoutQueue::with_clock(self.clock.clone()),
             request_timeout: self.request_timeout,
             transactions: HashMap::new(),
+            cancel_tx,
+            cancel_rx,
+            fatal_error: None,
         }
     }
 }
@@ -69,10 +87,124 @@ impl Default for ChannelBuilder {
     fn default() -> Self {
         ChannelBuilder {
             request_timeout: Duration::from_millis(Self::DEFAULT_REQUEST_TIMEOUT_MS),
+            clock: Arc::new(SystemClock),
         }
     }
 }
 
+/// Options for a single request/response transaction issued by [`Channel::call_with`].
+///
+/// Unspecified options fall back to the settings of the channel and its transporter.
+///
+/// [`Channel::call_with`]: ./struct.Channel.html#method.call_with
+#[derive(Debug, Default, Clone)]
+pub struct CallOptions {
+    timeout: Option<Duration>,
+    rto: Option<Duration>,
+    max_retransmits: Option<usize>,
+    no_throttle: bool,
+    priority: Priority,
+}
+impl CallOptions {
+    /// Makes a new `CallOptions` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the timeout duration of the transaction.
+    ///
+    /// The default value is the `request_timeout` of the channel.
+    pub fn timeout(&mut self, duration: Duration) -> &mut Self {
+        self.timeout = Some(duration);
+        self
+    }
+
+    /// Sets the initial RTO (Retransmission TimeOut) of the transaction.
+    ///
+    /// The default value is the (cached) RTO of the transporter.
+    ///
+    /// This option is only meaningful for transporters that retransmit requests (e.g., `StunUdpTransporter`).
+    pub fn rto(&mut self, rto: Duration) -> &mut Self {
+        self.rto = Some(rto);
+        self
+    }
+
+    /// Sets the maximum number of retransmissions of the request.
+    ///
+    /// After the last retransmission, the transaction waits for a response until it times out.
+    ///
+    /// By default, the request is retransmitted until the transaction times out.
+    ///
+    /// This option is only meaningful for transporters that retransmit requests (e.g., `StunUdpTransporter`).
+    pub fn max_retransmits(&mut self, max: usize) -> &mut Self {
+        self.max_retransmits = Some(max);
+        self
+    }
+
+    /// If `true` is specified, the request is sent immediately
+    /// regardless of the pacing and concurrency limits of the transporter.
+    ///
+    /// This is useful for requests that are paced by the application itself (e.g., ICE connectivity checks).
+    ///
+    /// The default value is `false`.
+    pub fn no_throttle(&mut self, enabled: bool) -> &mut Self {
+        self.no_throttle = enabled;
+        self
+    }
+
+    /// Sets the priority of the request.
+    ///
+    /// If the request cannot be sent immediately due to the pacing and concurrency limits of the transporter,
+    /// it waits in the queue of the destination peer ahead of the pending requests having lower priorities.
+    ///
+    /// The default value is `Priority::Normal`.
+    pub fn priority(&mut self, priority: Priority) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Returns the timeout duration of the transaction, if specified.
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns the initial RTO of the transaction, if specified.
+    pub fn get_rto(&self) -> Option<Duration> {
+        self.rto
+    }
+
+    /// Returns the maximum number of retransmissions of the request, if specified.
+    pub fn get_max_retransmits(&self) -> Option<usize> {
+        self.max_retransmits
+    }
+
+    /// Returns `true` if the request bypasses the pacing and concurrency limits of the transporter.
+    pub fn is_no_throttle(&self) -> bool {
+        self.no_throttle
+    }
+
+    /// Returns the priority of the request.
+    pub fn get_priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+/// Priority class of a request.
+///
+/// Variants are ordered from the most urgent one (i.e., `Urgent < Normal < Background`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Requests that should be sent as soon as possible (e.g., ICE nominations and consent checks).
+    Urgent,
+
+    /// Ordinary requests.
+    #[default]
+    Normal,
+
+    /// Requests that can be deferred (e.g., bulk probes).
+    Background,
+}
+
 /// Channel for sending and receiving STUN messages.
 pub struct Channel<A, T>
 where
@@ -82,7 +214,10 @@ where
     transporter: T,
     timeout_queue: TimeoutQueue<(T::PeerAddr, TransactionId)>,
     request_timeout: Duration,
-    transactions: HashMap<(T::PeerAddr, TransactionId), (Method, Reply<A>)>,
+    transactions: HashMap<(T::PeerAddr, TransactionId), Transaction<A>>,
+    cancel_tx: mpsc::Sender<(T::PeerAddr, TransactionId)>,
+    cancel_rx: mpsc::Receiver<(T::PeerAddr, TransactionId)>,
+    fatal_error: Option<Error>,
 }
 impl<A, T> fmt::Debug for Channel<A, T>
 where
@@ -107,34 +242,88 @@ where
 
     /// Sends the given request message to the destination peer and
     /// returns a future that waits the corresponding response.
+    ///
+    /// This is equivalent to `self.call_with(peer, request, &CallOptions::default())`.
+    pub fn call(&mut self, peer: T::PeerAddr, request: Request<A>) -> CallResponse<A, T::PeerAddr> {
+        self.call_with(peer, request, &CallOptions::default())
+    }
+
+    /// Sends the given request message to the destination peer with the given per-transaction options and
+    /// returns a future that waits the corresponding response.
+    ///
+    /// If the resulting future is dropped before completion, the transaction is cancelled.
     #[allow(clippy::map_entry)]
-    pub fn call(
+    pub fn call_with(
         &mut self,
         peer: T::PeerAddr,
         request: Request<A>,
-    ) -> impl Future<Item = Response<A>, Error = MessageError> {
+        options: &CallOptions,
+    ) -> CallResponse<A, T::PeerAddr> {
         let id = request.transaction_id();
         let method = request.method();
         let (tx, rx) = oneshot::monitor();
-        if self.transactions.contains_key(&(peer.clone(), id)) {
+        let span = trace::transaction_span(&peer, method, id);
+        let mut started = false;
+        if let Some(e) = self.fatal_error.as_ref() {
+            tx.exit(Err(track!(MessageError::from(e.clone()))));
+        } else if self.transactions.contains_key(&(peer.clone(), id)) {
             let e = MessageErrorKind::InvalidInput
                 .cause(format!("Transaction ID conflicts: transaction_id={id:?}"));
             tx.exit(Err(track!(e).into()));
-        } else if let Err(e) = track!(self
-            .transporter
-            .start_send(peer.clone(), request.into_message()))
-        {
+        } else if let Err(e) = span.in_scope(|| {
+            trace_event!(debug, "Sending request");
+            track!(self.start_request(peer.clone(), request.into_message(), options))
+        }) {
+            trace_event!(debug, parent: span, error = %e, "Cannot send request");
+            tx.exit(Err(e.into()));
+        } else {
+            self.transactions
+                .insert((peer.clone(), id), (method, tx, span));
+            let timeout = options.timeout.unwrap_or(self.request_timeout);
+            self.timeout_queue.push((peer.clone(), id), timeout);
+            started = true;
+        }
+        CallResponse {
+            reply: rx,
+            cancel_handle: CancelHandle {
+                cancel_tx: self.cancel_tx.clone(),
+                peer,
+                transaction_id: id,
+            },
+            cancel_on_drop: started,
+        }
+    }
+
+    /// Cancels the outstanding transaction identified by the given peer and transaction ID.
+    ///
+    /// The future waiting for the response of the transaction will return a `MessageErrorKind::Cancelled` error.
+    ///
+    /// If there is no such transaction, this will return `Ok(false)`.
+    pub fn cancel(&mut self, peer: &T::PeerAddr, transaction_id: TransactionId) -> Result<bool> {
+        if let Some((_, tx, span)) = self.transactions.remove(&(peer.clone(), transaction_id)) {
+            trace_event!(debug, parent: span, "Transaction cancelled");
+            let e = track!(MessageErrorKind::Cancelled.error());
             tx.exit(Err(e.into()));
+            let result = self.transporter.finish_transaction(peer, transaction_id);
+            track!(self.check(result))?;
+            Ok(true)
         } else {
-            self.transactions.insert((peer.clone(), id), (method, tx));
-            self.timeout_queue.push((peer, id), self.request_timeout);
+            Ok(false)
         }
-        rx.map_err(MessageError::from)
     }
 
     /// Sends the given indication message to the destination peer.
     pub fn cast(&mut self, peer: T::PeerAddr, indication: Indication<A>) -> MessageResult<()> {
-        track!(self.transporter.start_send(peer, indication.into_message()))?;
+        track!(self.check_fatal_error())?;
+        trace_event!(
+            debug,
+            peer = ?peer,
+            method = indication.method().as_u16(),
+            transaction_id = ?indication.transaction_id(),
+            "Sending indication"
+        );
+        let result = self.transporter.start_send(peer, indication.into_message());
+        track!(self.check(result))?;
         Ok(())
     }
 
@@ -143,7 +332,17 @@ where
         let message = response
             .map(|m| m.into_message())
             .unwrap_or_else(|m| m.into_message());
-        track!(self.transporter.start_send(peer, message))?;
+        track!(self.check_fatal_error())?;
+        trace_event!(
+            debug,
+            peer = ?peer,
+            class = ?message.class(),
+            method = message.method().as_u16(),
+            transaction_id = ?message.transaction_id(),
+            "Sending response"
+        );
+        let result = self.transporter.start_send(peer, message);
+        track!(self.check(result))?;
         Ok(())
     }
 
@@ -157,6 +356,19 @@ where
         &mut self.transporter
     }
 
+    /// Returns the fatal error that has broken the channel, if any.
+    ///
+    /// If the transporter returns an error that is not transient (see [`StunTransport::is_transient_error`]),
+    /// the channel never uses the transporter again.
+    /// All the outstanding transactions fail with the error, and subsequent operations return the error.
+    ///
+    /// Transient errors are simply returned to the caller, and the channel remains usable.
+    ///
+    /// [`StunTransport::is_transient_error`]: ../transport/trait.StunTransport.html#method.is_transient_error
+    pub fn fatal_error(&self) -> Option<&Error> {
+        self.fatal_error.as_ref()
+    }
+
     /// Returns the number of the outstanding request/response transactions in the channel.
     pub fn outstanding_transactions(&self) -> usize {
         self.transactions.len()
@@ -166,36 +378,94 @@ where
     ///
     /// If it has been completed, this will return `Ok(Async::Ready(()))`.
     pub fn poll_send(&mut self) -> Poll<(), Error> {
-        Ok(track!(self.transporter.poll_send())?)
+        track!(self.check_fatal_error())?;
+        track!(self.handle_cancel_requests())?;
+        let result = self.transporter.poll_send();
+        Ok(track!(self.check(result))?)
     }
 
     /// Polls reception of a message from a peer.
+    ///
+    /// If the transporter has terminated, this will return `Ok(Async::Ready(None))` and
+    /// the channel is regarded as broken.
     #[allow(clippy::type_complexity)]
     pub fn poll_recv(&mut self) -> Poll<Option<(T::PeerAddr, RecvMessage<A>)>, Error> {
+        track!(self.check_fatal_error())?;
+        track!(self.handle_cancel_requests())?;
         track!(self.handle_timeout())?;
-        while let Async::Ready(item) = track!(self.transporter.poll_recv())? {
+        loop {
+            let result = self.transporter.poll_recv();
+            let item = if let Async::Ready(item) = track!(self.check(result))? {
+                item
+            } else {
+                return Ok(Async::NotReady);
+            };
             if let Some((peer, message)) = item {
                 if let Some(item) = track!(self.handle_message(peer, message))? {
                     return Ok(Async::Ready(Some(item)));
                 }
             } else {
+                let e = ErrorKind::Other.cause("Transporter has terminated");
+                self.break_channel(track!(e).into());
                 return Ok(Async::Ready(None));
             }
         }
-        Ok(Async::NotReady)
+    }
+
+    fn start_request(
+        &mut self,
+        peer: T::PeerAddr,
+        request: Message<A>,
+        options: &CallOptions,
+    ) -> TransportResult<()> {
+        let result = self.transporter.start_request(peer, request, options);
+        track!(self.check(result))
+    }
+
+    fn check<U>(&mut self, result: TransportResult<U>) -> TransportResult<U> {
+        if let Err(e) = result.as_ref() {
+            if self.fatal_error.is_none() && !self.transporter.is_transient_error(e) {
+                self.break_channel(track!(Error::from(e.clone())));
+            }
+        }
+        result
+    }
+
+    fn check_fatal_error(&self) -> Result<()> {
+        if let Some(e) = self.fatal_error.as_ref() {
+            Err(track!(e.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn break_channel(&mut self, error: Error) {
+        trace_event!(warn, error = %error, "Channel broken");
+        for (_, (_, tx, _)) in self.transactions.drain() {
+            tx.exit(Err(track!(MessageError::from(error.clone()))));
+        }
+        self.fatal_error = Some(error);
+    }
+
+    fn handle_cancel_requests(&mut self) -> Result<()> {
+        while let Async::Ready(Some((peer, id))) = self.cancel_rx.poll().expect("never fails") {
+            track!(self.cancel(&peer, id))?;
+        }
+        Ok(())
     }
 
     fn handle_timeout(&mut self) -> Result<()> {
-        let transactions = &mut self.transactions;
         while let Some((peer, id)) = self
             .timeout_queue
-            .filter_pop(|entry| transactions.contains_key(entry))
+            .filter_pop(|entry| self.transactions.contains_key(entry))
         {
-            if let Some((_, tx)) = transactions.remove(&(peer.clone(), id)) {
+            if let Some((_, tx, span)) = self.transactions.remove(&(peer.clone(), id)) {
+                trace_event!(debug, parent: span, "Transaction timed out");
                 let e = track!(MessageErrorKind::Timeout.error());
                 tx.exit(Err(e.into()));
             }
-            track!(self.transporter.finish_transaction(&peer, id))?;
+            let result = self.transporter.finish_transaction(&peer, id);
+            track!(self.check(result))?;
         }
         Ok(())
     }
@@ -205,6 +475,24 @@ where
         peer: T::PeerAddr,
         message: std::result::Result<Message<A>, BrokenMessage>,
     ) -> Result<Option<(T::PeerAddr, RecvMessage<A>)>> {
+        #[cfg(feature = "tracing")]
+        match &message {
+            Ok(m) => tracing::debug!(
+                peer = ?peer,
+                class = ?m.class(),
+                method = m.method().as_u16(),
+                transaction_id = ?m.transaction_id(),
+                "Received message"
+            ),
+            Err(m) => tracing::debug!(
+                peer = ?peer,
+                class = ?m.class(),
+                method = m.method().as_u16(),
+                transaction_id = ?m.transaction_id(),
+                error = %m.error(),
+                "Received broken message"
+            ),
+        }
         let message = match message {
             Err(broken) => Some(self.handle_broken_message(&broken)),
             Ok(message) => match message.class() {
@@ -262,17 +550,31 @@ where
         let class = message.class();
         let method = message.method();
         let transaction_id = message.transaction_id();
-        if let Some((method, tx)) = self.transactions.remove(&(peer.clone(), transaction_id)) {
-            track!(self.transporter.finish_transaction(peer, transaction_id))?;
+        if let Some((method, tx, span)) = self.transactions.remove(&(peer.clone(), transaction_id))
+        {
+            let result = self.transporter.finish_transaction(peer, transaction_id);
+            track!(self.check(result))?;
             let result = track!(SuccessResponse::from_message(message))
                 .and_then(|m| {
                     track_assert_eq!(m.method(), method, MessageErrorKind::UnexpectedResponse);
                     Ok(m)
                 })
                 .map(Ok);
+            trace_event!(
+                debug,
+                parent: span,
+                valid = result.is_ok(),
+                "Transaction completed"
+            );
             tx.exit(result);
             Ok(None)
         } else {
+            trace_event!(
+                debug,
+                peer = ?peer,
+                transaction_id = ?transaction_id,
+                "Unexpected response"
+            );
             let error =
                 track!(MessageErrorKind::UnexpectedResponse.cause("Unknown transaction ID")).into();
             let message =
@@ -289,17 +591,31 @@ where
         let class = message.class();
         let method = message.method();
         let transaction_id = message.transaction_id();
-        if let Some((method, tx)) = self.transactions.remove(&(peer.clone(), transaction_id)) {
-            track!(self.transporter.finish_transaction(peer, transaction_id))?;
+        if let Some((method, tx, span)) = self.transactions.remove(&(peer.clone(), transaction_id))
+        {
+            let result = self.transporter.finish_transaction(peer, transaction_id);
+            track!(self.check(result))?;
             let result = track!(ErrorResponse::from_message(message))
                 .and_then(|m| {
                     track_assert_eq!(m.method(), method, MessageErrorKind::UnexpectedResponse);
                     Ok(m)
                 })
                 .map(Err);
+            trace_event!(
+                debug,
+                parent: span,
+                valid = result.is_ok(),
+                "Transaction completed"
+            );
             tx.exit(result);
             Ok(None)
         } else {
+            trace_event!(
+                debug,
+                peer = ?peer,
+                transaction_id = ?transaction_id,
+                "Unexpected response"
+            );
             let error =
                 track!(MessageErrorKind::UnexpectedResponse.cause("Unknown transaction ID")).into();
             let message =
@@ -309,6 +625,85 @@ where
     }
 }
 
+/// Future that waits for the response of a request sent by [`Channel::call`] or [`Channel::call_with`].
+///
+/// If this is dropped before completion, the corresponding transaction is cancelled
+/// (the cancellation takes effect the next time the channel is polled).
+///
+/// [`Channel::call`]: ./struct.Channel.html#method.call
+/// [`Channel::call_with`]: ./struct.Channel.html#method.call_with
+#[must_use = "futures do nothing unless polled"]
+pub struct CallResponse<A, P: Clone> {
+    reply: oneshot::Monitor<Response<A>, MessageError>,
+    cancel_handle: CancelHandle<P>,
+    cancel_on_drop: bool,
+}
+impl<A, P: Clone> CallResponse<A, P> {
+    /// Returns a handle for cancelling the transaction.
+    pub fn cancel_handle(&self) -> CancelHandle<P> {
+        self.cancel_handle.clone()
+    }
+}
+impl<A, P: Clone> Future for CallResponse<A, P> {
+    type Item = Response<A>;
+    type Error = MessageError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.reply.poll().map_err(MessageError::from);
+        if !matches!(result, Ok(Async::NotReady)) {
+            self.cancel_on_drop = false;
+        }
+        result
+    }
+}
+impl<A, P: Clone> Drop for CallResponse<A, P> {
+    fn drop(&mut self) {
+        if self.cancel_on_drop {
+            self.cancel_handle.cancel();
+        }
+    }
+}
+impl<A, P: Clone + fmt::Debug> fmt::Debug for CallResponse<A, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CallResponse {{ cancel_handle: {:?}, .. }}",
+            self.cancel_handle
+        )
+    }
+}
+
+/// Handle for cancelling an outstanding transaction of a [`Channel`].
+///
+/// [`Channel`]: ./struct.Channel.html
+#[derive(Debug, Clone)]
+pub struct CancelHandle<P> {
+    cancel_tx: mpsc::Sender<(P, TransactionId)>,
+    peer: P,
+    transaction_id: TransactionId,
+}
+impl<P: Clone> CancelHandle<P> {
+    /// Requests the channel to cancel the transaction.
+    ///
+    /// The cancellation takes effect the next time the channel is polled.
+    /// If the transaction has already finished, this does nothing.
+    pub fn cancel(&self) {
+        let _ = self
+            .cancel_tx
+            .send((self.peer.clone(), self.transaction_id));
+    }
+
+    /// Returns the destination peer of the transaction.
+    pub fn peer(&self) -> &P {
+        &self.peer
+    }
+
+    /// Returns the ID of the transaction.
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+}
+
 /// Received message.
 ///
 /// Messages are received by calling `Channel::poll` method.
@@ -319,3 +714,139 @@ pub enum RecvMessage<A> {
     Indication(Indication<A>),
     Invalid(InvalidMessage),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::{SimulatedNetwork, StunUdpTransporterBuilder};
+    use futures::future;
+    use stun_codec::rfc5389;
+    use trackable::error::MainError;
+
+    #[test]
+    fn request_timeout_works() -> std::result::Result<(), MainError> {
+        let clock = ManualClock::new();
+        let network = SimulatedNetwork::new();
+        let mut channel = ChannelBuilder::new()
+            .request_timeout(Duration::from_secs(1))
+            .clock(clock.clone())
+            .finish(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let mut response = channel.call(peer, request);
+            track!(channel.poll_send())?;
+
+            clock.advance(Duration::from_millis(999));
+            track!(channel.poll_recv())?;
+            assert!(matches!(response.poll(), Ok(Async::NotReady)));
+            assert_eq!(channel.outstanding_transactions(), 1);
+
+            clock.advance(Duration::from_millis(1));
+            track!(channel.poll_recv())?;
+            let e = response.poll().err().map(|e| e.kind().clone());
+            assert!(matches!(e, Some(MessageErrorKind::Timeout)));
+            assert_eq!(channel.outstanding_transactions(), 0);
+            Ok(())
+        }));
+        track!(result)?;
+        Ok(())
+    }
+
+    #[test]
+    fn per_call_timeout_works() -> std::result::Result<(), MainError> {
+        let clock = ManualClock::new();
+        let network = SimulatedNetwork::new();
+        let mut channel = ChannelBuilder::new()
+            .request_timeout(Duration::from_secs(1))
+            .clock(clock.clone())
+            .finish(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            let options = CallOptions::new()
+                .timeout(Duration::from_millis(100))
+                .clone();
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let mut short = channel.call_with(peer, request, &options);
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let mut long = channel.call(peer, request);
+            track!(channel.poll_send())?;
+
+            clock.advance(Duration::from_millis(100));
+            track!(channel.poll_recv())?;
+            assert!(short.poll().is_err());
+            assert!(matches!(long.poll(), Ok(Async::NotReady)));
+            assert_eq!(channel.outstanding_transactions(), 1);
+            Ok(())
+        }));
+        track!(result)?;
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_works() -> std::result::Result<(), MainError> {
+        let network = SimulatedNetwork::new();
+        let mut channel = Channel::new(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let mut response = channel.call(peer, request);
+            let handle = response.cancel_handle();
+            assert_eq!(channel.outstanding_transactions(), 1);
+
+            handle.cancel();
+            track!(channel.poll_recv())?;
+            let e = response.poll().err().map(|e| e.kind().clone());
+            assert!(matches!(e, Some(MessageErrorKind::Cancelled)));
+            assert_eq!(channel.outstanding_transactions(), 0);
+            assert!(!track!(channel.cancel(&peer, handle.transaction_id()))?);
+            Ok(())
+        }));
+        track!(result)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dropping_response_future_cancels_transaction() -> std::result::Result<(), MainError> {
+        let clock = ManualClock::new();
+        let network = SimulatedNetwork::new();
+        let transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_millis(100))
+            .max_outstanding_transactions(1)
+            .min_transaction_interval(Duration::from_secs(0))
+            .clock(clock.clone())
+            .finish(track!(network.bind_any())?);
+        let mut channel = ChannelBuilder::new()
+            .clock(clock.clone())
+            .finish(transporter);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let first = channel.call(peer, request);
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let mut second = channel.call(peer, request);
+            track!(channel.poll_send())?;
+            assert_eq!(network.stats().sent, 1);
+
+            // The pending request is sent as soon as the first one is cancelled
+            std::mem::drop(first);
+            track!(channel.poll_send())?;
+            assert_eq!(network.stats().sent, 2);
+            assert_eq!(channel.outstanding_transactions(), 1);
+
+            // Only the second request is retransmitted
+            clock.advance(Duration::from_millis(100));
+            track!(channel.poll_send())?;
+            assert_eq!(network.stats().sent, 3);
+            assert!(matches!(second.poll(), Ok(Async::NotReady)));
+            Ok(())
+        }));
+        track!(result)?;
+        Ok(())
+    }
+}
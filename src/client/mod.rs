@@ -0,0 +1,433 @@
+//! Basic STUN client.
+//!
+//! This module provides only a basic STUN client.
+//! If you want more elaborate one, please consider create your own client using [`Channel`] directly.
+//!
+//! [`Channel`]: ../channel/struct.Channel.html
+//!
+//! This module also provides [`ServerResolver`] that discovers STUN servers using DNS SRV records.
+//!
+//! [`ServerResolver`]: ./struct.ServerResolver.html
+//!
+//! For TCP, [`TcpClientPool`] manages connections to many servers (including reconnection).
+//!
+//! [`TcpClientPool`]: ./struct.TcpClientPool.html
+use crate::channel::{CallOptions, Channel};
+use crate::message::{Indication, Request, Response};
+use crate::transport::StunTransport;
+use crate::{Error, ErrorKind, Result};
+use fibers::sync::{mpsc, oneshot};
+use fibers::Spawn;
+use futures::future::{self, Loop};
+use futures::stream::Fuse;
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use stun_codec::{Attribute, TransactionId};
+use trackable::error::ErrorKindExt;
+
+pub use self::pool::{Connect, TcpClientPool, TcpClientPoolBuilder, TcpConnector};
+pub use self::resolver::{
+    AddressFamily, DnsResolver, ResolveFuture, ResolveSrv, ServerResolver, ServerTransport,
+    SrvRecord, StaticResolver,
+};
+
+mod pool;
+mod resolver;
+
+/// STUN client.
+///
+/// Transient errors of the underlying transporter (see [`StunTransport::is_transient_error`])
+/// do not stop the client, and only affect the messages being sent or received at that time.
+/// The current state of the client can be checked by [`Client::health`].
+///
+/// Note that which errors are transient depends on the transporter.
+/// For example, UDP clients recover from I/O errors such as `ENETUNREACH` only if they use [`StdUdpTransporter`],
+/// because `fibers_transport::UdpTransporter` cannot be used after any I/O error.
+///
+/// [`StunTransport::is_transient_error`]: ../transport/trait.StunTransport.html#method.is_transient_error
+/// [`Client::health`]: ./struct.Client.html#method.health
+/// [`StdUdpTransporter`]: ../transport/struct.StdUdpTransporter.html
+#[derive(Debug, Clone)]
+pub struct Client<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    command_tx: mpsc::Sender<Command<A, T::PeerAddr>>,
+    health: HealthState,
+    _phantom: PhantomData<T>,
+}
+impl<A, T> Client<A, T>
+where
+    A: Attribute + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    /// Makes a new `Client` instance that uses the given channel for sending/receiving messages.
+    pub fn new<S>(spawner: &S, channel: Channel<A, T>) -> Self
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (command_tx, command_rx) = mpsc::channel();
+        let health = HealthState::default();
+        let channel_driver = ChannelDriver {
+            spawner: spawner.clone(),
+            channel,
+            command_rx: command_rx.fuse(),
+            health: health.clone(),
+        };
+        spawner.spawn(channel_driver);
+        Client {
+            command_tx,
+            health,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the current health state of the client.
+    pub fn health(&self) -> ClientHealth {
+        self.health.get()
+    }
+
+    /// Sends the given request message to the destination peer and
+    /// returns a future that waits the corresponding response.
+    ///
+    /// This is equivalent to `self.call_with(peer, request, &CallOptions::default())`.
+    pub fn call(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+    ) -> impl Future<Item = Response<A>, Error = Error> {
+        self.call_with(peer, request, &CallOptions::default())
+    }
+
+    /// Sends the given request message to the destination peer with the given per-transaction options and
+    /// returns a future that waits the corresponding response.
+    ///
+    /// See [`CallOptions`] for the available options.
+    ///
+    /// If the resulting future is dropped before completion, the transaction is cancelled.
+    ///
+    /// [`CallOptions`]: ../channel/struct.CallOptions.html
+    pub fn call_with(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+        options: &CallOptions,
+    ) -> impl Future<Item = Response<A>, Error = Error> {
+        let (tx, rx) = oneshot::monitor();
+        let guard = CancelOnDrop {
+            command_tx: self.command_tx.clone(),
+            peer: peer.clone(),
+            transaction_id: request.transaction_id(),
+            armed: true,
+        };
+        let command = Command::Call(peer, request, options.clone(), tx);
+        track!(self.command_tx.send(command).map_err(Error::from))
+            .into_future()
+            .and_then(move |()| rx.map_err(|e| track!(Error::from(e))))
+            .then(move |result| {
+                guard.disarm();
+                result
+            })
+    }
+
+    /// Cancels the outstanding transaction identified by the given peer and transaction ID.
+    ///
+    /// The future waiting for the response of the transaction will return
+    /// an `ErrorKind::InvalidMessage(MessageErrorKind::Cancelled)` error.
+    /// If there is no such transaction, this does nothing.
+    ///
+    /// # Errors
+    ///
+    /// If the channel being used by the client has dropped,
+    /// this will return an `ErrorKind::Other` error.
+    pub fn cancel(&self, peer: T::PeerAddr, transaction_id: TransactionId) -> Result<()> {
+        let command = Command::Cancel(peer, transaction_id);
+        track!(self.command_tx.send(command).map_err(Error::from))
+    }
+
+    /// Sends a request message to the candidate peers in order until one of them responds.
+    ///
+    /// A request is made by `make_request` for each candidate.
+    /// If a transaction fails (e.g., timed out), a new request is sent to the next candidate.
+    /// Thus each request should have a new transaction ID,
+    /// and the attributes depending on it (e.g., `MESSAGE-INTEGRITY` and `FINGERPRINT`)
+    /// should be computed in `make_request`.
+    /// Note that error responses are not regarded as failures.
+    ///
+    /// The resulting future returns the peer that responded and the response.
+    /// Candidates are typically obtained by `ServerResolver::resolve`.
+    pub fn call_with_failover<I, F>(
+        &self,
+        candidates: I,
+        mut make_request: F,
+    ) -> impl Future<Item = (T::PeerAddr, Response<A>), Error = Error>
+    where
+        I: IntoIterator<Item = T::PeerAddr>,
+        F: FnMut() -> Request<A>,
+    {
+        let command_tx = self.command_tx.clone();
+        let health = self.health.clone();
+        let candidates = candidates.into_iter().collect::<VecDeque<_>>();
+        let error = track!(ErrorKind::Other.cause("No candidates")).into();
+        future::loop_fn(
+            (candidates, error),
+            move |(mut candidates, error): (_, Error)| {
+                let peer = if let Some(peer) = candidates.pop_front() {
+                    peer
+                } else {
+                    return future::Either::A(future::err(error));
+                };
+                let client = Client::<A, T> {
+                    command_tx: command_tx.clone(),
+                    health: health.clone(),
+                    _phantom: PhantomData,
+                };
+                let future = client
+                    .call(peer.clone(), make_request())
+                    .then(move |result| match result {
+                        Ok(response) => Ok(Loop::Break((peer, response))),
+                        Err(e) => Ok(Loop::Continue((candidates, track!(e)))),
+                    });
+                future::Either::B(future)
+            },
+        )
+    }
+
+    /// Sends the given indication message to the destination peer.
+    ///
+    /// # Errors
+    ///
+    /// If the channel being used by the client has dropped,
+    /// this will return an `ErrorKind::Other` error.
+    pub fn cast(&self, peer: T::PeerAddr, indication: Indication<A>) -> Result<()> {
+        let command = Command::Cast(peer, indication);
+        track!(self.command_tx.send(command).map_err(Error::from))
+    }
+}
+
+struct CancelOnDrop<A, P: Clone> {
+    command_tx: mpsc::Sender<Command<A, P>>,
+    peer: P,
+    transaction_id: TransactionId,
+    armed: bool,
+}
+impl<A, P: Clone> CancelOnDrop<A, P> {
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+impl<A, P: Clone> Drop for CancelOnDrop<A, P> {
+    fn drop(&mut self) {
+        if self.armed {
+            let command = Command::Cancel(self.peer.clone(), self.transaction_id);
+            let _ = self.command_tx.send(command);
+        }
+    }
+}
+
+enum Command<A, P> {
+    Call(
+        P,
+        Request<A>,
+        CallOptions,
+        oneshot::Monitored<Response<A>, Error>,
+    ),
+    Cast(P, Indication<A>),
+    Cancel(P, TransactionId),
+}
+impl<A, P> fmt::Debug for Command<A, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Call(..) => write!(f, "Call(..)"),
+            Command::Cast(..) => write!(f, "Cast(..)"),
+            Command::Cancel(..) => write!(f, "Cancel(..)"),
+        }
+    }
+}
+
+/// Health state of a [`Client`].
+///
+/// [`Client`]: ./struct.Client.html
+#[derive(Debug, Clone)]
+pub enum ClientHealth {
+    /// The client is working without errors.
+    Healthy,
+
+    /// The client has encountered transient errors since the last completed transaction.
+    ///
+    /// The client is still usable, but some messages may have been lost
+    /// (lost requests are retransmitted or time out as usual).
+    Degraded {
+        /// The number of the transient errors.
+        errors: usize,
+
+        /// The last transient error.
+        last_error: Error,
+    },
+
+    /// The client has encountered a fatal error.
+    ///
+    /// All the outstanding and subsequent transactions fail with the error.
+    Failed(Error),
+}
+impl ClientHealth {
+    /// Returns `true` if the state is `ClientHealth::Healthy`.
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, ClientHealth::Healthy)
+    }
+
+    /// Returns `true` if the state is `ClientHealth::Failed`.
+    pub fn is_failed(&self) -> bool {
+        matches!(self, ClientHealth::Failed(_))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HealthState(Arc<Mutex<ClientHealth>>);
+impl HealthState {
+    fn get(&self) -> ClientHealth {
+        self.0.lock().expect("never fails").clone()
+    }
+
+    fn transaction_completed(&self) {
+        let mut health = self.0.lock().expect("never fails");
+        if let ClientHealth::Degraded { .. } = *health {
+            *health = ClientHealth::Healthy;
+        }
+    }
+
+    fn transient_error(&self, error: Error) {
+        let mut health = self.0.lock().expect("never fails");
+        let errors = match *health {
+            ClientHealth::Healthy => 0,
+            ClientHealth::Degraded { errors, .. } => errors,
+            ClientHealth::Failed(_) => return,
+        };
+        *health = ClientHealth::Degraded {
+            errors: errors + 1,
+            last_error: error,
+        };
+    }
+
+    fn fatal_error(&self, error: Error) {
+        *self.0.lock().expect("never fails") = ClientHealth::Failed(error);
+    }
+}
+impl Default for HealthState {
+    fn default() -> Self {
+        HealthState(Arc::new(Mutex::new(ClientHealth::Healthy)))
+    }
+}
+
+struct ChannelDriver<S, A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    spawner: S,
+    channel: Channel<A, T>,
+    command_rx: Fuse<mpsc::Receiver<Command<A, T::PeerAddr>>>,
+    health: HealthState,
+}
+impl<S, A, T> ChannelDriver<S, A, T>
+where
+    S: Spawn,
+    A: Attribute + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    fn handle_command(&mut self, command: Command<A, T::PeerAddr>) {
+        match command {
+            Command::Cast(peer, indication) => {
+                if let Err(e) = track!(self.channel.cast(peer, indication)) {
+                    self.handle_error(e.into());
+                }
+            }
+            Command::Cancel(peer, transaction_id) => {
+                if let Err(e) = track!(self.channel.cancel(&peer, transaction_id)) {
+                    self.handle_error(e);
+                }
+            }
+            Command::Call(peer, request, options, reply) => {
+                let health = self.health.clone();
+                let future = self
+                    .channel
+                    .call_with(peer, request, &options)
+                    .map_err(Error::from)
+                    .then(move |result| {
+                        if result.is_ok() {
+                            health.transaction_completed();
+                        }
+                        reply.exit(track!(result));
+                        Ok(())
+                    });
+                self.spawner.spawn(future);
+                if let Some(e) = self.channel.fatal_error() {
+                    self.health.fatal_error(e.clone());
+                }
+            }
+        }
+    }
+
+    fn handle_error(&mut self, error: Error) {
+        if let Some(e) = self.channel.fatal_error() {
+            self.health.fatal_error(e.clone());
+        } else {
+            self.health.transient_error(error);
+        }
+    }
+
+    fn poll_channel(&mut self) {
+        while self.channel.fatal_error().is_none() {
+            match track!(self.channel.poll_recv()) {
+                Err(e) => self.handle_error(e),
+                Ok(Async::NotReady) => break,
+                Ok(Async::Ready(None)) => {
+                    let e = self.channel.fatal_error().cloned().expect("never fails");
+                    self.health.fatal_error(e);
+                }
+                Ok(Async::Ready(Some(_message))) => {
+                    // All received messages are ignored
+                }
+            }
+        }
+        while self.channel.fatal_error().is_none() {
+            match track!(self.channel.poll_send()) {
+                Err(e) => self.handle_error(e),
+                Ok(_) => break,
+            }
+        }
+    }
+}
+impl<S, A, T> Future for ChannelDriver<S, A, T>
+where
+    S: Spawn,
+    A: Attribute + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(command) = self.command_rx.poll().expect("never fails") {
+            if let Some(command) = command {
+                self.handle_command(command);
+            } else {
+                // All clients have dropped
+                if self.channel.outstanding_transactions() == 0 {
+                    return Ok(Async::Ready(()));
+                } else {
+                    break;
+                }
+            }
+        }
+        self.poll_channel();
+        Ok(Async::NotReady)
+    }
+}
@@ -0,0 +1,541 @@
+use crate::channel::{CallOptions, Channel};
+use crate::clock::{Clock, SystemClock, Timeout};
+use crate::message::{Indication, Request, Response};
+use crate::transport::{StunTcpTransporter, StunTransport};
+use crate::{Error, ErrorKind, Result};
+use fibers::sync::{mpsc, oneshot};
+use fibers::Spawn;
+use fibers_transport::TcpTransporter;
+use futures::stream::Fuse;
+use futures::{Async, Future, Poll, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use stun_codec::{Attribute, MessageDecoder, MessageEncoder};
+use trackable::error::ErrorKindExt;
+
+/// This trait allows for establishing connections used by [`TcpClientPool`].
+///
+/// [`TcpConnector`] is the default implementation that makes plain TCP connections.
+/// Other stream based transports (e.g., TLS) can be used by implementing this trait.
+///
+/// [`TcpClientPool`]: ./struct.TcpClientPool.html
+/// [`TcpConnector`]: ./struct.TcpConnector.html
+pub trait Connect<A: Attribute> {
+    /// The transporter of an established connection.
+    type Transporter: StunTransport<A, PeerAddr = ()> + Send + 'static;
+
+    /// The future that establishes a connection.
+    type Future: Future<Item = Self::Transporter, Error = Error> + Send + 'static;
+
+    /// Starts connecting to the given peer.
+    fn connect(&self, peer: SocketAddr) -> Self::Future;
+}
+
+/// [`Connect`] implementation that makes plain TCP connections.
+///
+/// [`Connect`]: ./trait.Connect.html
+pub struct TcpConnector<A>(PhantomData<fn() -> A>);
+impl<A> TcpConnector<A> {
+    /// Makes a new `TcpConnector` instance.
+    pub fn new() -> Self {
+        TcpConnector(PhantomData)
+    }
+}
+impl<A> Default for TcpConnector<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<A> Clone for TcpConnector<A> {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+impl<A> fmt::Debug for TcpConnector<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TcpConnector")
+    }
+}
+impl<A> Connect<A> for TcpConnector<A>
+where
+    A: Attribute + Send + 'static,
+    A::Decoder: Send + 'static,
+    A::Encoder: Send + 'static,
+{
+    type Transporter = StunTcpTransporter<TcpTransporter<MessageEncoder<A>, MessageDecoder<A>>>;
+    type Future = Box<dyn Future<Item = Self::Transporter, Error = Error> + Send + 'static>;
+
+    fn connect(&self, peer: SocketAddr) -> Self::Future {
+        Box::new(
+            TcpTransporter::connect(peer)
+                .map_err(|e| track!(Error::from(e)))
+                .map(StunTcpTransporter::new),
+        )
+    }
+}
+
+/// [`TcpClientPool`] builder.
+///
+/// [`TcpClientPool`]: ./struct.TcpClientPool.html
+#[derive(Debug, Clone)]
+pub struct TcpClientPoolBuilder {
+    idle_timeout: Duration,
+    connect_timeout: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_connect_attempts: usize,
+    clock: Arc<dyn Clock>,
+}
+impl TcpClientPoolBuilder {
+    /// The default value of `idle_timeout`.
+    pub const DEFAULT_IDLE_TIMEOUT_MS: u64 = 60 * 1000;
+
+    /// The default value of `connect_timeout`.
+    pub const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5 * 1000;
+
+    /// The default value of `initial_backoff`.
+    pub const DEFAULT_INITIAL_BACKOFF_MS: u64 = 100;
+
+    /// The default value of `max_backoff`.
+    pub const DEFAULT_MAX_BACKOFF_MS: u64 = 10 * 1000;
+
+    /// The default value of `max_connect_attempts`.
+    pub const DEFAULT_MAX_CONNECT_ATTEMPTS: usize = 3;
+
+    /// Makes a new `TcpClientPoolBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the duration after which connections that have no outstanding transactions are closed.
+    ///
+    /// The default value is `DEFAULT_IDLE_TIMEOUT_MS` milliseconds.
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the timeout of a connection attempt.
+    ///
+    /// The default value is `DEFAULT_CONNECT_TIMEOUT_MS` milliseconds.
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the delay before the first reconnection attempt.
+    ///
+    /// The delay is doubled after each consecutive failure up to `max_backoff`.
+    ///
+    /// The default value is `DEFAULT_INITIAL_BACKOFF_MS` milliseconds.
+    pub fn initial_backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Sets the maximum delay between reconnection attempts.
+    ///
+    /// The default value is `DEFAULT_MAX_BACKOFF_MS` milliseconds.
+    pub fn max_backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Sets the maximum number of consecutive connection attempts to a peer.
+    ///
+    /// If all of the attempts fail, the requests waiting for the connection fail.
+    ///
+    /// The default value is `DEFAULT_MAX_CONNECT_ATTEMPTS`.
+    pub fn max_connect_attempts(&mut self, max: usize) -> &mut Self {
+        self.max_connect_attempts = max;
+        self
+    }
+
+    /// Sets the clock used for connect timeouts, reconnection backoffs and idle timeouts of the resulting instance.
+    ///
+    /// The default value is `SystemClock`.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Makes a new `TcpClientPool` instance that makes plain TCP connections.
+    pub fn finish<A, S>(&self, spawner: &S) -> TcpClientPool<A>
+    where
+        A: Attribute + Send + 'static,
+        A::Decoder: Send + 'static,
+        A::Encoder: Send + 'static,
+        S: Spawn + Clone + Send + 'static,
+    {
+        self.finish_with_connector(spawner, TcpConnector::new())
+    }
+
+    /// Makes a new `TcpClientPool` instance that uses the given connector.
+    pub fn finish_with_connector<A, S, C>(&self, spawner: &S, connector: C) -> TcpClientPool<A>
+    where
+        A: Attribute + Send + 'static,
+        S: Spawn + Clone + Send + 'static,
+        C: Connect<A> + Send + 'static,
+    {
+        let (command_tx, command_rx) = mpsc::channel();
+        let connection_count = Arc::new(AtomicUsize::new(0));
+        let driver = PoolDriver {
+            spawner: spawner.clone(),
+            connector,
+            options: self.clone(),
+            command_rx: command_rx.fuse(),
+            connections: HashMap::new(),
+            connection_count: connection_count.clone(),
+        };
+        spawner.spawn(driver);
+        TcpClientPool {
+            command_tx,
+            connection_count,
+        }
+    }
+
+    fn backoff(&self, failures: usize) -> Duration {
+        let exp = failures.saturating_sub(1).min(31) as u32;
+        self.initial_backoff
+            .checked_mul(1 << exp)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+}
+impl Default for TcpClientPoolBuilder {
+    fn default() -> Self {
+        TcpClientPoolBuilder {
+            idle_timeout: Duration::from_millis(Self::DEFAULT_IDLE_TIMEOUT_MS),
+            connect_timeout: Duration::from_millis(Self::DEFAULT_CONNECT_TIMEOUT_MS),
+            initial_backoff: Duration::from_millis(Self::DEFAULT_INITIAL_BACKOFF_MS),
+            max_backoff: Duration::from_millis(Self::DEFAULT_MAX_BACKOFF_MS),
+            max_connect_attempts: Self::DEFAULT_MAX_CONNECT_ATTEMPTS,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// STUN client that sends requests to many servers over pooled TCP (or other stream based) connections.
+///
+/// A connection to a server is established lazily when the first request to the server is issued,
+/// and is closed after being idle for a while.
+///
+/// Requests issued while the pool is (re)connecting to the server are queued.
+/// If a connection attempt fails, the pool retries with exponential backoff,
+/// and the queued requests are sent after the connection is established.
+/// On the other hand, transactions that have been sent over a connection fail when the connection is closed.
+#[derive(Debug, Clone)]
+pub struct TcpClientPool<A: Attribute> {
+    command_tx: mpsc::Sender<Command<A>>,
+    connection_count: Arc<AtomicUsize>,
+}
+impl<A> TcpClientPool<A>
+where
+    A: Attribute + Send + 'static,
+    A::Decoder: Send + 'static,
+    A::Encoder: Send + 'static,
+{
+    /// Makes a new `TcpClientPool` instance with the default settings.
+    ///
+    /// This is equivalent to `TcpClientPoolBuilder::new().finish(spawner)`.
+    pub fn new<S>(spawner: &S) -> Self
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        TcpClientPoolBuilder::new().finish(spawner)
+    }
+}
+impl<A> TcpClientPool<A>
+where
+    A: Attribute + Send + 'static,
+{
+    /// Sends the given request message to the destination peer and
+    /// returns a future that waits the corresponding response.
+    ///
+    /// This is equivalent to `self.call_with(peer, request, &CallOptions::default())`.
+    pub fn call(
+        &self,
+        peer: SocketAddr,
+        request: Request<A>,
+    ) -> impl Future<Item = Response<A>, Error = Error> {
+        self.call_with(peer, request, &CallOptions::default())
+    }
+
+    /// Sends the given request message to the destination peer with the given per-transaction options and
+    /// returns a future that waits the corresponding response.
+    ///
+    /// See [`CallOptions`] for the available options.
+    ///
+    /// [`CallOptions`]: ../channel/struct.CallOptions.html
+    pub fn call_with(
+        &self,
+        peer: SocketAddr,
+        request: Request<A>,
+        options: &CallOptions,
+    ) -> impl Future<Item = Response<A>, Error = Error> {
+        let (tx, rx) = oneshot::monitor();
+        let command = Command::Call(peer, request, options.clone(), tx);
+        let sent = track!(self.command_tx.send(command).map_err(Error::from));
+        futures::future::result(sent).and_then(move |()| rx.map_err(|e| track!(Error::from(e))))
+    }
+
+    /// Sends the given indication message to the destination peer.
+    ///
+    /// # Errors
+    ///
+    /// If the pool has terminated, this will return an `ErrorKind::Other` error.
+    pub fn cast(&self, peer: SocketAddr, indication: Indication<A>) -> Result<()> {
+        let command = Command::Cast(peer, indication);
+        track!(self.command_tx.send(command).map_err(Error::from))
+    }
+
+    /// Returns the number of the connections currently established by the pool.
+    pub fn connection_count(&self) -> usize {
+        self.connection_count.load(Ordering::SeqCst)
+    }
+}
+
+enum Command<A> {
+    Call(
+        SocketAddr,
+        Request<A>,
+        CallOptions,
+        oneshot::Monitored<Response<A>, Error>,
+    ),
+    Cast(SocketAddr, Indication<A>),
+}
+impl<A> Command<A> {
+    fn peer(&self) -> SocketAddr {
+        match self {
+            Command::Call(peer, ..) | Command::Cast(peer, ..) => *peer,
+        }
+    }
+}
+impl<A> fmt::Debug for Command<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Call(..) => write!(f, "Call(..)"),
+            Command::Cast(..) => write!(f, "Cast(..)"),
+        }
+    }
+}
+
+struct PoolDriver<S, A, C>
+where
+    A: Attribute,
+    C: Connect<A>,
+{
+    spawner: S,
+    connector: C,
+    options: TcpClientPoolBuilder,
+    command_rx: Fuse<mpsc::Receiver<Command<A>>>,
+    connections: HashMap<SocketAddr, Connection<A, C>>,
+    connection_count: Arc<AtomicUsize>,
+}
+impl<S, A, C> Future for PoolDriver<S, A, C>
+where
+    S: Spawn,
+    A: Attribute + Send + 'static,
+    C: Connect<A>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(Some(command)) = self.command_rx.poll().expect("never fails") {
+            self.connections
+                .entry(command.peer())
+                .or_insert_with(Connection::new)
+                .pending
+                .push_back(command);
+        }
+
+        let mut connected = 0;
+        let peers = self.connections.keys().cloned().collect::<Vec<_>>();
+        for peer in peers {
+            let connection = self.connections.get_mut(&peer).expect("never fails");
+            let alive = connection.poll(peer, &self.spawner, &self.connector, &self.options);
+            if !alive {
+                self.connections.remove(&peer);
+            } else if connection.is_connected() {
+                connected += 1;
+            }
+        }
+        self.connection_count.store(connected, Ordering::SeqCst);
+
+        if self.command_rx.is_done() && self.connections.is_empty() {
+            // All handles have dropped and there are no outstanding transactions
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+enum ConnectionState<A, C>
+where
+    A: Attribute,
+    C: Connect<A>,
+{
+    Disconnected,
+    Connecting(C::Future, Timeout),
+    Backoff(Timeout),
+    Connected(Box<Channel<A, C::Transporter>>, Option<Timeout>),
+}
+
+struct Connection<A, C>
+where
+    A: Attribute,
+    C: Connect<A>,
+{
+    state: ConnectionState<A, C>,
+    pending: VecDeque<Command<A>>,
+    failures: usize,
+}
+impl<A, C> Connection<A, C>
+where
+    A: Attribute + Send + 'static,
+    C: Connect<A>,
+{
+    fn new() -> Self {
+        Connection {
+            state: ConnectionState::Disconnected,
+            pending: VecDeque::new(),
+            failures: 0,
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        matches!(self.state, ConnectionState::Connected(..))
+    }
+
+    /// Drives the connection and returns `false` if the connection is no longer needed.
+    fn poll<S: Spawn>(
+        &mut self,
+        peer: SocketAddr,
+        spawner: &S,
+        connector: &C,
+        options: &TcpClientPoolBuilder,
+    ) -> bool {
+        loop {
+            match &mut self.state {
+                ConnectionState::Disconnected => {
+                    if self.pending.is_empty() {
+                        return false;
+                    }
+                    let timeout = options.clock.timeout(options.connect_timeout);
+                    self.state = ConnectionState::Connecting(connector.connect(peer), timeout);
+                }
+                ConnectionState::Connecting(future, timeout) => {
+                    let result = match future.poll() {
+                        Err(e) => Err(track!(e)),
+                        Ok(Async::Ready(transporter)) => Ok(transporter),
+                        Ok(Async::NotReady) => {
+                            if let Ok(Async::NotReady) = timeout.poll() {
+                                return true;
+                            }
+                            let e = ErrorKind::Other.cause(format!("Connect timeout: peer={peer}"));
+                            Err(track!(Error::from(e)))
+                        }
+                    };
+                    match result {
+                        Err(e) => self.handle_connect_error(e, options),
+                        Ok(transporter) => {
+                            self.failures = 0;
+                            self.state = ConnectionState::Connected(
+                                Box::new(Channel::new(transporter)),
+                                None,
+                            );
+                        }
+                    }
+                }
+                ConnectionState::Backoff(timeout) => {
+                    if let Ok(Async::NotReady) = timeout.poll() {
+                        return true;
+                    }
+                    self.state = ConnectionState::Disconnected;
+                }
+                ConnectionState::Connected(channel, idle_timer) => {
+                    while let Some(command) = self.pending.pop_front() {
+                        match command {
+                            Command::Call(_, request, call_options, reply) => {
+                                let future = channel
+                                    .call_with((), request, &call_options)
+                                    .map_err(Error::from)
+                                    .then(move |result| {
+                                        reply.exit(track!(result));
+                                        Ok(())
+                                    });
+                                spawner.spawn(future);
+                            }
+                            Command::Cast(_, indication) => {
+                                let _ = channel.cast((), indication);
+                            }
+                        }
+                    }
+
+                    let closed = loop {
+                        match channel.poll_recv() {
+                            Err(_) | Ok(Async::Ready(None)) => break true,
+                            Ok(Async::NotReady) => break channel.poll_send().is_err(),
+                            Ok(Async::Ready(Some(_message))) => {
+                                // All received messages are ignored
+                            }
+                        }
+                    };
+                    if closed {
+                        // The outstanding transactions fail by dropping the channel
+                        self.state = ConnectionState::Disconnected;
+                        continue;
+                    }
+
+                    if channel.outstanding_transactions() > 0 {
+                        *idle_timer = None;
+                        return true;
+                    }
+                    let timer = idle_timer
+                        .get_or_insert_with(|| options.clock.timeout(options.idle_timeout));
+                    return timer.poll() == Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+
+    fn handle_connect_error(&mut self, e: Error, options: &TcpClientPoolBuilder) {
+        self.failures += 1;
+        if self.failures >= options.max_connect_attempts {
+            self.failures = 0;
+            for command in self.pending.drain(..) {
+                if let Command::Call(_, _, _, reply) = command {
+                    reply.exit(Err(track!(e.clone())));
+                }
+            }
+            self.state = ConnectionState::Disconnected;
+        } else {
+            let backoff = options.backoff(self.failures);
+            self.state = ConnectionState::Backoff(options.clock.timeout(backoff));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_works() {
+        let mut options = TcpClientPoolBuilder::new();
+        options
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_millis(500));
+        assert_eq!(options.backoff(1), Duration::from_millis(100));
+        assert_eq!(options.backoff(2), Duration::from_millis(200));
+        assert_eq!(options.backoff(3), Duration::from_millis(400));
+        assert_eq!(options.backoff(4), Duration::from_millis(500));
+        assert_eq!(options.backoff(100), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,768 @@
+use crate::server::{DEFAULT_PORT, DEFAULT_TLS_PORT};
+use crate::{Error, ErrorKind, Result};
+use fibers::net::UdpSocket;
+use fibers::time::timer::TimerExt;
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_TYPE_SRV: u16 = 33;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_RCODE_NXDOMAIN: u8 = 3;
+
+/// Future returned by [`ResolveSrv`] methods.
+///
+/// [`ResolveSrv`]: ./trait.ResolveSrv.html
+pub type ResolveFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// Transport protocol used for communicating with a STUN server.
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServerTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
+impl ServerTransport {
+    /// Returns the SRV service label of the transport.
+    ///
+    /// > The SRV service name is "stun" for UDP and TCP, and "stuns" for TLS-over-TCP.
+    /// > The protocol is "udp" for UDP and "tcp" for TCP and TLS-over-TCP.
+    /// >
+    /// > [RFC 5389 -- 9. DNS Discovery of a Server]
+    ///
+    /// [RFC 5389 -- 9. DNS Discovery of a Server]: https://tools.ietf.org/html/rfc5389#section-9
+    pub fn srv_label(self) -> &'static str {
+        match self {
+            ServerTransport::Udp => "_stun._udp",
+            ServerTransport::Tcp => "_stun._tcp",
+            ServerTransport::Tls => "_stuns._tcp",
+        }
+    }
+
+    /// Returns the default port used when no SRV records are found.
+    pub fn default_port(self) -> u16 {
+        match self {
+            ServerTransport::Udp | ServerTransport::Tcp => DEFAULT_PORT,
+            ServerTransport::Tls => DEFAULT_TLS_PORT,
+        }
+    }
+}
+
+/// Address family of STUN server candidates.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    /// IPv4 only.
+    Ipv4,
+
+    /// IPv6 only.
+    Ipv6,
+
+    /// Both IPv4 and IPv6.
+    ///
+    /// The addresses of each host are interleaved starting with an IPv6 one
+    /// as recommended by [RFC 8305 -- 4. Sorting Addresses].
+    ///
+    /// [RFC 8305 -- 4. Sorting Addresses]: https://tools.ietf.org/html/rfc8305#section-4
+    #[default]
+    Any,
+}
+impl AddressFamily {
+    /// Returns `true` if the given address belongs to this family.
+    pub fn contains(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+            AddressFamily::Any => true,
+        }
+    }
+
+    /// Returns the unspecified address (with port `0`) to which a client socket
+    /// for communicating with the candidates of this family should be bound.
+    ///
+    /// Note that a socket bound to `[::]:0` (i.e., the address for `Any`) can reach IPv4 candidates
+    /// only through IPv4-mapped IPv6 addresses.
+    pub fn unspecified_addr(self) -> SocketAddr {
+        match self {
+            AddressFamily::Ipv4 => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            AddressFamily::Ipv6 | AddressFamily::Any => {
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+            }
+        }
+    }
+
+    fn select(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<_>, Vec<_>) = addrs
+            .into_iter()
+            .filter(|a| self.contains(a))
+            .partition(|a| a.is_ipv6());
+        let mut v6 = v6.into_iter();
+        let mut v4 = v4.into_iter();
+        let mut selected = Vec::new();
+        loop {
+            match (v6.next(), v4.next()) {
+                (None, None) => break,
+                (a, b) => selected.extend(a.into_iter().chain(b)),
+            }
+        }
+        selected
+    }
+}
+
+/// DNS SRV record.
+///
+/// See [RFC 2782].
+///
+/// [RFC 2782]: https://tools.ietf.org/html/rfc2782
+#[allow(missing_docs)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// This trait allows for looking up DNS records used by [`ServerResolver`].
+///
+/// [`ServerResolver`]: ./struct.ServerResolver.html
+pub trait ResolveSrv {
+    /// Looks up the SRV records of the given name (e.g., `_stun._udp.example.com`).
+    ///
+    /// If there are no such records, the resulting future should return an empty vector.
+    fn resolve_srv(&self, name: &str) -> ResolveFuture<Vec<SrvRecord>>;
+
+    /// Looks up the IP addresses (i.e., A and AAAA records) of the given host.
+    fn resolve_host(&self, host: &str) -> ResolveFuture<Vec<IpAddr>>;
+}
+
+/// An implementation of [`ResolveSrv`] that serves fixed records.
+///
+/// This is useful for testing and for environments without DNS.
+///
+/// [`ResolveSrv`]: ./trait.ResolveSrv.html
+#[derive(Debug, Default, Clone)]
+pub struct StaticResolver {
+    srv_records: HashMap<String, Vec<SrvRecord>>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+impl StaticResolver {
+    /// Makes a new `StaticResolver` instance that has no records.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an SRV record for `name`.
+    pub fn add_srv_record(&mut self, name: &str, record: SrvRecord) -> &mut Self {
+        self.srv_records
+            .entry(normalize_name(name))
+            .or_default()
+            .push(record);
+        self
+    }
+
+    /// Adds an IP address for `host`.
+    pub fn add_host(&mut self, host: &str, addr: IpAddr) -> &mut Self {
+        self.hosts
+            .entry(normalize_name(host))
+            .or_default()
+            .push(addr);
+        self
+    }
+}
+impl ResolveSrv for StaticResolver {
+    fn resolve_srv(&self, name: &str) -> ResolveFuture<Vec<SrvRecord>> {
+        let records = self
+            .srv_records
+            .get(&normalize_name(name))
+            .cloned()
+            .unwrap_or_default();
+        Box::new(future::ok(records))
+    }
+
+    fn resolve_host(&self, host: &str) -> ResolveFuture<Vec<IpAddr>> {
+        let addrs = self
+            .hosts
+            .get(&normalize_name(host))
+            .cloned()
+            .unwrap_or_default();
+        Box::new(future::ok(addrs))
+    }
+}
+
+/// An implementation of [`ResolveSrv`] that sends DNS queries over UDP to a name server.
+///
+/// [`ResolveSrv`]: ./trait.ResolveSrv.html
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    name_server: SocketAddr,
+    timeout: Duration,
+}
+impl DnsResolver {
+    /// The default timeout of a DNS query.
+    pub const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+
+    /// Makes a new `DnsResolver` instance that sends queries to the given name server.
+    pub fn new(name_server: SocketAddr) -> Self {
+        DnsResolver {
+            name_server,
+            timeout: Duration::from_millis(Self::DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    /// Makes a new `DnsResolver` instance that uses the first name server listed in `/etc/resolv.conf`.
+    pub fn from_system_conf() -> Result<Self> {
+        let conf = track!(fs::read_to_string("/etc/resolv.conf").map_err(Error::from))?;
+        let name_server = conf
+            .lines()
+            .filter_map(|line| {
+                let mut tokens = line.split_whitespace();
+                if tokens.next() == Some("nameserver") {
+                    tokens.next().and_then(|t| t.parse::<IpAddr>().ok())
+                } else {
+                    None
+                }
+            })
+            .next();
+        let name_server = track_assert_some!(
+            name_server,
+            ErrorKind::Other,
+            "No name servers in /etc/resolv.conf"
+        );
+        Ok(Self::new(SocketAddr::new(name_server, 53)))
+    }
+
+    /// Sets the timeout of a DNS query.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_TIMEOUT_MS)`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the address of the name server.
+    pub fn name_server(&self) -> SocketAddr {
+        self.name_server
+    }
+
+    fn query(&self, name: &str, qtype: u16) -> ResolveFuture<Vec<DnsRecord>> {
+        let id = rand::random::<u16>();
+        let query = match track!(encode_dns_query(id, name, qtype)) {
+            Err(e) => return Box::new(future::err(e)),
+            Ok(query) => query,
+        };
+        let name_server = self.name_server;
+        let bind_addr = if name_server.is_ipv4() {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+        } else {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+        };
+        let future = UdpSocket::bind(bind_addr)
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(move |socket| {
+                socket
+                    .send_to(query, name_server)
+                    .map_err(|(_, _, e)| track!(Error::from(e)))
+            })
+            .and_then(move |(socket, _, _)| {
+                future::loop_fn(socket, move |socket| {
+                    socket
+                        .recv_from(vec![0; 4096])
+                        .map_err(|(_, _, e)| track!(Error::from(e)))
+                        .and_then(move |(socket, buf, size, from)| {
+                            if from != name_server || !is_dns_response_of(id, &buf[..size]) {
+                                return Ok(Loop::Continue(socket));
+                            }
+                            let records = track!(decode_dns_response(&buf[..size]))?;
+                            Ok(Loop::Break(records))
+                        })
+                })
+            })
+            .timeout_after(self.timeout)
+            .map_err(|e| {
+                e.unwrap_or_else(|| track!(ErrorKind::Other.cause("DNS query timed out")).into())
+            });
+        Box::new(future)
+    }
+}
+impl ResolveSrv for DnsResolver {
+    fn resolve_srv(&self, name: &str) -> ResolveFuture<Vec<SrvRecord>> {
+        let future = self.query(name, DNS_TYPE_SRV).map(|records| {
+            records
+                .into_iter()
+                .filter_map(|r| match r {
+                    DnsRecord::Srv(r) => Some(r),
+                    DnsRecord::Addr(_) => None,
+                })
+                .collect()
+        });
+        Box::new(future)
+    }
+
+    fn resolve_host(&self, host: &str) -> ResolveFuture<Vec<IpAddr>> {
+        let v4 = self.query(host, DNS_TYPE_A);
+        let v6 = self.query(host, DNS_TYPE_AAAA);
+        let future = v4.join(v6).map(|(v4, v6)| {
+            v4.into_iter()
+                .chain(v6)
+                .filter_map(|r| match r {
+                    DnsRecord::Addr(a) => Some(a),
+                    DnsRecord::Srv(_) => None,
+                })
+                .collect()
+        });
+        Box::new(future)
+    }
+}
+
+/// STUN server resolver.
+///
+/// This resolves the domain name of a STUN server to the list of candidate addresses
+/// as described in [RFC 5389 -- 9. DNS Discovery of a Server].
+///
+/// The candidates are ordered by the priorities and weights of the SRV records ([RFC 2782]),
+/// and are supposed to be tried in order (e.g., by `Client::call_with_failover`).
+///
+/// [RFC 5389 -- 9. DNS Discovery of a Server]: https://tools.ietf.org/html/rfc5389#section-9
+/// [RFC 2782]: https://tools.ietf.org/html/rfc2782
+#[derive(Debug)]
+pub struct ServerResolver<R> {
+    backend: Arc<R>,
+    family: AddressFamily,
+}
+impl<R> ServerResolver<R>
+where
+    R: ResolveSrv + Send + Sync + 'static,
+{
+    /// Makes a new `ServerResolver` instance that uses the given backend.
+    pub fn new(backend: R) -> Self {
+        ServerResolver {
+            backend: Arc::new(backend),
+            family: AddressFamily::default(),
+        }
+    }
+
+    /// Sets the address family of the candidates returned by the resolver.
+    ///
+    /// The default value is `AddressFamily::Any`.
+    pub fn address_family(&mut self, family: AddressFamily) -> &mut Self {
+        self.family = family;
+        self
+    }
+
+    /// Returns a reference to the backend of the resolver.
+    pub fn backend_ref(&self) -> &R {
+        &self.backend
+    }
+
+    /// Resolves the candidate addresses of the STUN server.
+    ///
+    /// If `host` is an IP address literal or `port` is specified, no SRV lookup is performed.
+    /// Otherwise, SRV records for `transport` are looked up and, if there are no such records,
+    /// the addresses of `host` with the default port of `transport` are returned.
+    pub fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        transport: ServerTransport,
+    ) -> ResolveFuture<Vec<SocketAddr>> {
+        let family = self.family;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            let addr = SocketAddr::new(ip, port.unwrap_or_else(|| transport.default_port()));
+            return Box::new(future::ok(family.select(vec![addr])));
+        }
+        if let Some(port) = port {
+            return resolve_host(&*self.backend, host, port, family);
+        }
+
+        let backend = Arc::clone(&self.backend);
+        let host = host.to_owned();
+        let name = format!("{}.{}", transport.srv_label(), host);
+        let future = self.backend.resolve_srv(&name).and_then(move |records| {
+            if records.is_empty() {
+                Either::A(resolve_host(
+                    &*backend,
+                    &host,
+                    transport.default_port(),
+                    family,
+                ))
+            } else {
+                let futures = order_srv_records(records)
+                    .into_iter()
+                    .filter(|r| !normalize_name(&r.target).is_empty())
+                    .map(|r| resolve_host(&*backend, &r.target, r.port, family))
+                    .collect::<Vec<_>>();
+                Either::B(future::join_all(futures).map(|addrs| addrs.concat()))
+            }
+        });
+        Box::new(future)
+    }
+}
+impl<R> Clone for ServerResolver<R> {
+    fn clone(&self) -> Self {
+        ServerResolver {
+            backend: Arc::clone(&self.backend),
+            family: self.family,
+        }
+    }
+}
+
+fn resolve_host<R: ResolveSrv + ?Sized>(
+    backend: &R,
+    host: &str,
+    port: u16,
+    family: AddressFamily,
+) -> ResolveFuture<Vec<SocketAddr>> {
+    let future = backend.resolve_host(host).map(move |ips| {
+        let addrs = ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        family.select(addrs)
+    });
+    Box::new(future)
+}
+
+/// Orders SRV records as described in [RFC 2782] ("Usage rules").
+///
+/// [RFC 2782]: https://tools.ietf.org/html/rfc2782
+fn order_srv_records(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    let mut rng = rand::thread_rng();
+    let mut ordered = Vec::with_capacity(records.len());
+    records.sort_by_key(|r| (r.priority, r.weight != 0));
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let end = records
+            .iter()
+            .position(|r| r.priority != priority)
+            .unwrap_or(records.len());
+        let mut group = records.drain(..end).collect::<Vec<_>>();
+        while !group.is_empty() {
+            let total = group.iter().map(|r| u32::from(r.weight)).sum::<u32>();
+            let threshold = rng.gen_range(0..=total);
+            let mut sum = 0;
+            let i = group
+                .iter()
+                .position(|r| {
+                    sum += u32::from(r.weight);
+                    sum >= threshold
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(i));
+        }
+    }
+    ordered
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[derive(Debug)]
+enum DnsRecord {
+    Srv(SrvRecord),
+    Addr(IpAddr),
+}
+
+fn encode_dns_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(512);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[0x01, 0x00]); // Recursion desired
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        track_assert!(
+            !label.is_empty() && label.len() < 64,
+            ErrorKind::InvalidInput,
+            "Invalid domain name: {:?}",
+            name
+        );
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+fn is_dns_response_of(id: u16, bytes: &[u8]) -> bool {
+    bytes.len() >= 12 && bytes[0..2] == id.to_be_bytes() && (bytes[2] & 0x80) != 0
+}
+
+fn decode_dns_response(bytes: &[u8]) -> Result<Vec<DnsRecord>> {
+    let mut reader = DnsReader { bytes, pos: 0 };
+    let _id = track!(reader.read_u16())?;
+    let flags = track!(reader.read_u16())?;
+    let rcode = (flags & 0x000F) as u8;
+    if rcode == DNS_RCODE_NXDOMAIN {
+        return Ok(Vec::new());
+    }
+    track_assert_eq!(rcode, 0, ErrorKind::Other, "DNS error response");
+    let qdcount = track!(reader.read_u16())?;
+    let ancount = track!(reader.read_u16())?;
+    let _nscount = track!(reader.read_u16())?;
+    let _arcount = track!(reader.read_u16())?;
+
+    for _ in 0..qdcount {
+        track!(reader.read_name())?;
+        track!(reader.read_u16())?;
+        track!(reader.read_u16())?;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..ancount {
+        track!(reader.read_name())?;
+        let rtype = track!(reader.read_u16())?;
+        let _class = track!(reader.read_u16())?;
+        let _ttl = track!(reader.read_u32())?;
+        let rdlength = usize::from(track!(reader.read_u16())?);
+        let rdata_end = reader.pos + rdlength;
+        track_assert!(
+            rdata_end <= bytes.len(),
+            ErrorKind::Other,
+            "Too short DNS message"
+        );
+        match (rtype, rdlength) {
+            (DNS_TYPE_A, 4) => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(&bytes[reader.pos..rdata_end]);
+                records.push(DnsRecord::Addr(IpAddr::from(octets)));
+            }
+            (DNS_TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&bytes[reader.pos..rdata_end]);
+                records.push(DnsRecord::Addr(IpAddr::from(octets)));
+            }
+            (DNS_TYPE_SRV, _) => {
+                let priority = track!(reader.read_u16())?;
+                let weight = track!(reader.read_u16())?;
+                let port = track!(reader.read_u16())?;
+                let target = track!(reader.read_name())?;
+                records.push(DnsRecord::Srv(SrvRecord {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                }));
+            }
+            _ => {}
+        }
+        reader.pos = rdata_end;
+    }
+    Ok(records)
+}
+
+struct DnsReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl DnsReader<'_> {
+    fn read_u16(&mut self) -> Result<u16> {
+        track_assert!(
+            self.pos + 2 <= self.bytes.len(),
+            ErrorKind::Other,
+            "Too short DNS message"
+        );
+        let n = u16::from_be_bytes([self.bytes[self.pos], self.bytes[self.pos + 1]]);
+        self.pos += 2;
+        Ok(n)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let high = track!(self.read_u16())?;
+        let low = track!(self.read_u16())?;
+        Ok((u32::from(high) << 16) | u32::from(low))
+    }
+
+    fn read_name(&mut self) -> Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        for _ in 0..128 {
+            track_assert!(
+                pos < self.bytes.len(),
+                ErrorKind::Other,
+                "Too short DNS message"
+            );
+            let len = usize::from(self.bytes[pos]);
+            if len == 0 {
+                self.pos = end.unwrap_or(pos + 1);
+                return Ok(labels.join("."));
+            } else if len & 0xC0 == 0xC0 {
+                track_assert!(
+                    pos + 1 < self.bytes.len(),
+                    ErrorKind::Other,
+                    "Too short DNS message"
+                );
+                end.get_or_insert(pos + 2);
+                pos = ((len & 0x3F) << 8) | usize::from(self.bytes[pos + 1]);
+            } else {
+                let label_end = pos + 1 + len;
+                track_assert!(
+                    label_end <= self.bytes.len(),
+                    ErrorKind::Other,
+                    "Too short DNS message"
+                );
+                labels.push(String::from_utf8_lossy(&self.bytes[pos + 1..label_end]).into_owned());
+                pos = label_end;
+            }
+        }
+        track_panic!(
+            ErrorKind::Other,
+            "Too many labels or compression loop in DNS message"
+        );
+    }
+}
+impl fmt::Debug for DnsReader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DnsReader {{ pos: {} }}", self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trackable::error::MainError;
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port,
+            target: target.to_owned(),
+        }
+    }
+
+    #[test]
+    fn srv_ordering_works() {
+        let records = vec![
+            srv(20, 0, 3478, "c.example.com"),
+            srv(10, 0, 3478, "a.example.com"),
+            srv(10, 100, 3478, "b.example.com"),
+        ];
+        let ordered = order_srv_records(records);
+        assert_eq!(ordered.len(), 3);
+        assert_eq!(ordered[0].priority, 10);
+        assert_eq!(ordered[1].priority, 10);
+        assert_eq!(ordered[2].target, "c.example.com");
+    }
+
+    #[test]
+    fn resolve_with_static_resolver_works() -> std::result::Result<(), MainError> {
+        let mut backend = StaticResolver::new();
+        backend
+            .add_srv_record(
+                "_stun._udp.example.com",
+                srv(10, 0, 10000, "a.example.com."),
+            )
+            .add_srv_record(
+                "_stun._udp.example.com",
+                srv(20, 0, 20000, "b.example.com."),
+            )
+            .add_host("a.example.com", "192.0.2.1".parse().unwrap())
+            .add_host("b.example.com", "192.0.2.2".parse().unwrap())
+            .add_host("c.example.com", "192.0.2.3".parse().unwrap());
+        let resolver = ServerResolver::new(backend);
+
+        let addrs =
+            fibers_global::execute(resolver.resolve("example.com", None, ServerTransport::Udp))?;
+        assert_eq!(
+            addrs,
+            [
+                "192.0.2.1:10000".parse().unwrap(),
+                "192.0.2.2:20000".parse().unwrap()
+            ]
+        );
+
+        // No SRV records
+        let addrs =
+            fibers_global::execute(resolver.resolve("c.example.com", None, ServerTransport::Tls))?;
+        assert_eq!(addrs, ["192.0.2.3:5349".parse().unwrap()]);
+
+        // Explicit port
+        let addrs = fibers_global::execute(resolver.resolve(
+            "example.com",
+            Some(1234),
+            ServerTransport::Udp,
+        ))?;
+        assert!(addrs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn address_family_selection_works() -> std::result::Result<(), MainError> {
+        let mut backend = StaticResolver::new();
+        backend
+            .add_host("example.com", "192.0.2.1".parse().unwrap())
+            .add_host("example.com", "192.0.2.2".parse().unwrap())
+            .add_host("example.com", "2001:db8::1".parse().unwrap());
+        let mut resolver = ServerResolver::new(backend);
+
+        let addrs = fibers_global::execute(resolver.resolve(
+            "example.com",
+            Some(3478),
+            ServerTransport::Udp,
+        ))?;
+        assert_eq!(
+            addrs,
+            [
+                "[2001:db8::1]:3478".parse().unwrap(),
+                "192.0.2.1:3478".parse().unwrap(),
+                "192.0.2.2:3478".parse().unwrap()
+            ]
+        );
+
+        resolver.address_family(AddressFamily::Ipv4);
+        let addrs = fibers_global::execute(resolver.resolve(
+            "example.com",
+            Some(3478),
+            ServerTransport::Udp,
+        ))?;
+        assert_eq!(
+            addrs,
+            [
+                "192.0.2.1:3478".parse().unwrap(),
+                "192.0.2.2:3478".parse().unwrap()
+            ]
+        );
+
+        resolver.address_family(AddressFamily::Ipv6);
+        let addrs = fibers_global::execute(resolver.resolve("::1", None, ServerTransport::Udp))?;
+        assert_eq!(addrs, ["[::1]:3478".parse().unwrap()]);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_dns_response_works() -> Result<()> {
+        let mut bytes = track!(encode_dns_query(7, "_stun._udp.example.com", DNS_TYPE_SRV))?;
+        bytes[2] = 0x81; // QR
+        bytes[3] = 0x80;
+        bytes[7] = 1; // ANCOUNT
+        bytes.extend_from_slice(&[0xC0, 12]); // Name (pointer to question)
+        bytes.extend_from_slice(&DNS_TYPE_SRV.to_be_bytes());
+        bytes.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 60]);
+        bytes.extend_from_slice(&[0, 12]);
+        bytes.extend_from_slice(&[0, 10, 0, 5, 0x0D, 0x96]);
+        bytes.extend_from_slice(&[3, b's', b't', b'n', 0xC0, 23]); // "stn" + pointer to "example.com"
+        assert!(is_dns_response_of(7, &bytes));
+
+        let records = track!(decode_dns_response(&bytes))?;
+        assert_eq!(records.len(), 1);
+        match &records[0] {
+            DnsRecord::Srv(r) => assert_eq!(r, &srv(10, 5, 3478, "stn.example.com")),
+            r => panic!("{r:?}"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Clock abstraction used by timers in this crate.
+//!
+//! By default, channels and transporters use [`SystemClock`] (i.e., wall-clock time).
+//! [`ManualClock`] can be used instead for testing timer-driven behavior deterministically.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers_global;
+//! # extern crate futures;
+//! # extern crate rustun;
+//! use futures::{future, Async, Future};
+//! use rustun::clock::{Clock, ManualClock};
+//! use std::time::Duration;
+//!
+//! let clock = ManualClock::new();
+//! let mut timeout = clock.timeout(Duration::from_secs(10));
+//!
+//! let result = fibers_global::execute(future::lazy(move || {
+//!     assert_eq!(timeout.poll(), Ok(Async::NotReady));
+//!     assert_eq!(clock.pending_timeouts(), 1);
+//!     clock.advance(Duration::from_secs(10));
+//!     assert_eq!(timeout.poll(), Ok(Async::Ready(())));
+//!     assert_eq!(clock.pending_timeouts(), 0);
+//!     Ok::<_, ()>(())
+//! }));
+//! assert!(result.is_ok());
+//! ```
+//!
+//! [`SystemClock`]: ./struct.SystemClock.html
+//! [`ManualClock`]: ./struct.ManualClock.html
+use fibers::sync::oneshot;
+use fibers::time::timer;
+use futures::{Async, Future, Poll};
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+
+/// This trait allows for abstracting the source of time.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns a future that expires after the given duration has elapsed.
+    fn timeout(&self, duration: Duration) -> Timeout;
+}
+
+/// A future that expires at a certain time of a [`Clock`].
+///
+/// [`Clock`]: ./trait.Clock.html
+pub struct Timeout(Box<dyn Future<Item = (), Error = ()> + Send + 'static>);
+impl Timeout {
+    /// Makes a new `Timeout` instance that expires when the given future completes.
+    pub fn new<F>(future: F) -> Self
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        Timeout(Box::new(future))
+    }
+}
+impl Future for Timeout {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+impl fmt::Debug for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timeout(_)")
+    }
+}
+
+/// [`Clock`] based on the system wall-clock time.
+///
+/// [`Clock`]: ./trait.Clock.html
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn timeout(&self, duration: Duration) -> Timeout {
+        Timeout::new(timer::timeout(duration).map_err(|_| ()))
+    }
+}
+
+/// [`Clock`] that is advanced only manually.
+///
+/// Timeouts created by the clock expire when the clock is advanced past their deadlines by [`advance`] method.
+///
+/// [`Clock`]: ./trait.Clock.html
+/// [`advance`]: ./struct.ManualClock.html#method.advance
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualClockState>>,
+}
+impl ManualClock {
+    /// Makes a new `ManualClock` instance that starts at the current system time.
+    pub fn new() -> Self {
+        Self::with_start_time(SystemTime::now())
+    }
+
+    /// Makes a new `ManualClock` instance that starts at the given time.
+    pub fn with_start_time(start_time: SystemTime) -> Self {
+        let state = ManualClockState {
+            now: start_time,
+            waiters: Vec::new(),
+        };
+        ManualClock {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Advances the clock by the given duration.
+    ///
+    /// All timeouts of which deadlines have been reached expire.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().expect("never fails");
+        state.now += duration;
+
+        let now = state.now;
+        let mut i = 0;
+        while i < state.waiters.len() {
+            if state.waiters[i].0 <= now {
+                let (_, tx, _) = state.waiters.swap_remove(i);
+                let _ = tx.send(());
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Returns the number of the timeouts that have neither expired nor been dropped.
+    ///
+    /// This is useful for waiting until a timer-driven component arms its timer
+    /// before advancing the clock.
+    pub fn pending_timeouts(&self) -> usize {
+        let state = self.state.lock().expect("never fails");
+        state
+            .waiters
+            .iter()
+            .filter(|w| w.2.upgrade().is_some())
+            .count()
+    }
+}
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.state.lock().expect("never fails").now
+    }
+
+    fn timeout(&self, duration: Duration) -> Timeout {
+        let (tx, rx) = oneshot::channel();
+        let token = Arc::new(());
+        let mut state = self.state.lock().expect("never fails");
+        let deadline = state.now + duration;
+        if duration == Duration::from_secs(0) {
+            let _ = tx.send(());
+        } else {
+            state.waiters.push((deadline, tx, Arc::downgrade(&token)));
+        }
+        Timeout::new(ManualTimeout { rx, _token: token })
+    }
+}
+
+#[derive(Debug)]
+struct ManualClockState {
+    now: SystemTime,
+    waiters: Vec<(SystemTime, oneshot::Sender<()>, Weak<()>)>,
+}
+
+#[derive(Debug)]
+struct ManualTimeout {
+    rx: oneshot::Receiver<()>,
+    _token: Arc<()>,
+}
+impl Future for ManualTimeout {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.rx.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => Ok(Async::Ready(())),
+            Err(_) => Ok(Async::NotReady), // The clock has been dropped
+        }
+    }
+}
@@ -0,0 +1,577 @@
+//! Consent freshness.
+//!
+//! This module provides [`ConsentMonitor`] that periodically sends consent checks
+//! to peers as described in [RFC 7675].
+//!
+//! > Consent expires after 30 seconds. That is, if a valid STUN binding response
+//! > corresponding to one of the STUN requests sent in the last 30 seconds has
+//! > not been received from the remote peer, the endpoint MUST cease transmission
+//! > on that 5-tuple until a new consent is established.
+//! >
+//! > [RFC 7675 -- 5.1. Expiration of Consent]
+//!
+//! [`ConsentMonitor`]: ./struct.ConsentMonitor.html
+//! [RFC 7675]: https://tools.ietf.org/html/rfc7675
+//! [RFC 7675 -- 5.1. Expiration of Consent]: https://tools.ietf.org/html/rfc7675#section-5.1
+use crate::channel::{CallOptions, Priority};
+use crate::client::Client;
+use crate::message::{Request, Response};
+use crate::timeout_queue::TimeoutQueue;
+use crate::transport::StunTransport;
+use crate::{Error, Result};
+use futures::{Async, Future, Poll, Stream};
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+use stun_codec::convert::TryAsRef;
+use stun_codec::rfc5245::errors::RoleConflict;
+use stun_codec::rfc5389;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::Attribute;
+
+type RequestFactory<A, P> = Box<dyn FnMut(&P) -> Result<Request<A>> + Send + 'static>;
+type ResponseValidator<A, P> = Box<dyn FnMut(&P, &Response<A>) -> Result<()> + Send + 'static>;
+type CheckFuture<A, P> = Box<dyn Future<Item = (P, Result<Response<A>>), Error = ()> + Send>;
+
+/// [`ConsentMonitor`] builder.
+///
+/// [`ConsentMonitor`]: ./struct.ConsentMonitor.html
+#[derive(Debug, Clone)]
+pub struct ConsentMonitorBuilder {
+    check_interval: Duration,
+    consent_timeout: Duration,
+}
+impl ConsentMonitorBuilder {
+    /// The default interval of consent checks.
+    ///
+    /// > Consent freshness is ... performed by sending a STUN binding request ...
+    /// > every **5 seconds** on average, and then randomized so that the interval
+    /// > is uniformly distributed between 0.8 and 1.2 times the basic period.
+    /// >
+    /// > [RFC 7675 -- 5.1. Expiration of Consent]
+    ///
+    /// [RFC 7675 -- 5.1. Expiration of Consent]: https://tools.ietf.org/html/rfc7675#section-5.1
+    pub const DEFAULT_CHECK_INTERVAL_MS: u64 = 5_000;
+
+    /// The default duration after which consent expires.
+    ///
+    /// > Consent expires after **30 seconds**.
+    /// >
+    /// > [RFC 7675 -- 5.1. Expiration of Consent]
+    ///
+    /// [RFC 7675 -- 5.1. Expiration of Consent]: https://tools.ietf.org/html/rfc7675#section-5.1
+    pub const DEFAULT_CONSENT_TIMEOUT_MS: u64 = 30_000;
+
+    /// Makes a new `ConsentMonitorBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the basic interval of consent checks.
+    ///
+    /// The actual intervals are randomized between 0.8 and 1.2 times of this value.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_CHECK_INTERVAL_MS)`.
+    pub fn check_interval(&mut self, interval: Duration) -> &mut Self {
+        self.check_interval = interval;
+        self
+    }
+
+    /// Sets the duration after which consent expires if no responses are received.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_CONSENT_TIMEOUT_MS)`.
+    pub fn consent_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.consent_timeout = timeout;
+        self
+    }
+
+    /// Makes a new `ConsentMonitor` instance with the given settings.
+    ///
+    /// By default, consent checks are plain `BINDING` requests and all responses are regarded as valid.
+    /// Use `ConsentMonitor::set_request_factory` and `ConsentMonitor::set_response_validator`
+    /// to customize them (e.g., adding and verifying ICE short-term credentials).
+    pub fn finish<A, T>(&self, client: Client<A, T>) -> ConsentMonitor<A, T>
+    where
+        A: Attribute + Send + 'static,
+        T: StunTransport<A> + Send + 'static,
+        T::PeerAddr: Send + 'static,
+    {
+        ConsentMonitor {
+            client,
+            check_interval: self.check_interval,
+            consent_timeout: self.consent_timeout,
+            request_factory: Box::new(|_| Ok(Request::new(rfc5389::methods::BINDING))),
+            response_validator: Box::new(|_, _| Ok(())),
+            peers: HashMap::new(),
+            timeout_queue: TimeoutQueue::new(),
+            outstanding_checks: Vec::new(),
+            events: VecDeque::new(),
+            next_generation: 0,
+        }
+    }
+}
+impl Default for ConsentMonitorBuilder {
+    fn default() -> Self {
+        ConsentMonitorBuilder {
+            check_interval: Duration::from_millis(Self::DEFAULT_CHECK_INTERVAL_MS),
+            consent_timeout: Duration::from_millis(Self::DEFAULT_CONSENT_TIMEOUT_MS),
+        }
+    }
+}
+
+/// Consent state of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConsentState {
+    /// Consent is fresh.
+    Granted,
+
+    /// No valid responses have been received within the consent timeout.
+    Expired,
+
+    /// Consent has been revoked by the local application or by an error response from the peer.
+    Revoked,
+}
+
+/// Event emitted by [`ConsentMonitor`].
+///
+/// [`ConsentMonitor`]: ./struct.ConsentMonitor.html
+#[derive(Debug)]
+pub enum ConsentEvent<P> {
+    /// A consent check to the peer has succeeded.
+    Refreshed(P),
+
+    /// Consent for the peer has expired.
+    Expired(P),
+
+    /// Consent for the peer has been revoked.
+    Revoked(P),
+}
+
+/// Consent freshness monitor.
+///
+/// This periodically sends consent checks to the registered peers via [`Client::call`],
+/// and reports the changes of the consent state of each peer as a stream of [`ConsentEvent`]s.
+///
+/// Once consent for a peer has expired or been revoked, no more checks are sent to the peer.
+///
+/// [`Client::call`]: ../client/struct.Client.html#method.call
+/// [`ConsentEvent`]: ./enum.ConsentEvent.html
+#[must_use = "streams do nothing unless polled"]
+pub struct ConsentMonitor<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    client: Client<A, T>,
+    check_interval: Duration,
+    consent_timeout: Duration,
+    request_factory: RequestFactory<A, T::PeerAddr>,
+    response_validator: ResponseValidator<A, T::PeerAddr>,
+    peers: HashMap<T::PeerAddr, PeerConsent>,
+    timeout_queue: TimeoutQueue<TimeoutEntry<T::PeerAddr>>,
+    outstanding_checks: Vec<CheckFuture<A, T::PeerAddr>>,
+    events: VecDeque<ConsentEvent<T::PeerAddr>>,
+    next_generation: u64,
+}
+impl<A, T> ConsentMonitor<A, T>
+where
+    A: Attribute + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    /// Makes a new `ConsentMonitor` instance.
+    ///
+    /// This is equivalent to `ConsentMonitorBuilder::default().finish(client)`.
+    pub fn new(client: Client<A, T>) -> Self {
+        ConsentMonitorBuilder::default().finish(client)
+    }
+
+    /// Sets the function used for making consent check requests.
+    pub fn set_request_factory<F>(&mut self, f: F)
+    where
+        F: FnMut(&T::PeerAddr) -> Result<Request<A>> + Send + 'static,
+    {
+        self.request_factory = Box::new(f);
+    }
+
+    /// Sets the function used for validating the responses of consent checks.
+    ///
+    /// Responses for which the function returns an error (e.g., ones without the valid
+    /// `MESSAGE-INTEGRITY` attribute) are ignored, thus they neither refresh nor revoke consent.
+    pub fn set_response_validator<F>(&mut self, f: F)
+    where
+        F: FnMut(&T::PeerAddr, &Response<A>) -> Result<()> + Send + 'static,
+    {
+        self.response_validator = Box::new(f);
+    }
+
+    /// Starts monitoring consent for the given peer.
+    ///
+    /// It is assumed that consent for the peer has just been obtained (e.g., by ICE connectivity checks).
+    /// If the peer is already being monitored, its consent is refreshed.
+    pub fn add_peer(&mut self, peer: T::PeerAddr) {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        self.peers.insert(
+            peer.clone(),
+            PeerConsent {
+                state: ConsentState::Granted,
+                last_refreshed: Instant::now(),
+                generation,
+            },
+        );
+        self.schedule_check(peer.clone(), generation);
+        self.timeout_queue.push(
+            TimeoutEntry::Expire { peer, generation },
+            self.consent_timeout,
+        );
+    }
+
+    /// Stops monitoring consent for the given peer.
+    ///
+    /// Unlike `revoke`, this does not emit any events.
+    pub fn remove_peer(&mut self, peer: &T::PeerAddr) {
+        self.peers.remove(peer);
+    }
+
+    /// Revokes consent for the given peer.
+    ///
+    /// A `ConsentEvent::Revoked` event will be emitted if the consent was granted.
+    pub fn revoke(&mut self, peer: &T::PeerAddr) {
+        if let Some(p) = self.peers.get_mut(peer) {
+            if p.state == ConsentState::Granted {
+                p.state = ConsentState::Revoked;
+                self.events.push_back(ConsentEvent::Revoked(peer.clone()));
+            }
+        }
+    }
+
+    /// Returns the consent state of the given peer.
+    ///
+    /// If the peer is not monitored, this will return `None`.
+    pub fn state(&self, peer: &T::PeerAddr) -> Option<ConsentState> {
+        self.peers.get(peer).map(|p| p.state)
+    }
+
+    /// Returns the elapsed time since consent for the given peer was last refreshed.
+    pub fn elapsed_since_refreshed(&self, peer: &T::PeerAddr) -> Option<Duration> {
+        self.peers.get(peer).map(|p| p.last_refreshed.elapsed())
+    }
+
+    fn schedule_check(&mut self, peer: T::PeerAddr, generation: u64) {
+        let factor = rand::thread_rng().gen_range(0.8..=1.2);
+        let interval = self.check_interval.mul_f64(factor);
+        self.timeout_queue
+            .push(TimeoutEntry::Check { peer, generation }, interval);
+    }
+
+    fn is_current(&self, peer: &T::PeerAddr, generation: u64) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|p| p.generation == generation && p.state == ConsentState::Granted)
+    }
+
+    fn send_check(&mut self, peer: T::PeerAddr, generation: u64) {
+        let future: CheckFuture<A, T::PeerAddr> = match track!((self.request_factory)(&peer)) {
+            Err(e) => Box::new(futures::finished((peer.clone(), Err(e)))),
+            Ok(request) => {
+                let p = peer.clone();
+                Box::new(
+                    self.client
+                        .call_with(
+                            peer.clone(),
+                            request,
+                            CallOptions::new().priority(Priority::Urgent),
+                        )
+                        .then(move |result| Ok((p, result))),
+                )
+            }
+        };
+        self.outstanding_checks.push(future);
+        self.schedule_check(peer, generation);
+    }
+
+    fn poll_outstanding_checks(&mut self) -> Option<(T::PeerAddr, Result<Response<A>>)> {
+        for i in 0..self.outstanding_checks.len() {
+            if let Ok(Async::Ready(item)) = self.outstanding_checks[i].poll() {
+                let _ = self.outstanding_checks.swap_remove(i);
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    fn handle_expire(
+        &mut self,
+        peer: T::PeerAddr,
+        generation: u64,
+    ) -> Option<ConsentEvent<T::PeerAddr>> {
+        if !self.is_current(&peer, generation) {
+            return None;
+        }
+        let consent_timeout = self.consent_timeout;
+        let consent = self.peers.get_mut(&peer).expect("never fails");
+        let elapsed = consent.last_refreshed.elapsed();
+        if let Some(remaining) = consent_timeout
+            .checked_sub(elapsed)
+            .filter(|d| !d.is_zero())
+        {
+            self.timeout_queue
+                .push(TimeoutEntry::Expire { peer, generation }, remaining);
+            None
+        } else {
+            consent.state = ConsentState::Expired;
+            Some(ConsentEvent::Expired(peer))
+        }
+    }
+}
+impl<A, T> ConsentMonitor<A, T>
+where
+    A: Attribute + TryAsRef<ErrorCode> + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    fn handle_check_result(
+        &mut self,
+        peer: T::PeerAddr,
+        result: Result<Response<A>>,
+    ) -> Option<ConsentEvent<T::PeerAddr>> {
+        let consent = self.peers.get_mut(&peer)?;
+        if consent.state != ConsentState::Granted {
+            return None;
+        }
+        if let Ok(response) = &result {
+            if track!((self.response_validator)(&peer, response)).is_err() {
+                return None;
+            }
+        }
+        match result {
+            Ok(Ok(_)) => {
+                consent.last_refreshed = Instant::now();
+                Some(ConsentEvent::Refreshed(peer))
+            }
+            Ok(Err(response)) => {
+                let code = response.get_attribute::<ErrorCode>().map(|e| e.code());
+                if code == Some(RoleConflict::CODEPOINT) {
+                    // The agent is expected to switch its role and retry (RFC 8445 -- 7.2.5.1),
+                    // thus this is tolerated like transaction timeouts
+                    return None;
+                }
+                consent.state = ConsentState::Revoked;
+                Some(ConsentEvent::Revoked(peer))
+            }
+            Err(_) => {
+                // Transaction timeouts are tolerated until the consent expires
+                None
+            }
+        }
+    }
+}
+impl<A, T> Stream for ConsentMonitor<A, T>
+where
+    A: Attribute + TryAsRef<ErrorCode> + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    type Item = ConsentEvent<T::PeerAddr>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Async::Ready(Some(event)));
+        }
+
+        while let Some(entry) = self.timeout_queue.pop() {
+            match entry {
+                TimeoutEntry::Check { peer, generation } => {
+                    if self.is_current(&peer, generation) {
+                        self.send_check(peer, generation);
+                    }
+                }
+                TimeoutEntry::Expire { peer, generation } => {
+                    if let Some(event) = self.handle_expire(peer, generation) {
+                        return Ok(Async::Ready(Some(event)));
+                    }
+                }
+            }
+        }
+
+        while let Some((peer, result)) = self.poll_outstanding_checks() {
+            if let Some(event) = self.handle_check_result(peer, result) {
+                return Ok(Async::Ready(Some(event)));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<A, T> fmt::Debug for ConsentMonitor<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ConsentMonitor {{ check_interval: {:?}, consent_timeout: {:?}, peers: {:?}, .. }}",
+            self.check_interval, self.consent_timeout, self.peers
+        )
+    }
+}
+
+#[derive(Debug)]
+struct PeerConsent {
+    state: ConsentState,
+    last_refreshed: Instant,
+    generation: u64,
+}
+
+#[derive(Debug)]
+enum TimeoutEntry<P> {
+    Check { peer: P, generation: u64 },
+    Expire { peer: P, generation: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Channel;
+    use crate::message::ErrorResponse;
+    use crate::server::{Action, BindingHandler, HandleMessage, RequestContext, UdpServer};
+    use crate::transport::StunUdpTransporter;
+    use crate::ErrorKind;
+    use fibers_transport::UdpTransporter;
+    use std::net::SocketAddr;
+    use stun_codec::rfc5389::attributes::MessageIntegrity;
+    use stun_codec::{MessageDecoder, MessageEncoder};
+    use trackable::error::MainError;
+
+    type UdpClient = Client<
+        rfc5389::Attribute,
+        StunUdpTransporter<
+            rfc5389::Attribute,
+            UdpTransporter<MessageEncoder<rfc5389::Attribute>, MessageDecoder<rfc5389::Attribute>>,
+        >,
+    >;
+
+    fn client() -> Result<UdpClient> {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let future = UdpTransporter::bind(addr)
+            .map_err(Error::from)
+            .map(StunUdpTransporter::new)
+            .map(Channel::new)
+            .map(|channel| Client::new(&fibers_global::handle(), channel));
+        track!(fibers_global::execute(future))
+    }
+
+    #[test]
+    fn consent_refresh_and_revoke_works() -> std::result::Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let mut monitor = ConsentMonitorBuilder::new()
+            .check_interval(Duration::from_millis(10))
+            .finish(track!(client())?);
+        monitor.add_peer(server_addr);
+
+        let (event, mut monitor) =
+            fibers_global::execute(monitor.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(event, Some(ConsentEvent::Refreshed(peer)) if peer == server_addr));
+
+        monitor.revoke(&server_addr);
+        assert_eq!(monitor.state(&server_addr), Some(ConsentState::Revoked));
+        let (event, _) = fibers_global::execute(monitor.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(event, Some(ConsentEvent::Revoked(peer)) if peer == server_addr));
+        Ok(())
+    }
+
+    #[test]
+    fn consent_expiration_works() -> std::result::Result<(), MainError> {
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let mut monitor = ConsentMonitorBuilder::new()
+            .check_interval(Duration::from_millis(10))
+            .consent_timeout(Duration::from_millis(100))
+            .finish(track!(client())?);
+        monitor.add_peer(peer);
+
+        let (event, monitor) = fibers_global::execute(monitor.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(event, Some(ConsentEvent::Expired(p)) if p == peer));
+        assert_eq!(monitor.state(&peer), Some(ConsentState::Expired));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_response_does_not_refresh_consent() -> std::result::Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let mut monitor = ConsentMonitorBuilder::new()
+            .check_interval(Duration::from_millis(10))
+            .consent_timeout(Duration::from_millis(100))
+            .finish(track!(client())?);
+        monitor.set_response_validator(|_, response| {
+            let response = track_assert_some!(response.as_ref().ok(), ErrorKind::Other);
+            let integrity = track_assert_some!(
+                response.get_attribute::<MessageIntegrity>(),
+                ErrorKind::Other,
+                "No MESSAGE-INTEGRITY attribute"
+            );
+            track!(integrity
+                .check_short_term_credential("password")
+                .map_err(Error::from))?;
+            Ok(())
+        });
+        monitor.add_peer(server_addr);
+
+        // `BindingHandler` does not authenticate its responses
+        let (event, monitor) = fibers_global::execute(monitor.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(event, Some(ConsentEvent::Expired(p)) if p == server_addr));
+        assert_eq!(monitor.state(&server_addr), Some(ConsentState::Expired));
+        Ok(())
+    }
+
+    #[test]
+    fn role_conflict_does_not_revoke_consent() -> std::result::Result<(), MainError> {
+        struct RoleConflictHandler;
+        impl HandleMessage for RoleConflictHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _context: &RequestContext,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                Action::Reply(Err(ErrorResponse::new(&request, RoleConflict.into())))
+            }
+        }
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            RoleConflictHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let mut monitor = ConsentMonitorBuilder::new()
+            .check_interval(Duration::from_millis(10))
+            .consent_timeout(Duration::from_millis(100))
+            .finish(track!(client())?);
+        monitor.add_peer(server_addr);
+
+        let (event, monitor) = fibers_global::execute(monitor.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(event, Some(ConsentEvent::Expired(p)) if p == server_addr));
+        assert_eq!(monitor.state(&server_addr), Some(ConsentState::Expired));
+        Ok(())
+    }
+}
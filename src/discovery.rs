@@ -0,0 +1,394 @@
+//! Public address discovery.
+//!
+//! This module provides [`AddressDiscovery`] that queries multiple STUN servers in parallel
+//! and determines the public address of the local endpoint by consensus.
+//!
+//! If the servers report different addresses, the local endpoint is probably behind
+//! a symmetric NAT (i.e., the mapping depends on the destination) or some of the servers are misbehaving.
+//!
+//! [`AddressDiscovery`]: ./struct.AddressDiscovery.html
+use crate::client::Client;
+use crate::message::{Request, Response};
+use crate::transport::StunTransport;
+use crate::{Error, ErrorKind, Result};
+use fibers::time::timer::TimerExt;
+use futures::{Async, Future, Poll};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use stun_codec::convert::TryAsRef;
+use stun_codec::rfc5389;
+use stun_codec::rfc5389::attributes::{ErrorCode, XorMappedAddress};
+use stun_codec::Attribute;
+use trackable::error::ErrorKindExt;
+
+type QueryFuture<S> = Box<dyn Future<Item = (S, Result<SocketAddr>), Error = ()> + Send + 'static>;
+
+/// [`AddressDiscovery`] builder.
+///
+/// [`AddressDiscovery`]: ./struct.AddressDiscovery.html
+#[derive(Debug, Clone)]
+pub struct AddressDiscoveryBuilder {
+    query_timeout: Duration,
+}
+impl AddressDiscoveryBuilder {
+    /// The default timeout of each query.
+    pub const DEFAULT_QUERY_TIMEOUT_MS: u64 = 5_000;
+
+    /// Makes a new `AddressDiscoveryBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the timeout of each query.
+    ///
+    /// Servers that do not respond within the timeout are regarded as unreachable.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_QUERY_TIMEOUT_MS)`.
+    pub fn query_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.query_timeout = timeout;
+        self
+    }
+
+    /// Makes a new `AddressDiscovery` instance with the given settings.
+    pub fn finish<S>(&self) -> AddressDiscovery<S>
+    where
+        S: Clone + Eq + fmt::Debug + Send + 'static,
+    {
+        AddressDiscovery {
+            query_timeout: self.query_timeout,
+            queries: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+}
+impl Default for AddressDiscoveryBuilder {
+    fn default() -> Self {
+        AddressDiscoveryBuilder {
+            query_timeout: Duration::from_millis(Self::DEFAULT_QUERY_TIMEOUT_MS),
+        }
+    }
+}
+
+/// Future that discovers the public address of the local endpoint by querying multiple STUN servers.
+///
+/// `S` is an arbitrary identifier of a server (e.g., its address or host name).
+///
+/// Each query is issued as soon as it is added, and the future completes
+/// once all the queries have completed, failed or timed out.
+/// Unreachable servers do not make the discovery fail as long as the other servers respond.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// # extern crate trackable;
+/// use fibers_transport::UdpTransporter;
+/// use futures::Future;
+/// use rustun::channel::Channel;
+/// use rustun::client::Client;
+/// use rustun::discovery::AddressDiscovery;
+/// use rustun::server::{BindingHandler, UdpServer};
+/// use rustun::transport::StunUdpTransporter;
+/// use stun_codec::{rfc5389, MessageDecoder, MessageEncoder};
+///
+/// # fn main() -> Result<(), trackable::error::MainError> {
+/// let addr = "127.0.0.1:0".parse().unwrap();
+///
+/// let mut servers = Vec::new();
+/// for _ in 0..3 {
+///     let server = fibers_global::execute(UdpServer::start(fibers_global::handle(), addr, BindingHandler))?;
+///     servers.push(server.local_addr());
+///     fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+/// }
+///
+/// let transporter = fibers_global::execute(
+///     UdpTransporter::<MessageEncoder<rfc5389::Attribute>, MessageDecoder<_>>::bind(addr))?;
+/// let client = Client::new(&fibers_global::handle(), Channel::new(StunUdpTransporter::new(transporter)));
+///
+/// let mut discovery = AddressDiscovery::new();
+/// for server in servers {
+///     discovery.query(server, &client, server);
+/// }
+/// let report = fibers_global::execute(discovery)?;
+/// assert!(report.public_addr().is_some());
+/// assert!(!report.has_disagreement());
+/// # Ok(())
+/// # }
+/// ```
+#[must_use = "futures do nothing unless polled"]
+pub struct AddressDiscovery<S> {
+    query_timeout: Duration,
+    queries: Vec<QueryFuture<S>>,
+    results: Vec<(S, Result<SocketAddr>)>,
+}
+impl<S> AddressDiscovery<S>
+where
+    S: Clone + Eq + fmt::Debug + Send + 'static,
+{
+    /// Makes a new `AddressDiscovery` instance.
+    ///
+    /// This is equivalent to `AddressDiscoveryBuilder::default().finish()`.
+    pub fn new() -> Self {
+        AddressDiscoveryBuilder::default().finish()
+    }
+
+    /// Queries the public address to `peer` using the given client.
+    ///
+    /// For UDP, a single client can be shared by all the servers.
+    /// For TCP, a client connected to each server should be given (and `peer` will be `()`).
+    pub fn query<A, T>(&mut self, server: S, client: &Client<A, T>, peer: T::PeerAddr)
+    where
+        A: Attribute + TryAsRef<XorMappedAddress> + TryAsRef<ErrorCode> + Send + 'static,
+        T: StunTransport<A> + Send + 'static,
+        T::PeerAddr: Send + 'static,
+    {
+        let request = Request::new(rfc5389::methods::BINDING);
+        let future = client.call(peer, request);
+        self.query_with(server, future);
+    }
+
+    /// Queries the public address using an arbitrary future that results in a `BINDING` response.
+    ///
+    /// This is useful when the transport needs to be set up lazily (e.g., connecting to a TCP server).
+    pub fn query_with<A, F>(&mut self, server: S, future: F)
+    where
+        A: Attribute + TryAsRef<XorMappedAddress> + TryAsRef<ErrorCode>,
+        F: Future<Item = Response<A>, Error = Error> + Send + 'static,
+    {
+        let future = future
+            .timeout_after(self.query_timeout)
+            .then(move |result| {
+                let result = match result {
+                    Ok(response) => track!(mapped_addr(response)),
+                    Err(Some(e)) => Err(track!(e)),
+                    Err(None) => Err(track!(ErrorKind::Other.cause("Query timed out")).into()),
+                };
+                Ok((server, result))
+            });
+        self.queries.push(Box::new(future));
+    }
+
+    /// Returns the number of the queries that have not completed yet.
+    pub fn outstanding_queries(&self) -> usize {
+        self.queries.len()
+    }
+}
+impl<S> Default for AddressDiscovery<S>
+where
+    S: Clone + Eq + fmt::Debug + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<S> Future for AddressDiscovery<S>
+where
+    S: Clone + Eq + fmt::Debug + Send + 'static,
+{
+    type Item = DiscoveryReport<S>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut i = 0;
+        while i < self.queries.len() {
+            if let Ok(Async::Ready(result)) = self.queries[i].poll() {
+                let _ = self.queries.swap_remove(i);
+                self.results.push(result);
+            } else {
+                i += 1;
+            }
+        }
+        if self.queries.is_empty() {
+            let results = std::mem::take(&mut self.results);
+            Ok(Async::Ready(DiscoveryReport::new(results)))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+impl<S: fmt::Debug> fmt::Debug for AddressDiscovery<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "AddressDiscovery {{ query_timeout: {:?}, queries: {}, results: {:?} }}",
+            self.query_timeout,
+            self.queries.len(),
+            self.results
+        )
+    }
+}
+
+/// The result of [`AddressDiscovery`].
+///
+/// [`AddressDiscovery`]: ./struct.AddressDiscovery.html
+#[derive(Debug)]
+pub struct DiscoveryReport<S> {
+    public_addr: Option<SocketAddr>,
+    results: Vec<(S, Result<SocketAddr>)>,
+}
+impl<S> DiscoveryReport<S> {
+    fn new(results: Vec<(S, Result<SocketAddr>)>) -> Self {
+        let mut votes: Vec<(SocketAddr, usize)> = Vec::new();
+        for addr in results.iter().filter_map(|(_, r)| r.as_ref().ok()) {
+            if let Some(v) = votes.iter_mut().find(|v| v.0 == *addr) {
+                v.1 += 1;
+            } else {
+                votes.push((*addr, 1));
+            }
+        }
+        let max_votes = votes.iter().map(|v| v.1).max().unwrap_or(0);
+        let mut winners = votes.iter().filter(|v| v.1 == max_votes);
+        let public_addr = match (winners.next(), winners.next()) {
+            (Some(v), None) => Some(v.0),
+            _ => None,
+        };
+        DiscoveryReport {
+            public_addr,
+            results,
+        }
+    }
+
+    /// Returns the public address agreed by the largest number of servers.
+    ///
+    /// If no servers responded or the top vote is tied between multiple addresses,
+    /// this will return `None`.
+    pub fn public_addr(&self) -> Option<SocketAddr> {
+        self.public_addr
+    }
+
+    /// Returns `true` if the servers reported different addresses, otherwise `false`.
+    ///
+    /// A disagreement suggests that the local endpoint is behind a symmetric NAT
+    /// or some of the servers are misbehaving.
+    pub fn has_disagreement(&self) -> bool {
+        self.succeeded()
+            .any(|(_, addr)| Some(addr) != self.public_addr)
+    }
+
+    /// Returns an iterator over the servers that reported an address other than the consensus one.
+    pub fn disagreeing_servers(&self) -> impl Iterator<Item = (&S, SocketAddr)> {
+        self.succeeded()
+            .filter(move |(_, addr)| Some(*addr) != self.public_addr)
+    }
+
+    /// Returns an iterator over the successful results.
+    pub fn succeeded(&self) -> impl Iterator<Item = (&S, SocketAddr)> {
+        self.results
+            .iter()
+            .filter_map(|(s, r)| r.as_ref().ok().map(|a| (s, *a)))
+    }
+
+    /// Returns an iterator over the servers that could not be queried.
+    pub fn failed(&self) -> impl Iterator<Item = (&S, &Error)> {
+        self.results
+            .iter()
+            .filter_map(|(s, r)| r.as_ref().err().map(|e| (s, e)))
+    }
+
+    /// Returns the results of the all servers in order of completion.
+    pub fn results(&self) -> &[(S, Result<SocketAddr>)] {
+        &self.results
+    }
+}
+
+fn mapped_addr<A>(response: Response<A>) -> Result<SocketAddr>
+where
+    A: Attribute + TryAsRef<XorMappedAddress> + TryAsRef<ErrorCode>,
+{
+    match response {
+        Ok(response) => {
+            let attr = track_assert_some!(
+                response.get_attribute::<XorMappedAddress>(),
+                ErrorKind::Other,
+                "No XOR-MAPPED-ADDRESS attribute"
+            );
+            Ok(attr.address())
+        }
+        Err(response) => {
+            let error = track_assert_some!(
+                response.get_attribute::<ErrorCode>(),
+                ErrorKind::Other,
+                "No ERROR-CODE attribute"
+            );
+            Err(track!(Error::from(error.clone())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Channel;
+    use crate::server::{BindingHandler, TcpServer};
+    use crate::transport::StunTcpTransporter;
+    use factory::DefaultFactory;
+    use fibers_transport::TcpTransporter;
+    use stun_codec::{MessageDecoder, MessageEncoder};
+    use trackable::error::MainError;
+
+    #[test]
+    fn consensus_works() {
+        let a: SocketAddr = "192.0.2.1:1000".parse().unwrap();
+        let b: SocketAddr = "192.0.2.1:2000".parse().unwrap();
+        let report = DiscoveryReport::new(vec![
+            ("s0", Ok(b)),
+            ("s1", Ok(a)),
+            ("s2", Err(ErrorKind::Other.error().into())),
+            ("s3", Ok(a)),
+        ]);
+        assert_eq!(report.public_addr(), Some(a));
+        assert!(report.has_disagreement());
+        assert_eq!(
+            report.disagreeing_servers().collect::<Vec<_>>(),
+            [(&"s0", b)]
+        );
+        assert_eq!(report.failed().count(), 1);
+    }
+
+    #[test]
+    fn tied_consensus_works() {
+        let a: SocketAddr = "192.0.2.1:1000".parse().unwrap();
+        let b: SocketAddr = "192.0.2.1:2000".parse().unwrap();
+        let report = DiscoveryReport::new(vec![("s0", Ok(a)), ("s1", Ok(b))]);
+        assert_eq!(report.public_addr(), None);
+        assert!(report.has_disagreement());
+        assert_eq!(report.disagreeing_servers().count(), 2);
+    }
+
+    #[test]
+    fn tcp_discovery_with_unreachable_server_works() -> std::result::Result<(), MainError> {
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let mut discovery = AddressDiscoveryBuilder::new()
+            .query_timeout(Duration::from_secs(1))
+            .finish();
+        for addr in [server_addr, "127.0.0.1:9".parse().unwrap()] {
+            let future = TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(addr)
+                .map_err(Error::from)
+                .and_then(|transporter| {
+                    let channel = Channel::new(StunTcpTransporter::new(transporter));
+                    let client = Client::new(&fibers_global::handle(), channel);
+                    let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                    client.call((), request)
+                });
+            discovery.query_with(addr, future);
+        }
+
+        let report = fibers_global::execute(discovery)?;
+        assert!(report.public_addr().is_some());
+        assert_eq!(report.succeeded().count(), 1);
+        assert_eq!(report.failed().count(), 1);
+        Ok(())
+    }
+}
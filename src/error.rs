@@ -133,6 +133,9 @@ pub enum MessageErrorKind {
     /// Operation timed out.
     Timeout,
 
+    /// Transaction was cancelled.
+    Cancelled,
+
     /// Other errors.
     Other,
 }
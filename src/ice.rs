@@ -0,0 +1,741 @@
+//! ICE connectivity checks.
+//!
+//! This module provides helpers for issuing and answering the connectivity checks
+//! defined in [RFC 8445 -- 7. Performing Connectivity Checks].
+//!
+//! [`IceAgent`] builds `BINDING` requests that contain `PRIORITY`, `USE-CANDIDATE`,
+//! `ICE-CONTROLLING`/`ICE-CONTROLLED` and short-term credentials,
+//! and validates incoming checks (including the role conflict resolution).
+//! [`ConnectivityChecker`] drives an agent on top of a [`Channel`] and paces outgoing checks by **Ta**.
+//!
+//! [RFC 8445 -- 7. Performing Connectivity Checks]: https://tools.ietf.org/html/rfc8445#section-7
+//! [`IceAgent`]: ./struct.IceAgent.html
+//! [`ConnectivityChecker`]: ./struct.ConnectivityChecker.html
+//! [`Channel`]: ../channel/struct.Channel.html
+use crate::channel::{self, CallOptions, Channel, RecvMessage};
+use crate::message::{ErrorResponse, MessageResult, Request, Response, SuccessResponse};
+use crate::timeout_queue::TimeoutQueue;
+use crate::transport::StunTransport;
+use crate::{Error, ErrorKind, Result};
+use futures::{Async, Future, Poll, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use stun_codec::define_attribute_enums;
+use stun_codec::rfc5245::attributes::{IceControlled, IceControlling, Priority, UseCandidate};
+use stun_codec::rfc5245::errors::RoleConflict;
+use stun_codec::rfc5389::attributes::{
+    AlternateServer, ErrorCode, Fingerprint, MappedAddress, MessageIntegrity, Nonce, Realm,
+    Software, UnknownAttributes, Username, XorMappedAddress, XorMappedAddress2,
+};
+use stun_codec::rfc5389::errors::{BadRequest, Unauthorized};
+use stun_codec::rfc5389::methods::BINDING;
+
+define_attribute_enums!(
+    Attribute,
+    AttributeDecoder,
+    AttributeEncoder,
+    [
+        MappedAddress,
+        Username,
+        MessageIntegrity,
+        ErrorCode,
+        UnknownAttributes,
+        Realm,
+        Nonce,
+        XorMappedAddress,
+        XorMappedAddress2,
+        Software,
+        AlternateServer,
+        Fingerprint,
+        Priority,
+        UseCandidate,
+        IceControlled,
+        IceControlling
+    ]
+);
+
+/// The default value of **Ta**.
+///
+/// > Regardless of the Ta value chosen for each agent, the combination of
+/// > all transactions from all agents (if a given implementation runs
+/// > several concurrent agents) MUST NOT be sent more often than once
+/// > every **5 ms** (as though there were one global Ta value for pacing
+/// > all agents).
+/// > ...
+/// > The default value of Ta is **50 ms**.
+/// >
+/// > [RFC 8445 -- 14.2. Ta]
+///
+/// [RFC 8445 -- 14.2. Ta]: https://tools.ietf.org/html/rfc8445#section-14.2
+pub const DEFAULT_TA_MS: u64 = 50;
+
+/// The role of an ICE agent.
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IceRole {
+    Controlling,
+    Controlled,
+}
+impl IceRole {
+    fn reverse(self) -> Self {
+        match self {
+            IceRole::Controlling => IceRole::Controlled,
+            IceRole::Controlled => IceRole::Controlling,
+        }
+    }
+}
+
+/// Short-term credentials exchanged between ICE agents.
+///
+/// See [RFC 8445 -- 7.2.2. Forming Credentials].
+///
+/// [RFC 8445 -- 7.2.2. Forming Credentials]: https://tools.ietf.org/html/rfc8445#section-7.2.2
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IceCredentials {
+    /// The username fragment of the local agent.
+    pub local_ufrag: String,
+
+    /// The password of the local agent.
+    pub local_password: String,
+
+    /// The username fragment of the remote agent.
+    pub remote_ufrag: String,
+
+    /// The password of the remote agent.
+    pub remote_password: String,
+}
+
+/// Incoming connectivity check that has been accepted by [`IceAgent::handle_check`].
+///
+/// [`IceAgent::handle_check`]: ./struct.IceAgent.html#method.handle_check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncomingCheck {
+    /// The address of the peer that sent the check.
+    pub peer: SocketAddr,
+
+    /// The value of the `PRIORITY` attribute of the check.
+    pub priority: u32,
+
+    /// Whether the check contained the `USE-CANDIDATE` attribute.
+    pub use_candidate: bool,
+}
+
+/// ICE agent state used for issuing and answering connectivity checks.
+#[derive(Debug, Clone)]
+pub struct IceAgent {
+    role: IceRole,
+    tie_breaker: u64,
+    credentials: IceCredentials,
+}
+impl IceAgent {
+    /// Makes a new `IceAgent` instance with a randomly generated tie-breaker.
+    pub fn new(role: IceRole, credentials: IceCredentials) -> Self {
+        Self::with_tie_breaker(role, rand::random(), credentials)
+    }
+
+    /// Makes a new `IceAgent` instance with the given tie-breaker.
+    pub fn with_tie_breaker(role: IceRole, tie_breaker: u64, credentials: IceCredentials) -> Self {
+        IceAgent {
+            role,
+            tie_breaker,
+            credentials,
+        }
+    }
+
+    /// Returns the current role of the agent.
+    pub fn role(&self) -> IceRole {
+        self.role
+    }
+
+    /// Returns the tie-breaker value of the agent.
+    pub fn tie_breaker(&self) -> u64 {
+        self.tie_breaker
+    }
+
+    /// Returns a reference to the credentials of the agent.
+    pub fn credentials(&self) -> &IceCredentials {
+        &self.credentials
+    }
+
+    /// Makes a connectivity check request.
+    ///
+    /// The resulting request contains `USERNAME`, `PRIORITY`, `ICE-CONTROLLING` or `ICE-CONTROLLED`,
+    /// `USE-CANDIDATE` (only if `use_candidate` is `true` and the agent is controlling),
+    /// `MESSAGE-INTEGRITY` and `FINGERPRINT` attributes.
+    pub fn make_check(&self, priority: u32, use_candidate: bool) -> Result<Request<Attribute>> {
+        let username = format!(
+            "{}:{}",
+            self.credentials.remote_ufrag, self.credentials.local_ufrag
+        );
+        let mut request = Request::new(BINDING);
+        request.add_attribute(track!(Username::new(username).map_err(Error::from))?.into());
+        request.add_attribute(Priority::new(priority).into());
+        match self.role {
+            IceRole::Controlling => {
+                request.add_attribute(IceControlling::new(self.tie_breaker).into());
+                if use_candidate {
+                    request.add_attribute(UseCandidate::new().into());
+                }
+            }
+            IceRole::Controlled => {
+                request.add_attribute(IceControlled::new(self.tie_breaker).into());
+            }
+        }
+        let integrity = track!(MessageIntegrity::new_short_term_credential(
+            request.as_ref(),
+            &self.credentials.remote_password
+        )
+        .map_err(Error::from))?;
+        request.add_attribute(integrity.into());
+        let fingerprint = track!(Fingerprint::new(request.as_ref()).map_err(Error::from))?;
+        request.add_attribute(fingerprint.into());
+        Ok(request)
+    }
+
+    /// Handles an incoming connectivity check and returns the response to be replied.
+    ///
+    /// If the check is accepted, the returned `IncomingCheck` will be `Some(_)`.
+    ///
+    /// The role conflict is resolved as described in [RFC 8445 -- 7.3.1.1. Detecting and Repairing Role Conflicts].
+    /// Note that the role of the agent may be switched by this method.
+    ///
+    /// [RFC 8445 -- 7.3.1.1. Detecting and Repairing Role Conflicts]: https://tools.ietf.org/html/rfc8445#section-7.3.1.1
+    pub fn handle_check(
+        &mut self,
+        peer: SocketAddr,
+        request: &Request<Attribute>,
+    ) -> Result<(Response<Attribute>, Option<IncomingCheck>)> {
+        if request.method() != BINDING {
+            let response = track!(self.make_error_response(request, BadRequest.into(), false))?;
+            return Ok((Err(response), None));
+        }
+
+        let username = request.get_attribute::<Username>();
+        let integrity = request.get_attribute::<MessageIntegrity>();
+        let priority = request.get_attribute::<Priority>();
+        let (username, integrity, priority) = match (username, integrity, priority) {
+            (Some(u), Some(i), Some(p)) => (u, i, p),
+            _ => {
+                let response = track!(self.make_error_response(request, BadRequest.into(), false))?;
+                return Ok((Err(response), None));
+            }
+        };
+
+        let expected_prefix = format!("{}:", self.credentials.local_ufrag);
+        let authorized = username.name().starts_with(&expected_prefix)
+            && integrity
+                .check_short_term_credential(&self.credentials.local_password)
+                .is_ok();
+        if !authorized {
+            let response = track!(self.make_error_response(request, Unauthorized.into(), false))?;
+            return Ok((Err(response), None));
+        }
+
+        match self.role {
+            IceRole::Controlling => {
+                if let Some(remote) = request.get_attribute::<IceControlling>() {
+                    if self.tie_breaker >= remote.prio() {
+                        let response =
+                            track!(self.make_error_response(request, RoleConflict.into(), true))?;
+                        return Ok((Err(response), None));
+                    }
+                    self.role = IceRole::Controlled;
+                }
+            }
+            IceRole::Controlled => {
+                if let Some(remote) = request.get_attribute::<IceControlled>() {
+                    if self.tie_breaker >= remote.prio() {
+                        self.role = IceRole::Controlling;
+                    } else {
+                        let response =
+                            track!(self.make_error_response(request, RoleConflict.into(), true))?;
+                        return Ok((Err(response), None));
+                    }
+                }
+            }
+        }
+
+        let mut response = SuccessResponse::new(request);
+        response.add_attribute(XorMappedAddress::new(peer).into());
+        let integrity = track!(MessageIntegrity::new_short_term_credential(
+            response.as_ref(),
+            &self.credentials.local_password
+        )
+        .map_err(Error::from))?;
+        response.add_attribute(integrity.into());
+        let fingerprint = track!(Fingerprint::new(response.as_ref()).map_err(Error::from))?;
+        response.add_attribute(fingerprint.into());
+
+        let check = IncomingCheck {
+            peer,
+            priority: priority.prio(),
+            use_candidate: request.get_attribute::<UseCandidate>().is_some(),
+        };
+        Ok((Ok(response), Some(check)))
+    }
+
+    /// Handles the response of a connectivity check issued by this agent.
+    ///
+    /// If the response is a `487 (Role Conflict)` error, the role of the agent is switched
+    /// as described in [RFC 8445 -- 7.2.5.1. Role Conflict] and `Ok(None)` is returned.
+    /// In that case, the check should be retried.
+    ///
+    /// If the response is a success one with the valid `MESSAGE-INTEGRITY` attribute,
+    /// this will return the mapped address of the check.
+    /// Otherwise, an `ErrorKind::Other` error is returned.
+    ///
+    /// [RFC 8445 -- 7.2.5.1. Role Conflict]: https://tools.ietf.org/html/rfc8445#section-7.2.5.1
+    pub fn handle_check_response(
+        &mut self,
+        request_role: IceRole,
+        response: &Response<Attribute>,
+    ) -> Result<Option<SocketAddr>> {
+        match response {
+            Ok(response) => {
+                let integrity = track_assert_some!(
+                    response.get_attribute::<MessageIntegrity>(),
+                    ErrorKind::Other,
+                    "No MESSAGE-INTEGRITY attribute"
+                );
+                track!(integrity
+                    .check_short_term_credential(&self.credentials.remote_password)
+                    .map_err(Error::from))?;
+                let mapped = track_assert_some!(
+                    response.get_attribute::<XorMappedAddress>(),
+                    ErrorKind::Other,
+                    "No XOR-MAPPED-ADDRESS attribute"
+                );
+                Ok(Some(mapped.address()))
+            }
+            Err(response) => {
+                let error =
+                    track_assert_some!(response.get_attribute::<ErrorCode>(), ErrorKind::Other);
+                if error.code() == RoleConflict::CODEPOINT {
+                    if self.role == request_role {
+                        self.role = request_role.reverse();
+                    }
+                    Ok(None)
+                } else {
+                    Err(track!(Error::from(error.clone())))
+                }
+            }
+        }
+    }
+
+    fn make_error_response(
+        &self,
+        request: &Request<Attribute>,
+        error: ErrorCode,
+        with_integrity: bool,
+    ) -> Result<ErrorResponse<Attribute>> {
+        let mut response = ErrorResponse::new(request, error);
+        if with_integrity {
+            let integrity = track!(MessageIntegrity::new_short_term_credential(
+                response.as_ref(),
+                &self.credentials.local_password
+            )
+            .map_err(Error::from))?;
+            response.add_attribute(integrity.into());
+        }
+        let fingerprint = track!(Fingerprint::new(response.as_ref()).map_err(Error::from))?;
+        response.add_attribute(fingerprint.into());
+        Ok(response)
+    }
+}
+
+/// Event emitted by [`ConnectivityChecker`].
+///
+/// [`ConnectivityChecker`]: ./struct.ConnectivityChecker.html
+#[derive(Debug)]
+pub enum CheckEvent {
+    /// An outgoing check has succeeded.
+    Succeeded {
+        /// The destination of the check.
+        peer: SocketAddr,
+
+        /// The mapped address reported by the peer.
+        mapped_addr: SocketAddr,
+
+        /// Whether the check contained the `USE-CANDIDATE` attribute.
+        nominated: bool,
+    },
+
+    /// An outgoing check has failed.
+    Failed {
+        /// The destination of the check.
+        peer: SocketAddr,
+
+        /// The reason of the failure.
+        error: Error,
+    },
+
+    /// An incoming check has been accepted.
+    ///
+    /// The ICE agent may want to issue a triggered check to the peer.
+    Incoming(IncomingCheck),
+
+    /// The role of the agent has been switched by a role conflict.
+    RoleChanged(IceRole),
+}
+
+type CheckFuture = Box<
+    dyn Future<
+            Item = (
+                SocketAddr,
+                CheckOptions,
+                IceRole,
+                MessageResult<Response<Attribute>>,
+            ),
+            Error = (),
+        > + Send
+        + 'static,
+>;
+
+#[derive(Debug, Clone, Copy)]
+struct CheckOptions {
+    priority: u32,
+    use_candidate: bool,
+}
+
+/// [`ConnectivityChecker`] builder.
+///
+/// [`ConnectivityChecker`]: ./struct.ConnectivityChecker.html
+#[derive(Debug, Clone)]
+pub struct ConnectivityCheckerBuilder {
+    ta: Duration,
+}
+impl ConnectivityCheckerBuilder {
+    /// Makes a new `ConnectivityCheckerBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the pacing interval (**Ta**) of outgoing checks.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_TA_MS)`.
+    pub fn ta(&mut self, ta: Duration) -> &mut Self {
+        self.ta = ta;
+        self
+    }
+
+    /// Makes a new `ConnectivityChecker` instance with the given settings.
+    pub fn finish<T>(
+        &self,
+        agent: IceAgent,
+        channel: Channel<Attribute, T>,
+    ) -> ConnectivityChecker<T>
+    where
+        T: StunTransport<Attribute, PeerAddr = SocketAddr>,
+    {
+        ConnectivityChecker {
+            agent,
+            channel,
+            ta: self.ta,
+            pacer: TimeoutQueue::new(),
+            pacing: false,
+            pending_checks: VecDeque::new(),
+            outstanding_checks: Vec::new(),
+            events: VecDeque::new(),
+        }
+    }
+}
+impl Default for ConnectivityCheckerBuilder {
+    fn default() -> Self {
+        ConnectivityCheckerBuilder {
+            ta: Duration::from_millis(DEFAULT_TA_MS),
+        }
+    }
+}
+
+/// ICE connectivity checker.
+///
+/// This sends connectivity checks queued by `check` method at intervals of **Ta**,
+/// and automatically answers incoming checks sent by the remote agent.
+///
+/// The results are reported as a stream of [`CheckEvent`]s.
+///
+/// [`CheckEvent`]: ./enum.CheckEvent.html
+#[must_use = "streams do nothing unless polled"]
+pub struct ConnectivityChecker<T>
+where
+    T: StunTransport<Attribute, PeerAddr = SocketAddr>,
+{
+    agent: IceAgent,
+    channel: Channel<Attribute, T>,
+    ta: Duration,
+    pacer: TimeoutQueue<()>,
+    pacing: bool,
+    pending_checks: VecDeque<(SocketAddr, CheckOptions)>,
+    outstanding_checks: Vec<CheckFuture>,
+    events: VecDeque<CheckEvent>,
+}
+impl<T> ConnectivityChecker<T>
+where
+    T: StunTransport<Attribute, PeerAddr = SocketAddr> + 'static,
+{
+    /// Makes a new `ConnectivityChecker` instance.
+    ///
+    /// This is equivalent to `ConnectivityCheckerBuilder::default().finish(agent, channel)`.
+    pub fn new(agent: IceAgent, channel: Channel<Attribute, T>) -> Self {
+        ConnectivityCheckerBuilder::default().finish(agent, channel)
+    }
+
+    /// Enqueues a connectivity check to `peer`.
+    ///
+    /// The check will be sent after the preceding checks have been paced out.
+    pub fn check(&mut self, peer: SocketAddr, priority: u32, use_candidate: bool) {
+        let options = CheckOptions {
+            priority,
+            use_candidate,
+        };
+        self.pending_checks.push_back((peer, options));
+    }
+
+    /// Returns a reference to the ICE agent.
+    pub fn agent(&self) -> &IceAgent {
+        &self.agent
+    }
+
+    /// Returns a reference to the underlying channel.
+    pub fn channel_ref(&self) -> &Channel<Attribute, T> {
+        &self.channel
+    }
+
+    /// Returns a mutable reference to the underlying channel.
+    pub fn channel_mut(&mut self) -> &mut Channel<Attribute, T> {
+        &mut self.channel
+    }
+
+    /// Returns the number of the checks waiting to be sent.
+    pub fn pending_checks(&self) -> usize {
+        self.pending_checks.len()
+    }
+
+    fn handle_request(&mut self, peer: SocketAddr, request: Request<Attribute>) -> Result<()> {
+        let role = self.agent.role();
+        let (response, check) = track!(self.agent.handle_check(peer, &request))?;
+        track!(self.channel.reply(peer, response))?;
+        if role != self.agent.role() {
+            self.events
+                .push_back(CheckEvent::RoleChanged(self.agent.role()));
+        }
+        if let Some(check) = check {
+            self.events.push_back(CheckEvent::Incoming(check));
+        }
+        Ok(())
+    }
+
+    fn send_pending_check(&mut self) -> Result<()> {
+        while let Some(()) = self.pacer.pop() {
+            self.pacing = false;
+        }
+        if self.pacing {
+            return Ok(());
+        }
+        if let Some((peer, options)) = self.pending_checks.pop_front() {
+            let role = self.agent.role();
+            let request = track!(self
+                .agent
+                .make_check(options.priority, options.use_candidate))?;
+            let mut call_options = CallOptions::new();
+            // Checks are already paced by `Ta`
+            call_options.no_throttle(true);
+            if options.use_candidate {
+                // Nominations should not wait behind other pending requests
+                call_options.priority(channel::Priority::Urgent);
+            }
+            let future = self
+                .channel
+                .call_with(peer, request, &call_options)
+                .then(move |result| Ok((peer, options, role, result)));
+            self.outstanding_checks.push(Box::new(future));
+            self.pacer.push((), self.ta);
+            self.pacing = true;
+        }
+        Ok(())
+    }
+
+    fn poll_outstanding_checks(
+        &mut self,
+    ) -> Option<(
+        SocketAddr,
+        CheckOptions,
+        IceRole,
+        MessageResult<Response<Attribute>>,
+    )> {
+        for i in 0..self.outstanding_checks.len() {
+            if let Ok(Async::Ready(item)) = self.outstanding_checks[i].poll() {
+                let _ = self.outstanding_checks.swap_remove(i);
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    fn handle_check_result(
+        &mut self,
+        peer: SocketAddr,
+        options: CheckOptions,
+        role: IceRole,
+        result: MessageResult<Response<Attribute>>,
+    ) {
+        let event = match result
+            .map_err(Error::from)
+            .and_then(|response| track!(self.agent.handle_check_response(role, &response)))
+        {
+            Err(error) => CheckEvent::Failed { peer, error },
+            Ok(Some(mapped_addr)) => CheckEvent::Succeeded {
+                peer,
+                mapped_addr,
+                nominated: options.use_candidate && role == IceRole::Controlling,
+            },
+            Ok(None) => {
+                self.pending_checks.push_front((peer, options));
+                if self.agent.role() == role {
+                    return;
+                }
+                CheckEvent::RoleChanged(self.agent.role())
+            }
+        };
+        self.events.push_back(event);
+    }
+}
+impl<T> Stream for ConnectivityChecker<T>
+where
+    T: StunTransport<Attribute, PeerAddr = SocketAddr> + 'static,
+{
+    type Item = CheckEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+
+            let mut did_something = false;
+            match track!(self.channel.poll_recv())? {
+                Async::NotReady => {}
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::Ready(Some((peer, RecvMessage::Request(request)))) => {
+                    track!(self.handle_request(peer, request))?;
+                    did_something = true;
+                }
+                Async::Ready(Some((_, RecvMessage::Invalid(_)))) => {
+                    // Malformed checks are silently discarded
+                    did_something = true;
+                }
+                Async::Ready(Some((_, RecvMessage::Indication(_)))) => {
+                    // Keepalive indications are ignored
+                    did_something = true;
+                }
+            }
+
+            track!(self.send_pending_check())?;
+            track!(self.channel.poll_send())?;
+
+            while let Some((peer, options, role, result)) = self.poll_outstanding_checks() {
+                self.handle_check_result(peer, options, role, result);
+                did_something = true;
+            }
+
+            if !did_something && self.events.is_empty() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+impl<T> fmt::Debug for ConnectivityChecker<T>
+where
+    T: StunTransport<Attribute, PeerAddr = SocketAddr>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ConnectivityChecker {{ agent: {:?}, ta: {:?}, pending_checks: {}, .. }}",
+            self.agent,
+            self.ta,
+            self.pending_checks.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(local: &str, remote: &str) -> IceCredentials {
+        IceCredentials {
+            local_ufrag: local.to_owned(),
+            local_password: format!("{local}-password"),
+            remote_ufrag: remote.to_owned(),
+            remote_password: format!("{remote}-password"),
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5000".parse().unwrap()
+    }
+
+    #[test]
+    fn check_works() -> Result<()> {
+        let alice = IceAgent::with_tie_breaker(IceRole::Controlling, 10, credentials("a", "b"));
+        let mut bob = IceAgent::with_tie_breaker(IceRole::Controlled, 20, credentials("b", "a"));
+
+        let request = track!(alice.make_check(100, true))?;
+        let (response, check) = track!(bob.handle_check(peer(), &request))?;
+        assert!(response.is_ok());
+        assert_eq!(
+            check,
+            Some(IncomingCheck {
+                peer: peer(),
+                priority: 100,
+                use_candidate: true
+            })
+        );
+        assert_eq!(bob.role(), IceRole::Controlled);
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_credentials_are_rejected() -> Result<()> {
+        let alice = IceAgent::with_tie_breaker(IceRole::Controlling, 10, credentials("a", "x"));
+        let mut bob = IceAgent::with_tie_breaker(IceRole::Controlled, 20, credentials("b", "a"));
+
+        let request = track!(alice.make_check(100, false))?;
+        let (response, check) = track!(bob.handle_check(peer(), &request))?;
+        let error = response.err().unwrap();
+        assert_eq!(
+            error.get_attribute::<ErrorCode>().map(|e| e.code()),
+            Some(Unauthorized::CODEPOINT)
+        );
+        assert_eq!(check, None);
+        Ok(())
+    }
+
+    #[test]
+    fn role_conflict_works() -> Result<()> {
+        // Both agents are controlling; the one with the larger tie-breaker wins.
+        let mut alice = IceAgent::with_tie_breaker(IceRole::Controlling, 10, credentials("a", "b"));
+        let mut bob = IceAgent::with_tie_breaker(IceRole::Controlling, 20, credentials("b", "a"));
+
+        let request = track!(alice.make_check(100, false))?;
+        let (response, _) = track!(bob.handle_check(peer(), &request))?;
+        assert_eq!(bob.role(), IceRole::Controlling);
+        assert_eq!(
+            track!(alice.handle_check_response(IceRole::Controlling, &response))?,
+            None
+        );
+        assert_eq!(alice.role(), IceRole::Controlled);
+
+        let request = track!(alice.make_check(100, false))?;
+        let (response, _) = track!(bob.handle_check(peer(), &request))?;
+        assert_eq!(
+            track!(alice.handle_check_response(IceRole::Controlled, &response))?,
+            Some(peer())
+        );
+        Ok(())
+    }
+}
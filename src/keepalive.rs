@@ -0,0 +1,472 @@
+//! NAT binding keepalive.
+//!
+//! This module provides [`NatKeepalive`] that periodically sends `BINDING` indications
+//! (and optionally `BINDING` requests) to STUN servers for keeping NAT bindings open.
+//!
+//! > STUN Binding Indications ... can be used as keepalives.
+//! > ...
+//! > Agents SHOULD use a Tr value of **15 seconds**.
+//! >
+//! > [RFC 8445 -- 11. ICE Keepalives]
+//!
+//! [`NatKeepalive`]: ./struct.NatKeepalive.html
+//! [RFC 8445 -- 11. ICE Keepalives]: https://tools.ietf.org/html/rfc8445#section-11
+use crate::client::Client;
+use crate::message::{Indication, Request, Response};
+use crate::timeout_queue::TimeoutQueue;
+use crate::transport::StunTransport;
+use crate::{Error, ErrorKind, Result};
+use futures::{Async, Future, Poll, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use stun_codec::convert::TryAsRef;
+use stun_codec::rfc5389;
+use stun_codec::rfc5389::attributes::{ErrorCode, XorMappedAddress};
+use stun_codec::Attribute;
+use trackable::error::ErrorKindExt;
+
+type RequestFuture<A, P> = Box<dyn Future<Item = (P, Result<Response<A>>), Error = ()> + Send>;
+
+/// [`NatKeepalive`] builder.
+///
+/// [`NatKeepalive`]: ./struct.NatKeepalive.html
+#[derive(Debug, Clone)]
+pub struct NatKeepaliveBuilder {
+    indication_interval: Option<Duration>,
+    request_interval: Option<Duration>,
+}
+impl NatKeepaliveBuilder {
+    /// The default interval of `BINDING` indications.
+    ///
+    /// > Agents SHOULD use a Tr value of **15 seconds**.
+    /// >
+    /// > [RFC 8445 -- 11. ICE Keepalives]
+    ///
+    /// [RFC 8445 -- 11. ICE Keepalives]: https://tools.ietf.org/html/rfc8445#section-11
+    pub const DEFAULT_INDICATION_INTERVAL_MS: u64 = 15_000;
+
+    /// Makes a new `NatKeepaliveBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the interval of `BINDING` indications.
+    ///
+    /// If `None` is specified, no indications are sent.
+    ///
+    /// The default value is `Some(Duration::from_millis(DEFAULT_INDICATION_INTERVAL_MS))`.
+    pub fn indication_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.indication_interval = interval;
+        self
+    }
+
+    /// Sets the interval of `BINDING` requests.
+    ///
+    /// Unlike indications, requests make it possible to detect the changes of the public mapping.
+    /// If `None` is specified, no requests are sent.
+    ///
+    /// The default value is `None`.
+    pub fn request_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.request_interval = interval;
+        self
+    }
+
+    /// Makes a new `NatKeepalive` instance with the given settings.
+    pub fn finish<A, T>(&self, client: Client<A, T>) -> NatKeepalive<A, T>
+    where
+        A: Attribute + TryAsRef<XorMappedAddress> + TryAsRef<ErrorCode> + Send + 'static,
+        T: StunTransport<A> + Send + 'static,
+        T::PeerAddr: Send + 'static,
+    {
+        NatKeepalive {
+            client,
+            indication_interval: self.indication_interval,
+            request_interval: self.request_interval,
+            servers: HashMap::new(),
+            timeout_queue: TimeoutQueue::new(),
+            outstanding_requests: Vec::new(),
+            events: VecDeque::new(),
+            next_generation: 0,
+        }
+    }
+}
+impl Default for NatKeepaliveBuilder {
+    fn default() -> Self {
+        NatKeepaliveBuilder {
+            indication_interval: Some(Duration::from_millis(Self::DEFAULT_INDICATION_INTERVAL_MS)),
+            request_interval: None,
+        }
+    }
+}
+
+/// Event emitted by [`NatKeepalive`].
+///
+/// [`NatKeepalive`]: ./struct.NatKeepalive.html
+#[derive(Debug)]
+pub enum KeepaliveEvent<P> {
+    /// The public mapping has been discovered by the first successful request to the server.
+    MappingDiscovered {
+        /// The STUN server.
+        server: P,
+
+        /// The public address reported by the server.
+        mapped_addr: SocketAddr,
+    },
+
+    /// The public mapping has changed.
+    MappingChanged {
+        /// The STUN server.
+        server: P,
+
+        /// The previously reported public address.
+        old_addr: SocketAddr,
+
+        /// The newly reported public address.
+        new_addr: SocketAddr,
+    },
+
+    /// A keepalive request or indication could not be completed.
+    Failed {
+        /// The STUN server.
+        server: P,
+
+        /// The reason of the failure.
+        error: Error,
+    },
+}
+
+/// NAT binding keepalive service.
+///
+/// This periodically sends `BINDING` indications via [`Client::cast`], and optionally
+/// `BINDING` requests via [`Client::call`], to the registered STUN servers.
+///
+/// The `XOR-MAPPED-ADDRESS` attributes of the responses are compared with the previous ones,
+/// and the changes of the public mapping are reported as a stream of [`KeepaliveEvent`]s.
+///
+/// [`Client::cast`]: ../client/struct.Client.html#method.cast
+/// [`Client::call`]: ../client/struct.Client.html#method.call
+/// [`KeepaliveEvent`]: ./enum.KeepaliveEvent.html
+#[must_use = "streams do nothing unless polled"]
+pub struct NatKeepalive<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    client: Client<A, T>,
+    indication_interval: Option<Duration>,
+    request_interval: Option<Duration>,
+    servers: HashMap<T::PeerAddr, ServerState>,
+    timeout_queue: TimeoutQueue<TimeoutEntry<T::PeerAddr>>,
+    outstanding_requests: Vec<RequestFuture<A, T::PeerAddr>>,
+    events: VecDeque<KeepaliveEvent<T::PeerAddr>>,
+    next_generation: u64,
+}
+impl<A, T> NatKeepalive<A, T>
+where
+    A: Attribute + TryAsRef<XorMappedAddress> + TryAsRef<ErrorCode> + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    /// Makes a new `NatKeepalive` instance.
+    ///
+    /// This is equivalent to `NatKeepaliveBuilder::default().finish(client)`.
+    pub fn new(client: Client<A, T>) -> Self {
+        NatKeepaliveBuilder::default().finish(client)
+    }
+
+    /// Starts sending keepalives to the given server.
+    ///
+    /// If request keepalives are enabled, the first request is sent immediately.
+    pub fn add_server(&mut self, server: T::PeerAddr) {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        self.servers.insert(
+            server.clone(),
+            ServerState {
+                mapped_addr: None,
+                generation,
+            },
+        );
+        if let Some(interval) = self.indication_interval {
+            let entry = TimeoutEntry::Indication {
+                server: server.clone(),
+                generation,
+            };
+            self.timeout_queue.push(entry, interval);
+        }
+        if self.request_interval.is_some() {
+            self.send_request(server, generation);
+        }
+    }
+
+    /// Stops sending keepalives to the given server.
+    pub fn remove_server(&mut self, server: &T::PeerAddr) {
+        self.servers.remove(server);
+    }
+
+    /// Returns the latest public address reported by the given server.
+    pub fn mapped_addr(&self, server: &T::PeerAddr) -> Option<SocketAddr> {
+        self.servers.get(server).and_then(|s| s.mapped_addr)
+    }
+
+    fn is_current(&self, server: &T::PeerAddr, generation: u64) -> bool {
+        self.servers
+            .get(server)
+            .is_some_and(|s| s.generation == generation)
+    }
+
+    fn send_indication(&mut self, server: T::PeerAddr, generation: u64) {
+        let indication = Indication::new(rfc5389::methods::BINDING);
+        if let Err(error) = track!(self.client.cast(server.clone(), indication)) {
+            self.events.push_back(KeepaliveEvent::Failed {
+                server: server.clone(),
+                error,
+            });
+        }
+        if let Some(interval) = self.indication_interval {
+            self.timeout_queue
+                .push(TimeoutEntry::Indication { server, generation }, interval);
+        }
+    }
+
+    fn send_request(&mut self, server: T::PeerAddr, generation: u64) {
+        let request = Request::new(rfc5389::methods::BINDING);
+        let s = server.clone();
+        let future = self
+            .client
+            .call(server.clone(), request)
+            .then(move |result| Ok((s, result)));
+        self.outstanding_requests.push(Box::new(future));
+        if let Some(interval) = self.request_interval {
+            self.timeout_queue
+                .push(TimeoutEntry::Request { server, generation }, interval);
+        }
+    }
+
+    fn poll_outstanding_requests(&mut self) -> Option<(T::PeerAddr, Result<Response<A>>)> {
+        for i in 0..self.outstanding_requests.len() {
+            if let Ok(Async::Ready(item)) = self.outstanding_requests[i].poll() {
+                let _ = self.outstanding_requests.swap_remove(i);
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    fn handle_response(
+        &mut self,
+        server: T::PeerAddr,
+        result: Result<Response<A>>,
+    ) -> Option<KeepaliveEvent<T::PeerAddr>> {
+        let state = self.servers.get_mut(&server)?;
+        let mapped_addr = match result {
+            Err(error) => return Some(KeepaliveEvent::Failed { server, error }),
+            Ok(Err(response)) => {
+                let error = response.get_attribute::<ErrorCode>().map_or_else(
+                    || ErrorKind::Other.cause("Error response").into(),
+                    |e| Error::from(e.clone()),
+                );
+                return Some(KeepaliveEvent::Failed { server, error });
+            }
+            Ok(Ok(response)) => {
+                if let Some(a) = response.get_attribute::<XorMappedAddress>() {
+                    a.address()
+                } else {
+                    let error = ErrorKind::Other
+                        .cause("No XOR-MAPPED-ADDRESS attribute")
+                        .into();
+                    return Some(KeepaliveEvent::Failed { server, error });
+                }
+            }
+        };
+        match state.mapped_addr.replace(mapped_addr) {
+            None => Some(KeepaliveEvent::MappingDiscovered {
+                server,
+                mapped_addr,
+            }),
+            Some(old_addr) if old_addr != mapped_addr => Some(KeepaliveEvent::MappingChanged {
+                server,
+                old_addr,
+                new_addr: mapped_addr,
+            }),
+            Some(_) => None,
+        }
+    }
+}
+impl<A, T> Stream for NatKeepalive<A, T>
+where
+    A: Attribute + TryAsRef<XorMappedAddress> + TryAsRef<ErrorCode> + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    type Item = KeepaliveEvent<T::PeerAddr>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while let Some(entry) = self.timeout_queue.pop() {
+            match entry {
+                TimeoutEntry::Indication { server, generation } => {
+                    if self.is_current(&server, generation) {
+                        self.send_indication(server, generation);
+                    }
+                }
+                TimeoutEntry::Request { server, generation } => {
+                    if self.is_current(&server, generation) {
+                        self.send_request(server, generation);
+                    }
+                }
+            }
+        }
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Async::Ready(Some(event)));
+        }
+
+        while let Some((server, result)) = self.poll_outstanding_requests() {
+            if let Some(event) = self.handle_response(server, result) {
+                return Ok(Async::Ready(Some(event)));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<A, T> fmt::Debug for NatKeepalive<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "NatKeepalive {{ indication_interval: {:?}, request_interval: {:?}, servers: {:?}, .. }}",
+            self.indication_interval, self.request_interval, self.servers
+        )
+    }
+}
+
+#[derive(Debug)]
+struct ServerState {
+    mapped_addr: Option<SocketAddr>,
+    generation: u64,
+}
+
+#[derive(Debug)]
+enum TimeoutEntry<P> {
+    Indication { server: P, generation: u64 },
+    Request { server: P, generation: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Channel;
+    use crate::message::SuccessResponse;
+    use crate::server::{Action, BindingHandler, HandleMessage, RequestContext, UdpServer};
+    use crate::transport::StunUdpTransporter;
+    use fibers_transport::{UdpTransport, UdpTransporter};
+    use stun_codec::{MessageDecoder, MessageEncoder};
+    use trackable::error::MainError;
+
+    #[test]
+    fn mapping_discovery_works() -> std::result::Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let transporter = fibers_global::execute(UdpTransporter::<
+            MessageEncoder<rfc5389::Attribute>,
+            MessageDecoder<rfc5389::Attribute>,
+        >::bind("127.0.0.1:0".parse().unwrap()))?;
+        let client_addr = transporter.local_addr();
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let mut keepalive = NatKeepaliveBuilder::new()
+            .indication_interval(Some(Duration::from_millis(10)))
+            .request_interval(Some(Duration::from_millis(50)))
+            .finish(client);
+        keepalive.add_server(server_addr);
+
+        let (event, keepalive) =
+            fibers_global::execute(keepalive.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(
+            event,
+            Some(KeepaliveEvent::MappingDiscovered { server, mapped_addr })
+                if server == server_addr && mapped_addr == client_addr
+        ));
+        assert_eq!(keepalive.mapped_addr(&server_addr), Some(client_addr));
+        Ok(())
+    }
+
+    #[test]
+    fn mapping_change_works() -> std::result::Result<(), MainError> {
+        // Reports a different public port for every request, as a rebinding NAT would do
+        struct RebindingHandler {
+            count: u16,
+        }
+        impl HandleMessage for RebindingHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                context: &RequestContext,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let mut mapped = context.peer();
+                mapped.set_port(10_000 + self.count);
+                self.count += 1;
+
+                let mut response = SuccessResponse::new(&request);
+                response.add_attribute(XorMappedAddress::new(mapped).into());
+                Action::Reply(Ok(response))
+            }
+        }
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            RebindingHandler { count: 0 },
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let transporter = fibers_global::execute(UdpTransporter::<
+            MessageEncoder<rfc5389::Attribute>,
+            MessageDecoder<rfc5389::Attribute>,
+        >::bind("127.0.0.1:0".parse().unwrap()))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let mut keepalive = NatKeepaliveBuilder::new()
+            .indication_interval(None)
+            .request_interval(Some(Duration::from_millis(10)))
+            .finish(client);
+        keepalive.add_server(server_addr);
+
+        let first_addr = SocketAddr::new(server_addr.ip(), 10_000);
+        let second_addr = SocketAddr::new(server_addr.ip(), 10_001);
+
+        let (event, keepalive) =
+            fibers_global::execute(keepalive.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(
+            event,
+            Some(KeepaliveEvent::MappingDiscovered { server, mapped_addr })
+                if server == server_addr && mapped_addr == first_addr
+        ));
+
+        let (event, keepalive) =
+            fibers_global::execute(keepalive.into_future().map_err(|(e, _)| e))?;
+        assert!(matches!(
+            event,
+            Some(KeepaliveEvent::MappingChanged { server, old_addr, new_addr })
+                if server == server_addr && old_addr == first_addr && new_addr == second_addr
+        ));
+        assert_eq!(keepalive.mapped_addr(&server_addr), Some(second_addr));
+        Ok(())
+    }
+}
@@ -6,20 +6,18 @@
 //!
 //! ```
 //! # extern crate fibers_global;
-//! # extern crate fibers_transport;
 //! # extern crate futures;
 //! # extern crate rustun;
 //! # extern crate stun_codec;
 //! # extern crate trackable;
-//! use fibers_transport::UdpTransporter;
 //! use futures::Future;
 //! use rustun::channel::Channel;
 //! use rustun::client::Client;
 //! use rustun::message::Request;
 //! use rustun::server::{BindingHandler, UdpServer};
-//! use rustun::transport::StunUdpTransporter;
+//! use rustun::transport::{StdUdpTransporter, StunUdpTransporter};
 //! use rustun::Error;
-//! use stun_codec::{rfc5389, MessageDecoder, MessageEncoder};
+//! use stun_codec::rfc5389;
 //!
 //! # fn main() -> Result<(), trackable::error::MainError> {
 //! let addr = "127.0.0.1:0".parse().unwrap();
@@ -30,7 +28,7 @@
 //! fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
 //!
 //! // Sents BINDING request
-//! let response = UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(addr)
+//! let response = StdUdpTransporter::bind(addr)
 //!     .map_err(Error::from)
 //!     .map(StunUdpTransporter::new)
 //!     .map(Channel::new)
@@ -55,15 +53,20 @@
 //!
 //! // Executes a STUN client in another shell.
 //! $ cargo run --example binding_cli -- 127.0.0.1
-//! Ok(SuccessResponse(Message {
-//!     class: SuccessResponse,
-//!     method: Method(1),
-//!     transaction_id: TransactionId(0x344A403694972F5E53B69465),
-//!     attributes: [Known { inner: XorMappedAddress(XorMappedAddress(V4(127.0.0.1:54754))),
-//!                          padding: Some(Padding([])) }]
-//! }))
+//! 127.0.0.1:3478: BINDING success response
+//!   transaction_id: 344a403694972f5e53b69465
+//!   XOR-MAPPED-ADDRESS: 127.0.0.1:54754
 //! ```
 //!
+//! # Tracing
+//!
+//! If the `tracing` feature is enabled, channels, transporters and servers emit [`tracing`] events
+//! (e.g., sending, retransmission and timeout of requests, and dispatching of messages to handlers).
+//! The events that belong to a transaction are emitted in a `stun_transaction` span
+//! that has the `peer`, `method` and `transaction_id` fields.
+//!
+//! [`tracing`]: https://docs.rs/tracing/0.1
+//!
 //! # References
 //!
 //! - [RFC 5389 - Session Traversal Utilities for NAT (STUN)][RFC 5389]
@@ -75,33 +78,63 @@ extern crate trackable;
 
 pub use error::{Error, ErrorKind};
 
+#[macro_use]
+mod trace;
+
 pub mod channel;
 pub mod client;
+pub mod clock;
+pub mod consent;
+pub mod discovery;
+pub mod ice;
+pub mod keepalive;
 pub mod message;
+pub mod replay;
 pub mod server;
 pub mod transport;
 
 mod error;
+mod timeout_queue;
 
 /// A specialized `Result` type for this crate.
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[cfg(test)]
 mod tests {
-    use crate::channel::Channel;
-    use crate::client::Client;
-    use crate::message::Request;
-    use crate::server::{BindingHandler, TcpServer, UdpServer};
-    use crate::transport::{StunTcpTransporter, StunUdpTransporter};
-    use crate::Error;
-    use factory::DefaultFactory;
-    use fibers_global;
+    use crate::channel::{Channel, ChannelBuilder};
+    use crate::client::{Client, ClientHealth, Connect, TcpClientPoolBuilder, TcpConnector};
+    use crate::clock::{Clock, ManualClock};
+    use crate::message::MessageErrorKind;
+    use crate::message::Response;
+    use crate::message::{ErrorResponse, Indication, Request, SuccessResponse};
+    use crate::replay::{PcapReplay, ReplayOutput};
+    #[cfg(unix)]
+    use crate::server::MultiSocketUdpServer;
+    use crate::server::{
+        Action, BindingHandler, CompositeServerBuilder, HandleMessage, HandleSharedMessage,
+        LimitExceeded, OverloadPolicy, Protocol, RequestContext, SharedHandler, TcpServer,
+        TcpServerBuilder, TransportKind, UdpServer,
+    };
+    #[cfg(target_os = "linux")]
+    use crate::transport::BatchUdpTransporter;
+    use crate::transport::{
+        CaptureDecoder, CaptureEncoder, CaptureProtocol, CaptureTransporter, CapturedPacket,
+        PcapCapture, Rfc3489Compat, StdUdpTransporter, StunTcpTransporter, StunUdpTransporter,
+    };
+    use crate::{Error, ErrorKind};
+    use bytecodec::marker::Never;
+    use bytecodec::EncodeExt;
+    use factory::{CloneFactory, DefaultFactory};
     use fibers_transport::{TcpTransporter, UdpTransporter};
     use futures::Future;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use stun_codec::rfc5389;
-    use stun_codec::{MessageDecoder, MessageEncoder};
+    use stun_codec::rfc5389::attributes::{ErrorCode, Fingerprint, XorMappedAddress};
+    use stun_codec::{MessageDecoder, MessageEncoder, Method};
     use trackable::error::MainError;
 
     #[test]
@@ -156,4 +189,1117 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn ipv6_udp_test() -> Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "[::1]:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let mapped = track!(udp_binding("[::1]:0".parse().unwrap(), server_addr))?;
+        assert_eq!(mapped.ip(), "::1".parse::<IpAddr>().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn ipv6_tcp_test() -> Result<(), MainError> {
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "[::1]:0".parse().unwrap(),
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let server_addr = server.local_addr();
+
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        let response = TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(server_addr)
+            .map_err(Error::from)
+            .map(StunTcpTransporter::new)
+            .map(Channel::new)
+            .and_then(move |channel| {
+                let client = Client::new(&fibers_global::handle(), channel);
+                let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                client.call((), request)
+            });
+        let response = track!(fibers_global::execute(response))?;
+        let mapped = response
+            .ok()
+            .and_then(|r| r.get_attribute::<XorMappedAddress>().map(|a| a.address()));
+        assert_eq!(mapped.map(|a| a.ip()), Some("::1".parse().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_udp_test() -> Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start_dual_stack(
+            fibers_global::handle(),
+            0,
+            BindingHandler,
+        ))?;
+        let port = server.local_addr().port();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // IPv4 clients observe their addresses as IPv4 ones (not IPv4-mapped IPv6 ones)
+        let server_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        let mapped = track!(udp_binding("127.0.0.1:0".parse().unwrap(), server_addr))?;
+        assert_eq!(mapped.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+
+        let server_addr = SocketAddr::new("::1".parse().unwrap(), port);
+        let mapped = track!(udp_binding("[::1]:0".parse().unwrap(), server_addr))?;
+        assert_eq!(mapped.ip(), "::1".parse::<IpAddr>().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_tcp_test() -> Result<(), MainError> {
+        let server = fibers_global::execute(TcpServer::start_dual_stack(
+            fibers_global::handle(),
+            0,
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let port = server.local_addr().port();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        let mapped = track!(tcp_binding(SocketAddr::new(
+            "127.0.0.1".parse().unwrap(),
+            port
+        )))?;
+        assert_eq!(mapped.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+
+        let mapped = track!(tcp_binding(SocketAddr::new("::1".parse().unwrap(), port)))?;
+        assert_eq!(mapped.ip(), "::1".parse::<IpAddr>().unwrap());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn multi_socket_udp_test() -> Result<(), MainError> {
+        let server = fibers_global::execute(MultiSocketUdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            4,
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        assert_eq!(server.socket_count(), 4);
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        for _ in 0..8 {
+            let mapped = track!(udp_binding("127.0.0.1:0".parse().unwrap(), server_addr))?;
+            assert_eq!(mapped.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        }
+
+        let result = fibers_global::execute(MultiSocketUdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            0,
+            DefaultFactory::<BindingHandler>::new(),
+        ));
+        let kind = result.err().map(|e| e.kind().clone());
+        assert!(matches!(kind, Some(ErrorKind::InvalidInput)));
+        Ok(())
+    }
+
+    #[test]
+    fn composite_server_test() -> Result<(), MainError> {
+        #[derive(Debug, Clone, Default)]
+        struct CountingHandler(Arc<AtomicUsize>, Arc<Mutex<Vec<RequestContext>>>);
+        impl HandleMessage for CountingHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                context: &RequestContext,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                self.1.lock().unwrap().push(context.clone());
+                BindingHandler.handle_call(context, request)
+            }
+        }
+
+        let handler = CountingHandler::default();
+        let count = handler.0.clone();
+        let contexts = handler.1.clone();
+        let mut tcp_options = TcpServerBuilder::new();
+        tcp_options.max_connections_per_ip(1);
+        let server = fibers_global::execute(
+            CompositeServerBuilder::new()
+                .udp("127.0.0.1:0".parse().unwrap())
+                .tcp("127.0.0.1:0".parse().unwrap())
+                .tcp_options(tcp_options)
+                .start(fibers_global::handle(), CloneFactory::new(handler)),
+        )?;
+        let (udp_protocol, udp_addr) = server.local_addrs()[0];
+        let (tcp_protocol, tcp_addr) = server.local_addrs()[1];
+        assert_eq!(udp_protocol, Protocol::Udp);
+        assert_eq!(tcp_protocol, Protocol::Tcp);
+        let handle = server.handle();
+        let (tx, rx) = std::sync::mpsc::channel();
+        fibers_global::spawn(server.then(move |result| {
+            let _ = tx.send(result.is_ok());
+            Ok(())
+        }));
+        thread::sleep(Duration::from_millis(50));
+
+        track!(udp_binding("127.0.0.1:0".parse().unwrap(), udp_addr))?;
+        let response = TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(tcp_addr)
+            .map_err(Error::from)
+            .map(StunTcpTransporter::new)
+            .map(Channel::new)
+            .and_then(move |channel| {
+                let client = Client::new(&fibers_global::handle(), channel);
+                let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                client.call((), request)
+            });
+        let response = track!(fibers_global::execute(response))?;
+        assert!(response.is_ok());
+
+        // The handlers of both listeners share the same counter
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        let stats = handle.stats();
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.success_responses, 2);
+        assert_eq!(stats.tcp_connections, 1);
+
+        let contexts = contexts.lock().unwrap().clone();
+        assert_eq!(contexts[0].transport(), TransportKind::Udp);
+        assert_eq!(contexts[0].local_addr(), udp_addr);
+        assert_eq!(contexts[0].connection_id(), None);
+        assert_eq!(contexts[1].transport(), TransportKind::Tcp);
+        assert_eq!(contexts[1].local_addr(), tcp_addr);
+        assert!(contexts[1].connection_id().is_some());
+        assert_ne!(contexts[0].peer(), contexts[1].peer());
+
+        // Connections refused by the limits are not counted as accepted ones
+        assert!(wait_until(|| handle.stats().active_tcp_connections == 0));
+        let _stream = std::net::TcpStream::connect(tcp_addr).map_err(Error::from)?;
+        assert!(wait_until(|| handle.stats().active_tcp_connections == 1));
+        let _refused_stream = std::net::TcpStream::connect(tcp_addr).map_err(Error::from)?;
+        assert!(wait_until(|| handle.stats().rejected_tcp_connections == 1));
+        let stats = handle.stats();
+        assert_eq!(stats.tcp_connections, 2);
+        assert_eq!(stats.active_tcp_connections, 1);
+
+        handle.shutdown();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).ok(), Some(true));
+
+        let result = fibers_global::execute(
+            CompositeServerBuilder::new()
+                .start(fibers_global::handle(), CloneFactory::new(BindingHandler)),
+        );
+        let kind = result.err().map(|e| e.kind().clone());
+        assert!(matches!(kind, Some(ErrorKind::InvalidInput)));
+        Ok(())
+    }
+
+    #[test]
+    fn shared_handler_test() -> Result<(), MainError> {
+        struct DelayedReply {
+            timer: fibers::time::timer::Timeout,
+            response: Option<Response<rfc5389::Attribute>>,
+        }
+        impl Future for DelayedReply {
+            type Item = Option<Response<rfc5389::Attribute>>;
+            type Error = Never;
+
+            fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+                if let Ok(futures::Async::NotReady) = self.timer.poll() {
+                    return Ok(futures::Async::NotReady);
+                }
+                Ok(futures::Async::Ready(self.response.take()))
+            }
+        }
+
+        #[derive(Debug, Default)]
+        struct DelayedHandler {
+            requests: AtomicUsize,
+        }
+        impl HandleSharedMessage for DelayedHandler {
+            type Attribute = rfc5389::Attribute;
+            type Reply = DelayedReply;
+
+            fn handle_call(
+                &self,
+                context: &RequestContext,
+                request: Request<Self::Attribute>,
+            ) -> Self::Reply {
+                self.requests.fetch_add(1, Ordering::SeqCst);
+                let response = match BindingHandler.handle_call(context, request) {
+                    Action::Reply(response) => response,
+                    _ => unreachable!(),
+                };
+                DelayedReply {
+                    timer: fibers::time::timer::timeout(Duration::from_millis(20)),
+                    response: Some(response),
+                }
+            }
+        }
+
+        let handler = SharedHandler::new(DelayedHandler::default());
+        let udp_server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            handler.clone(),
+        ))?;
+        let udp_addr = udp_server.local_addr();
+        fibers_global::spawn(udp_server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let tcp_server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            CloneFactory::new(handler.clone()),
+        ))?;
+        let tcp_addr = tcp_server.local_addr();
+        fibers_global::spawn(tcp_server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        let mapped = track!(udp_binding("127.0.0.1:0".parse().unwrap(), udp_addr))?;
+        assert_eq!(mapped.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        for _ in 0..2 {
+            let response =
+                TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(tcp_addr)
+                    .map_err(Error::from)
+                    .map(StunTcpTransporter::new)
+                    .map(Channel::new)
+                    .and_then(move |channel| {
+                        let client = Client::new(&fibers_global::handle(), channel);
+                        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                        client.call((), request)
+                    });
+            let response = track!(fibers_global::execute(response))?;
+            assert!(response.is_ok());
+        }
+
+        // All of the listeners and connections share the same handler
+        assert_eq!(handler.get_ref().requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_limits_test() -> Result<(), MainError> {
+        #[derive(Debug, Clone, Default)]
+        struct LimitRecorder(Arc<Mutex<Vec<LimitExceeded>>>, Arc<Mutex<Vec<SystemTime>>>);
+        impl HandleMessage for LimitRecorder {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                context: &RequestContext,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                self.1.lock().unwrap().push(context.received_at());
+                let response = match BindingHandler.handle_call(context, request) {
+                    Action::Reply(response) => response,
+                    _ => unreachable!(),
+                };
+                let delay = fibers::time::timer::timeout(Duration::from_millis(50));
+                Action::FutureReply(Box::new(delay.then(move |_| Ok(response))))
+            }
+
+            fn handle_channel_error(&mut self, error: &Error) {
+                if let Some(limit) = LimitExceeded::from_error(error) {
+                    self.0.lock().unwrap().push(limit.clone());
+                }
+            }
+        }
+
+        let handler = LimitRecorder::default();
+        let limits = handler.0.clone();
+        let has_limit = move |limit| limits.lock().unwrap().contains(&limit);
+        let received_at = handler.1.clone();
+        let clock = ManualClock::with_start_time(UNIX_EPOCH);
+        let server = fibers_global::execute(
+            TcpServerBuilder::new()
+                .idle_timeout(Duration::from_millis(300))
+                .clock(clock.clone())
+                .max_connections_per_ip(1)
+                .max_message_size(100)
+                .max_inflight_replies(1)
+                .start(
+                    fibers_global::handle(),
+                    "127.0.0.1:0".parse().unwrap(),
+                    CloneFactory::new(handler),
+                ),
+        )?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let connect = move || {
+            TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(server_addr)
+                .map_err(Error::from)
+                .map(StunTcpTransporter::new)
+                .map(Channel::new)
+                .map(|channel| Client::new(&fibers_global::handle(), channel))
+        };
+
+        // In-flight replies
+        let client = track!(fibers_global::execute(connect()))?;
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call((), request)))?;
+        assert!(response.is_ok());
+        assert!(has_limit(LimitExceeded::MaxInflightReplies(1)));
+        assert_eq!(received_at.lock().unwrap().clone(), vec![UNIX_EPOCH]);
+
+        // Connections per IP address
+        let _another_client = track!(fibers_global::execute(connect()))?;
+        assert!(wait_until(|| has_limit(
+            LimitExceeded::MaxConnectionsPerIp(1)
+        )));
+
+        // Idle timeout (the timer of the first connection can only fire once the clock reaches its deadline)
+        let idle_timeout = LimitExceeded::IdleTimeout(Duration::from_millis(300));
+        assert!(wait_until(|| clock.pending_timeouts() == 1));
+        clock.advance(Duration::from_millis(299));
+        assert!(!has_limit(idle_timeout.clone()));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(wait_until(|| has_limit(idle_timeout.clone())));
+        drop(client);
+
+        // Message size
+        let client = track!(fibers_global::execute(connect()))?;
+        let mut request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let username = rfc5389::attributes::Username::new("a".repeat(200))?;
+        request.add_attribute(username.into());
+        fibers_global::spawn(client.call((), request).then(|_| Ok(())));
+        assert!(wait_until(|| has_limit(LimitExceeded::MaxMessageSize(100))));
+        Ok(())
+    }
+
+    #[test]
+    fn udp_overload_test() -> Result<(), MainError> {
+        #[derive(Debug, Clone)]
+        struct SlowHandler {
+            delay: Duration,
+            limits: Arc<Mutex<Vec<LimitExceeded>>>,
+            live_futures: Arc<AtomicUsize>,
+        }
+
+        /// Counts the reply futures that have not been dropped yet.
+        struct LiveGuard(Arc<AtomicUsize>);
+        impl Drop for LiveGuard {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        impl HandleMessage for SlowHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                context: &RequestContext,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let response = match BindingHandler.handle_call(context, request) {
+                    Action::Reply(response) => response,
+                    _ => unreachable!(),
+                };
+                self.live_futures.fetch_add(1, Ordering::SeqCst);
+                let guard = LiveGuard(self.live_futures.clone());
+                let delay = fibers::time::timer::timeout(self.delay);
+                Action::FutureReply(Box::new(delay.then(move |_| {
+                    drop(guard);
+                    Ok(response)
+                })))
+            }
+
+            fn handle_channel_error(&mut self, error: &Error) {
+                if let Some(limit) = LimitExceeded::from_error(error) {
+                    self.limits.lock().unwrap().push(limit.clone());
+                }
+            }
+        }
+
+        let limits = Arc::new(Mutex::new(Vec::new()));
+        let live_futures = Arc::new(AtomicUsize::new(0));
+        let start_server = |delay| {
+            let handler = SlowHandler {
+                delay,
+                limits: limits.clone(),
+                live_futures: live_futures.clone(),
+            };
+            fibers_global::execute(UdpServer::start(
+                fibers_global::handle(),
+                "127.0.0.1:0".parse().unwrap(),
+                handler,
+            ))
+        };
+        let error_code = |response: Response<rfc5389::Attribute>| {
+            let response = response.expect_err("unexpected success response");
+            response
+                .get_attribute::<ErrorCode>()
+                .map(|e| e.code())
+                .expect("no ERROR-CODE attribute")
+        };
+
+        let client = || {
+            let client_addr = "127.0.0.1:0".parse().unwrap();
+            fibers_global::execute(
+                UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                    .map_err(Error::from)
+                    .map(StunUdpTransporter::new)
+                    .map(Channel::new)
+                    .map(|channel| Client::new(&fibers_global::handle(), channel)),
+            )
+        };
+        let (client, another_client) = (track!(client())?, track!(client())?);
+
+        // Requests beyond the limit are rejected
+        let mut server = start_server(Duration::from_millis(100))?;
+        server.set_max_inflight_replies(1);
+        server.set_overload_policy(OverloadPolicy::Reject);
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let first = client.call(server_addr, Request::new(rfc5389::methods::BINDING));
+        let (tx, rx) = std::sync::mpsc::channel();
+        fibers_global::spawn(first.then(move |result| {
+            let _ = tx.send(result);
+            Ok(())
+        }));
+        assert!(wait_until(|| live_futures.load(Ordering::SeqCst) == 1));
+        let second = another_client.call(server_addr, Request::new(rfc5389::methods::BINDING));
+        let second = track!(fibers_global::execute(second))?;
+        let first = track!(rx.recv().unwrap())?;
+        assert!(first.is_ok());
+        assert_eq!(error_code(second), 500);
+        assert!(limits
+            .lock()
+            .unwrap()
+            .contains(&LimitExceeded::MaxInflightReplies(1)));
+
+        // Replies that take too long are replaced by error responses
+        assert_eq!(live_futures.load(Ordering::SeqCst), 0);
+        let mut server = start_server(Duration::from_secs(10))?;
+        server.set_reply_timeout(Duration::from_millis(100));
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let request = Request::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        assert_eq!(error_code(response), 500);
+        assert!(limits
+            .lock()
+            .unwrap()
+            .contains(&LimitExceeded::ReplyTimeout(Duration::from_millis(100))));
+
+        // The abandoned future has been dropped
+        assert!(wait_until(|| live_futures.load(Ordering::SeqCst) == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_client_pool_test() -> Result<(), MainError> {
+        /// Counts the failed connection attempts.
+        #[derive(Debug, Clone, Default)]
+        struct CountingConnector(Arc<AtomicUsize>);
+        impl Connect<rfc5389::Attribute> for CountingConnector {
+            type Transporter =
+                <TcpConnector<rfc5389::Attribute> as Connect<rfc5389::Attribute>>::Transporter;
+            type Future = Box<dyn Future<Item = Self::Transporter, Error = Error> + Send>;
+
+            fn connect(&self, peer: SocketAddr) -> Self::Future {
+                let failures = self.0.clone();
+                Box::new(TcpConnector::new().connect(peer).map_err(move |e| {
+                    failures.fetch_add(1, Ordering::SeqCst);
+                    e
+                }))
+            }
+        }
+
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let clock = ManualClock::new();
+        let connector = CountingConnector::default();
+        let failures = connector.0.clone();
+        let pool = TcpClientPoolBuilder::new()
+            .idle_timeout(Duration::from_millis(200))
+            .initial_backoff(Duration::from_millis(50))
+            .max_backoff(Duration::from_millis(100))
+            .max_connect_attempts(20)
+            .clock(clock.clone())
+            .finish_with_connector(&fibers_global::handle(), connector);
+        assert_eq!(pool.connection_count(), 0);
+
+        // Connects lazily, and closes the idle connection
+        // (the idle timer can only fire once the clock reaches its deadline)
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(pool.call(server_addr, request)))?;
+        assert!(response.is_ok());
+        assert!(wait_until(
+            || pool.connection_count() == 1 && clock.pending_timeouts() == 1
+        ));
+        clock.advance(Duration::from_millis(199));
+        assert_eq!(pool.connection_count(), 1);
+        clock.advance(Duration::from_millis(1));
+        assert!(wait_until(|| pool.connection_count() == 0));
+
+        // Requests issued while the server is unavailable are sent after reconnecting
+        let socket = reserve_tcp_port();
+        let late_server_addr = track!(local_addr(&socket))?;
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = pool.call(late_server_addr, request);
+        assert!(wait_until(|| failures.load(Ordering::SeqCst) > 0));
+        drop(socket);
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            late_server_addr,
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        let response = track!(wait_with_clock(
+            response,
+            &clock,
+            Duration::from_millis(100)
+        ))?;
+        assert!(response.is_ok());
+
+        // Requests fail if all connection attempts fail
+        let clock = ManualClock::new();
+        let pool = TcpClientPoolBuilder::new()
+            .initial_backoff(Duration::from_millis(10))
+            .max_connect_attempts(2)
+            .clock(clock.clone())
+            .finish(&fibers_global::handle());
+        let socket = reserve_tcp_port();
+        let unavailable_addr = track!(local_addr(&socket))?;
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = pool.call(unavailable_addr, request);
+        assert!(wait_with_clock(response, &clock, Duration::from_millis(10)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn client_error_recovery_test() -> Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // Sending datagrams to the broadcast address fails because `SO_BROADCAST` is disabled
+        let broadcast_addr = "255.255.255.255:3478".parse().unwrap();
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0").map_err(Error::from)?;
+        let transporter = fibers_global::execute(StdUdpTransporter::from_socket(socket))?;
+        let client = Client::new(
+            &fibers_global::handle(),
+            Channel::new(StunUdpTransporter::new(transporter)),
+        );
+        assert!(client.health().is_healthy());
+
+        // Transient errors only affect the messages being sent
+        let indication = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        track!(client.cast(broadcast_addr, indication))?;
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        assert!(fibers_global::execute(client.call(broadcast_addr, request)).is_err());
+        assert!(matches!(
+            client.health(),
+            ClientHealth::Degraded { errors: 1, .. }
+        ));
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        assert!(response.is_ok());
+        assert!(client.health().is_healthy());
+
+        // `fibers_transport::UdpTransporter` cannot be used after I/O errors, thus the same error is fatal
+        let transporter = fibers_global::execute(UdpTransporter::<
+            MessageEncoder<_>,
+            MessageDecoder<_>,
+        >::bind("0.0.0.0:0".parse().unwrap()))?;
+        let client = Client::new(
+            &fibers_global::handle(),
+            Channel::new(StunUdpTransporter::new(transporter)),
+        );
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        assert!(fibers_global::execute(client.call(broadcast_addr, request)).is_err());
+        assert!(client.health().is_failed());
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        assert!(fibers_global::execute(client.call(server_addr, request)).is_err());
+
+        // Fatal errors (e.g., the connection closed by the peer) fail all the transactions
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").map_err(Error::from)?;
+        let listener_addr = listener.local_addr().map_err(Error::from)?;
+        let transporter = fibers_global::execute(TcpTransporter::<
+            MessageEncoder<_>,
+            MessageDecoder<_>,
+        >::connect(listener_addr))?;
+        let (stream, _) = listener.accept().map_err(Error::from)?;
+        let client = Client::new(
+            &fibers_global::handle(),
+            Channel::new(StunTcpTransporter::new(transporter)),
+        );
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = client.call((), request);
+        std::mem::drop(stream);
+        assert!(fibers_global::execute(response).is_err());
+        assert!(client.health().is_failed());
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        assert!(fibers_global::execute(client.call((), request)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn client_failover_test() -> Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // A candidate that never responds
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from)?;
+        let silent_addr = silent.local_addr().map_err(Error::from)?;
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let client = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<rfc5389::Attribute>, MessageDecoder<_>>::bind(
+                client_addr
+            )
+            .map_err(Error::from)
+            .map(StunUdpTransporter::new)
+            .map(|transporter| ChannelBuilder::new()
+                .request_timeout(Duration::from_millis(200))
+                .finish(transporter))
+            .map(|channel| Client::new(&fibers_global::handle(), channel))
+        ))?;
+
+        // Each attempt has its own transaction ID and FINGERPRINT
+        let transaction_ids = Arc::new(Mutex::new(Vec::new()));
+        let ids = transaction_ids.clone();
+        let make_request = move || {
+            let mut message = Request::new(rfc5389::methods::BINDING).into_message();
+            let fingerprint = Fingerprint::new(&message).expect("never fails");
+            message.add_attribute(rfc5389::Attribute::Fingerprint(fingerprint));
+            let request = Request::from_message(message).expect("never fails");
+            ids.lock().unwrap().push(request.transaction_id());
+            request
+        };
+        let (peer, response) = track!(fibers_global::execute(
+            client.call_with_failover(vec![silent_addr, server_addr], make_request)
+        ))?;
+        assert_eq!(peer, server_addr);
+        assert!(response.is_ok());
+
+        let transaction_ids = transaction_ids.lock().unwrap();
+        assert_eq!(transaction_ids.len(), 2);
+        assert_ne!(transaction_ids[0], transaction_ids[1]);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn batch_udp_test() -> Result<(), MainError> {
+        let transporter =
+            fibers_global::execute(BatchUdpTransporter::bind("127.0.0.1:0".parse().unwrap()))?;
+        let server =
+            UdpServer::with_transporter(fibers_global::handle(), transporter, BindingHandler);
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        for _ in 0..8 {
+            let mapped = track!(udp_binding("127.0.0.1:0".parse().unwrap(), server_addr))?;
+            assert_eq!(mapped.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rfc3489_compat_test() -> Result<(), MainError> {
+        let mut compat = Rfc3489Compat::new();
+        compat.source_address("192.0.2.1:3478".parse().unwrap());
+        let server = fibers_global::execute(UdpServer::start_rfc3489_compatible(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+            &compat,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // Classic (RFC 3489) client
+        let socket = track_any_err!(std::net::UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_secs(5))))?;
+        let client_addr = track_any_err!(socket.local_addr())?;
+        let mut request = vec![0x00, 0x01, 0x00, 0x00];
+        request.extend_from_slice(&[0xAB; 16]);
+        track_any_err!(socket.send_to(&request, server_addr))?;
+
+        let mut buf = [0; 1024];
+        let (size, _) = track_any_err!(socket.recv_from(&mut buf))?;
+        let response = &buf[..size];
+        assert_eq!(&response[..2], &[0x01, 0x01]);
+        assert_eq!(&response[4..20], &[0xAB; 16]);
+
+        let port = client_addr.port().to_be_bytes();
+        let mut attrs = vec![
+            0x00, 0x01, 0x00, 0x08, 0x00, 0x01, port[0], port[1], 127, 0, 0, 1,
+        ];
+        attrs.extend_from_slice(&[0x00, 0x04, 0x00, 0x08, 0x00, 0x01, 0x0D, 0x96, 192, 0, 2, 1]);
+        assert_eq!(&response[20..], &attrs[..]);
+
+        // Modern (RFC 5389) client
+        let mapped = track!(udp_binding("127.0.0.1:0".parse().unwrap(), server_addr))?;
+        assert_eq!(mapped.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn capture_test() -> Result<(), MainError> {
+        let dir = std::env::temp_dir();
+        let udp_path = dir.join(format!("rustun-capture-udp-{}.pcap", std::process::id()));
+        let tcp_path = dir.join(format!("rustun-capture-tcp-{}.pcap", std::process::id()));
+        let broken_path = dir.join(format!("rustun-capture-broken-{}.pcap", std::process::id()));
+
+        // UDP client
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let capture = track!(PcapCapture::create(&udp_path).map_err(Error::from))?;
+        let transporter = fibers_global::execute(UdpTransporter::<
+            CaptureEncoder<MessageEncoder<rfc5389::Attribute>>,
+            CaptureDecoder<MessageDecoder<rfc5389::Attribute>>,
+        >::bind("127.0.0.1:0".parse().unwrap()))?;
+        let transporter = CaptureTransporter::udp(transporter, capture.clone());
+        let client = Client::new(
+            &fibers_global::handle(),
+            Channel::new(StunUdpTransporter::new(transporter)),
+        );
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        assert!(response.is_ok());
+        track!(capture.flush().map_err(Error::from))?;
+        assert_eq!(pcap_packet_sizes(&udp_path), [20 + 8 + 20, 20 + 8 + 32]);
+
+        // TCP server
+        let capture = track!(PcapCapture::create(&tcp_path).map_err(Error::from))?;
+        let server =
+            fibers_global::execute(TcpServerBuilder::new().capture(capture.clone()).start(
+                fibers_global::handle(),
+                "127.0.0.1:0".parse().unwrap(),
+                DefaultFactory::<BindingHandler>::new(),
+            ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let transporter = fibers_global::execute(TcpTransporter::<
+            MessageEncoder<_>,
+            MessageDecoder<_>,
+        >::connect(server_addr))?;
+        let client = Client::new(
+            &fibers_global::handle(),
+            Channel::new(StunTcpTransporter::new(transporter)),
+        );
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call((), request)))?;
+        assert!(response.is_ok());
+        track!(capture.flush().map_err(Error::from))?;
+        assert_eq!(pcap_packet_sizes(&tcp_path), [20 + 20 + 20, 20 + 20 + 32]);
+
+        // Broken messages are recorded as received
+        let capture = track!(PcapCapture::create(&broken_path).map_err(Error::from))?;
+        let transporter = fibers_global::execute(UdpTransporter::<
+            CaptureEncoder<MessageEncoder<rfc5389::Attribute>>,
+            CaptureDecoder<MessageDecoder<rfc5389::Attribute>>,
+        >::bind("127.0.0.1:0".parse().unwrap()))?;
+        let mut transporter = CaptureTransporter::udp(transporter, capture.clone());
+        let mut broken = vec![0x00, 0x01, 0x00, 0x08, 0x21, 0x12, 0xA4, 0x42];
+        broken.extend_from_slice(&[0; 12]);
+        broken.extend_from_slice(&[0x00, 0x20, 0x00, 0x04, 0x00, 0x09, 0x00, 0x00]);
+        let socket = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        let local_addr = fibers_transport::UdpTransport::local_addr(&transporter);
+        track!(socket.send_to(&broken, local_addr).map_err(Error::from))?;
+        let received = fibers_global::execute(futures::future::poll_fn(move || {
+            fibers_transport::Transport::poll_recv(&mut transporter)
+        }))?;
+        assert!(matches!(received, Some((_, Err(_)))));
+        track!(capture.flush().map_err(Error::from))?;
+        assert_eq!(pcap_packet_sizes(&broken_path), [20 + 8 + broken.len()]);
+
+        let _ = std::fs::remove_file(udp_path);
+        let _ = std::fs::remove_file(tcp_path);
+        let _ = std::fs::remove_file(broken_path);
+        Ok(())
+    }
+
+    #[test]
+    fn pcap_replay_test() -> Result<(), MainError> {
+        let path = std::env::temp_dir().join(format!("rustun-replay-{}.pcap", std::process::id()));
+        let client: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.100:3478".parse().unwrap();
+        let encode = |m| {
+            MessageEncoder::<rfc5389::Attribute>::default()
+                .encode_into_bytes(m)
+                .unwrap()
+        };
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let mut response = SuccessResponse::new(&request);
+        response.add_attribute(XorMappedAddress::new(client).into());
+        let indication = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let unknown = Request::<rfc5389::Attribute>::new(Method::new(0x00B).unwrap());
+        let error = ErrorResponse::new(&unknown, rfc5389::errors::BadRequest.into());
+
+        let capture = track!(PcapCapture::create(&path).map_err(Error::from))?;
+        for (src, dst, message) in [
+            (client, server, request.clone().into_message()),
+            (server, client, response.into_message()),
+            (client, server, indication.into_message()),
+            (client, server, unknown.into_message()),
+            (server, client, error.into_message()),
+        ] {
+            let bytes = encode(message);
+            track!(capture
+                .write_packet(CaptureProtocol::Udp, src, dst, &bytes)
+                .map_err(Error::from))?;
+        }
+        track!(capture.flush().map_err(Error::from))?;
+        std::mem::drop(capture);
+
+        let clock = ManualClock::new();
+        let mut replay = track!(PcapReplay::open(&path))?;
+        let _ = std::fs::remove_file(&path);
+        replay
+            .response_timeout(Duration::from_millis(20))
+            .clock(clock.clone());
+        let expected = replay.expected_output::<rfc5389::Attribute>();
+        assert_eq!(expected.entries().len(), 3);
+        assert_eq!(expected.entries()[1].response, None);
+
+        let output = track!(replay_with_clock(&replay, &clock, BindingHandler))?;
+        assert_eq!(output, expected);
+        assert_eq!(output.diff(&expected.to_string()), None);
+
+        let text = output.to_string();
+        let first_line = text.lines().next().unwrap();
+        assert_eq!(first_line, format!("> {} {}", client, request.pretty()));
+
+        let modified = text.replace(
+            "XOR-MAPPED-ADDRESS=192.0.2.1:5000",
+            "XOR-MAPPED-ADDRESS=192.0.2.1:5001",
+        );
+        let diff = output.diff(&modified).unwrap();
+        assert!(diff.contains("- < 192.0.2.1:5000 BINDING success response"));
+        assert!(diff.contains("+ < 192.0.2.1:5000 BINDING success response"));
+        Ok(())
+    }
+
+    #[test]
+    fn pcap_replay_discards_late_responses_test() -> Result<(), MainError> {
+        // Replies to the first request after 25ms and to the others after 10ms
+        struct SlowHandler {
+            clock: ManualClock,
+            count: usize,
+        }
+        impl HandleMessage for SlowHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _context: &RequestContext,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let delay = if self.count == 0 { 25 } else { 10 };
+                self.count += 1;
+                let response = Ok(SuccessResponse::new(&request));
+                let timeout = self.clock.timeout(Duration::from_millis(delay));
+                Action::FutureReply(Box::new(timeout.then(move |_| Ok(response))))
+            }
+        }
+
+        let client: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.100:3478".parse().unwrap();
+        let first = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let second = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let packets = [&first, &second]
+            .iter()
+            .map(|request| CapturedPacket {
+                timestamp: std::time::SystemTime::UNIX_EPOCH,
+                protocol: CaptureProtocol::Udp,
+                src: client,
+                dst: server,
+                payload: MessageEncoder::default()
+                    .encode_into_bytes((*request).clone().into_message())
+                    .unwrap(),
+            })
+            .collect();
+
+        let clock = ManualClock::new();
+        let mut replay = PcapReplay::new(packets);
+        replay
+            .response_timeout(Duration::from_millis(20))
+            .clock(clock.clone());
+        let handler = SlowHandler {
+            clock: clock.clone(),
+            count: 0,
+        };
+        let output = track!(replay_with_clock(&replay, &clock, handler))?;
+        assert_eq!(output.entries().len(), 2);
+        assert_eq!(output.entries()[0].response, None);
+
+        let response = output.entries()[1].response.clone().unwrap();
+        let transaction_id = |request: &Request<_>| {
+            request
+                .transaction_id()
+                .as_bytes()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+        assert!(response.contains(&transaction_id(&second)));
+        assert!(!response.contains(&transaction_id(&first)));
+        Ok(())
+    }
+
+    /// Replays the requests while advancing the clock by 1ms every time the ticker fiber is scheduled.
+    ///
+    /// Since the replay runs on a single in-place executor, the interleaving of the fibers is deterministic.
+    fn replay_with_clock<H>(
+        replay: &PcapReplay,
+        clock: &ManualClock,
+        handler: H,
+    ) -> Result<ReplayOutput, Error>
+    where
+        H: HandleMessage<Attribute = rfc5389::Attribute> + Send + 'static,
+    {
+        let clock = clock.clone();
+        let ticker = futures::future::poll_fn(move || {
+            clock.advance(Duration::from_millis(1));
+            fibers::fiber::yield_poll::<(), Error>()
+        });
+        track!(replay.run_server(move |spawner, transporter| {
+            UdpServer::with_transporter(spawner, transporter, handler)
+                .join(ticker)
+                .map(|_| ())
+        }))
+    }
+
+    fn pcap_packet_sizes(path: &std::path::Path) -> Vec<usize> {
+        let bytes = std::fs::read(path).unwrap();
+        let mut sizes = Vec::new();
+        let mut offset = 24;
+        while offset < bytes.len() {
+            let size = u32::from_le_bytes(bytes[offset + 8..offset + 12].try_into().unwrap());
+            sizes.push(size as usize);
+            offset += 16 + size as usize;
+        }
+        sizes
+    }
+
+    /// Binds a TCP socket without listening, thus connections to the port are refused while the socket is alive.
+    fn reserve_tcp_port() -> socket2::Socket {
+        use socket2::{Domain, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&addr.into()).unwrap();
+        socket
+    }
+
+    fn local_addr(socket: &socket2::Socket) -> Result<SocketAddr, Error> {
+        let addr = track!(socket.local_addr().map_err(Error::from))?;
+        let kind = ErrorKind::InvalidInput;
+        Ok(track_assert_some!(addr.as_socket(), kind))
+    }
+
+    /// Polls the given condition until it holds or five seconds elapse.
+    ///
+    /// Returns `false` if the condition does not hold in time.
+    fn wait_until<F>(mut condition: F) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            if std::time::Instant::now() >= deadline {
+                return false;
+            }
+            thread::yield_now();
+        }
+        true
+    }
+
+    /// Waits for the given future while advancing the clock by `step` until the future completes.
+    fn wait_with_clock<F>(
+        future: F,
+        clock: &ManualClock,
+        step: Duration,
+    ) -> Result<F::Item, F::Error>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send + 'static,
+        F::Error: Send + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        fibers_global::spawn(future.then(move |result| {
+            let _ = tx.send(result);
+            Ok(())
+        }));
+        loop {
+            if let Ok(result) = rx.recv_timeout(Duration::from_millis(10)) {
+                return result;
+            }
+            clock.advance(step);
+        }
+    }
+
+    fn udp_binding(client_addr: SocketAddr, server_addr: SocketAddr) -> Result<SocketAddr, Error> {
+        let response = UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+            .map_err(Error::from)
+            .map(StunUdpTransporter::new)
+            .map(Channel::new)
+            .and_then(move |channel| {
+                let client = Client::new(&fibers_global::handle(), channel);
+                let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                client.call(server_addr, request)
+            });
+        let response = track!(fibers_global::execute(response))?;
+        let mapped = response
+            .ok()
+            .and_then(|r| r.get_attribute::<XorMappedAddress>().map(|a| a.address()));
+        let kind = ErrorKind::InvalidMessage(MessageErrorKind::Other);
+        Ok(track_assert_some!(mapped, kind))
+    }
+
+    fn tcp_binding(server_addr: SocketAddr) -> Result<SocketAddr, Error> {
+        let response = TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(server_addr)
+            .map_err(Error::from)
+            .map(StunTcpTransporter::new)
+            .map(Channel::new)
+            .and_then(move |channel| {
+                let client = Client::new(&fibers_global::handle(), channel);
+                let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                client.call((), request)
+            });
+        let response = track!(fibers_global::execute(response))?;
+        let mapped = response
+            .ok()
+            .and_then(|r| r.get_attribute::<XorMappedAddress>().map(|a| a.address()));
+        let kind = ErrorKind::InvalidMessage(MessageErrorKind::Other);
+        Ok(track_assert_some!(mapped, kind))
+    }
 }
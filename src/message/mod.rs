@@ -28,8 +28,14 @@ use stun_codec::convert::TryAsRef;
 use stun_codec::rfc5389::attributes::ErrorCode;
 use stun_codec::{Attribute, Message, MessageClass, Method, TransactionId};
 
+pub use self::pretty::Pretty;
+pub(crate) use self::pretty::{decode_address, encode_address};
 pub use crate::error::{MessageError, MessageErrorKind};
 
+mod pretty;
+#[cfg(feature = "serde")]
+mod serialize;
+
 /// A specialized `Result` type for message-level operations.
 pub type MessageResult<T> = Result<T, MessageError>;
 
@@ -62,6 +68,15 @@ impl InvalidMessage {
         &self.error
     }
 
+    /// Returns a human-readable representation of the message.
+    ///
+    /// See [`Pretty`] for details.
+    ///
+    /// [`Pretty`]: ./struct.Pretty.html
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+
     pub(crate) fn new(
         method: Method,
         class: MessageClass,
@@ -143,6 +158,15 @@ impl<A: Attribute> Request<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Returns a human-readable representation of the message.
+    ///
+    /// See [`Pretty`] for details.
+    ///
+    /// [`Pretty`]: ./struct.Pretty.html
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
@@ -222,6 +246,15 @@ impl<A: Attribute> Indication<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Returns a human-readable representation of the message.
+    ///
+    /// See [`Pretty`] for details.
+    ///
+    /// [`Pretty`]: ./struct.Pretty.html
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
@@ -301,6 +334,15 @@ impl<A: Attribute> SuccessResponse<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Returns a human-readable representation of the message.
+    ///
+    /// See [`Pretty`] for details.
+    ///
+    /// [`Pretty`]: ./struct.Pretty.html
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
@@ -393,6 +435,15 @@ impl<A: Attribute> ErrorResponse<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Returns a human-readable representation of the message.
+    ///
+    /// See [`Pretty`] for details.
+    ///
+    /// [`Pretty`]: ./struct.Pretty.html
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
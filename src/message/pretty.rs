@@ -0,0 +1,640 @@
+use super::{ErrorResponse, Indication, InvalidMessage, Request, Response, SuccessResponse};
+#[cfg(feature = "serde")]
+use crate::Error;
+use crate::{ErrorKind, Result};
+#[cfg(feature = "serde")]
+use bytecodec::DecodeExt;
+use bytecodec::EncodeExt;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "serde")]
+use stun_codec::MessageDecoder;
+use stun_codec::{Attribute, Message, MessageClass, MessageEncoder, Method, TransactionId};
+#[cfg(feature = "serde")]
+use trackable::error::ErrorKindExt;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// Human-readable representation of a message.
+///
+/// `{}` formats the message in a single line, and `{:#}` formats it in multiple lines
+/// (one line per attribute).
+///
+/// Well-known attributes (e.g., `XOR-MAPPED-ADDRESS` and `ERROR-CODE`) are shown with their decoded values,
+/// and the others are shown as hexadecimal strings.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use rustun::message::{Request, SuccessResponse};
+/// use stun_codec::rfc5389::{attributes::XorMappedAddress, methods::BINDING, Attribute};
+///
+/// let request = Request::<Attribute>::new(BINDING);
+/// let mut response = SuccessResponse::new(&request);
+/// response.add_attribute(XorMappedAddress::new("127.0.0.1:54754".parse().unwrap()).into());
+///
+/// let text = response.pretty().to_string();
+/// assert!(text.starts_with("BINDING success response (transaction_id="));
+/// assert!(text.ends_with("): XOR-MAPPED-ADDRESS=127.0.0.1:54754"));
+/// ```
+pub struct Pretty<'a, T>(&'a T);
+impl<'a, T> Pretty<'a, T> {
+    /// Makes a new `Pretty` instance that formats the given message.
+    ///
+    /// `T` is one of `Message`, `Request`, `Indication`, `SuccessResponse`, `ErrorResponse`,
+    /// `Response` and `InvalidMessage`.
+    pub fn new(message: &'a T) -> Self {
+        Pretty(message)
+    }
+}
+impl<T> fmt::Debug for Pretty<'_, T>
+where
+    Self: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+impl<A: Attribute> fmt::Display for Pretty<'_, Message<A>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match RawMessage::from_message(self.0) {
+            Err(e) => {
+                write_header(f, self.0.class(), self.0.method(), self.0.transaction_id())?;
+                write!(f, ": <unencodable: {:?}>", e.kind())
+            }
+            Ok(m) => {
+                write_header(f, m.class, m.method, m.transaction_id)?;
+                for (i, a) in m.attributes.iter().enumerate() {
+                    if f.alternate() {
+                        write!(f, "\n  {}", AttributeName(a.attr_type))?;
+                        if !a.value.is_empty() {
+                            write!(f, ": {}", a.display_value(m.transaction_id))?;
+                        }
+                    } else {
+                        write!(f, "{}", if i == 0 { ": " } else { ", " })?;
+                        write!(f, "{}", AttributeName(a.attr_type))?;
+                        if !a.value.is_empty() {
+                            write!(f, "={}", a.display_value(m.transaction_id))?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl<A: Attribute> fmt::Display for Pretty<'_, Request<A>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Pretty(self.0.as_ref()).fmt(f)
+    }
+}
+impl<A: Attribute> fmt::Display for Pretty<'_, Indication<A>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Pretty(self.0.as_ref()).fmt(f)
+    }
+}
+impl<A: Attribute> fmt::Display for Pretty<'_, SuccessResponse<A>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Pretty(self.0.as_ref()).fmt(f)
+    }
+}
+impl<A: Attribute> fmt::Display for Pretty<'_, ErrorResponse<A>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Pretty(self.0.as_ref()).fmt(f)
+    }
+}
+impl<A: Attribute> fmt::Display for Pretty<'_, Response<A>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Ok(m) => Pretty(m).fmt(f),
+            Err(m) => Pretty(m).fmt(f),
+        }
+    }
+}
+impl fmt::Display for Pretty<'_, InvalidMessage> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.0;
+        write_header(f, m.class(), m.method(), m.transaction_id())?;
+        let sep = if f.alternate() { "\n  " } else { ": " };
+        write!(f, "{sep}invalid ({:?})", m.error().kind())?;
+        if let Some(cause) = std::error::Error::source(m.error()) {
+            write!(f, ": {cause}")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_header(
+    f: &mut fmt::Formatter,
+    class: MessageClass,
+    method: Method,
+    transaction_id: TransactionId,
+) -> fmt::Result {
+    write!(f, "{} {}", MethodName(method), class)?;
+    if f.alternate() {
+        write!(f, "\n  transaction_id: {}", Hex(transaction_id.as_bytes()))
+    } else {
+        write!(f, " (transaction_id={})", Hex(transaction_id.as_bytes()))
+    }
+}
+
+/// Name of a method (e.g., `BINDING`).
+pub(super) struct MethodName(pub Method);
+impl MethodName {
+    const METHODS: &'static [(u16, &'static str)] = &[
+        (0x001, "BINDING"),
+        (0x003, "ALLOCATE"),
+        (0x004, "REFRESH"),
+        (0x006, "SEND"),
+        (0x007, "DATA"),
+        (0x008, "CREATE-PERMISSION"),
+        (0x009, "CHANNEL-BIND"),
+    ];
+
+    /// Parses a method name or a codepoint (e.g., `"0x001"`).
+    #[cfg(feature = "serde")]
+    pub fn parse(s: &str) -> Result<Method> {
+        let codepoint = if let Some(&(c, _)) = Self::METHODS.iter().find(|x| x.1 == s) {
+            c
+        } else {
+            track!(parse_u64(s))? as u16
+        };
+        track!(Method::new(codepoint).map_err(Error::from))
+    }
+}
+impl fmt::Display for MethodName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let codepoint = self.0.as_u16();
+        if let Some((_, name)) = Self::METHODS.iter().find(|x| x.0 == codepoint) {
+            write!(f, "{name}")
+        } else {
+            write!(f, "0x{codepoint:03X}")
+        }
+    }
+}
+
+/// Name of an attribute type (e.g., `XOR-MAPPED-ADDRESS`).
+pub(super) struct AttributeName(pub u16);
+impl AttributeName {
+    /// Parses an attribute name or a codepoint (e.g., `"0x0020"`).
+    #[cfg(feature = "serde")]
+    pub fn parse(s: &str) -> Result<u16> {
+        if let Some(&(c, _, _)) = ATTRIBUTES.iter().find(|x| x.1 == s) {
+            Ok(c)
+        } else {
+            let c = track!(parse_u64(s))?;
+            track_assert!(c <= 0xFFFF, ErrorKind::InvalidInput; s);
+            Ok(c as u16)
+        }
+    }
+}
+impl fmt::Display for AttributeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((_, name, _)) = ATTRIBUTES.iter().find(|x| x.0 == self.0) {
+            write!(f, "{name}")
+        } else {
+            write!(f, "0x{:04X}", self.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueKind {
+    Address,
+    XorAddress,
+    Text,
+    ErrorCode,
+    AttributeTypes,
+    U32,
+    U64,
+    Hex32,
+    Flag,
+    Bytes,
+}
+
+const ATTRIBUTES: &[(u16, &str, ValueKind)] = &[
+    (0x0001, "MAPPED-ADDRESS", ValueKind::Address),
+    (0x0003, "CHANGE-REQUEST", ValueKind::Hex32),
+    (0x0006, "USERNAME", ValueKind::Text),
+    (0x0008, "MESSAGE-INTEGRITY", ValueKind::Bytes),
+    (0x0009, "ERROR-CODE", ValueKind::ErrorCode),
+    (0x000A, "UNKNOWN-ATTRIBUTES", ValueKind::AttributeTypes),
+    (0x000C, "CHANNEL-NUMBER", ValueKind::Hex32),
+    (0x000D, "LIFETIME", ValueKind::U32),
+    (0x0012, "XOR-PEER-ADDRESS", ValueKind::XorAddress),
+    (0x0013, "DATA", ValueKind::Bytes),
+    (0x0014, "REALM", ValueKind::Text),
+    (0x0015, "NONCE", ValueKind::Text),
+    (0x0016, "XOR-RELAYED-ADDRESS", ValueKind::XorAddress),
+    (0x0018, "EVEN-PORT", ValueKind::Bytes),
+    (0x0019, "REQUESTED-TRANSPORT", ValueKind::Hex32),
+    (0x001A, "DONT-FRAGMENT", ValueKind::Flag),
+    (0x0020, "XOR-MAPPED-ADDRESS", ValueKind::XorAddress),
+    (0x0022, "RESERVATION-TOKEN", ValueKind::Bytes),
+    (0x0024, "PRIORITY", ValueKind::U32),
+    (0x0025, "USE-CANDIDATE", ValueKind::Flag),
+    (0x0026, "PADDING", ValueKind::Bytes),
+    (0x8022, "SOFTWARE", ValueKind::Text),
+    (0x8023, "ALTERNATE-SERVER", ValueKind::Address),
+    (0x8028, "FINGERPRINT", ValueKind::Hex32),
+    (0x8029, "ICE-CONTROLLED", ValueKind::U64),
+    (0x802A, "ICE-CONTROLLING", ValueKind::U64),
+    (0x802B, "RESPONSE-ORIGIN", ValueKind::Address),
+    (0x802C, "OTHER-ADDRESS", ValueKind::Address),
+];
+
+/// Message represented by its header fields and the undecoded values of its attributes.
+#[derive(Debug, Clone)]
+pub(super) struct RawMessage {
+    pub class: MessageClass,
+    pub method: Method,
+    pub transaction_id: TransactionId,
+    pub attributes: Vec<RawAttribute>,
+}
+impl RawMessage {
+    /// Makes a `RawMessage` from the wire format of the given message.
+    pub fn from_message<A: Attribute>(message: &Message<A>) -> Result<Self> {
+        let bytes = track!(MessageEncoder::<A>::default().encode_into_bytes(message.clone()))?;
+        let mut attributes = Vec::new();
+        let mut offset = 20;
+        while offset + 4 <= bytes.len() {
+            let attr_type = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+            let len = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+            let value =
+                track_assert_some!(bytes.get(offset + 4..offset + 4 + len), ErrorKind::Other);
+            attributes.push(RawAttribute {
+                attr_type,
+                value: value.to_owned(),
+            });
+            offset += 4 + len.div_ceil(4) * 4;
+        }
+        Ok(RawMessage {
+            class: message.class(),
+            method: message.method(),
+            transaction_id: message.transaction_id(),
+            attributes,
+        })
+    }
+
+    /// Decodes the message from the wire format built from the fields of this instance.
+    #[cfg(feature = "serde")]
+    pub fn to_message<A: Attribute>(&self) -> Result<Message<A>> {
+        let class = match self.class {
+            MessageClass::Request => 0b00,
+            MessageClass::Indication => 0b01,
+            MessageClass::SuccessResponse => 0b10,
+            MessageClass::ErrorResponse => 0b11,
+        };
+        let method = self.method.as_u16();
+        let message_type = (method & 0b0000_0000_1111)
+            | ((class & 0b01) << 4)
+            | ((method & 0b0000_0111_0000) << 1)
+            | ((class & 0b10) << 7)
+            | ((method & 0b1111_1000_0000) << 2);
+
+        let mut body = Vec::new();
+        for a in &self.attributes {
+            body.extend_from_slice(&a.attr_type.to_be_bytes());
+            body.extend_from_slice(&(a.value.len() as u16).to_be_bytes());
+            body.extend_from_slice(&a.value);
+            body.resize(body.len().div_ceil(4) * 4, 0);
+        }
+        let mut bytes = Vec::with_capacity(20 + body.len());
+        bytes.extend_from_slice(&message_type.to_be_bytes());
+        bytes.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        bytes.extend_from_slice(self.transaction_id.as_bytes());
+        bytes.extend_from_slice(&body);
+
+        let decoded = track!(MessageDecoder::<A>::default().decode_from_bytes(&bytes))?;
+        let message =
+            track!(decoded.map_err(|m| ErrorKind::InvalidInput.takes_over(m.error().clone())))?;
+        Ok(message)
+    }
+}
+
+/// Attribute represented by its type and undecoded value.
+#[derive(Debug, Clone)]
+pub(super) struct RawAttribute {
+    pub attr_type: u16,
+    pub value: Vec<u8>,
+}
+impl RawAttribute {
+    fn kind(&self) -> ValueKind {
+        ATTRIBUTES
+            .iter()
+            .find(|x| x.0 == self.attr_type)
+            .map_or(ValueKind::Bytes, |x| x.2)
+    }
+
+    /// Returns the textual representation of the value.
+    ///
+    /// Texts are not quoted unlike `display_value`.
+    #[cfg(feature = "serde")]
+    pub fn format_value(&self, transaction_id: TransactionId) -> String {
+        match (self.kind(), self.decode_value(transaction_id)) {
+            (ValueKind::Text, Some(Value::Text(s))) => s,
+            (_, Some(v)) => v.to_string(),
+            (_, None) => Hex(&self.value).to_string(),
+        }
+    }
+
+    /// Parses the given textual representation of the value of a `attr_type` attribute.
+    #[cfg(feature = "serde")]
+    pub fn parse_value(attr_type: u16, s: &str, transaction_id: TransactionId) -> Result<Self> {
+        let kind = ATTRIBUTES
+            .iter()
+            .find(|x| x.0 == attr_type)
+            .map_or(ValueKind::Bytes, |x| x.2);
+        let value = track!(encode_value(kind, s, transaction_id); attr_type, s)?;
+        Ok(RawAttribute { attr_type, value })
+    }
+
+    fn display_value(&self, transaction_id: TransactionId) -> impl fmt::Display {
+        match self.decode_value(transaction_id) {
+            Some(v) => v,
+            None => Value::Bytes(self.value.clone()),
+        }
+    }
+
+    fn decode_value(&self, transaction_id: TransactionId) -> Option<Value> {
+        let v = &self.value;
+        match self.kind() {
+            ValueKind::Address => decode_address(v, None).map(Value::Address),
+            ValueKind::XorAddress => decode_address(v, Some(transaction_id)).map(Value::Address),
+            ValueKind::Text => String::from_utf8(v.clone()).ok().map(Value::Text),
+            ValueKind::ErrorCode => {
+                let reason = std::str::from_utf8(v.get(4..)?).ok()?;
+                let code = u16::from(v[2] & 0b111) * 100 + u16::from(v[3]);
+                Some(Value::ErrorCode(code, reason.to_owned()))
+            }
+            ValueKind::AttributeTypes => {
+                if !v.len().is_multiple_of(2) {
+                    return None;
+                }
+                let types = v.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]]));
+                Some(Value::AttributeTypes(types.collect()))
+            }
+            ValueKind::U32 => Some(Value::Number(u64::from(u32::from_be_bytes(
+                v.as_slice().try_into().ok()?,
+            )))),
+            ValueKind::U64 => Some(Value::Number(u64::from_be_bytes(
+                v.as_slice().try_into().ok()?,
+            ))),
+            ValueKind::Hex32 => Some(Value::Hex32(u32::from_be_bytes(
+                v.as_slice().try_into().ok()?,
+            ))),
+            ValueKind::Flag if v.is_empty() => Some(Value::Flag),
+            ValueKind::Flag | ValueKind::Bytes => None,
+        }
+    }
+}
+
+enum Value {
+    Address(SocketAddr),
+    Text(String),
+    ErrorCode(u16, String),
+    AttributeTypes(Vec<u16>),
+    Number(u64),
+    Hex32(u32),
+    Flag,
+    Bytes(Vec<u8>),
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Address(a) => write!(f, "{a}"),
+            Value::Text(s) => write!(f, "{s:?}"),
+            Value::ErrorCode(code, reason) => write!(f, "{code} {reason}"),
+            Value::AttributeTypes(types) => {
+                for (i, t) in types.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", AttributeName(*t))?;
+                }
+                Ok(())
+            }
+            Value::Number(n) => write!(f, "{n}"),
+            Value::Hex32(n) => write!(f, "0x{n:08x}"),
+            Value::Flag => Ok(()),
+            Value::Bytes(b) => write!(f, "{}", Hex(b)),
+        }
+    }
+}
+
+fn xor_mask(transaction_id: Option<TransactionId>) -> [u8; 16] {
+    let mut mask = [0; 16];
+    if let Some(id) = transaction_id {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(id.as_bytes());
+    }
+    mask
+}
+
+/// Decodes the value of an (XOR-)address attribute.
+///
+/// If `transaction_id` is `Some(_)`, the value is regarded as an XOR-ed one.
+pub(crate) fn decode_address(
+    v: &[u8],
+    transaction_id: Option<TransactionId>,
+) -> Option<SocketAddr> {
+    let mask = xor_mask(transaction_id);
+    let port = u16::from_be_bytes([v.get(2)? ^ mask[0], v.get(3)? ^ mask[1]]);
+    let ip = match (v[1], v.len()) {
+        (1, 8) => {
+            let mut octets = [0; 4];
+            for (i, o) in octets.iter_mut().enumerate() {
+                *o = v[4 + i] ^ mask[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (2, 20) => {
+            let mut octets = [0; 16];
+            for (i, o) in octets.iter_mut().enumerate() {
+                *o = v[4 + i] ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Encodes the value of an (XOR-)address attribute.
+pub(crate) fn encode_address(addr: SocketAddr, transaction_id: Option<TransactionId>) -> Vec<u8> {
+    let mask = xor_mask(transaction_id);
+    let (family, octets) = match addr.ip() {
+        IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+    };
+    let port = addr.port().to_be_bytes();
+    let mut v = vec![0, family, port[0] ^ mask[0], port[1] ^ mask[1]];
+    v.extend(octets.iter().zip(mask.iter()).map(|(o, m)| o ^ m));
+    v
+}
+
+#[cfg(feature = "serde")]
+fn encode_value(kind: ValueKind, s: &str, transaction_id: TransactionId) -> Result<Vec<u8>> {
+    let value = match kind {
+        ValueKind::Address | ValueKind::XorAddress => {
+            let addr = track!(s.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+            let transaction_id = Some(transaction_id).filter(|_| kind == ValueKind::XorAddress);
+            encode_address(addr, transaction_id)
+        }
+        ValueKind::Text => s.as_bytes().to_owned(),
+        ValueKind::ErrorCode => {
+            let (code, reason) = s.split_once(' ').unwrap_or((s, ""));
+            let code = track!(parse_u64(code))?;
+            track_assert!((300..700).contains(&code), ErrorKind::InvalidInput; code);
+            let mut v = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+            v.extend_from_slice(reason.as_bytes());
+            v
+        }
+        ValueKind::AttributeTypes => {
+            let mut v = Vec::new();
+            for t in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+                v.extend_from_slice(&track!(AttributeName::parse(t))?.to_be_bytes());
+            }
+            v
+        }
+        ValueKind::U32 | ValueKind::Hex32 => {
+            let n = track!(parse_u64(s))?;
+            track_assert!(n <= u64::from(u32::MAX), ErrorKind::InvalidInput; n);
+            (n as u32).to_be_bytes().to_vec()
+        }
+        ValueKind::U64 => track!(parse_u64(s))?.to_be_bytes().to_vec(),
+        ValueKind::Flag => {
+            track_assert!(s.is_empty(), ErrorKind::InvalidInput);
+            Vec::new()
+        }
+        ValueKind::Bytes => track!(parse_hex(s))?,
+    };
+    Ok(value)
+}
+
+/// Parses a decimal or hexadecimal (prefixed by `0x`) number.
+#[cfg(feature = "serde")]
+fn parse_u64(s: &str) -> Result<u64> {
+    let result = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    track!(result.map_err(|e| ErrorKind::InvalidInput.cause(e).into()); s)
+}
+
+/// Parses a hexadecimal string.
+#[cfg(feature = "serde")]
+pub(super) fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    track_assert!(s.len().is_multiple_of(2), ErrorKind::InvalidInput; s);
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok());
+            Ok(track_assert_some!(byte, ErrorKind::InvalidInput; s))
+        })
+        .collect()
+}
+
+/// Hexadecimal representation of bytes.
+pub(super) struct Hex<'a>(pub &'a [u8]);
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stun_codec::rfc5389::attributes::{ErrorCode, Software, XorMappedAddress};
+    use stun_codec::rfc5389::{errors, methods, Attribute};
+
+    #[test]
+    fn pretty_works() {
+        let request = Request::<Attribute>::new(methods::BINDING);
+        let id = Hex(request.transaction_id().as_bytes()).to_string();
+        assert_eq!(
+            request.pretty().to_string(),
+            format!("BINDING request (transaction_id={id})")
+        );
+
+        let mut response = SuccessResponse::new(&request);
+        response.add_attribute(XorMappedAddress::new("[::1]:3478".parse().unwrap()).into());
+        response.add_attribute(Software::new("foo".to_owned()).unwrap().into());
+        assert_eq!(
+            response.pretty().to_string(),
+            format!(
+                "BINDING success response (transaction_id={id}): \
+                 XOR-MAPPED-ADDRESS=[::1]:3478, SOFTWARE=\"foo\""
+            )
+        );
+
+        let response = ErrorResponse::new(&request, ErrorCode::from(errors::BadRequest));
+        assert_eq!(
+            format!("{:#}", response.pretty()),
+            format!(
+                "BINDING error response\n  transaction_id: {id}\n  ERROR-CODE: 400 Bad Request"
+            )
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn raw_message_roundtrip_works() -> std::result::Result<(), trackable::error::MainError> {
+        let request = Request::<Attribute>::new(methods::BINDING);
+        let mut response = SuccessResponse::new(&request);
+        response.add_attribute(XorMappedAddress::new("127.0.0.1:54754".parse().unwrap()).into());
+        let raw = track!(RawMessage::from_message(response.as_ref()))?;
+        assert_eq!(raw.attributes.len(), 1);
+        assert_eq!(
+            raw.attributes[0].format_value(raw.transaction_id),
+            "127.0.0.1:54754"
+        );
+
+        let attribute = track!(RawAttribute::parse_value(
+            0x0020,
+            "127.0.0.1:54754",
+            raw.transaction_id
+        ))?;
+        assert_eq!(attribute.value, raw.attributes[0].value);
+
+        let message = track!(raw.to_message::<Attribute>())?;
+        let mapped = message
+            .get_attribute::<XorMappedAddress>()
+            .map(|a| a.address());
+        assert_eq!(mapped, Some("127.0.0.1:54754".parse().unwrap()));
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn large_method_roundtrip_works() -> std::result::Result<(), trackable::error::MainError> {
+        // Methods of `0x010` or larger are not encoded correctly by `MessageEncoder` of stun_codec-0.3,
+        // thus the raw messages have to build their headers by themselves
+        let method = track!(Method::new(0x0FF).map_err(Error::from))?;
+        let mut request = Request::<Attribute>::new(method);
+        request.add_attribute(Software::new("foo".to_owned())?.into());
+
+        let raw = track!(RawMessage::from_message(request.as_ref()))?;
+        let message = track!(raw.to_message::<Attribute>())?;
+        assert_eq!(message.method(), method);
+        assert_eq!(message.transaction_id(), request.transaction_id());
+
+        let json = serde_json::to_string(&request).unwrap();
+        assert!(json.contains("\"0x0FF\""), "{json}");
+        let decoded: Request<Attribute> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.method(), method);
+        assert_eq!(
+            decoded.get_attribute::<Software>().map(|a| a.description()),
+            Some("foo")
+        );
+        Ok(())
+    }
+}
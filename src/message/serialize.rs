@@ -0,0 +1,309 @@
+//! `serde` support for messages.
+//!
+//! Messages are represented as follows (e.g., in JSON):
+//!
+//! ```json
+//! {
+//!   "class": "success_response",
+//!   "method": "BINDING",
+//!   "transaction_id": "9b2b1d0a5f0e4c7a33c8d1e2",
+//!   "attributes": [
+//!     {"type": "XOR-MAPPED-ADDRESS", "value": "127.0.0.1:54754"},
+//!     {"type": "SOFTWARE", "value": "foo"}
+//!   ]
+//! }
+//! ```
+//!
+//! The values of the attributes are the same as the ones shown by [`Pretty`],
+//! except that texts are not quoted.
+//!
+//! [`Pretty`]: ../struct.Pretty.html
+use super::pretty::{parse_hex, AttributeName, Hex, MethodName, RawAttribute, RawMessage};
+use super::{
+    ErrorResponse, Indication, InvalidMessage, MessageError, MessageErrorKind, Request,
+    SuccessResponse,
+};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use stun_codec::{Attribute, AttributeType, Message, MessageClass, Method, TransactionId};
+use trackable::error::ErrorKindExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClassRepr {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+impl From<MessageClass> for ClassRepr {
+    fn from(f: MessageClass) -> Self {
+        match f {
+            MessageClass::Request => ClassRepr::Request,
+            MessageClass::Indication => ClassRepr::Indication,
+            MessageClass::SuccessResponse => ClassRepr::SuccessResponse,
+            MessageClass::ErrorResponse => ClassRepr::ErrorResponse,
+        }
+    }
+}
+impl From<ClassRepr> for MessageClass {
+    fn from(f: ClassRepr) -> Self {
+        match f {
+            ClassRepr::Request => MessageClass::Request,
+            ClassRepr::Indication => MessageClass::Indication,
+            ClassRepr::SuccessResponse => MessageClass::SuccessResponse,
+            ClassRepr::ErrorResponse => MessageClass::ErrorResponse,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct MessageRepr {
+    class: ClassRepr,
+    method: String,
+    transaction_id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attributes: Vec<AttributeRepr>,
+}
+impl MessageRepr {
+    fn from_message<A: Attribute, E: serde::ser::Error>(message: &Message<A>) -> Result<Self, E> {
+        let raw = RawMessage::from_message(message).map_err(E::custom)?;
+        let attributes = raw
+            .attributes
+            .iter()
+            .map(|a| AttributeRepr {
+                attr_type: AttributeName(a.attr_type).to_string(),
+                value: a.format_value(raw.transaction_id),
+            })
+            .collect();
+        Ok(MessageRepr {
+            class: raw.class.into(),
+            method: MethodName(raw.method).to_string(),
+            transaction_id: Hex(raw.transaction_id.as_bytes()).to_string(),
+            attributes,
+        })
+    }
+
+    fn to_message<A: Attribute, E: serde::de::Error>(
+        &self,
+        expected_class: MessageClass,
+    ) -> Result<Message<A>, E> {
+        let class = MessageClass::from(self.class);
+        if class != expected_class {
+            return Err(E::custom(format_args!(
+                "unexpected message class: expected={expected_class}, actual={class}"
+            )));
+        }
+        let (method, transaction_id) = parse_header(&self.method, &self.transaction_id)?;
+        let attributes = self
+            .attributes
+            .iter()
+            .map(|a| {
+                let attr_type = AttributeName::parse(&a.attr_type).map_err(E::custom)?;
+                RawAttribute::parse_value(attr_type, &a.value, transaction_id).map_err(E::custom)
+            })
+            .collect::<Result<_, E>>()?;
+        let raw = RawMessage {
+            class,
+            method,
+            transaction_id,
+            attributes,
+        };
+        raw.to_message().map_err(E::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AttributeRepr {
+    #[serde(rename = "type")]
+    attr_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InvalidMessageRepr {
+    class: ClassRepr,
+    method: String,
+    transaction_id: String,
+    error: MessageErrorRepr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MessageErrorRepr {
+    kind: ErrorKindRepr,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    unknown_attributes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKindRepr {
+    UnexpectedResponse,
+    MalformedAttribute,
+    UnknownAttributes,
+    InvalidInput,
+    Timeout,
+    Cancelled,
+    Other,
+}
+
+fn parse_header<E: serde::de::Error>(
+    method: &str,
+    transaction_id: &str,
+) -> Result<(Method, TransactionId), E> {
+    let method = MethodName::parse(method).map_err(E::custom)?;
+    let bytes = parse_hex(transaction_id).map_err(E::custom)?;
+    let bytes = <[u8; 12]>::try_from(bytes.as_slice())
+        .map_err(|_| E::invalid_length(bytes.len(), &"a 12 bytes transaction ID"))?;
+    Ok((method, TransactionId::new(bytes)))
+}
+
+macro_rules! impl_serde {
+    ($message:ident, $class:expr) => {
+        impl<A: Attribute> Serialize for $message<A> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                MessageRepr::from_message(self.as_ref())?.serialize(serializer)
+            }
+        }
+        impl<'de, A: Attribute> Deserialize<'de> for $message<A> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let repr = MessageRepr::deserialize(deserializer)?;
+                let message = repr.to_message($class)?;
+                $message::from_message(message).map_err(D::Error::custom)
+            }
+        }
+    };
+}
+impl_serde!(Request, MessageClass::Request);
+impl_serde!(Indication, MessageClass::Indication);
+impl_serde!(SuccessResponse, MessageClass::SuccessResponse);
+impl_serde!(ErrorResponse, MessageClass::ErrorResponse);
+
+impl Serialize for InvalidMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut unknown_attributes = Vec::new();
+        let kind = match self.error().kind() {
+            MessageErrorKind::UnexpectedResponse => ErrorKindRepr::UnexpectedResponse,
+            MessageErrorKind::MalformedAttribute => ErrorKindRepr::MalformedAttribute,
+            MessageErrorKind::UnknownAttributes(types) => {
+                unknown_attributes = types
+                    .iter()
+                    .map(|t| AttributeName(t.as_u16()).to_string())
+                    .collect();
+                ErrorKindRepr::UnknownAttributes
+            }
+            MessageErrorKind::InvalidInput => ErrorKindRepr::InvalidInput,
+            MessageErrorKind::Timeout => ErrorKindRepr::Timeout,
+            MessageErrorKind::Cancelled => ErrorKindRepr::Cancelled,
+            MessageErrorKind::Other => ErrorKindRepr::Other,
+        };
+        let reason = std::error::Error::source(self.error()).map(|e| e.to_string());
+        InvalidMessageRepr {
+            class: self.class().into(),
+            method: MethodName(self.method()).to_string(),
+            transaction_id: Hex(self.transaction_id().as_bytes()).to_string(),
+            error: MessageErrorRepr {
+                kind,
+                unknown_attributes,
+                reason,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for InvalidMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = InvalidMessageRepr::deserialize(deserializer)?;
+        let (method, transaction_id) = parse_header(&repr.method, &repr.transaction_id)?;
+        let kind = match repr.error.kind {
+            ErrorKindRepr::UnexpectedResponse => MessageErrorKind::UnexpectedResponse,
+            ErrorKindRepr::MalformedAttribute => MessageErrorKind::MalformedAttribute,
+            ErrorKindRepr::UnknownAttributes => {
+                let types = repr
+                    .error
+                    .unknown_attributes
+                    .iter()
+                    .map(|t| AttributeName::parse(t).map(AttributeType::new))
+                    .collect::<Result<_, _>>()
+                    .map_err(D::Error::custom)?;
+                MessageErrorKind::UnknownAttributes(types)
+            }
+            ErrorKindRepr::InvalidInput => MessageErrorKind::InvalidInput,
+            ErrorKindRepr::Timeout => MessageErrorKind::Timeout,
+            ErrorKindRepr::Cancelled => MessageErrorKind::Cancelled,
+            ErrorKindRepr::Other => MessageErrorKind::Other,
+        };
+        let error: MessageError = match repr.error.reason {
+            Some(reason) => kind.cause(reason).into(),
+            None => kind.error().into(),
+        };
+        Ok(InvalidMessage::new(
+            method,
+            repr.class.into(),
+            transaction_id,
+            error,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stun_codec::rfc5389::attributes::{ErrorCode, Software, XorMappedAddress};
+    use stun_codec::rfc5389::{errors, methods};
+
+    type Attr = stun_codec::rfc5389::Attribute;
+
+    #[test]
+    fn json_roundtrip_works() {
+        let request = Request::<Attr>::new(methods::BINDING);
+        let mut response = SuccessResponse::new(&request);
+        response.add_attribute(XorMappedAddress::new("127.0.0.1:54754".parse().unwrap()).into());
+        response.add_attribute(Software::new("foo".to_owned()).unwrap().into());
+
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["class"], "success_response");
+        assert_eq!(json["method"], "BINDING");
+        assert_eq!(json["attributes"][0]["type"], "XOR-MAPPED-ADDRESS");
+        assert_eq!(json["attributes"][0]["value"], "127.0.0.1:54754");
+        assert_eq!(json["attributes"][1]["value"], "foo");
+
+        let decoded: SuccessResponse<Attr> = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.transaction_id(), request.transaction_id());
+        assert_eq!(
+            decoded
+                .get_attribute::<XorMappedAddress>()
+                .map(|a| a.address()),
+            Some("127.0.0.1:54754".parse().unwrap())
+        );
+        assert!(
+            serde_json::from_str::<Request<Attr>>(&serde_json::to_string(&response).unwrap())
+                .is_err()
+        );
+
+        let response = ErrorResponse::<Attr>::new(&request, ErrorCode::from(errors::BadRequest));
+        let json = serde_json::to_string(&response).unwrap();
+        let decoded: ErrorResponse<Attr> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            decoded.get_attribute::<ErrorCode>().map(|e| e.code()),
+            Some(400)
+        );
+
+        let invalid = InvalidMessage::new(
+            methods::BINDING,
+            MessageClass::Request,
+            request.transaction_id(),
+            MessageErrorKind::MalformedAttribute.cause("foo").into(),
+        );
+        let json = serde_json::to_string(&invalid).unwrap();
+        let decoded: InvalidMessage = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            decoded.error().kind(),
+            MessageErrorKind::MalformedAttribute
+        ));
+        assert_eq!(decoded.transaction_id(), request.transaction_id());
+    }
+}
@@ -0,0 +1,435 @@
+//! Replaying captured STUN traffic against servers.
+//!
+//! [`PcapReplay`] reads the STUN requests sent to a server from a pcap/pcapng file,
+//! and feeds them into a handler (e.g., a [`HandleMessage`] implementation) or a whole server via a [`SimulatedNetwork`].
+//! The resulting responses are recorded as a [`ReplayOutput`] that can be compared with expected output.
+//! Thus captures attached to bug reports can be turned into regression tests.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # extern crate rustun;
+//! use rustun::replay::PcapReplay;
+//! use rustun::server::BindingHandler;
+//!
+//! # fn main() -> Result<(), trackable::error::MainError> {
+//! let replay = PcapReplay::open("tests/data/issue-123.pcapng")?;
+//! let output = replay.run_handler(BindingHandler)?;
+//!
+//! // Compares with the responses recorded in the capture file
+//! if let Some(diff) = output.diff(&replay.expected_output::<stun_codec::rfc5389::Attribute>().to_string()) {
+//!     panic!("Unexpected responses:\n{}", diff);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`PcapReplay`]: ./struct.PcapReplay.html
+//! [`HandleMessage`]: ../server/trait.HandleMessage.html
+//! [`SimulatedNetwork`]: ../transport/struct.SimulatedNetwork.html
+//! [`ReplayOutput`]: ./struct.ReplayOutput.html
+use crate::clock::{Clock, SystemClock, Timeout};
+use crate::message::{InvalidMessage, MessageErrorKind, Pretty};
+use crate::server::{Dispatch, UdpServer};
+use crate::transport::{
+    CaptureProtocol, CapturedPacket, MemoryTransporter, PcapReader, SimulatedNetworkBuilder,
+};
+use crate::{Error, ErrorKind, Result};
+use bytecodec::DecodeExt;
+use fibers::executor::InPlaceExecutorHandle;
+use fibers::{Executor, InPlaceExecutor, Spawn};
+use fibers_transport::Transport;
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use stun_codec::{Attribute, DecodedMessage, MessageDecoder};
+use trackable::error::ErrorKindExt;
+
+/// Replayer of the STUN requests recorded in a capture file.
+///
+/// Only UDP datagrams sent to the server address are replayed (one by one in the captured order).
+/// The source addresses of the datagrams are preserved.
+#[derive(Debug, Clone)]
+pub struct PcapReplay {
+    packets: Vec<CapturedPacket>,
+    server_addr: Option<SocketAddr>,
+    response_timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+impl PcapReplay {
+    /// The default value of `response_timeout`.
+    pub const DEFAULT_RESPONSE_TIMEOUT_MS: u64 = 100;
+
+    /// Makes a new `PcapReplay` instance that replays the given packets.
+    pub fn new(packets: Vec<CapturedPacket>) -> Self {
+        PcapReplay {
+            packets,
+            server_addr: None,
+            response_timeout: Duration::from_millis(Self::DEFAULT_RESPONSE_TIMEOUT_MS),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Makes a new `PcapReplay` instance that replays the packets recorded in the given pcap/pcapng file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = track!(PcapReader::open(path).map_err(Error::from))?;
+        let packets = track!(reader.read_all().map_err(Error::from))?;
+        Ok(Self::new(packets))
+    }
+
+    /// Sets the address of the server to which the replayed requests were sent.
+    ///
+    /// By default, the destination address of the first STUN request in the capture is used.
+    pub fn server_addr(&mut self, addr: SocketAddr) -> &mut Self {
+        self.server_addr = Some(addr);
+        self
+    }
+
+    /// Sets the duration to wait for the response of each replayed datagram.
+    ///
+    /// Datagrams that receive no responses within the duration (e.g., indications) are regarded as unanswered.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS)`.
+    pub fn response_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.response_timeout = timeout;
+        self
+    }
+
+    /// Sets the clock used for the simulated network and the response timeouts.
+    ///
+    /// If a [`ManualClock`] is specified, it has to be advanced by the caller
+    /// (e.g., by a fiber spawned by the function passed to `run_server`).
+    ///
+    /// The default value is `SystemClock`.
+    ///
+    /// [`ManualClock`]: ../clock/struct.ManualClock.html
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Returns the datagrams that will be replayed.
+    pub fn requests(&self) -> Result<Vec<&CapturedPacket>> {
+        let server_addr = track!(self.resolve_server_addr())?;
+        Ok(self
+            .packets
+            .iter()
+            .filter(|p| p.protocol == CaptureProtocol::Udp && p.dst == server_addr)
+            .collect())
+    }
+
+    /// Returns the output built from the responses recorded in the capture.
+    ///
+    /// The response to a replayed datagram is the first subsequent datagram that
+    /// the server sent to the source of the replayed one with the same transaction ID.
+    pub fn expected_output<A: Attribute>(&self) -> ReplayOutput {
+        let mut output = ReplayOutput::default();
+        let Ok(server_addr) = self.resolve_server_addr() else {
+            return output;
+        };
+        for (i, request) in self.packets.iter().enumerate() {
+            if request.protocol != CaptureProtocol::Udp || request.dst != server_addr {
+                continue;
+            }
+            let response = self.packets[i + 1..].iter().find(|p| {
+                p.protocol == CaptureProtocol::Udp
+                    && p.src == server_addr
+                    && p.dst == request.src
+                    && transaction_id(&p.payload).is_some()
+                    && transaction_id(&p.payload) == transaction_id(&request.payload)
+            });
+            output.entries.push(ReplayEntry {
+                peer: request.src,
+                request: describe_bytes::<A>(&request.payload),
+                response: response.map(|p| describe_bytes::<A>(&p.payload)),
+            });
+        }
+        output
+    }
+
+    /// Replays the requests against a `UdpServer` that uses the given handler.
+    pub fn run_handler<H>(&self, handler: H) -> Result<ReplayOutput>
+    where
+        H: Dispatch + Send + 'static,
+        <H::Attribute as Attribute>::Decoder: Send + 'static,
+        <H::Attribute as Attribute>::Encoder: Send + 'static,
+    {
+        track!(self.run_server(move |spawner, transporter| {
+            UdpServer::with_transporter(spawner, transporter, handler)
+        }))
+    }
+
+    /// Replays the requests against the server made by the given function.
+    ///
+    /// The function receives a spawner and a transporter bound to the server address.
+    ///
+    /// The response to a replayed datagram is the first datagram that the server sends to the source
+    /// of the replayed one with the same transaction ID within the response timeout.
+    /// Other datagrams (e.g., late responses to the previous requests) are discarded.
+    pub fn run_server<A, F, S>(&self, f: F) -> Result<ReplayOutput>
+    where
+        A: Attribute + Send + 'static,
+        A::Decoder: Send + 'static,
+        A::Encoder: Send + 'static,
+        F: FnOnce(InPlaceExecutorHandle, MemoryTransporter<A>) -> S,
+        S: Future<Error = Error> + Send + 'static,
+    {
+        let server_addr = track!(self.resolve_server_addr())?;
+        let network = SimulatedNetworkBuilder::new()
+            .clock(SharedClock(Arc::clone(&self.clock)))
+            .finish();
+        let mut executor = track!(InPlaceExecutor::new().map_err(Error::from))?;
+        let transporter = track!(network.bind(server_addr).map_err(Error::from))?;
+        let server_error = Arc::new(Mutex::new(None));
+        {
+            let server_error = Arc::clone(&server_error);
+            let server = f(executor.handle(), transporter);
+            executor.spawn(server.then(move |result| {
+                let e = result.err().unwrap_or_else(|| {
+                    ErrorKind::Other
+                        .cause("Server unexpectedly terminated")
+                        .into()
+                });
+                *server_error.lock().expect("never fails") = Some(e);
+                Ok(())
+            }));
+        }
+
+        let mut clients = HashMap::new();
+        let mut output = ReplayOutput::default();
+        for request in track!(self.requests())? {
+            let client = match clients.remove(&request.src) {
+                Some(client) => client,
+                None => track!(network.bind::<A>(request.src).map_err(Error::from))?,
+            };
+            network.send_raw(request.src, server_addr, request.payload.clone());
+
+            let timeout = self.clock.timeout(self.response_timeout);
+            let recv = RecvResponse::new(client, transaction_id(&request.payload), timeout);
+            let monitor = executor.spawn_monitor(recv);
+            let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
+            let (client, response) = track!(result.map_err(Error::from))?;
+            if let Some(e) = server_error.lock().expect("never fails").take() {
+                return Err(track!(e));
+            }
+
+            clients.insert(request.src, client);
+            output.entries.push(ReplayEntry {
+                peer: request.src,
+                request: describe_bytes::<A>(&request.payload),
+                response: response.map(|m| describe(&m)),
+            });
+        }
+        Ok(output)
+    }
+
+    fn resolve_server_addr(&self) -> Result<SocketAddr> {
+        if let Some(addr) = self.server_addr {
+            return Ok(addr);
+        }
+        let request = self.packets.iter().find(|p| {
+            p.protocol == CaptureProtocol::Udp
+                && p.payload.len() >= 20
+                && p.payload[0] >> 6 == 0
+                && p.payload[0] & 0b0000_0001 == 0
+                && p.payload[1] & 0b0001_0000 == 0
+        });
+        let request = track_assert_some!(
+            request,
+            ErrorKind::InvalidInput,
+            "No STUN requests in the capture"
+        );
+        Ok(request.dst)
+    }
+}
+
+/// Output of a replay (or the expected one).
+///
+/// `Display` formats the output as follows (one line per datagram):
+///
+/// ```text
+/// > 192.0.2.1:5000 BINDING request (transaction_id=7a6fa1e5b2c34d9e0f1a2b3c)
+/// < 192.0.2.1:5000 BINDING success response (transaction_id=7a6fa1e5b2c34d9e0f1a2b3c): XOR-MAPPED-ADDRESS=192.0.2.1:5000
+/// > 192.0.2.1:5000 BINDING indication (transaction_id=0c1d2e3f4a5b6c7d8e9fa0b1)
+/// < 192.0.2.1:5000 (no response)
+/// ```
+///
+/// Messages are formatted by [`Pretty`].
+///
+/// [`Pretty`]: ../message/struct.Pretty.html
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplayOutput {
+    entries: Vec<ReplayEntry>,
+}
+impl ReplayOutput {
+    /// Returns the entries of the output.
+    pub fn entries(&self) -> &[ReplayEntry] {
+        &self.entries
+    }
+
+    /// Compares this output with the given expected output text.
+    ///
+    /// Blank lines and trailing whitespaces are ignored.
+    /// If they differ, this returns a line-based diff (`-` for expected lines and `+` for actual lines).
+    pub fn diff(&self, expected: &str) -> Option<String> {
+        let lines = |s: &str| {
+            s.lines()
+                .map(str::trim_end)
+                .filter(|l| !l.is_empty())
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>()
+        };
+        let expected = lines(expected);
+        let actual = lines(&self.to_string());
+        if expected == actual {
+            return None;
+        }
+
+        // Longest common subsequence of the lines
+        let (n, m) = (expected.len(), actual.len());
+        let mut lcs = vec![vec![0; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if expected[i] == actual[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let mut diff = String::new();
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && expected[i] == actual[j] {
+                diff += &format!("  {}\n", expected[i]);
+                i += 1;
+                j += 1;
+            } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                diff += &format!("- {}\n", expected[i]);
+                i += 1;
+            } else {
+                diff += &format!("+ {}\n", actual[j]);
+                j += 1;
+            }
+        }
+        Some(diff)
+    }
+}
+impl fmt::Display for ReplayOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "> {} {}", entry.peer, entry.request)?;
+            match &entry.response {
+                Some(response) => writeln!(f, "< {} {}", entry.peer, response)?,
+                None => writeln!(f, "< {} (no response)", entry.peer)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replayed datagram and its response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayEntry {
+    /// Source address of the replayed datagram.
+    pub peer: SocketAddr,
+
+    /// Human-readable representation of the replayed datagram.
+    pub request: String,
+
+    /// Human-readable representation of the response.
+    pub response: Option<String>,
+}
+
+#[derive(Debug)]
+struct RecvResponse<A: Attribute> {
+    transporter: Option<MemoryTransporter<A>>,
+    transaction_id: Option<Vec<u8>>,
+    timeout: Timeout,
+}
+impl<A: Attribute> RecvResponse<A> {
+    fn new(
+        transporter: MemoryTransporter<A>,
+        transaction_id: Option<&[u8]>,
+        timeout: Timeout,
+    ) -> Self {
+        RecvResponse {
+            transporter: Some(transporter),
+            transaction_id: transaction_id.map(ToOwned::to_owned),
+            timeout,
+        }
+    }
+
+    fn is_response(&self, message: &DecodedMessage<A>) -> bool {
+        let transaction_id = match message {
+            Ok(m) => m.transaction_id(),
+            Err(m) => m.transaction_id(),
+        };
+        self.transaction_id.as_deref() == Some(transaction_id.as_bytes())
+    }
+}
+impl<A: Attribute> Future for RecvResponse<A> {
+    type Item = (MemoryTransporter<A>, Option<DecodedMessage<A>>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let transporter = self.transporter.as_mut().expect("Cannot poll twice");
+            match track!(transporter.poll_recv().map_err(Error::from))? {
+                Async::NotReady => break,
+                Async::Ready(Some((_, message))) if !self.is_response(&message) => {}
+                Async::Ready(item) => {
+                    let transporter = self.transporter.take().expect("never fails");
+                    return Ok(Async::Ready((transporter, item.map(|(_, m)| m))));
+                }
+            }
+        }
+        if self.timeout.poll().map_or(true, |x| x.is_ready()) {
+            let transporter = self.transporter.take().expect("never fails");
+            return Ok(Async::Ready((transporter, None)));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[derive(Debug)]
+struct SharedClock(Arc<dyn Clock>);
+impl Clock for SharedClock {
+    fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+
+    fn timeout(&self, duration: Duration) -> Timeout {
+        self.0.timeout(duration)
+    }
+}
+
+fn transaction_id(payload: &[u8]) -> Option<&[u8]> {
+    payload.get(8..20)
+}
+
+fn describe_bytes<A: Attribute>(bytes: &[u8]) -> String {
+    match MessageDecoder::<A>::default().decode_from_bytes(bytes) {
+        Ok(message) => describe(&message),
+        Err(_) => format!("undecodable ({} bytes)", bytes.len()),
+    }
+}
+
+fn describe<A: Attribute>(message: &DecodedMessage<A>) -> String {
+    match message {
+        Ok(message) => Pretty::new(message).to_string(),
+        Err(broken) => {
+            let message = InvalidMessage::new(
+                broken.method(),
+                broken.class(),
+                broken.transaction_id(),
+                MessageErrorKind::MalformedAttribute.error().into(),
+            );
+            Pretty::new(&message).to_string()
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use super::dispatch::Reply;
+use super::{Dispatch, LimitExceeded, RequestContext, TcpServer, TcpServerBuilder, UdpServer};
+use crate::message::{Indication, InvalidMessage, Request, Response};
+use crate::{Error, ErrorKind};
+use bytecodec::marker::Never;
+use factory::Factory;
+use fibers::sync::mpsc;
+use fibers::sync::oneshot::Monitor;
+use fibers::Spawn;
+use futures::{future, Async, Future, Poll, Stream};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use stun_codec::Attribute;
+
+/// Transport protocol of a listener of [`CompositeServer`].
+///
+/// [`CompositeServer`]: ./struct.CompositeServer.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// UDP.
+    Udp,
+
+    /// TCP.
+    Tcp,
+}
+
+/// [`CompositeServer`] builder.
+///
+/// [`CompositeServer`]: ./struct.CompositeServer.html
+#[derive(Debug, Default, Clone)]
+pub struct CompositeServerBuilder {
+    listeners: Vec<(Protocol, SocketAddr)>,
+    tcp_options: TcpServerBuilder,
+}
+impl CompositeServerBuilder {
+    /// Makes a new `CompositeServerBuilder` instance that has no listeners.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a listener of the given protocol bound to `bind_addr`.
+    pub fn listen(&mut self, protocol: Protocol, bind_addr: SocketAddr) -> &mut Self {
+        self.listeners.push((protocol, bind_addr));
+        self
+    }
+
+    /// Adds a UDP listener bound to `bind_addr`.
+    ///
+    /// This is equivalent to `self.listen(Protocol::Udp, bind_addr)`.
+    pub fn udp(&mut self, bind_addr: SocketAddr) -> &mut Self {
+        self.listen(Protocol::Udp, bind_addr)
+    }
+
+    /// Adds a TCP listener bound to `bind_addr`.
+    ///
+    /// This is equivalent to `self.listen(Protocol::Tcp, bind_addr)`.
+    pub fn tcp(&mut self, bind_addr: SocketAddr) -> &mut Self {
+        self.listen(Protocol::Tcp, bind_addr)
+    }
+
+    /// Sets the options (e.g., connection limits) of the TCP listeners.
+    ///
+    /// The default value is `TcpServerBuilder::new()`.
+    pub fn tcp_options(&mut self, options: TcpServerBuilder) -> &mut Self {
+        self.tcp_options = options;
+        self
+    }
+
+    /// Starts the server.
+    ///
+    /// Handlers are created by `handler_factory`: one for each UDP listener and one for each TCP connection.
+    /// State to be shared among the listeners (e.g., nonces or rate limits) should be
+    /// kept in a backend referred by all of the handlers (e.g., an `Arc` cloned by [`CloneFactory`]).
+    ///
+    /// # Errors
+    ///
+    /// If no listeners have been added, this will return an `ErrorKind::InvalidInput` error.
+    ///
+    /// [`CloneFactory`]: https://docs.rs/factory/0.1/factory/struct.CloneFactory.html
+    pub fn start<S, H>(
+        &self,
+        spawner: S,
+        handler_factory: H,
+    ) -> impl Future<Item = CompositeServer, Error = Error>
+    where
+        S: Spawn + Clone + Send + 'static,
+        H: Factory + Send + Sync + 'static,
+        H::Item: Dispatch + Send + 'static,
+        <<H::Item as Dispatch>::Attribute as Attribute>::Decoder: Send + 'static,
+        <<H::Item as Dispatch>::Attribute as Attribute>::Encoder: Send + 'static,
+    {
+        let metrics = Arc::new(Metrics::default());
+        let factory = Arc::new(handler_factory);
+        let binds = self
+            .listeners
+            .iter()
+            .map(|&(protocol, bind_addr)| -> BoxListenerFuture<S, H> {
+                match protocol {
+                    Protocol::Udp => {
+                        let handler =
+                            MeteredFactory::new(factory.clone(), metrics.clone(), false).create();
+                        let future = UdpServer::start(spawner.clone(), bind_addr, handler);
+                        Box::new(future.map(|server| Listener::Udp(Box::new(server))))
+                    }
+                    Protocol::Tcp => {
+                        let factory = MeteredFactory::new(factory.clone(), metrics.clone(), true);
+                        let future = self.tcp_options.start(spawner.clone(), bind_addr, factory);
+                        Box::new(future.map(|server| Listener::Tcp(Box::new(server))))
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let no_listeners = binds.is_empty();
+        future::lazy(move || {
+            track_assert!(!no_listeners, ErrorKind::InvalidInput; "No listeners");
+            Ok(())
+        })
+        .and_then(move |()| future::join_all(binds))
+        .map(move |listeners| {
+            let shutdown = ShutdownSignal::new();
+            let mut local_addrs = Vec::new();
+            let listeners = listeners
+                .into_iter()
+                .map(|listener| match listener {
+                    Listener::Udp(server) => {
+                        local_addrs.push((Protocol::Udp, server.local_addr()));
+                        spawner.spawn_monitor(shutdown.wrap(*server))
+                    }
+                    Listener::Tcp(mut server) => {
+                        local_addrs.push((Protocol::Tcp, server.local_addr()));
+                        server.shutdown = Some(shutdown.clone());
+                        spawner.spawn_monitor(shutdown.wrap(server))
+                    }
+                })
+                .collect();
+            CompositeServer {
+                local_addrs,
+                listeners,
+                handle: CompositeServerHandle { metrics, shutdown },
+            }
+        })
+    }
+}
+
+/// STUN server that serves requests on multiple UDP and TCP listeners.
+///
+/// Each listener is driven by a dedicated fiber, and all of them share a handler factory and a set of metrics.
+///
+/// This future completes after the server is shut down by [`CompositeServerHandle::shutdown`]
+/// (or terminates with an error if any of the listeners fails).
+/// Dropping the future also shuts down the server.
+///
+/// [`CompositeServerHandle::shutdown`]: ./struct.CompositeServerHandle.html#method.shutdown
+#[must_use = "future do nothing unless polled"]
+pub struct CompositeServer {
+    local_addrs: Vec<(Protocol, SocketAddr)>,
+    listeners: Vec<Monitor<(), Error>>,
+    handle: CompositeServerHandle,
+}
+impl CompositeServer {
+    /// Returns the protocols and addresses of the listeners (in the order they were added to the builder).
+    pub fn local_addrs(&self) -> &[(Protocol, SocketAddr)] {
+        &self.local_addrs
+    }
+
+    /// Returns a handle to control the server.
+    pub fn handle(&self) -> CompositeServerHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the statistics of the server.
+    pub fn stats(&self) -> ServerStats {
+        self.handle.stats()
+    }
+}
+impl Future for CompositeServer {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut i = 0;
+        while i < self.listeners.len() {
+            match self.listeners[i].poll() {
+                Err(e) => {
+                    self.handle.shutdown();
+                    return Err(track!(Error::from(e)));
+                }
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(())) => {
+                    self.listeners.swap_remove(i);
+                }
+            }
+        }
+        if self.listeners.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+impl Drop for CompositeServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+    }
+}
+impl fmt::Debug for CompositeServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CompositeServer {{ local_addrs: {:?}, .. }}",
+            self.local_addrs
+        )
+    }
+}
+
+/// Handle to control a [`CompositeServer`].
+///
+/// [`CompositeServer`]: ./struct.CompositeServer.html
+#[derive(Debug, Clone)]
+pub struct CompositeServerHandle {
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownSignal,
+}
+impl CompositeServerHandle {
+    /// Shuts down the server.
+    ///
+    /// All of the listeners and the established TCP connections are closed.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Returns the statistics of the server.
+    pub fn stats(&self) -> ServerStats {
+        self.metrics.stats()
+    }
+}
+
+/// Statistics of the messages handled by a [`CompositeServer`].
+///
+/// [`CompositeServer`]: ./struct.CompositeServer.html
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Number of requests received.
+    pub requests: u64,
+
+    /// Number of indications received.
+    pub indications: u64,
+
+    /// Number of invalid messages received.
+    pub invalid_messages: u64,
+
+    /// Number of success responses replied.
+    pub success_responses: u64,
+
+    /// Number of error responses replied.
+    pub error_responses: u64,
+
+    /// Number of channel errors reported to the handlers.
+    pub channel_errors: u64,
+
+    /// Number of TCP connections accepted.
+    ///
+    /// Connections refused due to the connection limits of the server are not included.
+    pub tcp_connections: u64,
+
+    /// Number of TCP connections currently established.
+    pub active_tcp_connections: u64,
+
+    /// Number of TCP connections refused due to the connection limits of the server
+    /// (i.e., `LimitExceeded::MaxConnections` and `LimitExceeded::MaxConnectionsPerIp`).
+    pub rejected_tcp_connections: u64,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    requests: AtomicU64,
+    indications: AtomicU64,
+    invalid_messages: AtomicU64,
+    success_responses: AtomicU64,
+    error_responses: AtomicU64,
+    channel_errors: AtomicU64,
+    tcp_connections: AtomicU64,
+    active_tcp_connections: AtomicU64,
+    rejected_tcp_connections: AtomicU64,
+}
+impl Metrics {
+    fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn decrement(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn count_response<A: Attribute>(&self, response: &Response<A>) {
+        if response.is_ok() {
+            Self::increment(&self.success_responses);
+        } else {
+            Self::increment(&self.error_responses);
+        }
+    }
+
+    fn stats(&self) -> ServerStats {
+        ServerStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            indications: self.indications.load(Ordering::Relaxed),
+            invalid_messages: self.invalid_messages.load(Ordering::Relaxed),
+            success_responses: self.success_responses.load(Ordering::Relaxed),
+            error_responses: self.error_responses.load(Ordering::Relaxed),
+            channel_errors: self.channel_errors.load(Ordering::Relaxed),
+            tcp_connections: self.tcp_connections.load(Ordering::Relaxed),
+            active_tcp_connections: self.active_tcp_connections.load(Ordering::Relaxed),
+            rejected_tcp_connections: self.rejected_tcp_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct MeteredFactory<H> {
+    inner: Arc<H>,
+    metrics: Arc<Metrics>,
+    tcp: bool,
+}
+impl<H> MeteredFactory<H> {
+    fn new(inner: Arc<H>, metrics: Arc<Metrics>, tcp: bool) -> Self {
+        MeteredFactory {
+            inner,
+            metrics,
+            tcp,
+        }
+    }
+}
+impl<H: Factory> Factory for MeteredFactory<H> {
+    type Item = MeteredHandler<H::Item>;
+
+    fn create(&self) -> Self::Item {
+        if self.tcp {
+            Metrics::increment(&self.metrics.tcp_connections);
+            Metrics::increment(&self.metrics.active_tcp_connections);
+        }
+        MeteredHandler {
+            inner: self.inner.create(),
+            metrics: self.metrics.clone(),
+            connected: self.tcp,
+        }
+    }
+}
+
+/// Handler wrapper that counts the messages handled by the inner handler.
+///
+/// A TCP handler lives as long as its connection, thus dropping it means that the connection has been closed.
+/// The handler of a connection refused by the connection limits is notified of the refusal
+/// right after being created, and the connection is counted as a rejected one instead.
+#[derive(Debug)]
+struct MeteredHandler<H> {
+    inner: H,
+    metrics: Arc<Metrics>,
+    connected: bool,
+}
+impl<H: Dispatch> MeteredHandler<H> {
+    fn count_reply(
+        &self,
+        reply: Reply<H::Attribute, H::Pending>,
+    ) -> Reply<H::Attribute, MeteredReply<H::Pending>> {
+        match reply {
+            Reply::None => Reply::None,
+            Reply::Now(response) => {
+                self.metrics.count_response(&response);
+                Reply::Now(response)
+            }
+            Reply::Spawn(future) => {
+                let metrics = self.metrics.clone();
+                Reply::Spawn(Box::new(future.map(move |response| {
+                    metrics.count_response(&response);
+                    response
+                })))
+            }
+            Reply::Detached(future) => Reply::Detached(future),
+            Reply::Pending(future) => Reply::Pending(MeteredReply {
+                inner: future,
+                metrics: self.metrics.clone(),
+            }),
+        }
+    }
+}
+impl<H: Dispatch> Dispatch for MeteredHandler<H> {
+    type Attribute = H::Attribute;
+    type Pending = MeteredReply<H::Pending>;
+
+    fn dispatch_call(
+        &mut self,
+        context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        Metrics::increment(&self.metrics.requests);
+        let reply = self.inner.dispatch_call(context, request);
+        self.count_reply(reply)
+    }
+
+    fn dispatch_cast(
+        &mut self,
+        context: &RequestContext,
+        indication: Indication<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        Metrics::increment(&self.metrics.indications);
+        let reply = self.inner.dispatch_cast(context, indication);
+        self.count_reply(reply)
+    }
+
+    fn dispatch_invalid_message(
+        &mut self,
+        context: &RequestContext,
+        message: InvalidMessage,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        Metrics::increment(&self.metrics.invalid_messages);
+        let reply = self.inner.dispatch_invalid_message(context, message);
+        self.count_reply(reply)
+    }
+
+    fn dispatch_channel_error(&mut self, error: &Error) {
+        Metrics::increment(&self.metrics.channel_errors);
+        if self.connected
+            && matches!(
+                LimitExceeded::from_error(error),
+                Some(LimitExceeded::MaxConnections(_) | LimitExceeded::MaxConnectionsPerIp(_))
+            )
+        {
+            self.connected = false;
+            Metrics::decrement(&self.metrics.tcp_connections);
+            Metrics::decrement(&self.metrics.active_tcp_connections);
+            Metrics::increment(&self.metrics.rejected_tcp_connections);
+        }
+        self.inner.dispatch_channel_error(error);
+    }
+}
+
+/// Future that counts the response of the inner reply future.
+#[derive(Debug)]
+struct MeteredReply<F> {
+    inner: F,
+    metrics: Arc<Metrics>,
+}
+impl<A, F> Future for MeteredReply<F>
+where
+    A: Attribute,
+    F: Future<Item = Option<Response<A>>, Error = Never>,
+{
+    type Item = Option<Response<A>>;
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let reply = futures::try_ready!(self.inner.poll());
+        if let Some(response) = &reply {
+            self.metrics.count_response(response);
+        }
+        Ok(Async::Ready(reply))
+    }
+}
+impl<H> Drop for MeteredHandler<H> {
+    fn drop(&mut self) {
+        if self.connected {
+            Metrics::decrement(&self.metrics.active_tcp_connections);
+        }
+    }
+}
+
+enum Listener<S, H>
+where
+    H: Factory,
+    H::Item: Dispatch,
+{
+    Udp(Box<UdpServer<MeteredHandler<H::Item>>>),
+    Tcp(Box<TcpServer<S, MeteredFactory<H>>>),
+}
+
+type BoxListenerFuture<S, H> = Box<dyn Future<Item = Listener<S, H>, Error = Error> + Send>;
+
+/// Signal that stops the futures wrapped by it.
+///
+/// Each wrapped future holds a receiver whose sender is kept by the signal,
+/// and the signal is triggered by dropping all of the senders.
+#[derive(Debug, Clone)]
+pub(super) struct ShutdownSignal {
+    subscribers: Arc<Mutex<Option<Vec<mpsc::Sender<()>>>>>,
+}
+impl ShutdownSignal {
+    fn new() -> Self {
+        ShutdownSignal {
+            subscribers: Arc::new(Mutex::new(Some(Vec::new()))),
+        }
+    }
+
+    pub fn wrap<F: Future>(&self, future: F) -> Shutdownable<F> {
+        let (tx, rx) = mpsc::channel();
+        if let Some(subscribers) = self.subscribers.lock().expect("never fails").as_mut() {
+            subscribers.retain(|tx| !tx.is_disconnected());
+            subscribers.push(tx);
+        }
+        Shutdownable {
+            future,
+            shutdown: rx,
+        }
+    }
+
+    fn trigger(&self) {
+        self.subscribers.lock().expect("never fails").take();
+    }
+}
+
+/// Future that completes when either the inner future completes or the shutdown signal is triggered.
+#[derive(Debug)]
+pub(super) struct Shutdownable<F> {
+    future: F,
+    shutdown: mpsc::Receiver<()>,
+}
+impl<F: Future> Future for Shutdownable<F> {
+    type Item = ();
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(None) = self.shutdown.poll().expect("never fails") {
+            return Ok(Async::Ready(()));
+        }
+        self.future.poll().map(|item| item.map(|_| ()))
+    }
+}
@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Transport over which a message has been received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    /// UDP.
+    Udp,
+
+    /// TCP.
+    Tcp,
+
+    /// TLS-over-TCP.
+    Tls,
+}
+
+/// Information about the TLS session over which a message has been received.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsPeerInfo {
+    /// The server name indicated by the client (SNI).
+    pub server_name: Option<String>,
+
+    /// The DER encoded certificate chain presented by the client (empty if it did not authenticate itself).
+    pub peer_certificates: Vec<Vec<u8>>,
+
+    /// The application protocol negotiated by ALPN.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+/// Context of an incoming message passed to [`HandleMessage`].
+///
+/// [`HandleMessage`]: ./trait.HandleMessage.html
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    transport: TransportKind,
+    connection_id: Option<u64>,
+    received_at: SystemTime,
+    tls_peer_info: Option<TlsPeerInfo>,
+}
+impl RequestContext {
+    /// Makes a new `RequestContext` instance.
+    ///
+    /// The receive timestamp is set to the current time.
+    pub fn new(peer: SocketAddr, local_addr: SocketAddr, transport: TransportKind) -> Self {
+        RequestContext {
+            peer,
+            local_addr,
+            transport,
+            connection_id: None,
+            received_at: SystemTime::now(),
+            tls_peer_info: None,
+        }
+    }
+
+    /// Sets the identifier of the connection over which the message has been received.
+    pub fn set_connection_id(&mut self, id: u64) {
+        self.connection_id = Some(id);
+    }
+
+    /// Sets the time when the message has been received.
+    pub fn set_received_at(&mut self, time: SystemTime) {
+        self.received_at = time;
+    }
+
+    /// Sets the information about the TLS session over which the message has been received.
+    pub fn set_tls_peer_info(&mut self, info: TlsPeerInfo) {
+        self.tls_peer_info = Some(info);
+    }
+
+    /// Returns the address of the peer that sent the message.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns the local address at which the message has been received.
+    ///
+    /// This is the address to which the receiving socket is bound.
+    /// Note that it is an unspecified address (e.g., `0.0.0.0:3478`) if the socket is bound to such an address,
+    /// thus multi-homed servers that need the exact address should bind a socket to each address
+    /// (e.g., by using [`MultiSocketUdpServer`] or [`CompositeServer`]).
+    ///
+    /// [`MultiSocketUdpServer`]: ./struct.MultiSocketUdpServer.html
+    /// [`CompositeServer`]: ./struct.CompositeServer.html
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the transport over which the message has been received.
+    pub fn transport(&self) -> TransportKind {
+        self.transport
+    }
+
+    /// Returns the identifier of the connection over which the message has been received.
+    ///
+    /// Connection oriented servers (e.g., [`TcpServer`]) assign an identifier unique in the process to each connection.
+    /// For connectionless transports, this is always `None`.
+    ///
+    /// [`TcpServer`]: ./struct.TcpServer.html
+    pub fn connection_id(&self) -> Option<u64> {
+        self.connection_id
+    }
+
+    /// Returns the time when the message has been received.
+    ///
+    /// Servers take this from their clock (e.g., [`TcpServerBuilder::clock`]) when reading the message.
+    ///
+    /// [`TcpServerBuilder::clock`]: ./struct.TcpServerBuilder.html#method.clock
+    pub fn received_at(&self) -> SystemTime {
+        self.received_at
+    }
+
+    /// Returns the information about the TLS session over which the message has been received.
+    ///
+    /// The built-in servers do not terminate TLS, thus this is `None` for them.
+    pub fn tls_peer_info(&self) -> Option<&TlsPeerInfo> {
+        self.tls_peer_info.as_ref()
+    }
+}
+
+/// Returns a new connection identifier unique in the process.
+pub(super) fn next_connection_id() -> u64 {
+    static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
@@ -0,0 +1,110 @@
+use super::{Action, HandleMessage, RequestContext};
+use crate::message::{Indication, InvalidMessage, Request, Response};
+use crate::Error;
+use bytecodec::marker::Never;
+use futures::{future, Future};
+use stun_codec::Attribute;
+
+/// Message handler that can be driven by the servers.
+///
+/// This is implemented for every [`HandleMessage`] and [`SharedHandler`],
+/// and cannot be implemented outside of this crate.
+///
+/// [`HandleMessage`]: ./trait.HandleMessage.html
+/// [`SharedHandler`]: ./struct.SharedHandler.html
+pub trait Dispatch {
+    /// The attributes that the handler can recognize.
+    type Attribute: Attribute + Send + 'static;
+
+    /// Future of a reply that is polled by the server directly.
+    type Pending: Future<Item = Option<Response<Self::Attribute>>, Error = Never> + Send + 'static;
+
+    #[doc(hidden)]
+    fn dispatch_call(
+        &mut self,
+        context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending>;
+
+    #[doc(hidden)]
+    fn dispatch_cast(
+        &mut self,
+        context: &RequestContext,
+        indication: Indication<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending>;
+
+    #[doc(hidden)]
+    fn dispatch_invalid_message(
+        &mut self,
+        context: &RequestContext,
+        message: InvalidMessage,
+    ) -> Reply<Self::Attribute, Self::Pending>;
+
+    #[doc(hidden)]
+    fn dispatch_channel_error(&mut self, error: &Error);
+}
+impl<H: HandleMessage> Dispatch for H {
+    type Attribute = H::Attribute;
+    type Pending = future::Empty<Option<Response<H::Attribute>>, Never>;
+
+    fn dispatch_call(
+        &mut self,
+        context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        Reply::from(self.handle_call(context, request))
+    }
+
+    fn dispatch_cast(
+        &mut self,
+        context: &RequestContext,
+        indication: Indication<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        match self.handle_cast(context, indication) {
+            Action::NoReply => Reply::None,
+            Action::FutureNoReply(future) => Reply::Detached(future),
+            _ => unreachable!(),
+        }
+    }
+
+    fn dispatch_invalid_message(
+        &mut self,
+        context: &RequestContext,
+        message: InvalidMessage,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        Reply::from(self.handle_invalid_message(context, message))
+    }
+
+    fn dispatch_channel_error(&mut self, error: &Error) {
+        self.handle_channel_error(error);
+    }
+}
+
+/// Reply of a handler to an incoming message.
+#[doc(hidden)]
+pub enum Reply<A: Attribute, F> {
+    /// Does not reply.
+    None,
+
+    /// Replies the response immediately.
+    Now(Response<A>),
+
+    /// Spawns the future and replies its result.
+    Spawn(Box<dyn Future<Item = Response<A>, Error = Never> + Send + 'static>),
+
+    /// Spawns the future and does not reply.
+    Detached(Box<dyn Future<Item = (), Error = Never> + Send + 'static>),
+
+    /// Polls the future in the server and replies its result (if any).
+    Pending(F),
+}
+impl<A: Attribute, F> From<Action<Response<A>>> for Reply<A, F> {
+    fn from(f: Action<Response<A>>) -> Self {
+        match f {
+            Action::Reply(response) => Reply::Now(response),
+            Action::FutureReply(future) => Reply::Spawn(future),
+            Action::NoReply => Reply::None,
+            Action::FutureNoReply(future) => Reply::Detached(future),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{Error, ErrorKind};
+use bytecodec::{ByteCount, Decode, Eos};
+use factory::Factory;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stun_codec::{Attribute, MessageDecoder};
+use trackable::error::ErrorKindExt;
+
+/// A limit of [`TcpServer`] (or [`UdpServer`]) exceeded by a client.
+///
+/// Violations are reported to [`HandleMessage::handle_channel_error`]
+/// as errors caused by this value (use [`LimitExceeded::from_error`] to extract it).
+///
+/// [`TcpServer`]: ./struct.TcpServer.html
+/// [`UdpServer`]: ./struct.UdpServer.html
+/// [`HandleMessage::handle_channel_error`]: ./trait.HandleMessage.html#method.handle_channel_error
+/// [`LimitExceeded::from_error`]: ./enum.LimitExceeded.html#method.from_error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The connection has been idle for the given duration, and has been closed.
+    IdleTimeout(Duration),
+
+    /// The server already has the given number of connections, thus a new connection has been refused.
+    MaxConnections(usize),
+
+    /// The server already has the given number of connections from the client IP address,
+    /// thus a new connection has been refused.
+    MaxConnectionsPerIp(usize),
+
+    /// The client sent a message larger than the given number of bytes, and the connection has been closed.
+    MaxMessageSize(usize),
+
+    /// The handler has the given number of in-flight replies (e.g., `Action::FutureReply` responses),
+    /// thus the server handles the subsequent requests according to its [`OverloadPolicy`]
+    /// until some of the replies complete.
+    ///
+    /// [`OverloadPolicy`]: ./enum.OverloadPolicy.html
+    MaxInflightReplies(usize),
+
+    /// The handler did not reply to a request within the given duration,
+    /// thus the server has replied a `500 Server Error` response instead.
+    ReplyTimeout(Duration),
+}
+impl LimitExceeded {
+    /// Returns the `LimitExceeded` that caused the given error if exists.
+    pub fn from_error(error: &Error) -> Option<&Self> {
+        error.concrete_cause()
+    }
+}
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitExceeded::IdleTimeout(d) => write!(f, "Idle connection timed out ({d:?})"),
+            LimitExceeded::MaxConnections(n) => write!(f, "Too many connections (max={n})"),
+            LimitExceeded::MaxConnectionsPerIp(n) => {
+                write!(f, "Too many connections from the same IP address (max={n})")
+            }
+            LimitExceeded::MaxMessageSize(n) => write!(f, "Too large message (max={n} bytes)"),
+            LimitExceeded::MaxInflightReplies(n) => {
+                write!(f, "Too many in-flight replies (max={n})")
+            }
+            LimitExceeded::ReplyTimeout(d) => write!(f, "Reply timed out ({d:?})"),
+        }
+    }
+}
+impl std::error::Error for LimitExceeded {}
+impl From<LimitExceeded> for Error {
+    fn from(f: LimitExceeded) -> Self {
+        ErrorKind::Other.cause(f).into()
+    }
+}
+
+/// Policy for requests that arrive while the handler has too many in-flight replies.
+///
+/// The default value is `OverloadPolicy::Suspend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverloadPolicy {
+    /// Stops receiving messages until some of the in-flight replies complete.
+    ///
+    /// Incoming messages are queued by the underlying socket (or TCP flow control) in the meantime.
+    #[default]
+    Suspend,
+
+    /// Replies `500 Server Error` responses to the requests without passing them to the handler.
+    Reject,
+
+    /// Drops the requests without passing them to the handler.
+    Drop,
+}
+
+/// Decoder that rejects messages larger than a certain size.
+///
+/// The size of a message is determined by its header,
+/// thus too large messages are rejected before their bodies are buffered.
+#[derive(Debug)]
+pub(super) struct LimitedMessageDecoder<A: Attribute> {
+    inner: MessageDecoder<A>,
+    max_message_size: Option<usize>,
+    consumed_bytes: usize,
+}
+impl<A: Attribute> Decode for LimitedMessageDecoder<A> {
+    type Item = <MessageDecoder<A> as Decode>::Item;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let size = track!(self.inner.decode(buf, eos))?;
+        self.consumed_bytes += size;
+        if let Some(max) = self.max_message_size {
+            let remaining_bytes = match self.inner.requiring_bytes() {
+                ByteCount::Finite(n) => n as usize,
+                _ => 0,
+            };
+            if self.consumed_bytes + remaining_bytes > max {
+                let e =
+                    bytecodec::ErrorKind::InvalidInput.cause(LimitExceeded::MaxMessageSize(max));
+                return Err(track!(e).into());
+            }
+        }
+        Ok(size)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.consumed_bytes = 0;
+        track!(self.inner.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+/// Factory of `LimitedMessageDecoder`.
+#[derive(Debug)]
+pub(super) struct LimitedMessageDecoderFactory<A> {
+    max_message_size: Option<usize>,
+    _phantom: PhantomData<fn() -> A>,
+}
+impl<A> LimitedMessageDecoderFactory<A> {
+    pub fn new(max_message_size: Option<usize>) -> Self {
+        LimitedMessageDecoderFactory {
+            max_message_size,
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<A> Default for LimitedMessageDecoderFactory<A> {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+impl<A: Attribute> Factory for LimitedMessageDecoderFactory<A> {
+    type Item = LimitedMessageDecoder<A>;
+
+    fn create(&self) -> Self::Item {
+        LimitedMessageDecoder {
+            inner: MessageDecoder::default(),
+            max_message_size: self.max_message_size,
+            consumed_bytes: 0,
+        }
+    }
+}
+
+/// Counter of the established connections of a server.
+#[derive(Debug, Clone)]
+pub(super) struct ConnectionTracker {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    state: Arc<Mutex<ConnectionCounts>>,
+}
+impl ConnectionTracker {
+    pub fn new(max_connections: Option<usize>, max_connections_per_ip: Option<usize>) -> Self {
+        ConnectionTracker {
+            max_connections,
+            max_connections_per_ip,
+            state: Arc::default(),
+        }
+    }
+
+    /// Registers a new connection from `ip`.
+    ///
+    /// The connection is deregistered when the returned guard is dropped.
+    pub fn acquire(&self, ip: IpAddr) -> Result<ConnectionGuard, LimitExceeded> {
+        let mut state = self.state.lock().expect("never fails");
+        if let Some(max) = self.max_connections {
+            if state.total >= max {
+                return Err(LimitExceeded::MaxConnections(max));
+            }
+        }
+        let per_ip = state.per_ip.get(&ip).cloned().unwrap_or(0);
+        if let Some(max) = self.max_connections_per_ip {
+            if per_ip >= max {
+                return Err(LimitExceeded::MaxConnectionsPerIp(max));
+            }
+        }
+        state.total += 1;
+        state.per_ip.insert(ip, per_ip + 1);
+        Ok(ConnectionGuard {
+            ip,
+            state: self.state.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Guard of a connection registered to `ConnectionTracker`.
+#[derive(Debug)]
+pub(super) struct ConnectionGuard {
+    ip: IpAddr,
+    state: Arc<Mutex<ConnectionCounts>>,
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("never fails");
+        state.total -= 1;
+        if let Some(n) = state.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_tracker_works() {
+        let tracker = ConnectionTracker::new(Some(3), Some(2));
+        let ip0 = "127.0.0.1".parse().unwrap();
+        let ip1 = "127.0.0.2".parse().unwrap();
+
+        let a = tracker.acquire(ip0).unwrap();
+        let _b = tracker.acquire(ip0).unwrap();
+        assert_eq!(
+            tracker.acquire(ip0).err(),
+            Some(LimitExceeded::MaxConnectionsPerIp(2))
+        );
+
+        let _c = tracker.acquire(ip1).unwrap();
+        assert_eq!(
+            tracker.acquire(ip1).err(),
+            Some(LimitExceeded::MaxConnections(3))
+        );
+
+        drop(a);
+        assert!(tracker.acquire(ip0).is_ok());
+    }
+}
@@ -0,0 +1,1264 @@
+//! Basic STUN servers.
+//!
+//! This module provides only a basic STUN servers.
+//! If you want more elaborate one, please consider create your own server using [`Channel`] directly.
+//!
+//! [`Channel`]: ../channel/struct.Channel.html
+use self::composite::ShutdownSignal;
+use self::context::next_connection_id;
+use self::dispatch::Reply;
+use self::limits::{
+    ConnectionGuard, ConnectionTracker, LimitedMessageDecoder, LimitedMessageDecoderFactory,
+};
+use crate::channel::{Channel, RecvMessage};
+use crate::clock::{Clock, SystemClock, Timeout};
+use crate::message::{
+    ErrorResponse, Indication, InvalidMessage, Request, Response, SuccessResponse,
+};
+use crate::timeout_queue::TimeoutQueue;
+use crate::trace::{self, Span};
+use crate::transport::{
+    CaptureDecoder, CaptureDecoderFactory, CaptureEncoder, CaptureTransporter, PcapCapture,
+    Rfc3489Compat, Rfc3489UdpTransporter, StdUdpTransporter, StunTcpTransporter, StunTransport,
+    StunUdpTransporter,
+};
+use crate::{Error, ErrorKind, Result};
+use bytecodec::marker::Never;
+use factory::DefaultFactory;
+use factory::Factory;
+use fibers::net::TcpListener as RawTcpListener;
+#[cfg(unix)]
+use fibers::sync::oneshot::Monitor;
+use fibers::sync::{mpsc, oneshot};
+use fibers::{BoxSpawn, Spawn};
+use fibers_transport::{
+    FixedPeerTransporter, TcpListenerBuilder, TcpTransport, TcpTransporter, UdpTransport,
+};
+use futures::future;
+use futures::{Async, Future, Poll, Stream};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use stun_codec::rfc5389;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::{
+    Attribute, DecodedMessage, Message, MessageClass, MessageDecoder, MessageEncoder, Method,
+    TransactionId,
+};
+
+/// The default TCP and UDP port for STUN.
+pub const DEFAULT_PORT: u16 = 3478;
+
+/// The default TLS port for STUN.
+pub const DEFAULT_TLS_PORT: u16 = 5349;
+
+pub use self::composite::{
+    CompositeServer, CompositeServerBuilder, CompositeServerHandle, Protocol, ServerStats,
+};
+pub use self::context::{RequestContext, TlsPeerInfo, TransportKind};
+pub use self::dispatch::Dispatch;
+pub use self::limits::{LimitExceeded, OverloadPolicy};
+pub use self::shared::{HandleSharedMessage, SharedHandler};
+
+mod composite;
+mod context;
+mod dispatch;
+mod limits;
+mod shared;
+
+type UdpTransporter<A> = fibers_transport::UdpTransporter<MessageEncoder<A>, MessageDecoder<A>>;
+
+/// UDP based STUN server.
+///
+/// The type parameter `T` is the datagram transport used by the server.
+/// Usually it is a real UDP socket, but any [`UdpTransport`] that implements [`StunTransport`]
+/// (e.g., [`MemoryTransporter`]) can be used via [`UdpServer::with_transporter`].
+///
+/// [`UdpTransport`]: https://docs.rs/fibers_transport/0.1/fibers_transport/trait.UdpTransport.html
+/// [`StunTransport`]: ../transport/trait.StunTransport.html
+/// [`MemoryTransporter`]: ../transport/struct.MemoryTransporter.html
+/// [`UdpServer::with_transporter`]: ./struct.UdpServer.html#method.with_transporter
+#[derive(Debug)]
+#[must_use = "future do nothing unless polled"]
+pub struct UdpServer<H: Dispatch, T = UdpTransporter<<H as Dispatch>::Attribute>>
+where
+    T: UdpTransport<
+            SendItem = Message<<H as Dispatch>::Attribute>,
+            RecvItem = DecodedMessage<<H as Dispatch>::Attribute>,
+        > + StunTransport<<H as Dispatch>::Attribute>,
+{
+    driver: HandlerDriver<H, StunUdpTransporter<H::Attribute, T>>,
+}
+impl<H: Dispatch> UdpServer<H> {
+    /// Starts the server.
+    pub fn start<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        handler: H,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        UdpTransporter::bind(bind_addr)
+            .map_err(|e| track!(Error::from(e)))
+            .map(move |transporter| UdpServer::with_transporter(spawner, transporter, handler))
+    }
+}
+impl<H: Dispatch> UdpServer<H, StdUdpTransporter<H::Attribute>> {
+    /// Starts the server that accepts both IPv4 and IPv6 clients on the given port.
+    ///
+    /// The server binds a single socket to `[::]:port` with `IPV6_V6ONLY` disabled
+    /// (see [`StdUdpTransporter::bind_dual_stack`]),
+    /// thus the addresses of IPv4 clients are passed to the handler as IPv4-mapped IPv6 addresses
+    /// (see also [`canonical_peer_addr`]).
+    ///
+    /// # Errors
+    ///
+    /// If the platform does not support dual-stack sockets, this will return an error.
+    ///
+    /// [`StdUdpTransporter::bind_dual_stack`]: ../transport/struct.StdUdpTransporter.html#method.bind_dual_stack
+    /// [`canonical_peer_addr`]: ./fn.canonical_peer_addr.html
+    pub fn start_dual_stack<S>(
+        spawner: S,
+        port: u16,
+        handler: H,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        StdUdpTransporter::bind_dual_stack(port)
+            .map_err(|e| track!(Error::from(e)))
+            .map(move |transporter| UdpServer::with_transporter(spawner, transporter, handler))
+    }
+}
+impl<H: Dispatch> UdpServer<H, Rfc3489UdpTransporter<H::Attribute>> {
+    /// Starts the server that also accepts classic [RFC 3489] requests.
+    ///
+    /// See [`Rfc3489Compat`] for how classic requests and their responses are handled.
+    ///
+    /// [RFC 3489]: https://tools.ietf.org/html/rfc3489
+    /// [`Rfc3489Compat`]: ../transport/struct.Rfc3489Compat.html
+    pub fn start_rfc3489_compatible<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        handler: H,
+        compat: &Rfc3489Compat,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        compat
+            .bind(bind_addr)
+            .map_err(|e| track!(Error::from(e)))
+            .map(move |transporter| UdpServer::with_transporter(spawner, transporter, handler))
+    }
+}
+impl<H, T> UdpServer<H, T>
+where
+    H: Dispatch,
+    T: UdpTransport + StunTransport<H::Attribute>,
+{
+    /// Makes a new server that uses the given transporter.
+    pub fn with_transporter<S>(spawner: S, transporter: T, handler: H) -> Self
+    where
+        S: Spawn + Send + 'static,
+    {
+        let local_addr = transporter.local_addr();
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let driver = HandlerDriver::new(
+            spawner.boxed(),
+            handler,
+            channel,
+            local_addr,
+            TransportKind::Udp,
+            true,
+        );
+        UdpServer { driver }
+    }
+
+    /// Sets the maximum number of in-flight replies (e.g., `Action::FutureReply` responses).
+    ///
+    /// What happens to the requests received while the server is at the limit depends on the [`OverloadPolicy`]
+    /// (by default, the server stops reading requests until some of the replies complete).
+    ///
+    /// [`OverloadPolicy`]: ./enum.OverloadPolicy.html
+    pub fn set_max_inflight_replies(&mut self, max: usize) {
+        self.driver.max_inflight_replies = Some(max);
+    }
+
+    /// Sets how to handle the requests received while the number of in-flight replies is at the limit.
+    pub fn set_overload_policy(&mut self, policy: OverloadPolicy)
+    where
+        H::Attribute: From<ErrorCode>,
+    {
+        self.driver.overload_policy = policy;
+        self.driver.server_error = Some(server_error);
+    }
+
+    /// Sets the duration after which in-flight replies are abandoned.
+    ///
+    /// When a handler does not complete a reply within the timeout,
+    /// the server drops the future of the reply and replies a `500 Server Error` response to the request instead.
+    pub fn set_reply_timeout(&mut self, timeout: Duration)
+    where
+        H::Attribute: From<ErrorCode>,
+    {
+        self.driver.reply_timeout = Some(timeout);
+        self.driver.server_error = Some(server_error);
+    }
+
+    /// Returns the address to which the server is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.driver
+            .channel
+            .transporter_ref()
+            .inner_ref()
+            .local_addr()
+    }
+}
+impl<H, T> Future for UdpServer<H, T>
+where
+    H: Dispatch,
+    T: UdpTransport + StunTransport<H::Attribute>,
+{
+    type Item = Never;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(()) = track!(self.driver.poll())? {
+            track_panic!(ErrorKind::Other, "STUN UDP server unexpectedly terminated");
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// UDP based STUN server that uses multiple sockets bound to the same address.
+///
+/// Each socket is created with `SO_REUSEPORT` enabled and is driven by a dedicated fiber
+/// with its own handler instance created by a [`Factory`] (like [`TcpServer`]).
+/// Thus incoming requests are distributed among the sockets by the kernel and
+/// can be handled in parallel by a multi-threaded executor.
+///
+/// The server terminates with an error if any of the sockets fails.
+///
+/// [`Factory`]: https://docs.rs/factory/0.1/factory/trait.Factory.html
+/// [`TcpServer`]: ./struct.TcpServer.html
+#[cfg(unix)]
+#[must_use = "future do nothing unless polled"]
+pub struct MultiSocketUdpServer {
+    local_addr: SocketAddr,
+    sockets: Vec<Monitor<Never, Error>>,
+}
+#[cfg(unix)]
+impl MultiSocketUdpServer {
+    /// Starts the server that uses `sockets` sockets.
+    ///
+    /// If the port of `bind_addr` is `0`, an ephemeral port assigned to the first socket is shared by all sockets.
+    ///
+    /// # Errors
+    ///
+    /// If `sockets` is `0`, this will return an `ErrorKind::InvalidInput` error.
+    pub fn start<S, H>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        sockets: usize,
+        handler_factory: H,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Clone + Send + 'static,
+        H: Factory + Send + 'static,
+        H::Item: Dispatch + Send + 'static,
+        <<H::Item as Dispatch>::Attribute as Attribute>::Decoder: Send + 'static,
+        <<H::Item as Dispatch>::Attribute as Attribute>::Encoder: Send + 'static,
+    {
+        let transporters = future::lazy(move || {
+            track_assert_ne!(sockets, 0, ErrorKind::InvalidInput);
+            Ok(())
+        })
+        .and_then(move |()| StdUdpTransporter::bind_reuse_port(bind_addr).map_err(Error::from))
+        .and_then(move |first| {
+            let local_addr = first.local_addr();
+            let rest = (1..sockets)
+                .map(|_| StdUdpTransporter::bind_reuse_port(local_addr).map_err(Error::from))
+                .collect::<Vec<_>>();
+            future::join_all(rest).map(move |mut rest| {
+                rest.insert(0, first);
+                (local_addr, rest)
+            })
+        });
+        transporters.map(move |(local_addr, transporters)| {
+            let sockets = transporters
+                .into_iter()
+                .map(|transporter| {
+                    let handler = handler_factory.create();
+                    let server = UdpServer::with_transporter(spawner.clone(), transporter, handler);
+                    spawner.spawn_monitor(server)
+                })
+                .collect();
+            MultiSocketUdpServer {
+                local_addr,
+                sockets,
+            }
+        })
+    }
+
+    /// Returns the address to which the server is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the number of the sockets used by the server.
+    pub fn socket_count(&self) -> usize {
+        self.sockets.len()
+    }
+}
+#[cfg(unix)]
+impl Future for MultiSocketUdpServer {
+    type Item = Never;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        for socket in &mut self.sockets {
+            if let Async::Ready(never) = track!(socket.poll().map_err(Error::from))? {
+                return Ok(Async::Ready(never));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+#[cfg(unix)]
+impl fmt::Debug for MultiSocketUdpServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MultiSocketUdpServer {{ local_addr: {:?}, sockets: {}, .. }}",
+            self.local_addr,
+            self.sockets.len()
+        )
+    }
+}
+
+type TcpListener<A> = fibers_transport::TcpListener<
+    DefaultFactory<CaptureEncoder<MessageEncoder<A>>>,
+    CaptureDecoderFactory<LimitedMessageDecoderFactory<A>>,
+>;
+
+type AcceptedTransporter<A> =
+    TcpTransporter<CaptureEncoder<MessageEncoder<A>>, CaptureDecoder<LimitedMessageDecoder<A>>>;
+
+/// [`TcpServer`] builder.
+///
+/// By default, the server has no limits (i.e., `TcpServerBuilder::new().start(..)` is equivalent to `TcpServer::start(..)`).
+/// Violations of the configured limits are reported to [`HandleMessage::handle_channel_error`]
+/// (see [`LimitExceeded`]).
+///
+/// [`TcpServer`]: ./struct.TcpServer.html
+/// [`HandleMessage::handle_channel_error`]: ./trait.HandleMessage.html#method.handle_channel_error
+/// [`LimitExceeded`]: ./enum.LimitExceeded.html
+#[derive(Debug, Clone)]
+pub struct TcpServerBuilder {
+    idle_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_message_size: Option<usize>,
+    max_inflight_replies: Option<usize>,
+    capture: Option<PcapCapture>,
+    clock: Arc<dyn Clock>,
+}
+impl TcpServerBuilder {
+    /// Makes a new `TcpServerBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the duration after which connections that send no messages are closed.
+    ///
+    /// Connections that have in-flight replies are never regarded as idle.
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum number of concurrent connections.
+    ///
+    /// Connections accepted beyond the limit are closed immediately.
+    pub fn max_connections(&mut self, max: usize) -> &mut Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of concurrent connections from a single IP address.
+    ///
+    /// Connections accepted beyond the limit are closed immediately.
+    pub fn max_connections_per_ip(&mut self, max: usize) -> &mut Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Sets the maximum size of a message (including its header) in bytes.
+    ///
+    /// A connection that sends a larger message is closed.
+    pub fn max_message_size(&mut self, max: usize) -> &mut Self {
+        self.max_message_size = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of in-flight `Action::FutureReply` responses per connection.
+    ///
+    /// By default, when a connection reaches the limit, the server stops reading requests from it
+    /// until some of the replies complete (see also [`TcpServer::set_overload_policy`]).
+    ///
+    /// [`TcpServer::set_overload_policy`]: ./struct.TcpServer.html#method.set_overload_policy
+    pub fn max_inflight_replies(&mut self, max: usize) -> &mut Self {
+        self.max_inflight_replies = Some(max);
+        self
+    }
+
+    /// Records the messages sent and received over the accepted connections to the given pcap file.
+    pub fn capture(&mut self, capture: PcapCapture) -> &mut Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Sets the clock used for idle timeouts of the connections.
+    ///
+    /// The default value is `SystemClock`.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Starts the server.
+    pub fn start<S, H>(
+        &self,
+        spawner: S,
+        bind_addr: SocketAddr,
+        handler_factory: H,
+    ) -> impl Future<Item = TcpServer<S, H>, Error = Error>
+    where
+        S: Spawn + Clone + Send + 'static,
+        H: Factory,
+        H::Item: Dispatch,
+    {
+        let options = self.clone();
+        self.listener_builder()
+            .listen(bind_addr)
+            .map_err(|e| track!(Error::from(e)))
+            .map(move |listener| TcpServer::new(spawner, handler_factory, listener, options))
+    }
+
+    /// Starts the server that accepts both IPv4 and IPv6 clients on the given port.
+    ///
+    /// See [`TcpServer::start_dual_stack`] for more details.
+    ///
+    /// [`TcpServer::start_dual_stack`]: ./struct.TcpServer.html#method.start_dual_stack
+    pub fn start_dual_stack<S, H>(
+        &self,
+        spawner: S,
+        port: u16,
+        handler_factory: H,
+    ) -> impl Future<Item = TcpServer<S, H>, Error = Error>
+    where
+        S: Spawn + Clone + Send + 'static,
+        H: Factory,
+        H::Item: Dispatch,
+    {
+        let options = self.clone();
+        let builder = self.listener_builder();
+        let ipv4_builder = self.listener_builder();
+        let bind_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+        RawTcpListener::bind(bind_addr)
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(|listener| {
+                // `IPV6_V6ONLY` cannot be changed after binding,
+                // thus an IPv4 listener is added instead if the platform makes the socket IPv6-only
+                let only_v6 = track!(listener.with_inner(|l| l.only_v6()).map_err(Error::from))?;
+                let listener = track!(builder.finish(listener).map_err(Error::from))?;
+                Ok((listener, only_v6))
+            })
+            .and_then(move |(listener, only_v6)| {
+                if only_v6 {
+                    let port = listener.local_addr().port();
+                    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+                    future::Either::A(
+                        ipv4_builder
+                            .listen(bind_addr)
+                            .map_err(|e| track!(Error::from(e)))
+                            .map(move |ipv4_listener| (listener, Some(ipv4_listener))),
+                    )
+                } else {
+                    future::Either::B(future::ok((listener, None)))
+                }
+            })
+            .map(move |(listener, ipv4_listener)| {
+                let mut server = TcpServer::new(spawner, handler_factory, listener, options);
+                server.ipv4_listener = ipv4_listener;
+                server
+            })
+    }
+
+    fn listener_builder<A: Attribute>(
+        &self,
+    ) -> TcpListenerBuilder<
+        DefaultFactory<CaptureEncoder<MessageEncoder<A>>>,
+        CaptureDecoderFactory<LimitedMessageDecoderFactory<A>>,
+    > {
+        // The codecs record bytes only if the connections are wrapped by `CaptureTransporter`
+        TcpListenerBuilder::with_codec(
+            DefaultFactory::new(),
+            CaptureDecoderFactory(LimitedMessageDecoderFactory::new(self.max_message_size)),
+        )
+    }
+}
+
+impl Default for TcpServerBuilder {
+    fn default() -> Self {
+        TcpServerBuilder {
+            idle_timeout: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_message_size: None,
+            max_inflight_replies: None,
+            capture: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// TCP based STUN server.
+///
+/// Use [`TcpServerBuilder`] for limiting connections.
+///
+/// [`TcpServerBuilder`]: ./struct.TcpServerBuilder.html
+#[must_use = "future do nothing unless polled"]
+pub struct TcpServer<S, H>
+where
+    H: Factory,
+    H::Item: Dispatch,
+{
+    spawner: S,
+    handler_factory: H,
+    listener: TcpListener<<H::Item as Dispatch>::Attribute>,
+    ipv4_listener: Option<TcpListener<<H::Item as Dispatch>::Attribute>>,
+    options: TcpServerBuilder,
+    connections: ConnectionTracker,
+    shutdown: Option<ShutdownSignal>,
+    overload_policy: OverloadPolicy,
+    reply_timeout: Option<Duration>,
+    server_error: Option<ServerErrorFn<<H::Item as Dispatch>::Attribute>>,
+}
+impl<S, H> TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: Dispatch,
+{
+    /// Starts the server.
+    ///
+    /// This is equivalent to `TcpServerBuilder::new().start(spawner, bind_addr, handler_factory)`.
+    pub fn start(
+        spawner: S,
+        bind_addr: SocketAddr,
+        handler_factory: H,
+    ) -> impl Future<Item = Self, Error = Error> {
+        TcpServerBuilder::new().start(spawner, bind_addr, handler_factory)
+    }
+
+    /// Starts the server that accepts both IPv4 and IPv6 clients on the given port.
+    ///
+    /// The server listens on `[::]:port`, and
+    /// the addresses of IPv4 clients are passed to handlers as IPv4-mapped IPv6 addresses
+    /// (see also [`canonical_peer_addr`]).
+    ///
+    /// If the platform makes IPv6 sockets IPv6-only (e.g., `net.ipv6.bindv6only=1` on Linux),
+    /// the server additionally listens on `0.0.0.0:port` for IPv4 clients.
+    ///
+    /// [`canonical_peer_addr`]: ./fn.canonical_peer_addr.html
+    pub fn start_dual_stack(
+        spawner: S,
+        port: u16,
+        handler_factory: H,
+    ) -> impl Future<Item = Self, Error = Error> {
+        TcpServerBuilder::new().start_dual_stack(spawner, port, handler_factory)
+    }
+
+    /// Returns the address to which the server is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr()
+    }
+
+    /// Sets how to handle the requests received over a connection
+    /// while the number of its in-flight replies is at the limit
+    /// (see [`TcpServerBuilder::max_inflight_replies`]).
+    ///
+    /// This only affects the connections accepted after the call.
+    ///
+    /// [`TcpServerBuilder::max_inflight_replies`]: ./struct.TcpServerBuilder.html#method.max_inflight_replies
+    pub fn set_overload_policy(&mut self, policy: OverloadPolicy)
+    where
+        <H::Item as Dispatch>::Attribute: From<ErrorCode>,
+    {
+        self.overload_policy = policy;
+        self.server_error = Some(server_error);
+    }
+
+    /// Sets the duration after which in-flight replies are abandoned.
+    ///
+    /// When a handler does not complete a reply within the timeout,
+    /// the server drops the future of the reply and replies a `500 Server Error` response to the request instead.
+    ///
+    /// This only affects the connections accepted after the call.
+    pub fn set_reply_timeout(&mut self, timeout: Duration)
+    where
+        <H::Item as Dispatch>::Attribute: From<ErrorCode>,
+    {
+        self.reply_timeout = Some(timeout);
+        self.server_error = Some(server_error);
+    }
+
+    fn new(
+        spawner: S,
+        handler_factory: H,
+        listener: TcpListener<<H::Item as Dispatch>::Attribute>,
+        options: TcpServerBuilder,
+    ) -> Self {
+        let connections =
+            ConnectionTracker::new(options.max_connections, options.max_connections_per_ip);
+        TcpServer {
+            spawner,
+            handler_factory,
+            listener,
+            ipv4_listener: None,
+            options,
+            connections,
+            shutdown: None,
+            overload_policy: OverloadPolicy::default(),
+            reply_timeout: None,
+            server_error: None,
+        }
+    }
+}
+impl<S, H> Future for TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: Dispatch + Send + 'static,
+    <<H::Item as Dispatch>::Attribute as Attribute>::Decoder: Send + 'static,
+    <<H::Item as Dispatch>::Attribute as Attribute>::Encoder: Send + 'static,
+{
+    type Item = Never;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(transporter) = track!(self.listener.poll())? {
+            track!(self.handle_accepted(transporter))?;
+        }
+        while let Some(listener) = self.ipv4_listener.as_mut() {
+            if let Async::Ready(transporter) = track!(listener.poll())? {
+                track!(self.handle_accepted(transporter))?;
+            } else {
+                break;
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<S, H> TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: Dispatch + Send + 'static,
+    <<H::Item as Dispatch>::Attribute as Attribute>::Decoder: Send + 'static,
+    <<H::Item as Dispatch>::Attribute as Attribute>::Encoder: Send + 'static,
+{
+    fn handle_accepted(
+        &mut self,
+        transporter: Option<AcceptedTransporter<<H::Item as Dispatch>::Attribute>>,
+    ) -> Result<()> {
+        let transporter = if let Some(transporter) = transporter {
+            transporter
+        } else {
+            track_panic!(ErrorKind::Other, "STUN TCP server unexpectedly terminated");
+        };
+        let peer_addr = transporter.peer_addr();
+        let mut handler = self.handler_factory.create();
+        let guard = match self
+            .connections
+            .acquire(canonical_peer_addr(peer_addr).ip())
+        {
+            Err(limit) => {
+                handler.dispatch_channel_error(&track!(Error::from(limit)));
+                return Ok(());
+            }
+            Ok(guard) => guard,
+        };
+
+        if let Some(capture) = self.options.capture.clone() {
+            let transporter = CaptureTransporter::tcp(transporter, capture);
+            self.spawn_driver(handler, peer_addr, transporter, guard);
+        } else {
+            self.spawn_driver(handler, peer_addr, transporter, guard);
+        }
+        Ok(())
+    }
+}
+impl<S, H> TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: Dispatch + Send + 'static,
+{
+    fn spawn_driver<T>(
+        &self,
+        handler: H::Item,
+        peer_addr: SocketAddr,
+        transporter: T,
+        guard: ConnectionGuard,
+    ) where
+        T: TcpTransport<
+                SendItem = Message<<H::Item as Dispatch>::Attribute>,
+                RecvItem = DecodedMessage<<H::Item as Dispatch>::Attribute>,
+            > + Send
+            + 'static,
+    {
+        let local_addr = transporter.local_addr();
+        let transporter =
+            FixedPeerTransporter::new(peer_addr, (), StunTcpTransporter::new(transporter));
+        let channel = Channel::new(transporter);
+        let mut driver = HandlerDriver::new(
+            self.spawner.clone().boxed(),
+            handler,
+            channel,
+            local_addr,
+            TransportKind::Tcp,
+            false,
+        );
+        driver.connection_id = Some(next_connection_id());
+        driver.idle_timeout = self.options.idle_timeout;
+        driver.clock = Arc::clone(&self.options.clock);
+        driver.max_inflight_replies = self.options.max_inflight_replies;
+        driver.overload_policy = self.overload_policy;
+        driver.reply_timeout = self.reply_timeout;
+        driver.server_error = self.server_error;
+        let future = driver.then(move |result| {
+            drop(guard);
+            result
+        });
+        if let Some(shutdown) = &self.shutdown {
+            self.spawner.spawn(shutdown.wrap(future).map_err(|_| ()));
+        } else {
+            self.spawner.spawn(future.map_err(|_| ()));
+        }
+    }
+}
+impl<S, H> fmt::Debug for TcpServer<S, H>
+where
+    H: Factory,
+    H::Item: Dispatch,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TcpServer {{ .. }}")
+    }
+}
+
+/// Action instructed by an operation of a message handler.
+pub enum Action<T> {
+    /// Replies an response to the client immediately.
+    Reply(T),
+
+    /// Replies an response to the client in the future.
+    FutureReply(Box<dyn Future<Item = T, Error = Never> + Send + 'static>),
+
+    /// Does not reply to the client.
+    NoReply,
+
+    /// Does not reply to the client, but does something for handling the incoming message.
+    FutureNoReply(Box<dyn Future<Item = (), Error = Never> + Send + 'static>),
+}
+impl<T: fmt::Debug> fmt::Debug for Action<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Reply(t) => write!(f, "Reply({t:?})"),
+            Action::FutureReply(_) => write!(f, "FutureReply(_)"),
+            Action::NoReply => write!(f, "NoReply"),
+            Action::FutureNoReply(_) => write!(f, "FutureNoReply(_)"),
+        }
+    }
+}
+
+/// This trait allows for handling messages sent by clients.
+///
+/// Each incoming message is passed together with its [`RequestContext`]
+/// (e.g., the address of the peer and the local address at which the message has been received).
+///
+/// Note that `Action::FutureReply` requires boxing and spawning a future for each reply.
+/// If the handler replies asynchronously to most requests or its state should be shared by the whole server,
+/// consider using [`HandleSharedMessage`] instead.
+///
+/// [`RequestContext`]: ./struct.RequestContext.html
+/// [`HandleSharedMessage`]: ./trait.HandleSharedMessage.html
+#[allow(unused_variables)]
+pub trait HandleMessage {
+    /// The attributes that the handler can recognize.
+    type Attribute: Attribute + Send + 'static;
+
+    /// Handles a request message.
+    ///
+    /// The default implementation always returns `Action::NoReply`.
+    fn handle_call(
+        &mut self,
+        context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Action<Response<Self::Attribute>> {
+        Action::NoReply
+    }
+
+    /// Handles an indication message.
+    ///
+    /// The default implementation always returns `Action::NoReply`.
+    fn handle_cast(
+        &mut self,
+        context: &RequestContext,
+        indication: Indication<Self::Attribute>,
+    ) -> Action<Never> {
+        Action::NoReply
+    }
+
+    /// Handles an invalid incoming message.
+    ///
+    /// Note that this method should not return `Action::Reply(_)` or `Action::FutureReply(_)`
+    /// if the class of `message` is not `MessageClass::Request`.
+    ///
+    /// The default implementation always returns `Action::NoReply`.
+    fn handle_invalid_message(
+        &mut self,
+        context: &RequestContext,
+        message: InvalidMessage,
+    ) -> Action<Response<Self::Attribute>> {
+        Action::NoReply
+    }
+
+    /// Handles an error before the channel drops by the error.
+    ///
+    /// The default implementation does nothing.
+    fn handle_channel_error(&mut self, error: &Error) {}
+}
+
+/// Function that makes a `500 Server Error` response to the request that has the given method and transaction ID.
+type ServerErrorFn<A> = fn(Method, TransactionId) -> Response<A>;
+
+fn server_error<A>(method: Method, transaction_id: TransactionId) -> Response<A>
+where
+    A: Attribute + From<ErrorCode>,
+{
+    let request = Message::new(MessageClass::Request, method, transaction_id);
+    let request = Request::from_message(request).expect("never fails");
+    Err(ErrorResponse::new(
+        &request,
+        rfc5389::errors::ServerError.into(),
+    ))
+}
+
+#[derive(Debug)]
+struct HandlerDriver<H, T>
+where
+    H: Dispatch,
+    T: StunTransport<H::Attribute, PeerAddr = SocketAddr>,
+{
+    spawner: BoxSpawn,
+    handler: H,
+    channel: Channel<H::Attribute, T>,
+    response_tx: mpsc::Sender<(u64, Response<H::Attribute>)>,
+    response_rx: mpsc::Receiver<(u64, Response<H::Attribute>)>,
+    local_addr: SocketAddr,
+    transport: TransportKind,
+    connection_id: Option<u64>,
+    recoverable_channel: bool,
+    idle_timeout: Option<Duration>,
+    idle_timer: Option<Timeout>,
+    clock: Arc<dyn Clock>,
+    pending_replies: PendingReplies<H>,
+    inflight_replies: HashMap<u64, InflightReply>,
+    next_reply_id: u64,
+    max_inflight_replies: Option<usize>,
+    overload_policy: OverloadPolicy,
+    overloaded: bool,
+    reply_timeout: Option<Duration>,
+    reply_timeouts: TimeoutQueue<u64>,
+    server_error: Option<ServerErrorFn<H::Attribute>>,
+}
+impl<H, T> HandlerDriver<H, T>
+where
+    H: Dispatch,
+    T: StunTransport<H::Attribute, PeerAddr = SocketAddr>,
+{
+    fn new(
+        spawner: BoxSpawn,
+        handler: H,
+        channel: Channel<H::Attribute, T>,
+        local_addr: SocketAddr,
+        transport: TransportKind,
+        recoverable_channel: bool,
+    ) -> Self {
+        let (response_tx, response_rx) = mpsc::channel();
+        HandlerDriver {
+            spawner,
+            handler,
+            channel,
+            response_tx,
+            response_rx,
+            local_addr,
+            transport,
+            connection_id: None,
+            recoverable_channel,
+            idle_timeout: None,
+            idle_timer: None,
+            clock: Arc::new(SystemClock),
+            pending_replies: PendingReplies(Vec::new()),
+            inflight_replies: HashMap::new(),
+            next_reply_id: 0,
+            max_inflight_replies: None,
+            overload_policy: OverloadPolicy::default(),
+            overloaded: false,
+            reply_timeout: None,
+            reply_timeouts: TimeoutQueue::new(),
+            server_error: None,
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        peer: SocketAddr,
+        message: RecvMessage<H::Attribute>,
+        received_at: SystemTime,
+    ) -> Result<()> {
+        let (method, transaction_id, is_request) = match &message {
+            RecvMessage::Indication(m) => (m.method(), m.transaction_id(), false),
+            RecvMessage::Request(m) => (m.method(), m.transaction_id(), true),
+            RecvMessage::Invalid(m) => (
+                m.method(),
+                m.transaction_id(),
+                m.class() == MessageClass::Request,
+            ),
+        };
+        let span = trace::transaction_span(&peer, method, transaction_id);
+        if is_request && self.is_overloaded() {
+            if let (OverloadPolicy::Reject, Some(f)) = (self.overload_policy, self.server_error) {
+                trace_event!(debug, parent: span, "Rejecting request: too many in-flight replies");
+                span.in_scope(|| track!(self.channel.reply(peer, f(method, transaction_id))))?;
+            } else {
+                trace_event!(debug, parent: span, "Dropping request: too many in-flight replies");
+            }
+            return Ok(());
+        }
+
+        let mut context = RequestContext::new(peer, self.local_addr, self.transport);
+        context.set_received_at(received_at);
+        if let Some(id) = self.connection_id {
+            context.set_connection_id(id);
+        }
+        let reply = span.in_scope(|| {
+            trace_event!(debug, "Dispatching message to handler");
+            match message {
+                RecvMessage::Indication(m) => self.handler.dispatch_cast(&context, m),
+                RecvMessage::Request(m) => self.handler.dispatch_call(&context, m),
+                RecvMessage::Invalid(m) => self.handler.dispatch_invalid_message(&context, m),
+            }
+        });
+        let inflight = InflightReply {
+            peer,
+            method,
+            transaction_id,
+            span: span.clone(),
+            cancel: None,
+        };
+        span.in_scope(|| track!(self.handle_reply(inflight, reply)))
+    }
+
+    fn handle_reply(
+        &mut self,
+        inflight: InflightReply,
+        reply: Reply<H::Attribute, H::Pending>,
+    ) -> Result<()> {
+        match reply {
+            Reply::None => {}
+            Reply::Now(m) => track!(self.channel.reply(inflight.peer, m))?,
+            Reply::Spawn(future) => {
+                // The future is dropped when the reply is abandoned (or the driver terminates)
+                let (cancel_tx, cancel_rx) = oneshot::channel();
+                let inflight = InflightReply {
+                    cancel: Some(cancel_tx),
+                    ..inflight
+                };
+                let id = self.start_inflight_reply(inflight);
+                let tx = self.response_tx.clone();
+                self.spawner.spawn(
+                    future
+                        .map(move |response| {
+                            let _ = tx.send((id, response));
+                        })
+                        .select(cancel_rx.then(|_| Ok(())))
+                        .map(|_| ())
+                        .map_err(|_| unreachable!()),
+                );
+            }
+            Reply::Detached(future) => self.spawner.spawn(future.map_err(|_| unreachable!())),
+            Reply::Pending(mut future) => {
+                if let Async::Ready(m) = future.poll().expect("never fails") {
+                    if let Some(m) = m {
+                        track!(self.channel.reply(inflight.peer, m))?;
+                    }
+                } else {
+                    let id = self.start_inflight_reply(inflight);
+                    self.pending_replies.0.push((id, future));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn start_inflight_reply(&mut self, inflight: InflightReply) -> u64 {
+        let id = self.next_reply_id;
+        self.next_reply_id += 1;
+        self.inflight_replies.insert(id, inflight);
+        if let Some(timeout) = self.reply_timeout {
+            self.reply_timeouts.push(id, timeout);
+        }
+        id
+    }
+
+    fn finish_inflight_reply(
+        &mut self,
+        id: u64,
+        response: Option<Response<H::Attribute>>,
+    ) -> Result<()> {
+        // The reply may have already timed out
+        if let Some(inflight) = self.inflight_replies.remove(&id) {
+            self.idle_timer = None;
+            if let Some(m) = response {
+                let channel = &mut self.channel;
+                inflight
+                    .span
+                    .in_scope(|| track!(channel.reply(inflight.peer, m)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_pending_replies(&mut self) -> Result<bool> {
+        let mut did_something = false;
+        let mut i = 0;
+        while i < self.pending_replies.0.len() {
+            if let Async::Ready(m) = self.pending_replies.0[i].1.poll().expect("never fails") {
+                let (id, _) = self.pending_replies.0.swap_remove(i);
+                track!(self.finish_inflight_reply(id, m))?;
+                did_something = true;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(did_something)
+    }
+
+    fn poll_reply_timeouts(&mut self) -> Result<bool> {
+        let mut did_something = false;
+        loop {
+            let inflight_replies = &self.inflight_replies;
+            let id = match self
+                .reply_timeouts
+                .filter_pop(|id| inflight_replies.contains_key(id))
+            {
+                None => break,
+                Some(id) => id,
+            };
+            let inflight = self.inflight_replies.remove(&id).expect("never fails");
+            self.pending_replies.0.retain(|x| x.0 != id);
+            if let Some(cancel) = inflight.cancel {
+                let _ = cancel.send(());
+            }
+            inflight.span.in_scope(|| {
+                if let Some(timeout) = self.reply_timeout {
+                    let e = track!(Error::from(LimitExceeded::ReplyTimeout(timeout)));
+                    self.handle_channel_error(&e);
+                }
+                if let Some(f) = self.server_error {
+                    let response = f(inflight.method, inflight.transaction_id);
+                    track!(self.channel.reply(inflight.peer, response))?;
+                }
+                Ok::<_, Error>(())
+            })?;
+            did_something = true;
+        }
+        Ok(did_something)
+    }
+
+    fn is_overloaded(&mut self) -> bool {
+        let max = match self.max_inflight_replies {
+            Some(max) if self.inflight_replies.len() >= max => max,
+            _ => {
+                self.overloaded = false;
+                return false;
+            }
+        };
+        if !self.overloaded {
+            self.overloaded = true;
+            let e = track!(Error::from(LimitExceeded::MaxInflightReplies(max)));
+            self.handle_channel_error(&e);
+        }
+        true
+    }
+
+    fn handle_channel_error(&mut self, error: &Error) {
+        trace_event!(warn, error = %error, "Channel error");
+        self.handler.dispatch_channel_error(error);
+    }
+
+    fn is_recv_suspended(&mut self) -> bool {
+        self.overload_policy == OverloadPolicy::Suspend && self.is_overloaded()
+    }
+
+    fn poll_idle_timeout(&mut self) -> Result<()> {
+        while let Some(timeout) = self.idle_timeout {
+            let clock = &self.clock;
+            let timer = self
+                .idle_timer
+                .get_or_insert_with(|| clock.timeout(timeout));
+            if let Ok(Async::NotReady) = timer.poll() {
+                break;
+            }
+            self.idle_timer = None;
+            if self.inflight_replies.is_empty() {
+                let e = track!(Error::from(LimitExceeded::IdleTimeout(timeout)));
+                self.handle_channel_error(&e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reply that the handler has not completed yet.
+#[derive(Debug)]
+struct InflightReply {
+    peer: SocketAddr,
+    method: Method,
+    transaction_id: TransactionId,
+    span: Span,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// Replies polled by `HandlerDriver` directly.
+struct PendingReplies<H: Dispatch>(Vec<(u64, H::Pending)>);
+impl<H: Dispatch> fmt::Debug for PendingReplies<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PendingReplies {{ len: {} }}", self.0.len())
+    }
+}
+
+impl<H, T> Future for HandlerDriver<H, T>
+where
+    H: Dispatch,
+    T: StunTransport<H::Attribute, PeerAddr = SocketAddr>,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut did_something = true;
+        while did_something {
+            did_something = false;
+
+            let result = if self.is_recv_suspended() {
+                Ok(Async::NotReady)
+            } else {
+                track!(self.channel.poll_recv())
+            };
+            match result {
+                Err(e) => {
+                    self.handle_channel_error(&e);
+                    if !self.recoverable_channel {
+                        return Err(e);
+                    }
+                    did_something = true;
+                }
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::Ready(Some((peer, message)))) => {
+                    let received_at = self.clock.now();
+                    self.idle_timer = None;
+                    track!(self.handle_message(peer, message, received_at))?;
+                    did_something = true;
+                }
+            }
+            if let Err(e) = track!(self.channel.poll_send()) {
+                self.handle_channel_error(&e);
+                return Err(e);
+            }
+            if let Async::Ready(item) = self.response_rx.poll().expect("never fails") {
+                let (id, response) = item.expect("never fails");
+                track!(self.finish_inflight_reply(id, Some(response)))?;
+                did_something = true;
+            }
+            if track!(self.poll_pending_replies())? {
+                did_something = true;
+            }
+            if track!(self.poll_reply_timeouts())? {
+                did_something = true;
+            }
+        }
+        track!(self.poll_idle_timeout())?;
+        Ok(Async::NotReady)
+    }
+}
+
+/// Example `BINDING` request handler.
+///
+/// Note that this is provided only for test and example purposes.
+///
+/// Channel errors are printed to the standard error
+/// (or emitted as `tracing` events if the `tracing` feature is enabled).
+#[derive(Debug, Default, Clone)]
+pub struct BindingHandler;
+impl HandleMessage for BindingHandler {
+    type Attribute = rfc5389::Attribute;
+
+    fn handle_call(
+        &mut self,
+        context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Action<Response<Self::Attribute>> {
+        if request.method() == rfc5389::methods::BINDING {
+            let mut response = SuccessResponse::new(&request);
+            let mapped = canonical_peer_addr(context.peer());
+            response.add_attribute(rfc5389::attributes::XorMappedAddress::new(mapped).into());
+            Action::Reply(Ok(response))
+        } else {
+            let response = ErrorResponse::new(&request, rfc5389::errors::BadRequest.into());
+            Action::Reply(Err(response))
+        }
+    }
+
+    fn handle_channel_error(&mut self, error: &Error) {
+        #[cfg(feature = "tracing")]
+        tracing::error!(error = %error, "BINDING handler error");
+        #[cfg(not(feature = "tracing"))]
+        eprintln!("[ERROR] {error}");
+    }
+}
+
+/// Converts an IPv4-mapped IPv6 address (e.g., `[::ffff:192.0.2.1]:3478`) to the corresponding IPv4 address.
+///
+/// Dual-stack servers observe IPv4 clients through such addresses,
+/// but the addresses reported to the clients (e.g., `XOR-MAPPED-ADDRESS`) should be plain IPv4 ones.
+/// Other addresses are returned as is.
+pub fn canonical_peer_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(a) => match a.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), a.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
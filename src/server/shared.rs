@@ -0,0 +1,178 @@
+use super::dispatch::{Dispatch, Reply};
+use super::RequestContext;
+use crate::message::{Indication, InvalidMessage, Request, Response};
+use crate::Error;
+use bytecodec::marker::Never;
+use futures::Future;
+use std::sync::Arc;
+use stun_codec::Attribute;
+
+/// This trait allows for handling messages sent by clients with a handler shared by the whole server.
+///
+/// Unlike [`HandleMessage`], the methods take `&self`, thus a single handler (and its state)
+/// can be shared among the listeners, TCP connections and threads of servers via [`SharedHandler`].
+/// Mutable state should be kept in synchronized containers (e.g., `Mutex` or atomics).
+///
+/// Replies are returned as futures of the associated type `Reply`.
+/// They are polled by the server directly, thus no heap allocation nor spawning is required per request.
+/// Immediate replies can be returned as `futures::future::FutureResult`
+/// (e.g., `futures::future::ok(Some(response))`).
+///
+/// [`HandleMessage`]: ./trait.HandleMessage.html
+/// [`SharedHandler`]: ./struct.SharedHandler.html
+#[allow(unused_variables)]
+pub trait HandleSharedMessage: Send + Sync + 'static {
+    /// The attributes that the handler can recognize.
+    type Attribute: Attribute + Send + 'static;
+
+    /// Future of the reply to a request.
+    ///
+    /// If it results in `None`, nothing is replied to the client.
+    type Reply: Future<Item = Option<Response<Self::Attribute>>, Error = Never> + Send + 'static;
+
+    /// Handles a request message.
+    ///
+    /// Note that the returned future is polled by the server that received the request,
+    /// thus it should not block.
+    fn handle_call(
+        &self,
+        context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Self::Reply;
+
+    /// Handles an indication message.
+    ///
+    /// The default implementation does nothing.
+    fn handle_cast(&self, context: &RequestContext, indication: Indication<Self::Attribute>) {}
+
+    /// Handles an invalid incoming message.
+    ///
+    /// Note that this method should not return `Some(_)`
+    /// if the class of `message` is not `MessageClass::Request`.
+    ///
+    /// The default implementation always returns `None`.
+    fn handle_invalid_message(
+        &self,
+        context: &RequestContext,
+        message: InvalidMessage,
+    ) -> Option<Response<Self::Attribute>> {
+        None
+    }
+
+    /// Handles an error before the channel drops by the error.
+    ///
+    /// The default implementation does nothing.
+    fn handle_channel_error(&self, error: &Error) {}
+}
+
+/// [`HandleSharedMessage`] implementation that can be passed to servers.
+///
+/// Clones of a `SharedHandler` refer to the same handler,
+/// thus [`TcpServer`] can share it among connections by using [`CloneFactory`].
+///
+/// # Examples
+///
+/// ```
+/// # extern crate bytecodec;
+/// # extern crate factory;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use factory::CloneFactory;
+/// use futures::future::{self, FutureResult};
+/// use rustun::message::{Request, Response, SuccessResponse};
+/// use rustun::server::{HandleSharedMessage, RequestContext, SharedHandler};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use stun_codec::rfc5389;
+///
+/// #[derive(Debug, Default)]
+/// struct CountingHandler {
+///     count: AtomicUsize,
+/// }
+/// impl HandleSharedMessage for CountingHandler {
+///     type Attribute = rfc5389::Attribute;
+///     type Reply = FutureResult<Option<Response<Self::Attribute>>, bytecodec::marker::Never>;
+///
+///     fn handle_call(
+///         &self,
+///         _context: &RequestContext,
+///         request: Request<Self::Attribute>,
+///     ) -> Self::Reply {
+///         self.count.fetch_add(1, Ordering::Relaxed);
+///         future::ok(Some(Ok(SuccessResponse::new(&request))))
+///     }
+/// }
+///
+/// let handler = SharedHandler::new(CountingHandler::default());
+/// let factory = CloneFactory::new(handler.clone());
+/// # let _ = factory;
+/// assert_eq!(handler.get_ref().count.load(Ordering::Relaxed), 0);
+/// ```
+///
+/// [`HandleSharedMessage`]: ./trait.HandleSharedMessage.html
+/// [`TcpServer`]: ./struct.TcpServer.html
+/// [`CloneFactory`]: https://docs.rs/factory/0.1/factory/struct.CloneFactory.html
+#[derive(Debug)]
+pub struct SharedHandler<H>(Arc<H>);
+impl<H: HandleSharedMessage> SharedHandler<H> {
+    /// Makes a new `SharedHandler` instance.
+    pub fn new(handler: H) -> Self {
+        SharedHandler(Arc::new(handler))
+    }
+
+    /// Makes a new `SharedHandler` instance from an already shared handler.
+    pub fn from_arc(handler: Arc<H>) -> Self {
+        SharedHandler(handler)
+    }
+
+    /// Returns a reference to the handler.
+    pub fn get_ref(&self) -> &H {
+        &self.0
+    }
+
+    /// Returns the shared handler.
+    pub fn as_arc(&self) -> &Arc<H> {
+        &self.0
+    }
+}
+impl<H> Clone for SharedHandler<H> {
+    fn clone(&self) -> Self {
+        SharedHandler(Arc::clone(&self.0))
+    }
+}
+impl<H: HandleSharedMessage> Dispatch for SharedHandler<H> {
+    type Attribute = H::Attribute;
+    type Pending = H::Reply;
+
+    fn dispatch_call(
+        &mut self,
+        context: &RequestContext,
+        request: Request<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        Reply::Pending(self.0.handle_call(context, request))
+    }
+
+    fn dispatch_cast(
+        &mut self,
+        context: &RequestContext,
+        indication: Indication<Self::Attribute>,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        self.0.handle_cast(context, indication);
+        Reply::None
+    }
+
+    fn dispatch_invalid_message(
+        &mut self,
+        context: &RequestContext,
+        message: InvalidMessage,
+    ) -> Reply<Self::Attribute, Self::Pending> {
+        match self.0.handle_invalid_message(context, message) {
+            None => Reply::None,
+            Some(response) => Reply::Now(response),
+        }
+    }
+
+    fn dispatch_channel_error(&mut self, error: &Error) {
+        self.0.handle_channel_error(error);
+    }
+}
@@ -0,0 +1,135 @@
+//! Timeout queue used by channels, transporters and services in this crate.
+//!
+//! This is almost the same as [`fibers_timeout_queue::TimeoutQueue`],
+//! but is driven by a [`Clock`] and always polls its timer right after (re)arming it.
+//! Otherwise the wakeup of the current fiber would not be registered and
+//! the next timeout could be missed if nothing else wakes the fiber up.
+//!
+//! [`Clock`]: ../clock/trait.Clock.html
+//! [`fibers_timeout_queue::TimeoutQueue`]: https://docs.rs/fibers_timeout_queue/0.1/fibers_timeout_queue/struct.TimeoutQueue.html
+use crate::clock::{Clock, SystemClock, Timeout};
+use futures::Future;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub(crate) struct TimeoutQueue<T> {
+    clock: Arc<dyn Clock>,
+    queue: BinaryHeap<Reverse<Item<T>>>,
+    next_timeout: Option<(SystemTime, Timeout)>,
+    next_seqno: u64,
+}
+impl<T> TimeoutQueue<T> {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        TimeoutQueue {
+            clock,
+            queue: BinaryHeap::new(),
+            next_timeout: None,
+            next_seqno: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T, timeout: Duration) {
+        let expiry_time = self.clock.now() + timeout;
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+        self.queue.push(Reverse(Item {
+            expiry_time,
+            seqno,
+            item,
+        }));
+        while self.poll_timer() {}
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.filter_pop(|_| true)
+    }
+
+    /// Dequeues an expired item.
+    ///
+    /// Items located in the queue's prefix for which `filter(item)` returns `false` are discarded.
+    pub fn filter_pop<F>(&mut self, filter: F) -> Option<T>
+    where
+        F: Fn(&T) -> bool,
+    {
+        loop {
+            let now = self.clock.now();
+            while let Some(x) = self.queue.peek() {
+                if !filter(&x.0.item) {
+                    self.queue.pop();
+                    continue;
+                }
+                if x.0.expiry_time > now {
+                    break;
+                }
+                return self.queue.pop().map(|x| x.0.item);
+            }
+            if !self.poll_timer() {
+                return None;
+            }
+        }
+    }
+
+    /// Returns `true` if the timer for the earliest item has fired.
+    fn poll_timer(&mut self) -> bool {
+        let expiry_time = if let Some(x) = self.queue.peek() {
+            x.0.expiry_time
+        } else {
+            self.next_timeout = None;
+            return false;
+        };
+        if self.next_timeout.as_ref().map(|t| t.0) != Some(expiry_time) {
+            let delay = expiry_time
+                .duration_since(self.clock.now())
+                .unwrap_or_default()
+                .max(Duration::from_millis(1));
+            self.next_timeout = Some((expiry_time, self.clock.timeout(delay)));
+        }
+
+        let timeout = &mut self.next_timeout.as_mut().expect("never fails").1;
+        if timeout.poll().map_or(true, |x| x.is_ready()) {
+            self.next_timeout = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+impl<T> Default for TimeoutQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> fmt::Debug for TimeoutQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TimeoutQueue {{ len: {}, .. }}", self.queue.len())
+    }
+}
+
+struct Item<T> {
+    expiry_time: SystemTime,
+    seqno: u64,
+    item: T,
+}
+impl<T> PartialOrd for Item<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<T> Ord for Item<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.expiry_time, self.seqno).cmp(&(other.expiry_time, other.seqno))
+    }
+}
+impl<T> PartialEq for Item<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.seqno == other.seqno
+    }
+}
+impl<T> Eq for Item<T> {}
@@ -0,0 +1,204 @@
+//! Instrumentation via the [`tracing`] crate.
+//!
+//! If the `tracing` feature is disabled, spans and events defined in this module compile to nothing.
+//!
+//! [`tracing`]: https://docs.rs/tracing/0.1
+use std::fmt::Debug;
+use stun_codec::{Method, TransactionId};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// No-op substitute for `tracing::Span`.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn current() -> Self {
+        Span
+    }
+
+    pub fn in_scope<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        f()
+    }
+}
+
+/// Emits an event at the given level (e.g., `debug`) if the `tracing` feature is enabled.
+///
+/// If the event belongs to a transaction, its span can be specified as `parent: span`.
+macro_rules! trace_event {
+    ($level:ident, parent: $span:expr, $($arg:tt)+) => {{
+        let _ = &$span;
+        #[cfg(feature = "tracing")]
+        tracing::$level!(parent: &$span, $($arg)+);
+    }};
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+    }};
+}
+
+/// Makes the span of the transaction identified by the given peer and transaction ID.
+#[allow(unused_variables)]
+pub(crate) fn transaction_span<P: Debug>(
+    peer: &P,
+    method: Method,
+    transaction_id: TransactionId,
+) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::debug_span!(
+            "stun_transaction",
+            peer = ?peer,
+            method = method.as_u16(),
+            transaction_id = ?transaction_id
+        )
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use crate::channel::ChannelBuilder;
+    use crate::clock::ManualClock;
+    use crate::message::Request;
+    use crate::transport::{SimulatedNetwork, StunUdpTransporterBuilder};
+    use crate::Result;
+    use futures::future;
+    use std::fmt::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use stun_codec::rfc5389;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+    use tracing_core::span::Current;
+    use trackable::error::MainError;
+
+    #[derive(Default)]
+    struct Fields(String);
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            let _ = write!(self.0, "{}={:?} ", field.name(), value);
+        }
+    }
+
+    /// Subscriber that records events as `(message, fields of the parent span)`.
+    #[derive(Default)]
+    struct Recorder {
+        spans: Mutex<Vec<(String, &'static Metadata<'static>)>>,
+        stack: Mutex<Vec<Id>>,
+        events: Arc<Mutex<Vec<(String, String)>>>,
+    }
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((fields.0, span.metadata()));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let parent = if event.is_contextual() {
+                self.stack.lock().unwrap().last().cloned()
+            } else {
+                event.parent().cloned()
+            };
+            let span = parent
+                .map(|id| {
+                    self.spans.lock().unwrap()[id.into_u64() as usize - 1]
+                        .0
+                        .clone()
+                })
+                .unwrap_or_default();
+            self.events.lock().unwrap().push((fields.0, span));
+        }
+
+        fn enter(&self, span: &Id) {
+            self.stack.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, _span: &Id) {
+            self.stack.lock().unwrap().pop();
+        }
+
+        fn current_span(&self) -> Current {
+            match self.stack.lock().unwrap().last() {
+                None => Current::none(),
+                Some(id) => {
+                    let metadata = self.spans.lock().unwrap()[id.into_u64() as usize - 1].1;
+                    Current::new(id.clone(), metadata)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_events_work() -> std::result::Result<(), MainError> {
+        let clock = ManualClock::new();
+        let network = SimulatedNetwork::new();
+        let transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_millis(100))
+            .clock(clock.clone())
+            .finish(track!(network.bind_any())?);
+        let mut channel = ChannelBuilder::new()
+            .request_timeout(Duration::from_millis(250))
+            .clock(clock.clone())
+            .finish(transporter);
+        let peer: std::net::SocketAddr = "127.0.0.1:3478".parse().unwrap();
+
+        let recorder = Recorder::default();
+        let events = Arc::clone(&recorder.events);
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let transaction_id = request.transaction_id();
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            tracing::subscriber::with_default(recorder, || {
+                let _response = channel.call(peer, request);
+                track!(channel.poll_send())?;
+
+                clock.advance(Duration::from_millis(100));
+                track!(channel.poll_send())?;
+
+                clock.advance(Duration::from_millis(150));
+                track!(channel.poll_recv())?;
+                Ok(())
+            })
+        }));
+        track!(result)?;
+
+        let span = format!("peer={peer:?} method=1 transaction_id={transaction_id:?} ");
+        let events = events.lock().unwrap();
+        for message in [
+            "Sending request",
+            "Retransmitting request",
+            "Transaction timed out",
+        ] {
+            assert!(
+                events.iter().any(
+                    |(fields, s)| fields.starts_with(&format!("message={message}")) && *s == span
+                ),
+                "{message:?} is not found in {events:?}"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+use bytecodec::{DecodeExt, EncodeExt};
+use fibers::io::poll::{EventedHandle, Interest};
+use fibers::sync::oneshot::Monitor;
+use fibers_transport::{Error, PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::{future, Async, Future};
+use mio::net::UdpSocket as MioUdpSocket;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use stun_codec::{
+    Attribute, DecodedMessage, Message, MessageDecoder, MessageEncoder, TransactionId,
+};
+
+use super::std_udp::{self, RegisterSocket};
+use super::StunTransport;
+
+/// [`BatchUdpTransporter`] builder.
+///
+/// [`BatchUdpTransporter`]: ./struct.BatchUdpTransporter.html
+#[derive(Debug, Clone)]
+pub struct BatchUdpTransporterBuilder {
+    batch_size: usize,
+    buf_size: usize,
+}
+impl BatchUdpTransporterBuilder {
+    /// The default maximum number of datagrams read or written by a system call.
+    pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+    /// The default size of the buffer for each received datagram.
+    pub const DEFAULT_BUF_SIZE: usize = 4096;
+
+    /// Makes a new `BatchUdpTransporterBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of datagrams read or written by a system call.
+    ///
+    /// The default value is `DEFAULT_BATCH_SIZE`.
+    pub fn batch_size(&mut self, size: usize) -> &mut Self {
+        self.batch_size = size;
+        self
+    }
+
+    /// Sets the size of the buffer for each received datagram.
+    ///
+    /// Datagrams larger than this are truncated (and then fail to be decoded).
+    ///
+    /// The default value is `DEFAULT_BUF_SIZE`.
+    pub fn buf_size(&mut self, size: usize) -> &mut Self {
+        self.buf_size = size;
+        self
+    }
+
+    /// Makes a future that creates a `BatchUdpTransporter` instance bound to the given address.
+    pub fn bind<A: Attribute>(
+        &self,
+        bind_addr: SocketAddr,
+    ) -> impl Future<Item = BatchUdpTransporter<A>, Error = Error> {
+        match UdpSocket::bind(bind_addr) {
+            Err(e) => future::Either::A(future::err(track!(Error::from(e)))),
+            Ok(socket) => future::Either::B(self.finish(socket)),
+        }
+    }
+
+    /// Makes a future that creates a `BatchUdpTransporter` instance bound to the given address
+    /// with `SO_REUSEPORT` enabled.
+    pub fn bind_reuse_port<A: Attribute>(
+        &self,
+        bind_addr: SocketAddr,
+    ) -> impl Future<Item = BatchUdpTransporter<A>, Error = Error> {
+        match std_udp::bind_reuse_port(bind_addr) {
+            Err(e) => future::Either::A(future::err(track!(Error::from(e)))),
+            Ok(socket) => future::Either::B(self.finish(socket)),
+        }
+    }
+
+    /// Makes a future that creates a `BatchUdpTransporter` instance which uses the given socket.
+    ///
+    /// Note that the resulting future must be polled on a fiber.
+    pub fn finish<A: Attribute>(
+        &self,
+        socket: UdpSocket,
+    ) -> impl Future<Item = BatchUdpTransporter<A>, Error = Error> {
+        let batch_size = self.batch_size.max(1);
+        let buf_size = self.buf_size;
+        RegisterSocket::new(socket).map(move |(local_addr, socket)| BatchUdpTransporter {
+            socket,
+            local_addr,
+            encoder: MessageEncoder::default(),
+            decoder: MessageDecoder::default(),
+            outgoing_queue: VecDeque::new(),
+            recv_bufs: vec![vec![0; buf_size]; batch_size],
+            received: VecDeque::new(),
+            read_monitor: None,
+            write_monitor: None,
+            batch_size,
+        })
+    }
+}
+impl Default for BatchUdpTransporterBuilder {
+    fn default() -> Self {
+        BatchUdpTransporterBuilder {
+            batch_size: Self::DEFAULT_BATCH_SIZE,
+            buf_size: Self::DEFAULT_BUF_SIZE,
+        }
+    }
+}
+
+/// An implementation of [`UdpTransport`] that reads and writes datagrams in batches
+/// by using `recvmmsg(2)` and `sendmmsg(2)` (Linux only).
+///
+/// This reduces the number of system calls issued by servers receiving many small datagrams.
+/// It can be used by [`UdpServer`] via [`UdpServer::with_transporter`].
+///
+/// [`UdpTransport`]: https://docs.rs/fibers_transport/0.1/fibers_transport/trait.UdpTransport.html
+/// [`UdpServer`]: ../server/struct.UdpServer.html
+/// [`UdpServer::with_transporter`]: ../server/struct.UdpServer.html#method.with_transporter
+pub struct BatchUdpTransporter<A: Attribute> {
+    socket: Arc<EventedHandle<MioUdpSocket>>,
+    local_addr: SocketAddr,
+    encoder: MessageEncoder<A>,
+    decoder: MessageDecoder<A>,
+    outgoing_queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    recv_bufs: Vec<Vec<u8>>,
+    received: VecDeque<(usize, usize, SocketAddr)>,
+    read_monitor: Option<Monitor<(), io::Error>>,
+    write_monitor: Option<Monitor<(), io::Error>>,
+    batch_size: usize,
+}
+impl<A: Attribute> BatchUdpTransporter<A> {
+    /// Makes a future that creates a `BatchUdpTransporter` instance bound to the given address.
+    ///
+    /// This is equivalent to `BatchUdpTransporterBuilder::new().bind(bind_addr)`.
+    pub fn bind(bind_addr: SocketAddr) -> impl Future<Item = Self, Error = Error> {
+        BatchUdpTransporterBuilder::new().bind(bind_addr)
+    }
+
+    /// Returns the number of unsent messages in the queue of the instance.
+    pub fn message_queue_len(&self) -> usize {
+        self.outgoing_queue.len()
+    }
+
+    fn recv_batch(&mut self) -> io::Result<()> {
+        let fd = self.socket.inner().as_raw_fd();
+        let mut received = Vec::with_capacity(self.recv_bufs.len());
+        sys::recv_batch(fd, &mut self.recv_bufs, &mut received)?;
+        self.received.extend(
+            received
+                .into_iter()
+                .enumerate()
+                .map(|(i, (size, peer))| (i, size, peer)),
+        );
+        Ok(())
+    }
+}
+impl<A: Attribute> Transport for BatchUdpTransporter<A> {
+    type PeerAddr = SocketAddr;
+    type SendItem = Message<A>;
+    type RecvItem = DecodedMessage<A>;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let bytes = track!(self.encoder.encode_into_bytes(item))?;
+        self.outgoing_queue.push_back((peer, bytes));
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        while track!(std_udp::poll_monitor(&mut self.write_monitor))?.is_ready() {
+            if self.outgoing_queue.is_empty() {
+                return Ok(Async::Ready(()));
+            }
+
+            let fd = self.socket.inner().as_raw_fd();
+            let messages = self.outgoing_queue.make_contiguous();
+            let n = messages.len().min(self.batch_size);
+            match sys::send_batch(fd, &messages[..n]) {
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        // `sendmmsg(2)` fails only if the first datagram cannot be sent
+                        self.outgoing_queue.pop_front();
+                        return Err(track!(Error::from(e)));
+                    }
+                    self.write_monitor = Some(self.socket.monitor(Interest::Write));
+                }
+                Ok(sent) => {
+                    self.outgoing_queue.drain(..sent);
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        loop {
+            if let Some((i, size, peer)) = self.received.pop_front() {
+                let item =
+                    track!(self.decoder.decode_from_bytes(&self.recv_bufs[i][..size]); peer)?;
+                return Ok(Async::Ready(Some((peer, item))));
+            }
+            if track!(std_udp::poll_monitor(&mut self.read_monitor))?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            if let Err(e) = self.recv_batch() {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    return Err(track!(Error::from(e)));
+                }
+                self.read_monitor = Some(self.socket.monitor(Interest::Read));
+            }
+        }
+    }
+}
+impl<A: Attribute> UdpTransport for BatchUdpTransporter<A> {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+impl<A: Attribute> StunTransport<A> for BatchUdpTransporter<A> {
+    fn finish_transaction(
+        &mut self,
+        _peer: &SocketAddr,
+        _transaction_id: TransactionId,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_transient_error(&self, error: &Error) -> bool {
+        super::is_transient_datagram_error(error)
+    }
+}
+impl<A: Attribute> fmt::Debug for BatchUdpTransporter<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BatchUdpTransporter {{ local_addr: {:?}, batch_size: {}, .. }}",
+            self.local_addr, self.batch_size
+        )
+    }
+}
+
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+
+    /// Receives datagrams into `bufs` by a `recvmmsg(2)` call.
+    ///
+    /// The size and the sender of the `i`-th datagram are pushed to `received`,
+    /// and the datagram itself is stored in `bufs[i]`.
+    pub fn recv_batch(
+        fd: RawFd,
+        bufs: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        let mut addrs = vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; bufs.len()];
+        let mut iovecs = bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut headers = addrs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .map(|(addr, iovec)| {
+                let mut h = unsafe { mem::zeroed::<libc::mmsghdr>() };
+                h.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                h.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+                h.msg_hdr.msg_iov = iovec;
+                h.msg_hdr.msg_iovlen = 1;
+                h
+            })
+            .collect::<Vec<_>>();
+
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (h, addr) in headers.iter().zip(addrs.iter()).take(n as usize) {
+            received.push((h.msg_len as usize, from_sockaddr(addr)?));
+        }
+        Ok(())
+    }
+
+    /// Sends the given datagrams by a `sendmmsg(2)` call.
+    ///
+    /// Returns the number of the datagrams actually sent.
+    pub fn send_batch(fd: RawFd, messages: &[(SocketAddr, Vec<u8>)]) -> io::Result<usize> {
+        let mut addrs = messages
+            .iter()
+            .map(|(peer, _)| to_sockaddr(peer))
+            .collect::<Vec<_>>();
+        let mut iovecs = messages
+            .iter()
+            .map(|(_, bytes)| libc::iovec {
+                iov_base: bytes.as_ptr() as *mut libc::c_void,
+                iov_len: bytes.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut headers = addrs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .map(|((addr, len), iovec)| {
+                let mut h = unsafe { mem::zeroed::<libc::mmsghdr>() };
+                h.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                h.msg_hdr.msg_namelen = *len;
+                h.msg_hdr.msg_iov = iovec;
+                h.msg_hdr.msg_iovlen = 1;
+                h
+            })
+            .collect::<Vec<_>>();
+
+        let n = unsafe {
+            libc::sendmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn from_sockaddr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match libc::c_int::from(addr.ss_family) {
+            libc::AF_INET => {
+                let a = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+                Ok(SocketAddrV4::new(ip, u16::from_be(a.sin_port)).into())
+            }
+            libc::AF_INET6 => {
+                let a = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+                let port = u16::from_be(a.sin6_port);
+                Ok(SocketAddrV6::new(ip, port, a.sin6_flowinfo, a.sin6_scope_id).into())
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported address family: {family}"),
+            )),
+        }
+    }
+
+    fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+        let len = match addr {
+            SocketAddr::V4(a) => {
+                let s = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                s.sin_family = libc::AF_INET as libc::sa_family_t;
+                s.sin_port = a.port().to_be();
+                s.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(a) => {
+                let s = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                s.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                s.sin6_port = a.port().to_be();
+                s.sin6_addr.s6_addr = a.ip().octets();
+                s.sin6_flowinfo = a.flowinfo();
+                s.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Request;
+    use futures::future;
+    use stun_codec::rfc5389;
+    use trackable::error::MainError;
+
+    #[test]
+    fn batched_send_and_recv_work() -> std::result::Result<(), MainError> {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let future = BatchUdpTransporterBuilder::new()
+            .batch_size(8)
+            .bind::<rfc5389::Attribute>(addr)
+            .join(BatchUdpTransporter::<rfc5389::Attribute>::bind(addr))
+            .and_then(|(mut sender, receiver)| {
+                let peer = receiver.local_addr();
+                let mut ids = Vec::new();
+                for _ in 0..20 {
+                    let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                    ids.push(request.transaction_id());
+                    track!(sender.start_send(peer, request.into_message()))?;
+                }
+                Ok((sender, receiver, ids))
+            })
+            .and_then(|(mut sender, mut receiver, ids)| {
+                let mut received = Vec::new();
+                future::poll_fn(move || {
+                    track!(sender.poll_send())?;
+                    while let Async::Ready(item) = track!(receiver.poll_recv())? {
+                        let (peer, message) = item.expect("never fails");
+                        assert_eq!(peer, sender.local_addr());
+                        let message =
+                            track!(message
+                                .map_err(|_| Error::from(io::Error::other("Broken message"))))?;
+                        received.push(message.transaction_id());
+                    }
+                    if received.len() == ids.len() {
+                        assert_eq!(received, ids);
+                        Ok(Async::Ready(()))
+                    } else {
+                        Ok(Async::NotReady)
+                    }
+                })
+            });
+        track!(fibers_global::execute(future))?;
+        Ok(())
+    }
+}
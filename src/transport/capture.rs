@@ -0,0 +1,1266 @@
+use bytecodec::{ByteCount, Decode, Encode, Eos, SizedEncode};
+use factory::Factory;
+use fibers_transport::{
+    Error, ErrorKind, PeerAddr, PollRecv, PollSend, Result, TcpTransport, TcpTransporter,
+    Transport, UdpTransport, UdpTransporter,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use stun_codec::{Attribute, Message, TransactionId};
+
+use super::StunTransport;
+use crate::channel::CallOptions;
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4;
+const PCAP_SNAPLEN: u32 = 0xFFFF;
+const PCAP_MAX_BLOCK_LEN: usize = 256 * 1024;
+const PCAPNG_SHB_TYPE: u32 = 0x0A0D_0D0A;
+const PCAPNG_IDB_TYPE: u32 = 1;
+const PCAPNG_SPB_TYPE: u32 = 3;
+const PCAPNG_EPB_TYPE: u32 = 6;
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+const DLT_RAW1: u32 = 12;
+const DLT_RAW2: u32 = 14;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// Transport layer protocol recorded in capture files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CaptureProtocol {
+    /// UDP.
+    Udp,
+
+    /// TCP.
+    Tcp,
+}
+
+/// Writer of pcap files that record STUN messages.
+///
+/// Each message is recorded as a raw IPv4/IPv6 packet (`LINKTYPE_RAW`) that has a synthesized UDP or TCP header,
+/// thus the resulting file can be inspected by Wireshark's STUN dissector.
+/// Messages of a TCP connection are recorded as consecutive segments of the connection
+/// (the handshake of the connection is not recorded).
+///
+/// `PcapCapture` is a cheap handle, so a file can be shared by multiple transporters by cloning it.
+///
+/// Failures in writing packets never affect transporters.
+/// The first failure is reported by [`PcapCapture::flush`] instead.
+///
+/// [`PcapCapture::flush`]: ./struct.PcapCapture.html#method.flush
+#[derive(Clone)]
+pub struct PcapCapture {
+    inner: Arc<Mutex<PcapWriter>>,
+}
+impl PcapCapture {
+    /// Makes a new `PcapCapture` instance that writes packets to the given writer.
+    ///
+    /// The pcap file header is written immediately.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        track!(writer.write_all(&header).map_err(Error::from))?;
+
+        let writer = PcapWriter {
+            writer: Box::new(writer),
+            tcp_seqs: HashMap::new(),
+            error: None,
+        };
+        Ok(PcapCapture {
+            inner: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Creates a pcap file at the given path, and makes a new `PcapCapture` instance that writes packets to the file.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = track!(File::create(path.as_ref()).map_err(Error::from); path.as_ref())?;
+        track!(Self::new(BufWriter::new(file)))
+    }
+
+    /// Records a packet that conveys the given payload from `src` to `dst`.
+    pub fn write_packet(
+        &self,
+        protocol: CaptureProtocol,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> Result<()> {
+        let mut inner = self.inner.lock().expect("never fails");
+        if let Some(e) = inner.error.clone() {
+            return Err(track!(e));
+        }
+        let result = inner.write_packet(protocol, src, dst, payload);
+        if let Err(e) = &result {
+            inner.error = Some(e.clone());
+        }
+        track!(result)
+    }
+
+    /// Flushes the buffered packets.
+    ///
+    /// If writing a packet has failed before, this method returns the error.
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock().expect("never fails");
+        if let Some(e) = inner.error.clone() {
+            return Err(track!(e));
+        }
+        track!(inner.writer.flush().map_err(Error::from))
+    }
+}
+impl fmt::Debug for PcapCapture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PcapCapture {{ .. }}")
+    }
+}
+
+struct PcapWriter {
+    writer: Box<dyn Write + Send>,
+    tcp_seqs: HashMap<(SocketAddr, SocketAddr), u32>,
+    error: Option<Error>,
+}
+impl PcapWriter {
+    fn write_packet(
+        &mut self,
+        protocol: CaptureProtocol,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> Result<()> {
+        let (src, dst) = unify_address_family(src, dst);
+        let (next_header, segment) = match protocol {
+            CaptureProtocol::Udp => (IPPROTO_UDP, udp_segment(src, dst, payload)),
+            CaptureProtocol::Tcp => {
+                let seq = self.tcp_seqs.entry((src, dst)).or_insert(1);
+                let current_seq = *seq;
+                *seq = seq.wrapping_add(payload.len() as u32);
+                let ack = self.tcp_seqs.get(&(dst, src)).copied().unwrap_or(1);
+                (
+                    IPPROTO_TCP,
+                    tcp_segment(src, dst, current_seq, ack, payload),
+                )
+            }
+        };
+        let packet = ip_packet(src.ip(), dst.ip(), next_header, &segment);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured_len = packet.len().min(PCAP_SNAPLEN as usize);
+        let mut record = Vec::with_capacity(16 + captured_len);
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured_len as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..captured_len]);
+        track!(self.writer.write_all(&record).map_err(Error::from))
+    }
+}
+
+/// Converts the addresses of a packet so that both belong to the same address family.
+fn unify_address_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v4 = |a: SocketAddr| match a.ip() {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map_or(a, |ip| SocketAddr::new(IpAddr::V4(ip), a.port())),
+        IpAddr::V4(_) => a,
+    };
+    let to_v6 = |a: SocketAddr| match a.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), a.port()),
+        IpAddr::V6(_) => a,
+    };
+    let (src, dst) = (to_v4(src), to_v4(dst));
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (to_v6(src), to_v6(dst))
+    }
+}
+
+fn udp_segment(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(8 + payload.len());
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(payload);
+
+    let checksum = match transport_checksum(src.ip(), dst.ip(), IPPROTO_UDP, &segment) {
+        0 => 0xFFFF,
+        n => n,
+    };
+    segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+fn tcp_segment(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    const FLAGS_PSH_ACK: u8 = 0x18;
+
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[5 << 4, FLAGS_PSH_ACK]);
+    segment.extend_from_slice(&0xFFFFu16.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+
+    let checksum = transport_checksum(src.ip(), dst.ip(), IPPROTO_TCP, &segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+fn ip_packet(src: IpAddr, dst: IpAddr, next_header: u8, segment: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + segment.len());
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, next_header, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = !sum_words(0, &packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        _ => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[next_header, 64]);
+            packet.extend_from_slice(&ipv6_octets(src));
+            packet.extend_from_slice(&ipv6_octets(dst));
+        }
+    }
+    packet.extend_from_slice(segment);
+    packet
+}
+
+fn transport_checksum(src: IpAddr, dst: IpAddr, next_header: u8, segment: &[u8]) -> u16 {
+    let mut pseudo_header = Vec::with_capacity(40);
+    if let (IpAddr::V4(src), IpAddr::V4(dst)) = (src, dst) {
+        pseudo_header.extend_from_slice(&src.octets());
+        pseudo_header.extend_from_slice(&dst.octets());
+        pseudo_header.extend_from_slice(&[0, next_header]);
+        pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    } else {
+        pseudo_header.extend_from_slice(&ipv6_octets(src));
+        pseudo_header.extend_from_slice(&ipv6_octets(dst));
+        pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+        pseudo_header.extend_from_slice(&[0, 0, 0, next_header]);
+    }
+    !sum_words(sum_words(0, &pseudo_header), segment)
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Calculates the one's complement sum of the 16-bit words in `bytes`.
+fn sum_words(initial: u16, bytes: &[u8]) -> u16 {
+    let mut sum = u32::from(initial);
+    for word in bytes.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([
+            word[0],
+            word.get(1).copied().unwrap_or(0),
+        ]));
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Packet read from a capture file by [`PcapReader`].
+///
+/// [`PcapReader`]: ./struct.PcapReader.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+    /// Time when the packet was captured.
+    pub timestamp: SystemTime,
+
+    /// Transport layer protocol of the packet.
+    pub protocol: CaptureProtocol,
+
+    /// Source address of the packet.
+    pub src: SocketAddr,
+
+    /// Destination address of the packet.
+    pub dst: SocketAddr,
+
+    /// UDP payload or TCP segment data of the packet.
+    pub payload: Vec<u8>,
+}
+
+/// Reader of pcap and pcapng files.
+///
+/// The reader extracts UDP datagrams and TCP segments conveyed by IPv4/IPv6 packets.
+/// Other packets (e.g., ARP, ICMP, IP fragments and TCP segments without data) are skipped.
+///
+/// The following link-layer types are supported:
+/// Ethernet, raw IP, Linux cooked capture (v1 and v2) and BSD loopback.
+///
+/// Note that TCP segments are returned as they are (i.e., streams are not reassembled).
+///
+/// Records and blocks longer than 256 KiB are regarded as corrupted,
+/// and reading them results in an `ErrorKind::InvalidInput` error.
+pub struct PcapReader<R> {
+    reader: R,
+    format: CaptureFormat,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+}
+impl PcapReader<BufReader<File>> {
+    /// Opens the capture file at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = track!(File::open(path.as_ref()).map_err(Error::from); path.as_ref())?;
+        track!(Self::new(BufReader::new(file)))
+    }
+}
+impl<R: Read> PcapReader<R> {
+    /// Makes a new `PcapReader` instance that reads packets from the given reader.
+    ///
+    /// The file header is read immediately.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 4];
+        track!(reader.read_exact(&mut magic).map_err(Error::from))?;
+        if magic == PCAPNG_SHB_TYPE.to_le_bytes() {
+            let mut this = PcapReader {
+                reader,
+                format: CaptureFormat::Pcapng,
+                big_endian: false,
+                interfaces: Vec::new(),
+            };
+            let mut len = [0; 4];
+            track!(this.reader.read_exact(&mut len).map_err(Error::from))?;
+            track!(this.read_section_header(len))?;
+            return Ok(this);
+        }
+
+        let (big_endian, nanos) = match magic {
+            [0xD4, 0xC3, 0xB2, 0xA1] => (false, false),
+            [0xA1, 0xB2, 0xC3, 0xD4] => (true, false),
+            [0x4D, 0x3C, 0xB2, 0xA1] => (false, true),
+            [0xA1, 0xB2, 0x3C, 0x4D] => (true, true),
+            _ => track_panic!(
+                ErrorKind::InvalidInput,
+                "Not a pcap file: magic={:?}",
+                magic
+            ),
+        };
+        let mut header = [0; 20];
+        track!(reader.read_exact(&mut header).map_err(Error::from))?;
+        let mut this = PcapReader {
+            reader,
+            format: CaptureFormat::Pcap,
+            big_endian,
+            interfaces: Vec::new(),
+        };
+        let link_type = this.u32(&header[16..20]) & 0x0FFF_FFFF;
+        let ticks_per_sec = if nanos { 1_000_000_000 } else { 1_000_000 };
+        this.interfaces.push(Interface {
+            link_type,
+            ticks_per_sec,
+        });
+        Ok(this)
+    }
+
+    /// Reads the next packet.
+    ///
+    /// If the reader reaches the end of the file, this will return `Ok(None)`.
+    pub fn read_packet(&mut self) -> Result<Option<CapturedPacket>> {
+        loop {
+            let frame = match self.format {
+                CaptureFormat::Pcap => track!(self.read_pcap_record())?,
+                CaptureFormat::Pcapng => track!(self.read_pcapng_block())?,
+            };
+            let (interface, timestamp, data) = match frame {
+                None => return Ok(None),
+                Some(Frame::Packet(interface, timestamp, data)) => (interface, timestamp, data),
+                Some(Frame::Other) => continue,
+            };
+            let interface = track_assert_some!(
+                self.interfaces.get(interface),
+                ErrorKind::InvalidInput,
+                "Unknown interface: {}",
+                interface
+            );
+            let timestamp = UNIX_EPOCH
+                + Duration::from_secs(timestamp / interface.ticks_per_sec)
+                + Duration::from_nanos(
+                    (timestamp % interface.ticks_per_sec) * 1_000_000_000 / interface.ticks_per_sec,
+                );
+            if let Some(packet) = parse_frame(interface.link_type, timestamp, &data) {
+                return Ok(Some(packet));
+            }
+        }
+    }
+
+    /// Reads all the remaining packets.
+    pub fn read_all(&mut self) -> Result<Vec<CapturedPacket>> {
+        let mut packets = Vec::new();
+        while let Some(packet) = track!(self.read_packet())? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    fn read_pcap_record(&mut self) -> Result<Option<Frame>> {
+        let mut header = [0; 16];
+        if !track!(self.read_exact_or_eof(&mut header))? {
+            return Ok(None);
+        }
+        let ticks_per_sec = self.interfaces[0].ticks_per_sec;
+        let timestamp =
+            u64::from(self.u32(&header[0..4])) * ticks_per_sec + u64::from(self.u32(&header[4..8]));
+        let captured_len = self.u32(&header[8..12]) as usize;
+        track_assert!(captured_len <= PCAP_MAX_BLOCK_LEN, ErrorKind::InvalidInput; captured_len);
+        let mut data = vec![0; captured_len];
+        track!(self.reader.read_exact(&mut data).map_err(Error::from))?;
+        Ok(Some(Frame::Packet(0, timestamp, data)))
+    }
+
+    fn read_pcapng_block(&mut self) -> Result<Option<Frame>> {
+        let mut header = [0; 8];
+        if !track!(self.read_exact_or_eof(&mut header))? {
+            return Ok(None);
+        }
+        if header[..4] == PCAPNG_SHB_TYPE.to_le_bytes() {
+            let len = [header[4], header[5], header[6], header[7]];
+            track!(self.read_section_header(len))?;
+            return Ok(Some(Frame::Other));
+        }
+
+        let block_type = self.u32(&header[0..4]);
+        let block_len = self.u32(&header[4..8]) as usize;
+        track_assert!(block_len >= 12, ErrorKind::InvalidInput; block_len);
+        track_assert!(block_len <= PCAP_MAX_BLOCK_LEN, ErrorKind::InvalidInput; block_len);
+        let mut body = vec![0; block_len - 8];
+        track!(self.reader.read_exact(&mut body).map_err(Error::from))?;
+        let body = &body[..body.len() - 4];
+        match block_type {
+            PCAPNG_IDB_TYPE => {
+                track_assert!(body.len() >= 8, ErrorKind::InvalidInput);
+                let link_type = u32::from(self.u16(&body[0..2]));
+                let ticks_per_sec = self.if_tsresol(&body[8..]);
+                self.interfaces.push(Interface {
+                    link_type,
+                    ticks_per_sec,
+                });
+                Ok(Some(Frame::Other))
+            }
+            PCAPNG_EPB_TYPE => {
+                track_assert!(body.len() >= 20, ErrorKind::InvalidInput);
+                let interface = self.u32(&body[0..4]) as usize;
+                let timestamp =
+                    (u64::from(self.u32(&body[4..8])) << 32) | u64::from(self.u32(&body[8..12]));
+                let captured_len = self.u32(&body[12..16]) as usize;
+                let data =
+                    track_assert_some!(body.get(20..20 + captured_len), ErrorKind::InvalidInput);
+                Ok(Some(Frame::Packet(interface, timestamp, data.to_owned())))
+            }
+            PCAPNG_SPB_TYPE => {
+                track_assert!(body.len() >= 4, ErrorKind::InvalidInput);
+                let original_len = self.u32(&body[0..4]) as usize;
+                let data = &body[4..];
+                let data = &data[..original_len.min(data.len())];
+                Ok(Some(Frame::Packet(0, 0, data.to_owned())))
+            }
+            _ => Ok(Some(Frame::Other)),
+        }
+    }
+
+    /// Reads the rest of a section header block (following the block length).
+    fn read_section_header(&mut self, block_len: [u8; 4]) -> Result<()> {
+        let mut magic = [0; 4];
+        track!(self.reader.read_exact(&mut magic).map_err(Error::from))?;
+        self.big_endian = match magic {
+            [0x4D, 0x3C, 0x2B, 0x1A] => false,
+            [0x1A, 0x2B, 0x3C, 0x4D] => true,
+            _ => track_panic!(
+                ErrorKind::InvalidInput,
+                "Invalid byte-order magic: {:?}",
+                magic
+            ),
+        };
+        let block_len = self.u32(&block_len) as usize;
+        track_assert!(block_len >= 16, ErrorKind::InvalidInput; block_len);
+        track_assert!(block_len <= PCAP_MAX_BLOCK_LEN, ErrorKind::InvalidInput; block_len);
+        let mut rest = vec![0; block_len - 12];
+        track!(self.reader.read_exact(&mut rest).map_err(Error::from))?;
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    fn if_tsresol(&self, mut options: &[u8]) -> u64 {
+        const IF_TSRESOL: u16 = 9;
+        while options.len() >= 4 {
+            let code = self.u16(&options[0..2]);
+            let len = usize::from(self.u16(&options[2..4]));
+            let value = &options[4..];
+            if code == IF_TSRESOL && len == 1 && !value.is_empty() {
+                let exp = u32::from(value[0] & 0x7F);
+                return if value[0] & 0x80 == 0 {
+                    10u64.saturating_pow(exp)
+                } else {
+                    2u64.saturating_pow(exp)
+                };
+            }
+            let padded_len = 4 + len.div_ceil(4) * 4;
+            options = options.get(padded_len..).unwrap_or(&[]);
+        }
+        1_000_000
+    }
+
+    fn read_exact_or_eof(&mut self, buf: &mut [u8]) -> Result<bool> {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let n = track!(self.reader.read(&mut buf[offset..]).map_err(Error::from))?;
+            if n == 0 {
+                track_assert_eq!(offset, 0, ErrorKind::InvalidInput, "Truncated capture file");
+                return Ok(false);
+            }
+            offset += n;
+        }
+        Ok(true)
+    }
+
+    fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+impl<R> fmt::Debug for PcapReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PcapReader {{ format: {:?}, big_endian: {}, .. }}",
+            self.format, self.big_endian
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CaptureFormat {
+    Pcap,
+    Pcapng,
+}
+
+#[derive(Debug)]
+struct Interface {
+    link_type: u32,
+    ticks_per_sec: u64,
+}
+
+enum Frame {
+    Packet(usize, u64, Vec<u8>),
+    Other,
+}
+
+fn parse_frame(link_type: u32, timestamp: SystemTime, data: &[u8]) -> Option<CapturedPacket> {
+    const ETHERTYPE_IPV4: u16 = 0x0800;
+    const ETHERTYPE_IPV6: u16 = 0x86DD;
+    const ETHERTYPE_VLAN: u16 = 0x8100;
+
+    let ethertype = |bytes: &[u8], offset: usize| {
+        bytes
+            .get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    let is_ip = |t| t == ETHERTYPE_IPV4 || t == ETHERTYPE_IPV6;
+    let packet = match link_type {
+        LINKTYPE_NULL | LINKTYPE_LOOP => data.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let (t, offset) = match ethertype(data, 12)? {
+                ETHERTYPE_VLAN => (ethertype(data, 16)?, 18),
+                t => (t, 14),
+            };
+            if !is_ip(t) {
+                return None;
+            }
+            data.get(offset..)?
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 | DLT_RAW1 | DLT_RAW2 => data,
+        LINKTYPE_LINUX_SLL if is_ip(ethertype(data, 14)?) => data.get(16..)?,
+        LINKTYPE_LINUX_SLL2 if is_ip(ethertype(data, 0)?) => data.get(20..)?,
+        _ => return None,
+    };
+    parse_ip_packet(timestamp, packet)
+}
+
+fn parse_ip_packet(timestamp: SystemTime, packet: &[u8]) -> Option<CapturedPacket> {
+    let be16 = |bytes: &[u8], offset: usize| {
+        bytes
+            .get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    let (src, dst, next_header, segment) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0F) * 4;
+            let total_len = match usize::from(be16(packet, 2)?) {
+                0 => packet.len(),
+                n => n.min(packet.len()),
+            };
+            if be16(packet, 6)? & 0x3FFF != 0 {
+                // A fragment
+                return None;
+            }
+            let src = <[u8; 4]>::try_from(packet.get(12..16)?).ok()?;
+            let dst = <[u8; 4]>::try_from(packet.get(16..20)?).ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                packet[9],
+                packet.get(header_len..total_len)?,
+            )
+        }
+        6 => {
+            let end = (40 + usize::from(be16(packet, 4)?)).min(packet.len());
+            let src = <[u8; 16]>::try_from(packet.get(8..24)?).ok()?;
+            let dst = <[u8; 16]>::try_from(packet.get(24..40)?).ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                packet[6],
+                packet.get(40..end)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let (protocol, payload) = match next_header {
+        IPPROTO_UDP => {
+            let end = usize::from(be16(segment, 4)?).min(segment.len());
+            (CaptureProtocol::Udp, segment.get(8..end)?)
+        }
+        IPPROTO_TCP => {
+            let offset = usize::from(segment.get(12)? >> 4) * 4;
+            let payload = segment.get(offset..)?;
+            if payload.is_empty() {
+                return None;
+            }
+            (CaptureProtocol::Tcp, payload)
+        }
+        _ => return None,
+    };
+    Some(CapturedPacket {
+        timestamp,
+        protocol,
+        src: SocketAddr::new(src, be16(segment, 0)?),
+        dst: SocketAddr::new(dst, be16(segment, 2)?),
+        payload: payload.to_owned(),
+    })
+}
+
+/// Peer address types of the transporters that can be wrapped by [`CaptureTransporter`].
+///
+/// [`CaptureTransporter`]: ./struct.CaptureTransporter.html
+pub trait CapturePeerAddr: PeerAddr {
+    /// Returns the socket address denoted by this peer address.
+    ///
+    /// `None` means that the transporter is connected to a fixed peer.
+    fn socket_addr(&self) -> Option<SocketAddr>;
+}
+impl CapturePeerAddr for SocketAddr {
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        Some(*self)
+    }
+}
+impl CapturePeerAddr for () {
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Encoder that records the bytes produced by the inner encoder.
+///
+/// The bytes are recorded only after a [`CaptureTransporter`] that wraps the transporter using this encoder is made,
+/// thus this behaves exactly like the inner encoder otherwise.
+///
+/// [`CaptureTransporter`]: ./struct.CaptureTransporter.html
+#[derive(Debug, Default)]
+pub struct CaptureEncoder<E> {
+    inner: E,
+    recording: bool,
+    current: Option<Vec<u8>>,
+    encoded: Vec<Vec<u8>>,
+}
+impl<E> CaptureEncoder<E> {
+    /// Makes a new `CaptureEncoder` instance.
+    pub fn new(inner: E) -> Self {
+        CaptureEncoder {
+            inner,
+            recording: false,
+            current: None,
+            encoded: Vec::new(),
+        }
+    }
+
+    /// Returns a reference to the inner encoder.
+    pub fn inner_ref(&self) -> &E {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner encoder.
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+}
+impl<E: Encode> Encode for CaptureEncoder<E> {
+    type Item = E::Item;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        let result = self.inner.encode(buf, eos);
+        if let Some(current) = self.current.as_mut() {
+            if let Ok(size) = &result {
+                current.extend_from_slice(&buf[..*size]);
+            }
+            if result.is_err() || self.inner.is_idle() {
+                self.encoded.extend(self.current.take());
+            }
+        }
+        track!(result)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        let result = self.inner.start_encoding(item);
+        if self.recording {
+            // Each item has an entry (even if it is empty) so that it can be associated with its peer
+            if result.is_ok() {
+                self.current = Some(Vec::new());
+            } else {
+                self.encoded.push(Vec::new());
+            }
+        }
+        track!(result)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+impl<E: SizedEncode> SizedEncode for CaptureEncoder<E> {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.inner.exact_requiring_bytes()
+    }
+}
+
+/// Decoder that records the bytes consumed by the inner decoder.
+///
+/// The bytes are recorded only after a [`CaptureTransporter`] that wraps the transporter using this decoder is made,
+/// thus this behaves exactly like the inner decoder otherwise.
+///
+/// [`CaptureTransporter`]: ./struct.CaptureTransporter.html
+#[derive(Debug, Default)]
+pub struct CaptureDecoder<D> {
+    inner: D,
+    recording: bool,
+    decoded: Vec<u8>,
+}
+impl<D> CaptureDecoder<D> {
+    /// Makes a new `CaptureDecoder` instance.
+    pub fn new(inner: D) -> Self {
+        CaptureDecoder {
+            inner,
+            recording: false,
+            decoded: Vec::new(),
+        }
+    }
+
+    /// Returns a reference to the inner decoder.
+    pub fn inner_ref(&self) -> &D {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner decoder.
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+}
+impl<D: Decode> Decode for CaptureDecoder<D> {
+    type Item = D::Item;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let result = self.inner.decode(buf, eos);
+        if self.recording {
+            // Bytes that the inner decoder rejected are recorded as is
+            let size = result.as_ref().map_or(buf.len(), |size| *size);
+            self.decoded.extend_from_slice(&buf[..size]);
+        }
+        track!(result)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.inner.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+/// Factory of [`CaptureDecoder`] that wraps the decoders made by the inner factory.
+///
+/// [`CaptureDecoder`]: ./struct.CaptureDecoder.html
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CaptureDecoderFactory<F>(pub F);
+impl<F: Factory> Factory for CaptureDecoderFactory<F> {
+    type Item = CaptureDecoder<F::Item>;
+
+    fn create(&self) -> Self::Item {
+        CaptureDecoder::new(self.0.create())
+    }
+}
+
+/// Transporters that use [`CaptureEncoder`] and [`CaptureDecoder`].
+///
+/// [`CaptureEncoder`]: ./struct.CaptureEncoder.html
+/// [`CaptureDecoder`]: ./struct.CaptureDecoder.html
+pub trait CaptureCodec {
+    /// Makes the encoder and decoder start recording bytes.
+    fn start_recording(&mut self);
+
+    /// Takes the bytes of the items encoded since the last call.
+    ///
+    /// The resulting vector has an entry for each item in the order in which the items were sent.
+    fn take_encoded(&mut self) -> Vec<Vec<u8>>;
+
+    /// Takes the bytes decoded since the last call.
+    fn take_decoded(&mut self) -> Vec<u8>;
+}
+impl<E: Encode, D: Decode> CaptureCodec for UdpTransporter<CaptureEncoder<E>, CaptureDecoder<D>> {
+    fn start_recording(&mut self) {
+        self.encoder_mut().recording = true;
+        self.decoder_mut().recording = true;
+    }
+
+    fn take_encoded(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.encoder_mut().encoded)
+    }
+
+    fn take_decoded(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.decoder_mut().decoded)
+    }
+}
+impl<E: Encode, D: Decode> CaptureCodec for TcpTransporter<CaptureEncoder<E>, CaptureDecoder<D>> {
+    fn start_recording(&mut self) {
+        self.encoder_mut().recording = true;
+        self.decoder_mut().recording = true;
+    }
+
+    fn take_encoded(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.encoder_mut().encoded)
+    }
+
+    fn take_decoded(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.decoder_mut().decoded)
+    }
+}
+
+/// Transporter that records the messages sent and received by the inner transporter to a pcap file.
+///
+/// The messages are recorded as the raw bytes written to and read from the wire
+/// by [`CaptureEncoder`] and [`CaptureDecoder`], which the inner transporter has to use.
+/// Thus retransmissions and messages that could not be decoded (e.g., `BrokenMessage`) are recorded as well.
+/// Note that UDP datagrams whose decoding failed with an error are not recorded, because their peers are unknown.
+///
+/// ```no_run
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// # extern crate trackable;
+/// use fibers_transport::UdpTransporter;
+/// use futures::Future;
+/// use rustun::channel::Channel;
+/// use rustun::transport::{
+///     CaptureDecoder, CaptureEncoder, CaptureTransporter, PcapCapture, StunUdpTransporter,
+/// };
+/// use stun_codec::rfc5389::Attribute;
+/// use stun_codec::{MessageDecoder, MessageEncoder};
+///
+/// # fn main() -> Result<(), trackable::error::MainError> {
+/// let capture = PcapCapture::create("stun.pcap")?;
+/// let transporter = fibers_global::execute(UdpTransporter::<
+///     CaptureEncoder<MessageEncoder<Attribute>>,
+///     CaptureDecoder<MessageDecoder<Attribute>>,
+/// >::bind("127.0.0.1:0".parse().unwrap()))?;
+/// let _channel = Channel::new(StunUdpTransporter::new(CaptureTransporter::udp(
+///     transporter,
+///     capture,
+/// )));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`CaptureEncoder`]: ./struct.CaptureEncoder.html
+/// [`CaptureDecoder`]: ./struct.CaptureDecoder.html
+#[derive(Debug)]
+pub struct CaptureTransporter<T> {
+    inner: T,
+    capture: PcapCapture,
+    protocol: CaptureProtocol,
+    local_addr: SocketAddr,
+    peer_addr: Option<SocketAddr>,
+    outgoing_peers: VecDeque<Option<SocketAddr>>,
+}
+impl<T: UdpTransport + CaptureCodec> CaptureTransporter<T> {
+    /// Makes a new `CaptureTransporter` instance that wraps the given UDP transporter.
+    pub fn udp(mut inner: T, capture: PcapCapture) -> Self {
+        inner.start_recording();
+        CaptureTransporter {
+            local_addr: inner.local_addr(),
+            peer_addr: None,
+            inner,
+            capture,
+            protocol: CaptureProtocol::Udp,
+            outgoing_peers: VecDeque::new(),
+        }
+    }
+}
+impl<T: TcpTransport + CaptureCodec> CaptureTransporter<T> {
+    /// Makes a new `CaptureTransporter` instance that wraps the given TCP transporter.
+    pub fn tcp(mut inner: T, capture: PcapCapture) -> Self {
+        inner.start_recording();
+        CaptureTransporter {
+            local_addr: inner.local_addr(),
+            peer_addr: Some(inner.peer_addr()),
+            inner,
+            capture,
+            protocol: CaptureProtocol::Tcp,
+            outgoing_peers: VecDeque::new(),
+        }
+    }
+}
+impl<T> CaptureTransporter<T> {
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns a reference to the capture file.
+    pub fn capture(&self) -> &PcapCapture {
+        &self.capture
+    }
+
+    fn record(&self, bytes: &[u8], peer: Option<SocketAddr>, outgoing: bool) {
+        let Some(peer) = peer.or(self.peer_addr) else {
+            return;
+        };
+        if bytes.is_empty() {
+            return;
+        }
+        let (src, dst) = if outgoing {
+            (self.local_addr, peer)
+        } else {
+            (peer, self.local_addr)
+        };
+
+        // The error is reported by `PcapCapture::flush`
+        let _ = self.capture.write_packet(self.protocol, src, dst, bytes);
+    }
+}
+impl<T: CaptureCodec> CaptureTransporter<T> {
+    fn record_encoded(&mut self) {
+        for bytes in self.inner.take_encoded() {
+            let peer = self.outgoing_peers.pop_front().unwrap_or(None);
+            self.record(&bytes, peer, true);
+        }
+    }
+}
+impl<T> Transport for CaptureTransporter<T>
+where
+    T: Transport + CaptureCodec,
+    T::PeerAddr: CapturePeerAddr,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.outgoing_peers.push_back(peer.socket_addr());
+        let result = self.inner.start_send(peer, item);
+        self.record_encoded();
+        track!(result)
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let polled = self.inner.poll_send();
+        self.record_encoded();
+        track!(polled)
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let polled = self.inner.poll_recv();
+        let peer = match &polled {
+            Ok(futures::Async::Ready(Some((peer, _)))) => peer.socket_addr(),
+            _ => None,
+        };
+        let bytes = self.inner.take_decoded();
+        self.record(&bytes, peer, false);
+        track!(polled)
+    }
+}
+impl<T> UdpTransport for CaptureTransporter<T>
+where
+    T: UdpTransport + CaptureCodec,
+    T::PeerAddr: CapturePeerAddr,
+{
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T> TcpTransport for CaptureTransporter<T>
+where
+    T: TcpTransport + CaptureCodec,
+    T::PeerAddr: CapturePeerAddr,
+{
+    fn peer_addr(&self) -> SocketAddr {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<A, T> StunTransport<A> for CaptureTransporter<T>
+where
+    A: Attribute,
+    T: StunTransport<A> + CaptureCodec,
+    T::PeerAddr: CapturePeerAddr,
+{
+    fn start_request(
+        &mut self,
+        peer: Self::PeerAddr,
+        request: Message<A>,
+        options: &CallOptions,
+    ) -> Result<()> {
+        self.outgoing_peers.push_back(peer.socket_addr());
+        let result = self.inner.start_request(peer, request, options);
+        self.record_encoded();
+        track!(result)
+    }
+
+    fn finish_transaction(
+        &mut self,
+        peer: &Self::PeerAddr,
+        transaction_id: TransactionId,
+    ) -> Result<()> {
+        track!(self.inner.finish_transaction(peer, transaction_id))
+    }
+
+    fn is_transient_error(&self, error: &Error) -> bool {
+        self.inner.is_transient_error(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pcap_capture_works() -> std::result::Result<(), trackable::error::MainError> {
+        let buf = SharedBuf::default();
+        let capture = track!(PcapCapture::new(buf.clone()))?;
+        let client = "127.0.0.1:5000".parse().unwrap();
+        let server = "[::ffff:127.0.0.1]:3478".parse().unwrap();
+        track!(capture.write_packet(CaptureProtocol::Udp, client, server, b"foo"))?;
+        track!(capture.write_packet(CaptureProtocol::Tcp, client, server, b"barbaz"))?;
+        track!(capture.write_packet(CaptureProtocol::Tcp, server, client, b"qux"))?;
+        track!(capture.flush())?;
+
+        let bytes = buf.0.lock().unwrap().clone();
+        assert_eq!(&bytes[..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&bytes[20..24], &LINKTYPE_RAW.to_le_bytes());
+
+        // UDP over IPv4 (the IPv4-mapped address is recorded as an IPv4 address)
+        let packet = &bytes[24 + 16..];
+        assert_eq!(&bytes[24 + 8..24 + 12], &(20 + 8 + 3u32).to_le_bytes());
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(packet[9], IPPROTO_UDP);
+        assert_eq!(sum_words(0, &packet[..20]), 0xFFFF);
+        assert_eq!(&packet[22..24], &3478u16.to_be_bytes());
+        assert_eq!(&packet[28..31], b"foo");
+
+        // TCP segments acknowledge each other
+        let packet = &bytes[24 + 16 + 31 + 16..];
+        assert_eq!(packet[9], IPPROTO_TCP);
+        assert_eq!(&packet[24..28], &1u32.to_be_bytes());
+        assert_eq!(&packet[40..46], b"barbaz");
+        let packet = &packet[46 + 16..];
+        assert_eq!(&packet[24..28], &1u32.to_be_bytes());
+        assert_eq!(&packet[28..32], &7u32.to_be_bytes());
+        assert_eq!(&packet[40..43], b"qux");
+        Ok(())
+    }
+
+    #[test]
+    fn pcap_reader_works() -> std::result::Result<(), trackable::error::MainError> {
+        // pcap
+        let buf = SharedBuf::default();
+        let capture = track!(PcapCapture::new(buf.clone()))?;
+        let client = "[2001:db8::1]:5000".parse().unwrap();
+        let server = "[2001:db8::2]:3478".parse().unwrap();
+        track!(capture.write_packet(CaptureProtocol::Udp, client, server, b"foo"))?;
+        track!(capture.write_packet(CaptureProtocol::Tcp, server, client, b"bar"))?;
+
+        let bytes = buf.0.lock().unwrap().clone();
+        let packets = track!(track!(PcapReader::new(&bytes[..]))?.read_all())?;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].protocol, CaptureProtocol::Udp);
+        assert_eq!((packets[0].src, packets[0].dst), (client, server));
+        assert_eq!(packets[0].payload, b"foo");
+        assert_eq!(packets[1].protocol, CaptureProtocol::Tcp);
+        assert_eq!((packets[1].src, packets[1].dst), (server, client));
+        assert_eq!(packets[1].payload, b"bar");
+
+        // pcapng (Ethernet)
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let server: SocketAddr = "127.0.0.1:3478".parse().unwrap();
+        let ip = ip_packet(
+            client.ip(),
+            server.ip(),
+            IPPROTO_UDP,
+            &udp_segment(client, server, b"baz"),
+        );
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&ip);
+        frame.resize(frame.len().div_ceil(4) * 4, 0);
+
+        let mut bytes = Vec::new();
+        let mut block = |block_type: u32, body: &[u8]| {
+            let len = (12 + body.len()) as u32;
+            bytes.extend_from_slice(&block_type.to_le_bytes());
+            bytes.extend_from_slice(&len.to_le_bytes());
+            bytes.extend_from_slice(body);
+            bytes.extend_from_slice(&len.to_le_bytes());
+        };
+        block(
+            PCAPNG_SHB_TYPE,
+            &[
+                0x4D, 0x3C, 0x2B, 0x1A, 1, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255,
+            ],
+        );
+        block(
+            PCAPNG_IDB_TYPE,
+            &[1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 9, 0, 0, 0],
+        );
+        let mut epb = Vec::new();
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&1_500_000_000u32.to_le_bytes());
+        epb.extend_from_slice(&((14 + ip.len()) as u32).to_le_bytes());
+        epb.extend_from_slice(&((14 + ip.len()) as u32).to_le_bytes());
+        epb.extend_from_slice(&frame);
+        block(PCAPNG_EPB_TYPE, &epb);
+
+        let packets = track!(track!(PcapReader::new(&bytes[..]))?.read_all())?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!((packets[0].src, packets[0].dst), (client, server));
+        assert_eq!(packets[0].payload, b"baz");
+        assert_eq!(
+            packets[0].timestamp,
+            UNIX_EPOCH + Duration::from_millis(1500)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pcap_reader_rejects_oversized_lengths(
+    ) -> std::result::Result<(), trackable::error::MainError> {
+        // pcap record
+        let buf = SharedBuf::default();
+        let capture = track!(PcapCapture::new(buf.clone()))?;
+        track!(capture.flush())?;
+        let mut bytes = buf.0.lock().unwrap().clone();
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = track!(PcapReader::new(&bytes[..]))?;
+        let error = reader.read_packet().err().unwrap();
+        assert_eq!(*error.kind(), ErrorKind::InvalidInput);
+
+        // pcapng section header block
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&PCAPNG_SHB_TYPE.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0x4D, 0x3C, 0x2B, 0x1A]);
+        let error = PcapReader::new(&bytes[..]).err().unwrap();
+        assert_eq!(*error.kind(), ErrorKind::InvalidInput);
+
+        // pcapng block
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&PCAPNG_SHB_TYPE.to_le_bytes());
+        bytes.extend_from_slice(&28u32.to_le_bytes());
+        bytes.extend_from_slice(&[0x4D, 0x3C, 0x2B, 0x1A, 1, 0, 0, 0]);
+        bytes.extend_from_slice(&[255; 8]);
+        bytes.extend_from_slice(&28u32.to_le_bytes());
+        bytes.extend_from_slice(&PCAPNG_EPB_TYPE.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = track!(PcapReader::new(&bytes[..]))?;
+        let error = reader.read_packet().err().unwrap();
+        assert_eq!(*error.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+use crate::clock::{Clock, SystemClock, Timeout};
+use bytecodec::{DecodeExt, EncodeExt};
+use fibers::sync::mpsc;
+use fibers_transport::{Error, ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::{Async, Future, Stream};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use stun_codec::{
+    Attribute, DecodedMessage, Message, MessageDecoder, MessageEncoder, TransactionId,
+};
+use trackable::error::ErrorKindExt;
+
+use super::StunTransport;
+
+/// [`SimulatedNetwork`] builder.
+///
+/// [`SimulatedNetwork`]: ./struct.SimulatedNetwork.html
+#[derive(Debug, Clone)]
+pub struct SimulatedNetworkBuilder {
+    latency: Duration,
+    jitter: Duration,
+    loss_rate: f64,
+    duplicate_rate: f64,
+    reorder_rate: f64,
+    seed: u64,
+    clock: Arc<dyn Clock>,
+}
+impl SimulatedNetworkBuilder {
+    /// The default seed of the random number generator used by the network.
+    pub const DEFAULT_SEED: u64 = 0;
+
+    /// Makes a new `SimulatedNetworkBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the one-way latency of the datagrams delivered by the resulting network.
+    ///
+    /// The default value is `Duration::from_millis(0)`.
+    pub fn latency(&mut self, latency: Duration) -> &mut Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Sets the maximum jitter added to the latency of each datagram.
+    ///
+    /// The actual jitter is uniformly chosen from the range `[0, jitter]`.
+    ///
+    /// The default value is `Duration::from_millis(0)`.
+    pub fn jitter(&mut self, jitter: Duration) -> &mut Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the probability that a datagram is dropped.
+    ///
+    /// The default value is `0.0`.
+    pub fn loss_rate(&mut self, rate: f64) -> &mut Self {
+        self.loss_rate = rate;
+        self
+    }
+
+    /// Sets the probability that a datagram is delivered twice.
+    ///
+    /// The default value is `0.0`.
+    pub fn duplicate_rate(&mut self, rate: f64) -> &mut Self {
+        self.duplicate_rate = rate;
+        self
+    }
+
+    /// Sets the probability that a datagram is held back so that datagrams sent after it can overtake it.
+    ///
+    /// A reordered datagram is delayed by an extra `latency + jitter` (at least one millisecond).
+    ///
+    /// The default value is `0.0`.
+    pub fn reorder_rate(&mut self, rate: f64) -> &mut Self {
+        self.reorder_rate = rate;
+        self
+    }
+
+    /// Sets the seed of the random number generator that decides the fate of each datagram.
+    ///
+    /// Networks built with the same settings and seed behave identically for the same sequence of sends.
+    ///
+    /// The default value is `DEFAULT_SEED`.
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sets the clock used for delaying datagrams of the resulting network.
+    ///
+    /// The default value is `SystemClock`.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Makes a new `SimulatedNetwork` instance with the given settings.
+    pub fn finish(&self) -> SimulatedNetwork {
+        let state = NetworkState {
+            settings: self.clone(),
+            rng: StdRng::seed_from_u64(self.seed),
+            endpoints: HashMap::new(),
+            next_port: 49152,
+            next_seqno: 0,
+            stats: NetworkStats::default(),
+        };
+        SimulatedNetwork {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+}
+impl Default for SimulatedNetworkBuilder {
+    fn default() -> Self {
+        SimulatedNetworkBuilder {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            seed: Self::DEFAULT_SEED,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// Statistics of the datagrams handled by a [`SimulatedNetwork`].
+///
+/// [`SimulatedNetwork`]: ./struct.SimulatedNetwork.html
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NetworkStats {
+    /// Number of datagrams sent by the endpoints.
+    pub sent: u64,
+
+    /// Number of datagrams dropped by the network (including ones sent to unbound addresses).
+    pub dropped: u64,
+
+    /// Number of extra copies made by the network.
+    pub duplicated: u64,
+
+    /// Number of datagrams held back by the network.
+    pub reordered: u64,
+
+    /// Number of datagrams received by the endpoints.
+    pub delivered: u64,
+}
+
+/// In-memory network that connects [`MemoryTransporter`] endpoints.
+///
+/// The network behaves like a lossy datagram network:
+/// it can drop, duplicate, reorder and delay datagrams according to the settings given by [`SimulatedNetworkBuilder`].
+/// Since the random number generator is seeded, the behavior is reproducible.
+///
+/// This is mainly intended for testing STUN clients and servers without real sockets.
+///
+/// [`MemoryTransporter`]: ./struct.MemoryTransporter.html
+/// [`SimulatedNetworkBuilder`]: ./struct.SimulatedNetworkBuilder.html
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+impl SimulatedNetwork {
+    /// Makes a new `SimulatedNetwork` instance.
+    ///
+    /// This is equivalent to `SimulatedNetworkBuilder::default().finish()`.
+    pub fn new() -> Self {
+        SimulatedNetworkBuilder::default().finish()
+    }
+
+    /// Makes a new endpoint bound to the given address.
+    ///
+    /// If the port of `addr` is `0`, an unused port is assigned.
+    ///
+    /// # Errors
+    ///
+    /// If the address is already bound by another endpoint, this will return an `ErrorKind::InvalidInput` error.
+    pub fn bind<A: Attribute>(&self, addr: SocketAddr) -> Result<MemoryTransporter<A>> {
+        let (local_addr, incoming_rx, clock) = {
+            let mut state = self.state.lock().expect("never fails");
+            let local_addr = if addr.port() == 0 {
+                track!(state.assign_port(addr.ip()))?
+            } else {
+                addr
+            };
+            track_assert!(
+                !state.endpoints.contains_key(&local_addr),
+                ErrorKind::InvalidInput,
+                "Address already in use: {}",
+                local_addr
+            );
+            let (tx, rx) = mpsc::channel();
+            state.endpoints.insert(local_addr, tx);
+            (local_addr, rx, state.settings.clock.clone())
+        };
+        Ok(MemoryTransporter {
+            network: self.clone(),
+            local_addr,
+            clock,
+            encoder: MessageEncoder::default(),
+            decoder: MessageDecoder::default(),
+            incoming_rx,
+            inbox: BinaryHeap::new(),
+            timeout: None,
+        })
+    }
+
+    /// Makes a new endpoint bound to an unused port of `127.0.0.1`.
+    pub fn bind_any<A: Attribute>(&self) -> Result<MemoryTransporter<A>> {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        track!(self.bind(addr))
+    }
+
+    /// Sends the given raw datagram from `from` to `to` as if it were sent by an endpoint bound to `from`.
+    ///
+    /// This is useful for delivering malformed messages or replaying captured datagrams.
+    /// The datagram is subject to the settings of the network like other datagrams.
+    pub fn send_raw(&self, from: SocketAddr, to: SocketAddr, bytes: Vec<u8>) {
+        self.send(from, to, bytes);
+    }
+
+    /// Returns the statistics of the network.
+    pub fn stats(&self) -> NetworkStats {
+        self.state.lock().expect("never fails").stats.clone()
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, bytes: Vec<u8>) {
+        let mut state = self.state.lock().expect("never fails");
+        state.send(from, to, bytes);
+    }
+
+    fn unbind(&self, addr: SocketAddr) {
+        if let Ok(mut state) = self.state.lock() {
+            state.endpoints.remove(&addr);
+        }
+    }
+}
+impl Default for SimulatedNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl fmt::Debug for SimulatedNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SimulatedNetwork {{ .. }}")
+    }
+}
+
+/// An implementation of [`UdpTransport`] and [`StunTransport`] that exchanges messages via a [`SimulatedNetwork`].
+///
+/// Note that this does not retransmit requests by itself.
+/// If the network may lose datagrams, wrap the transporter with [`StunUdpTransporter`].
+///
+/// The endpoint is unbound from the network when the transporter is dropped.
+///
+/// [`UdpTransport`]: https://docs.rs/fibers_transport/0.1/fibers_transport/trait.UdpTransport.html
+/// [`StunTransport`]: ./trait.StunTransport.html
+/// [`SimulatedNetwork`]: ./struct.SimulatedNetwork.html
+/// [`StunUdpTransporter`]: ./struct.StunUdpTransporter.html
+pub struct MemoryTransporter<A: Attribute> {
+    network: SimulatedNetwork,
+    local_addr: SocketAddr,
+    clock: Arc<dyn Clock>,
+    encoder: MessageEncoder<A>,
+    decoder: MessageDecoder<A>,
+    incoming_rx: mpsc::Receiver<Datagram>,
+    inbox: BinaryHeap<Reverse<Datagram>>,
+    timeout: Option<(SystemTime, Timeout)>,
+}
+impl<A: Attribute> MemoryTransporter<A> {
+    /// Returns a reference to the network to which the transporter belongs.
+    pub fn network(&self) -> &SimulatedNetwork {
+        &self.network
+    }
+
+    fn poll_deliver_time(&mut self, deliver_at: SystemTime) -> bool {
+        if self.timeout.as_ref().map(|t| t.0) != Some(deliver_at) {
+            let clock = &self.clock;
+            let delay = deliver_at.duration_since(clock.now()).unwrap_or_default();
+            self.timeout = Some((deliver_at, clock.timeout(delay)));
+        }
+        let timeout = &mut self.timeout.as_mut().expect("never fails").1;
+        if timeout.poll().map_or(true, |x| x.is_ready()) {
+            self.timeout = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+impl<A: Attribute> Transport for MemoryTransporter<A> {
+    type PeerAddr = SocketAddr;
+    type SendItem = Message<A>;
+    type RecvItem = DecodedMessage<A>;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let bytes = track!(self.encoder.encode_into_bytes(item))?;
+        self.network.send(self.local_addr, peer, bytes);
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        while let Async::Ready(datagram) = self.incoming_rx.poll().expect("never fails") {
+            let datagram = track_assert_some!(datagram, ErrorKind::Other, "Network disconnected");
+            self.inbox.push(Reverse(datagram));
+        }
+        loop {
+            let deliver_at = match self.inbox.peek() {
+                None => return Ok(Async::NotReady),
+                Some(d) => d.0.deliver_at,
+            };
+            if deliver_at <= self.clock.now() {
+                break;
+            }
+            if !self.poll_deliver_time(deliver_at) {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        let datagram = self.inbox.pop().expect("never fails").0;
+        self.timeout = None;
+        self.network
+            .state
+            .lock()
+            .expect("never fails")
+            .stats
+            .delivered += 1;
+        let item = track!(self.decoder.decode_from_bytes(&datagram.bytes); datagram.from)?;
+        Ok(Async::Ready(Some((datagram.from, item))))
+    }
+}
+impl<A: Attribute> UdpTransport for MemoryTransporter<A> {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+impl<A: Attribute> StunTransport<A> for MemoryTransporter<A> {
+    fn finish_transaction(
+        &mut self,
+        _peer: &SocketAddr,
+        _transaction_id: TransactionId,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_transient_error(&self, error: &Error) -> bool {
+        super::is_transient_datagram_error(error)
+    }
+}
+impl<A: Attribute> Drop for MemoryTransporter<A> {
+    fn drop(&mut self) {
+        self.network.unbind(self.local_addr);
+    }
+}
+impl<A: Attribute> fmt::Debug for MemoryTransporter<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MemoryTransporter {{ local_addr: {:?}, .. }}",
+            self.local_addr
+        )
+    }
+}
+
+struct NetworkState {
+    settings: SimulatedNetworkBuilder,
+    rng: StdRng,
+    endpoints: HashMap<SocketAddr, mpsc::Sender<Datagram>>,
+    next_port: u16,
+    next_seqno: u64,
+    stats: NetworkStats,
+}
+impl NetworkState {
+    fn assign_port(&mut self, ip: IpAddr) -> Result<SocketAddr> {
+        for _ in 0..=u16::MAX {
+            let addr = SocketAddr::new(ip, self.next_port);
+            self.next_port = self.next_port.checked_add(1).unwrap_or(49152);
+            if !self.endpoints.contains_key(&addr) {
+                return Ok(addr);
+            }
+        }
+        Err(track!(ErrorKind::Other.cause("No available port")).into())
+    }
+
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, bytes: Vec<u8>) {
+        self.stats.sent += 1;
+        if self.rng.gen_bool(self.settings.loss_rate) || !self.endpoints.contains_key(&to) {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let copies = if self.rng.gen_bool(self.settings.duplicate_rate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let now = self.settings.clock.now();
+        for _ in 0..copies {
+            let delay = self.delay();
+            let datagram = Datagram {
+                deliver_at: now + delay,
+                seqno: self.next_seqno,
+                from,
+                bytes: bytes.clone(),
+            };
+            self.next_seqno += 1;
+
+            let _ = self.endpoints[&to].send(datagram);
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        let settings = &self.settings;
+        let mut delay = settings.latency;
+        if settings.jitter > Duration::from_millis(0) {
+            delay += settings.jitter.mul_f64(self.rng.gen::<f64>());
+        }
+        if self.rng.gen_bool(settings.reorder_rate) {
+            self.stats.reordered += 1;
+            delay += std::cmp::max(settings.latency + settings.jitter, Duration::from_millis(1));
+        }
+        delay
+    }
+}
+
+struct Datagram {
+    deliver_at: SystemTime,
+    seqno: u64,
+    from: SocketAddr,
+    bytes: Vec<u8>,
+}
+impl PartialOrd for Datagram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Datagram {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.seqno).cmp(&(other.deliver_at, other.seqno))
+    }
+}
+impl PartialEq for Datagram {
+    fn eq(&self, other: &Self) -> bool {
+        self.seqno == other.seqno
+    }
+}
+impl Eq for Datagram {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{Channel, ChannelBuilder};
+    use crate::client::Client;
+    use crate::message::{MessageErrorKind, Request};
+    use crate::server::{BindingHandler, UdpServer};
+    use crate::transport::{StunUdpTransporter, StunUdpTransporterBuilder};
+    use crate::ErrorKind as StunErrorKind;
+    use futures::future;
+    use stun_codec::rfc5389;
+    use trackable::error::MainError;
+
+    #[test]
+    fn binding_over_simulated_network_works() -> std::result::Result<(), MainError> {
+        let network = SimulatedNetwork::new();
+        let server = UdpServer::with_transporter(
+            fibers_global::handle(),
+            track!(network.bind_any())?,
+            BindingHandler,
+        );
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let transporter = track!(network.bind_any())?;
+        let client_addr = transporter.local_addr();
+        let client = Client::new(&fibers_global::handle(), Channel::new(transporter));
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        let mapped = response.ok().and_then(|r| {
+            r.get_attribute::<rfc5389::attributes::XorMappedAddress>()
+                .map(|a| a.address())
+        });
+        assert_eq!(mapped, Some(client_addr));
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_retransmitted_over_lossy_network() -> std::result::Result<(), MainError> {
+        let network = SimulatedNetworkBuilder::new()
+            .latency(Duration::from_millis(5))
+            .jitter(Duration::from_millis(5))
+            .loss_rate(0.3)
+            .duplicate_rate(0.2)
+            .reorder_rate(0.2)
+            .seed(1)
+            .finish();
+        let server = UdpServer::with_transporter(
+            fibers_global::handle(),
+            track!(network.bind_any())?,
+            BindingHandler,
+        );
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_millis(20))
+            .min_transaction_interval(Duration::from_millis(0))
+            .finish(track!(network.bind_any())?);
+        let client = Client::new(&fibers_global::handle(), Channel::new(transporter));
+
+        let calls = (0..20)
+            .map(|_| {
+                let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                client.call(server_addr, request)
+            })
+            .collect::<Vec<_>>();
+        let responses = track!(fibers_global::execute(future::join_all(calls)))?;
+        assert!(responses.iter().all(|r| r.is_ok()));
+
+        let stats = network.stats();
+        assert!(stats.dropped > 0);
+        assert!(stats.sent > 40);
+        Ok(())
+    }
+
+    #[test]
+    fn request_to_unreachable_peer_times_out() -> std::result::Result<(), MainError> {
+        let network = SimulatedNetwork::new();
+        let unreachable_addr = "127.0.0.1:3478".parse().unwrap();
+
+        let transporter: StunUdpTransporter<rfc5389::Attribute, _> =
+            StunUdpTransporterBuilder::new()
+                .rto(Duration::from_millis(5))
+                .finish(track!(network.bind_any())?);
+        let channel = ChannelBuilder::new()
+            .request_timeout(Duration::from_millis(100))
+            .finish(transporter);
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let result = fibers_global::execute(client.call(unreachable_addr, request));
+        assert!(matches!(
+            result.err().map(|e| e.kind().clone()),
+            Some(StunErrorKind::InvalidMessage(MessageErrorKind::Timeout))
+        ));
+
+        let stats = network.stats();
+        assert!(stats.sent > 1);
+        assert_eq!(stats.sent, stats.dropped);
+        Ok(())
+    }
+}
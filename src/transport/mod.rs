@@ -1,10 +1,30 @@
 //! Transport layer abstractions and its built-in implementations.
-use fibers_transport::{FixedPeerTransporter, PeerAddr, Result, Transport};
+use crate::channel::CallOptions;
+use fibers_transport::{Error, FixedPeerTransporter, PeerAddr, Result, Transport};
+use std::io;
 use stun_codec::{Attribute, DecodedMessage, Message, TransactionId};
 
+#[cfg(target_os = "linux")]
+pub use self::batch_udp::{BatchUdpTransporter, BatchUdpTransporterBuilder};
+pub(crate) use self::capture::CaptureDecoderFactory;
+pub use self::capture::{
+    CaptureCodec, CaptureDecoder, CaptureEncoder, CapturePeerAddr, CaptureProtocol,
+    CaptureTransporter, CapturedPacket, PcapCapture, PcapReader,
+};
+pub use self::memory::{
+    MemoryTransporter, NetworkStats, SimulatedNetwork, SimulatedNetworkBuilder,
+};
+pub use self::rfc3489::{Rfc3489Compat, Rfc3489Decoder, Rfc3489Encoder, Rfc3489UdpTransporter};
+pub use self::std_udp::StdUdpTransporter;
 pub use self::tcp::StunTcpTransporter;
-pub use self::udp::{StunUdpTransporter, StunUdpTransporterBuilder};
+pub use self::udp::{StunUdpTransporter, StunUdpTransporterBuilder, TooManyPendingRequests};
 
+#[cfg(target_os = "linux")]
+mod batch_udp;
+mod capture;
+mod memory;
+mod rfc3489;
+mod std_udp;
 mod tcp;
 mod udp;
 
@@ -13,12 +33,39 @@ pub trait StunTransport<A>: Transport<SendItem = Message<A>, RecvItem = DecodedM
 where
     A: Attribute,
 {
+    /// Starts sending the given request message that begins a new request/response transaction.
+    ///
+    /// The default implementation ignores `options` and simply calls `start_send` method.
+    #[allow(unused_variables)]
+    fn start_request(
+        &mut self,
+        peer: Self::PeerAddr,
+        request: Message<A>,
+        options: &CallOptions,
+    ) -> Result<()> {
+        track!(self.start_send(peer, request))
+    }
+
     /// Finishes a request/response transaction.
     fn finish_transaction(
         &mut self,
         peer: &Self::PeerAddr,
         transaction_id: TransactionId,
     ) -> Result<()>;
+
+    /// Returns `true` if the transporter can continue to be used after the given error
+    /// returned by the transporter.
+    ///
+    /// Such an error (e.g., `ENETUNREACH` on sending a datagram) affects only the message being sent or received.
+    /// Otherwise, the transporter is regarded as broken and is never used again by [`Channel`].
+    ///
+    /// The default implementation always returns `false`.
+    ///
+    /// [`Channel`]: ../channel/struct.Channel.html
+    #[allow(unused_variables)]
+    fn is_transient_error(&self, error: &Error) -> bool {
+        false
+    }
 }
 impl<A, T, P> StunTransport<A> for FixedPeerTransporter<T, P>
 where
@@ -26,8 +73,43 @@ where
     T: StunTransport<A>,
     P: PeerAddr,
 {
+    fn start_request(
+        &mut self,
+        _peer: P,
+        request: Message<A>,
+        options: &CallOptions,
+    ) -> Result<()> {
+        let peer = self.interior_peer().clone();
+        track!(self.inner_mut().start_request(peer, request, options))
+    }
+
     fn finish_transaction(&mut self, _peer: &P, transaction_id: TransactionId) -> Result<()> {
         let peer = self.interior_peer().clone();
         track!(self.inner_mut().finish_transaction(&peer, transaction_id))
     }
+
+    fn is_transient_error(&self, error: &Error) -> bool {
+        self.inner_ref().is_transient_error(error)
+    }
+}
+
+/// Returns `true` if the given error returned by a datagram oriented transporter
+/// affects only a single datagram.
+///
+/// Errors other than I/O errors (e.g., malformed datagrams) are regarded as transient.
+fn is_transient_datagram_error(error: &Error) -> bool {
+    error.concrete_cause::<io::Error>().is_none_or(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::NetworkDown
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::PermissionDenied
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+        )
+    })
 }
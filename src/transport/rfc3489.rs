@@ -0,0 +1,489 @@
+//! Compatibility mode for the classic STUN protocol defined in [RFC 3489].
+//!
+//! [RFC 3489]: https://tools.ietf.org/html/rfc3489
+use crate::message::{decode_address, encode_address};
+use bytecodec::bytes::BytesEncoder;
+use bytecodec::{ByteCount, Decode, DecodeExt, Encode, EncodeExt, Eos};
+use fibers_transport::{UdpTransporter, UdpTransporterBuilder};
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use stun_codec::{
+    Attribute, DecodedMessage, Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
+};
+
+const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xA4, 0x42];
+const HEADER_SIZE: usize = 20;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const CHANGE_REQUEST: u16 = 0x0003;
+const SOURCE_ADDRESS: u16 = 0x0004;
+const CHANGED_ADDRESS: u16 = 0x0005;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FINGERPRINT: u16 = 0x8028;
+
+/// UDP transporter that accepts both [RFC 5389] and classic [RFC 3489] messages.
+///
+/// See [`Rfc3489Compat`] for details.
+///
+/// [RFC 5389]: https://tools.ietf.org/html/rfc5389
+/// [RFC 3489]: https://tools.ietf.org/html/rfc3489
+/// [`Rfc3489Compat`]: ./struct.Rfc3489Compat.html
+pub type Rfc3489UdpTransporter<A> = UdpTransporter<Rfc3489Encoder<A>, Rfc3489Decoder<A>>;
+
+/// Opt-in compatibility mode for legacy clients speaking the classic STUN protocol ([RFC 3489]).
+///
+/// Classic messages have no magic cookie, and their transaction IDs are 128 bits long.
+/// Without this mode, such messages are regarded as broken ones.
+///
+/// The decoder made by this accepts classic requests as if they were [RFC 5389] messages
+/// (the first 32 bits of the transaction ID are replaced with the magic cookie), and
+/// the encoder restores the original transaction ID in the responses to them.
+/// In addition, the responses are converted as follows:
+///
+/// - `XOR-MAPPED-ADDRESS` is replaced with a plain `MAPPED-ADDRESS`
+/// - `MESSAGE-INTEGRITY` and `FINGERPRINT` are removed
+/// - `SOURCE-ADDRESS` and `CHANGED-ADDRESS` are appended if configured
+///
+/// Thus existing handlers such as [`BindingHandler`] can serve classic clients as is.
+/// [RFC 5389] messages are handled as usual.
+///
+/// The transporter made by [`Rfc3489Compat::bind`] can be used by servers
+/// (see also [`UdpServer::start_rfc3489_compatible`]) and channels
+/// (wrap it with [`StunUdpTransporter`]) alike.
+///
+/// A `CHANGE-REQUEST` attribute whose flags are all zero is silently dropped from classic requests.
+/// The other `CHANGE-REQUEST` attributes are passed to the handler
+/// (they are reported as unknown attributes unless the attribute set of the handler supports them).
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate fibers_global;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use rustun::server::{BindingHandler, UdpServer};
+/// use rustun::transport::Rfc3489Compat;
+///
+/// let addr = "0.0.0.0:3478".parse().unwrap();
+/// let mut compat = Rfc3489Compat::new();
+/// compat.source_address("192.0.2.1:3478".parse().unwrap());
+/// let server = fibers_global::execute(UdpServer::start_rfc3489_compatible(
+///     fibers_global::handle(),
+///     addr,
+///     BindingHandler,
+///     &compat,
+/// ))?;
+/// fibers_global::execute(server)?;
+/// # Ok::<(), rustun::Error>(())
+/// ```
+///
+/// [RFC 5389]: https://tools.ietf.org/html/rfc5389
+/// [RFC 3489]: https://tools.ietf.org/html/rfc3489
+/// [`BindingHandler`]: ../server/struct.BindingHandler.html
+/// [`Rfc3489Compat::bind`]: ./struct.Rfc3489Compat.html#method.bind
+/// [`UdpServer::start_rfc3489_compatible`]: ../server/struct.UdpServer.html#method.start_rfc3489_compatible
+/// [`StunUdpTransporter`]: ./struct.StunUdpTransporter.html
+#[derive(Debug, Clone)]
+pub struct Rfc3489Compat {
+    source_address: Option<SocketAddr>,
+    changed_address: Option<SocketAddr>,
+    transactions: Arc<Mutex<ClassicTransactions>>,
+}
+impl Rfc3489Compat {
+    /// The maximum number of classic transactions waiting for their responses.
+    ///
+    /// If the number is exceeded, the oldest transaction is forgotten
+    /// (i.e., its response will be sent as an [RFC 5389] message).
+    ///
+    /// [RFC 5389]: https://tools.ietf.org/html/rfc5389
+    pub const MAX_PENDING_TRANSACTIONS: usize = 1024;
+
+    /// Makes a new `Rfc3489Compat` instance.
+    pub fn new() -> Self {
+        Rfc3489Compat {
+            source_address: None,
+            changed_address: None,
+            transactions: Arc::new(Mutex::new(ClassicTransactions::default())),
+        }
+    }
+
+    /// Sets the address included in the responses to classic requests as `SOURCE-ADDRESS`.
+    ///
+    /// It is usually the address to which the server is bound.
+    pub fn source_address(&mut self, addr: SocketAddr) -> &mut Self {
+        self.source_address = Some(addr);
+        self
+    }
+
+    /// Sets the address included in the responses to classic requests as `CHANGED-ADDRESS`.
+    pub fn changed_address(&mut self, addr: SocketAddr) -> &mut Self {
+        self.changed_address = Some(addr);
+        self
+    }
+
+    /// Returns `true` if the given transaction has been started by a classic request
+    /// and has not been responded yet.
+    pub fn is_classic_transaction(&self, transaction_id: TransactionId) -> bool {
+        self.lock_transactions()
+            .prefixes
+            .contains_key(&transaction_id)
+    }
+
+    /// Makes a new encoder.
+    ///
+    /// The encoder must be used together with a decoder made by the same `Rfc3489Compat` instance.
+    pub fn encoder<A: Attribute>(&self) -> Rfc3489Encoder<A> {
+        Rfc3489Encoder {
+            inner: MessageEncoder::default(),
+            bytes: BytesEncoder::new(),
+            source_address: self.source_address,
+            changed_address: self.changed_address,
+            transactions: Arc::clone(&self.transactions),
+        }
+    }
+
+    /// Makes a new decoder.
+    pub fn decoder<A: Attribute>(&self) -> Rfc3489Decoder<A> {
+        Rfc3489Decoder {
+            inner: MessageDecoder::default(),
+            buf: Vec::new(),
+            transactions: Arc::clone(&self.transactions),
+        }
+    }
+
+    /// Makes a new UDP transporter bound to the given address.
+    pub fn bind<A: Attribute>(
+        &self,
+        addr: SocketAddr,
+    ) -> impl Future<Item = Rfc3489UdpTransporter<A>, Error = fibers_transport::Error> {
+        UdpTransporterBuilder::with_codec(self.encoder(), self.decoder()).bind(addr)
+    }
+
+    fn lock_transactions(&self) -> std::sync::MutexGuard<'_, ClassicTransactions> {
+        self.transactions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Default for Rfc3489Compat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The first 32 bits of the transaction IDs of pending classic transactions.
+#[derive(Debug, Default)]
+struct ClassicTransactions {
+    prefixes: HashMap<TransactionId, [u8; 4]>,
+    order: VecDeque<TransactionId>,
+}
+impl ClassicTransactions {
+    fn insert(&mut self, transaction_id: TransactionId, prefix: [u8; 4]) {
+        if self.prefixes.insert(transaction_id, prefix).is_none() {
+            self.order.push_back(transaction_id);
+        }
+        while self.order.len() > Rfc3489Compat::MAX_PENDING_TRANSACTIONS {
+            if let Some(id) = self.order.pop_front() {
+                self.prefixes.remove(&id);
+            }
+        }
+    }
+
+    fn remove(&mut self, transaction_id: TransactionId) -> Option<[u8; 4]> {
+        let prefix = self.prefixes.remove(&transaction_id)?;
+        self.order.retain(|id| *id != transaction_id);
+        Some(prefix)
+    }
+}
+
+/// Encoder of [`Rfc3489Compat`].
+///
+/// [`Rfc3489Compat`]: ./struct.Rfc3489Compat.html
+#[derive(Debug)]
+pub struct Rfc3489Encoder<A: Attribute> {
+    inner: MessageEncoder<A>,
+    bytes: BytesEncoder<Vec<u8>>,
+    source_address: Option<SocketAddr>,
+    changed_address: Option<SocketAddr>,
+    transactions: Arc<Mutex<ClassicTransactions>>,
+}
+impl<A: Attribute> Rfc3489Encoder<A> {
+    fn take_classic_prefix(&self, item: &Message<A>) -> Option<[u8; 4]> {
+        if item.class() == MessageClass::Request || item.class() == MessageClass::Indication {
+            return None;
+        }
+        self.transactions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(item.transaction_id())
+    }
+
+    fn classicize(&self, bytes: &[u8], prefix: [u8; 4]) -> Vec<u8> {
+        let transaction_id = transaction_id_of(bytes);
+        let attrs = attributes(&bytes[HEADER_SIZE..]).collect::<Vec<_>>();
+        let has = |ty| attrs.iter().any(|&(t, _)| t == ty);
+
+        let mut classic = bytes[..HEADER_SIZE].to_vec();
+        classic[4..8].copy_from_slice(&prefix);
+        for &(ty, value) in &attrs {
+            match ty {
+                MESSAGE_INTEGRITY | FINGERPRINT => {}
+                XOR_MAPPED_ADDRESS => {
+                    if has(MAPPED_ADDRESS) {
+                        continue;
+                    }
+                    if let Some(addr) = decode_address(value, Some(transaction_id)) {
+                        push_attribute(&mut classic, MAPPED_ADDRESS, &encode_address(addr, None));
+                    }
+                }
+                _ => push_attribute(&mut classic, ty, value),
+            }
+        }
+        for (ty, addr) in [
+            (SOURCE_ADDRESS, self.source_address),
+            (CHANGED_ADDRESS, self.changed_address),
+        ] {
+            if let Some(addr) = addr.filter(|_| !has(ty)) {
+                push_attribute(&mut classic, ty, &encode_address(addr, None));
+            }
+        }
+
+        let len = (classic.len() - HEADER_SIZE) as u16;
+        classic[2..4].copy_from_slice(&len.to_be_bytes());
+        classic
+    }
+}
+impl<A: Attribute> Encode for Rfc3489Encoder<A> {
+    type Item = Message<A>;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.bytes.encode(buf, eos))
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        let prefix = self.take_classic_prefix(&item);
+        let mut bytes = track!(self.inner.encode_into_bytes(item))?;
+        if let Some(prefix) = prefix {
+            bytes = self.classicize(&bytes, prefix);
+        }
+        track!(self.bytes.start_encoding(bytes))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.bytes.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.bytes.is_idle()
+    }
+}
+
+/// Decoder of [`Rfc3489Compat`].
+///
+/// [`Rfc3489Compat`]: ./struct.Rfc3489Compat.html
+#[derive(Debug)]
+pub struct Rfc3489Decoder<A: Attribute> {
+    inner: MessageDecoder<A>,
+    buf: Vec<u8>,
+    transactions: Arc<Mutex<ClassicTransactions>>,
+}
+impl<A: Attribute> Rfc3489Decoder<A> {
+    fn message_size(&self) -> Option<usize> {
+        if self.buf.len() < HEADER_SIZE {
+            None
+        } else {
+            Some(HEADER_SIZE + u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize)
+        }
+    }
+
+    fn modernize(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut prefix = [0; 4];
+        prefix.copy_from_slice(&bytes[4..8]);
+
+        let mut modern = bytes[..HEADER_SIZE].to_vec();
+        modern[4..8].copy_from_slice(&MAGIC_COOKIE);
+        for (ty, value) in attributes(&bytes[HEADER_SIZE..]) {
+            if ty == CHANGE_REQUEST && value.iter().all(|&b| b == 0) {
+                continue;
+            }
+            push_attribute(&mut modern, ty, value);
+        }
+        let len = (modern.len() - HEADER_SIZE) as u16;
+        modern[2..4].copy_from_slice(&len.to_be_bytes());
+
+        self.transactions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(transaction_id_of(&modern), prefix);
+        modern
+    }
+}
+impl<A: Attribute> Decode for Rfc3489Decoder<A> {
+    type Item = DecodedMessage<A>;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let mut offset = 0;
+        if self.buf.len() < HEADER_SIZE {
+            let size = buf.len().min(HEADER_SIZE - self.buf.len());
+            self.buf.extend_from_slice(&buf[..size]);
+            offset += size;
+        }
+        if let Some(message_size) = self.message_size() {
+            let size = (buf.len() - offset).min(message_size - self.buf.len());
+            self.buf.extend_from_slice(&buf[offset..][..size]);
+            offset += size;
+        }
+        if eos.is_reached() && offset == buf.len() && !(self.is_idle() || self.buf.is_empty()) {
+            // The decoder is never reset by the transporter, thus the truncated message is discarded here
+            self.buf.clear();
+            track_panic!(bytecodec::ErrorKind::UnexpectedEos);
+        }
+        Ok(offset)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        if !self.is_idle() {
+            self.buf.clear();
+            track_panic!(bytecodec::ErrorKind::IncompleteDecoding);
+        }
+        let bytes = std::mem::take(&mut self.buf);
+        let is_classic = bytes[4..8] != MAGIC_COOKIE
+            && bytes[0] >> 6 == 0
+            && u16::from_be_bytes([bytes[0], bytes[1]]) & 0x0110 == 0;
+        if is_classic {
+            let modern = self.modernize(&bytes);
+            track!(self.inner.decode_from_bytes(&modern))
+        } else {
+            track!(self.inner.decode_from_bytes(&bytes))
+        }
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        match self.message_size() {
+            None => ByteCount::Finite((HEADER_SIZE - self.buf.len()) as u64),
+            Some(size) => ByteCount::Finite((size - self.buf.len()) as u64),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.message_size() == Some(self.buf.len())
+    }
+}
+
+fn transaction_id_of(bytes: &[u8]) -> TransactionId {
+    let mut id = [0; 12];
+    id.copy_from_slice(&bytes[8..HEADER_SIZE]);
+    TransactionId::new(id)
+}
+
+/// Iterates over the `(type, value)` pairs of the attributes in the given message body.
+///
+/// A truncated trailing attribute is ignored.
+fn attributes(mut body: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if body.len() < 4 {
+            return None;
+        }
+        let ty = u16::from_be_bytes([body[0], body[1]]);
+        let len = u16::from_be_bytes([body[2], body[3]]) as usize;
+        let value = body.get(4..4 + len)?;
+        let padded_len = (4 + len).div_ceil(4) * 4;
+        body = body.get(padded_len..).unwrap_or(&[]);
+        Some((ty, value))
+    })
+}
+
+fn push_attribute(bytes: &mut Vec<u8>, ty: u16, value: &[u8]) {
+    bytes.extend_from_slice(&ty.to_be_bytes());
+    bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(value);
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stun_codec::rfc5389::attributes::{Fingerprint, XorMappedAddress};
+    use stun_codec::rfc5389::{methods::BINDING, Attribute};
+    use trackable::result::TestResult;
+
+    #[test]
+    fn rfc3489_compat_works() -> TestResult {
+        let mut compat = Rfc3489Compat::new();
+        compat
+            .source_address("192.0.2.1:3478".parse().unwrap())
+            .changed_address("192.0.2.2:3479".parse().unwrap());
+        let mut decoder = compat.decoder::<Attribute>();
+        let mut encoder = compat.encoder::<Attribute>();
+
+        // A classic Binding request with a zero CHANGE-REQUEST attribute
+        let mut request = vec![0x00, 0x01, 0x00, 0x08];
+        request.extend((1..=16).collect::<Vec<u8>>());
+        request.extend([0x00, 0x03, 0x00, 0x04, 0, 0, 0, 0]);
+
+        let Ok(request) = track!(decoder.decode_from_bytes(&request))? else {
+            panic!("classic request is regarded as broken");
+        };
+        assert_eq!(request.class(), MessageClass::Request);
+        assert_eq!(request.method(), BINDING);
+        assert_eq!(request.attributes().count(), 0);
+        assert!(compat.is_classic_transaction(request.transaction_id()));
+
+        let peer = "198.51.100.7:5000".parse().unwrap();
+        let mut response = Message::new(
+            MessageClass::SuccessResponse,
+            BINDING,
+            request.transaction_id(),
+        );
+        response.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(peer)));
+        response.add_attribute(Attribute::Fingerprint(track!(Fingerprint::new(&response))?));
+        let bytes = track!(encoder.encode_into_bytes(response))?;
+        assert!(!compat.is_classic_transaction(request.transaction_id()));
+
+        let mut expected = vec![0x01, 0x01, 0x00, 0x24];
+        expected.extend((1..=16).collect::<Vec<u8>>());
+        expected.extend([
+            0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x13, 0x88, 198, 51, 100, 7,
+        ]);
+        expected.extend([0x00, 0x04, 0x00, 0x08, 0x00, 0x01, 0x0D, 0x96, 192, 0, 2, 1]);
+        expected.extend([0x00, 0x05, 0x00, 0x08, 0x00, 0x01, 0x0D, 0x97, 192, 0, 2, 2]);
+        assert_eq!(bytes, expected);
+
+        // RFC 5389 messages are left as is
+        let mut request =
+            Message::<Attribute>::new(MessageClass::Request, BINDING, TransactionId::new([3; 12]));
+        request.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(peer)));
+        let bytes = track!(MessageEncoder::default().encode_into_bytes(request.clone()))?;
+        let decoded = track!(decoder.decode_from_bytes(&bytes))?;
+        assert_eq!(
+            decoded.ok().map(|m| m.transaction_id()),
+            Some(request.transaction_id())
+        );
+        assert!(!compat.is_classic_transaction(request.transaction_id()));
+        assert_eq!(track!(encoder.encode_into_bytes(request))?, bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_datagram_does_not_break_decoder() -> TestResult {
+        let compat = Rfc3489Compat::new();
+        let mut decoder = compat.decoder::<Attribute>();
+
+        assert!(decoder
+            .decode_from_bytes(&[0x00, 0x01, 0x00, 0x08, 0x21])
+            .is_err());
+
+        let request =
+            Message::<Attribute>::new(MessageClass::Request, BINDING, TransactionId::new([5; 12]));
+        let bytes = track!(MessageEncoder::default().encode_into_bytes(request.clone()))?;
+        for _ in 0..2 {
+            let decoded = track!(decoder.decode_from_bytes(&bytes))?;
+            assert_eq!(
+                decoded.ok().map(|m| m.transaction_id()),
+                Some(request.transaction_id())
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use bytecodec::{DecodeExt, EncodeExt};
+use fibers::fiber;
+use fibers::io::poll::{EventedHandle, Interest, Register};
+use fibers::sync::oneshot::{Monitor, MonitorError};
+use fibers_transport::{Error, ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::{Async, Future, Poll};
+use mio::net::UdpSocket as MioUdpSocket;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use stun_codec::{
+    Attribute, DecodedMessage, Message, MessageDecoder, MessageEncoder, TransactionId,
+};
+use trackable::error::ErrorKindExt;
+
+use super::StunTransport;
+
+const RECV_BUF_SIZE: usize = 4096;
+
+/// An implementation of [`UdpTransport`] that uses a socket created by [`std::net::UdpSocket`].
+///
+/// Unlike `fibers_transport::UdpTransporter`, this can use sockets configured before binding
+/// (e.g., sockets with `SO_REUSEPORT` enabled).
+/// In addition, I/O errors that only affect a single datagram (e.g., `ENETUNREACH`) are transient,
+/// thus clients using this transporter survive them.
+///
+/// [`UdpTransport`]: https://docs.rs/fibers_transport/0.1/fibers_transport/trait.UdpTransport.html
+/// [`std::net::UdpSocket`]: https://doc.rust-lang.org/std/net/struct.UdpSocket.html
+pub struct StdUdpTransporter<A: Attribute> {
+    socket: Arc<EventedHandle<MioUdpSocket>>,
+    local_addr: SocketAddr,
+    encoder: MessageEncoder<A>,
+    decoder: MessageDecoder<A>,
+    outgoing_queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    recv_buf: Vec<u8>,
+    read_monitor: Option<Monitor<(), io::Error>>,
+    write_monitor: Option<Monitor<(), io::Error>>,
+}
+impl<A: Attribute> StdUdpTransporter<A> {
+    /// Makes a future that registers the given socket to the I/O poller of the current fiber and
+    /// returns a new `StdUdpTransporter` instance which uses the socket.
+    ///
+    /// Note that the resulting future must be polled on a fiber.
+    pub fn from_socket(socket: UdpSocket) -> impl Future<Item = Self, Error = Error> {
+        RegisterSocket::new(socket).map(|(local_addr, socket)| StdUdpTransporter {
+            socket,
+            local_addr,
+            encoder: MessageEncoder::default(),
+            decoder: MessageDecoder::default(),
+            outgoing_queue: VecDeque::new(),
+            recv_buf: vec![0; RECV_BUF_SIZE],
+            read_monitor: None,
+            write_monitor: None,
+        })
+    }
+
+    /// Makes a future that creates a UDP socket bound to the given address.
+    pub fn bind(bind_addr: SocketAddr) -> impl Future<Item = Self, Error = Error> {
+        use futures::future;
+
+        match UdpSocket::bind(bind_addr) {
+            Err(e) => future::Either::A(future::err(track!(Error::from(e)))),
+            Ok(socket) => future::Either::B(Self::from_socket(socket)),
+        }
+    }
+
+    /// Makes a future that creates a UDP socket bound to the given address with `SO_REUSEPORT` enabled.
+    ///
+    /// Multiple transporters can be bound to the same address by this method,
+    /// and the kernel distributes incoming datagrams among them.
+    #[cfg(unix)]
+    pub fn bind_reuse_port(bind_addr: SocketAddr) -> impl Future<Item = Self, Error = Error> {
+        use futures::future;
+
+        match bind_reuse_port(bind_addr) {
+            Err(e) => future::Either::A(future::err(track!(Error::from(e)))),
+            Ok(socket) => future::Either::B(Self::from_socket(socket)),
+        }
+    }
+
+    /// Makes a future that creates a UDP socket bound to `[::]:port` with `IPV6_V6ONLY` disabled.
+    ///
+    /// The socket accepts both IPv4 and IPv6 peers,
+    /// and the addresses of IPv4 peers are represented as IPv4-mapped IPv6 addresses.
+    ///
+    /// # Errors
+    ///
+    /// If the platform does not support dual-stack sockets, this will return an error.
+    pub fn bind_dual_stack(port: u16) -> impl Future<Item = Self, Error = Error> {
+        use futures::future;
+
+        match bind_dual_stack(port) {
+            Err(e) => future::Either::A(future::err(track!(Error::from(e)))),
+            Ok(socket) => future::Either::B(Self::from_socket(socket)),
+        }
+    }
+
+    /// Returns the number of unsent messages in the queue of the instance.
+    pub fn message_queue_len(&self) -> usize {
+        self.outgoing_queue.len()
+    }
+}
+impl<A: Attribute> Transport for StdUdpTransporter<A> {
+    type PeerAddr = SocketAddr;
+    type SendItem = Message<A>;
+    type RecvItem = DecodedMessage<A>;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let bytes = track!(self.encoder.encode_into_bytes(item))?;
+        self.outgoing_queue.push_back((peer, bytes));
+        track!(self.poll_send())?;
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        while track!(poll_monitor(&mut self.write_monitor))?.is_ready() {
+            let (peer, bytes) = if let Some(x) = self.outgoing_queue.pop_front() {
+                x
+            } else {
+                return Ok(Async::Ready(()));
+            };
+            match self.socket.inner().send_to(&bytes, &peer) {
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        return Err(track!(Error::from(e)));
+                    }
+                    self.outgoing_queue.push_front((peer, bytes));
+                    self.write_monitor = Some(self.socket.monitor(Interest::Write));
+                }
+                Ok(size) => {
+                    track_assert_eq!(size, bytes.len(), ErrorKind::Other);
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        while track!(poll_monitor(&mut self.read_monitor))?.is_ready() {
+            let result = self.socket.inner().recv_from(&mut self.recv_buf);
+            match result {
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        return Err(track!(Error::from(e)));
+                    }
+                    self.read_monitor = Some(self.socket.monitor(Interest::Read));
+                }
+                Ok((size, peer)) => {
+                    let item =
+                        track!(self.decoder.decode_from_bytes(&self.recv_buf[..size]); peer)?;
+                    return Ok(Async::Ready(Some((peer, item))));
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<A: Attribute> UdpTransport for StdUdpTransporter<A> {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+impl<A: Attribute> StunTransport<A> for StdUdpTransporter<A> {
+    fn finish_transaction(
+        &mut self,
+        _peer: &SocketAddr,
+        _transaction_id: TransactionId,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_transient_error(&self, error: &Error) -> bool {
+        super::is_transient_datagram_error(error)
+    }
+}
+impl<A: Attribute> fmt::Debug for StdUdpTransporter<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "StdUdpTransporter {{ local_addr: {:?}, .. }}",
+            self.local_addr
+        )
+    }
+}
+
+/// Future that registers a socket to the I/O poller of the current fiber.
+pub(super) struct RegisterSocket {
+    socket: Option<UdpSocket>,
+    register: Option<(SocketAddr, Register<MioUdpSocket>)>,
+}
+impl RegisterSocket {
+    pub fn new(socket: UdpSocket) -> Self {
+        RegisterSocket {
+            socket: Some(socket),
+            register: None,
+        }
+    }
+}
+impl Future for RegisterSocket {
+    type Item = (SocketAddr, Arc<EventedHandle<MioUdpSocket>>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(socket) = self.socket.take() {
+            let local_addr = track!(socket.local_addr().map_err(Error::from))?;
+            let socket = track!(MioUdpSocket::from_socket(socket).map_err(Error::from))?;
+            let register = fiber::with_current_context(|mut c| c.poller().register(socket));
+            let register = track_assert_some!(register, ErrorKind::Other; "Not on a fiber");
+            self.register = Some((local_addr, register));
+        }
+
+        let (local_addr, register) = self.register.as_mut().expect("never fails");
+        match register.poll() {
+            Err(e) => {
+                let e = ErrorKind::Other.cause(format!("Cannot register a socket: {e:?}"));
+                Err(track!(e).into())
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(socket)) => Ok(Async::Ready((*local_addr, socket))),
+        }
+    }
+}
+
+/// Creates a UDP socket bound to the given address with `SO_REUSEPORT` enabled.
+#[cfg(unix)]
+pub(super) fn bind_reuse_port(bind_addr: SocketAddr) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(
+        Domain::for_address(bind_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&bind_addr.into())?;
+    Ok(socket.into())
+}
+
+/// Creates a UDP socket bound to `[::]:port` with `IPV6_V6ONLY` disabled.
+fn bind_dual_stack(port: u16) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    use std::net::Ipv6Addr;
+
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    Ok(socket.into())
+}
+
+/// Polls the given readiness monitor.
+///
+/// Returns `Async::Ready(())` if there is no monitor or the monitored event has occurred.
+pub(super) fn poll_monitor(monitor: &mut Option<Monitor<(), io::Error>>) -> Poll<(), Error> {
+    if let Some(m) = monitor.as_mut() {
+        match m.poll() {
+            Err(e) => return Err(track!(Error::from(into_io_error(e)))),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {}
+        }
+    }
+    *monitor = None;
+    Ok(Async::Ready(()))
+}
+
+fn into_io_error(e: MonitorError<io::Error>) -> io::Error {
+    e.unwrap_or_else(|| io::Error::other("Monitor channel disconnected"))
+}
@@ -1,10 +1,21 @@
-use fibers_timeout_queue::TimeoutQueue;
-use fibers_transport::{PollRecv, PollSend, Result, Transport, UdpTransport};
+use crate::channel::CallOptions;
+use crate::clock::{Clock, SystemClock};
+use crate::timeout_queue::TimeoutQueue;
+use crate::trace::Span;
+use bytecodec::{Decode, Encode};
+use fibers_transport::{
+    Error, ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport, UdpTransporter,
+};
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use stun_codec::{Attribute, DecodedMessage, Message, MessageClass, TransactionId};
 
+use trackable::error::ErrorKindExt;
+
 use super::StunTransport;
 
 /// [`StunUdpTransporter`] builder.
@@ -16,6 +27,8 @@ pub struct StunUdpTransporterBuilder {
     rto_cache_duration: Duration,
     min_transaction_interval: Duration,
     max_outstanding_transactions: usize,
+    max_pending_requests: usize,
+    clock: Arc<dyn Clock>,
 }
 impl StunUdpTransporterBuilder {
     /// The default value of RTO (Retransmission TimeOut).
@@ -76,6 +89,9 @@ impl StunUdpTransporterBuilder {
     /// [RFC 5389 -- 7.2. Sending the Request or Indication]: https://tools.ietf.org/html/rfc5389#section-7.2
     pub const DEFAULT_MIN_TRANSACTION_INTERVAL_MS: u64 = Self::DEFAULT_RTO_MS;
 
+    /// The default max number of requests waiting to be sent to a server.
+    pub const DEFAULT_MAX_PENDING_REQUESTS: usize = 1000;
+
     /// Makes a new `StunUdpTransporterBuilder` instance with the default settings.
     pub fn new() -> Self {
         Self::default()
@@ -114,6 +130,26 @@ impl StunUdpTransporterBuilder {
         self
     }
 
+    /// Sets the number of the maximum requests waiting to be sent to a peer of the resulting instance.
+    ///
+    /// If a new request would exceed this limit, it is rejected with an error caused by [`TooManyPendingRequests`].
+    ///
+    /// The default value is `DEFAULT_MAX_PENDING_REQUESTS`.
+    ///
+    /// [`TooManyPendingRequests`]: ./struct.TooManyPendingRequests.html
+    pub fn max_pending_requests(&mut self, max: usize) -> &mut Self {
+        self.max_pending_requests = max;
+        self
+    }
+
+    /// Sets the clock used for retransmission timers and transaction pacing of the resulting instance.
+    ///
+    /// The default value is `SystemClock`.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     /// Makes a new `StunUdpTransporter` instance with the given settings.
     pub fn finish<A, T>(&self, inner: T) -> StunUdpTransporter<A, T>
     where
@@ -122,12 +158,14 @@ impl StunUdpTransporterBuilder {
     {
         let inner = RetransmitTransporter {
             inner,
-            timeout_queue: TimeoutQueue::new(),
+            timeout_queue: TimeoutQueue::with_clock(self.clock.clone()),
+            clock: self.clock.clone(),
             peers: HashMap::new(),
             rto: self.rto,
             rto_cache_duration: self.rto_cache_duration,
             min_transaction_interval: self.min_transaction_interval,
             max_outstanding_transactions: self.max_outstanding_transactions,
+            max_pending_requests: self.max_pending_requests,
         };
         StunUdpTransporter { inner }
     }
@@ -141,11 +179,52 @@ impl Default for StunUdpTransporterBuilder {
                 Self::DEFAULT_MIN_TRANSACTION_INTERVAL_MS,
             ),
             max_outstanding_transactions: Self::DEFAULT_MAX_OUTSTANDING_TRANSACTIONS,
+            max_pending_requests: Self::DEFAULT_MAX_PENDING_REQUESTS,
+            clock: Arc::new(SystemClock),
         }
     }
 }
 
+/// A request rejected by [`StunUdpTransporter`] because the given number of requests
+/// were already waiting to be sent to the peer (see [`StunUdpTransporterBuilder::max_pending_requests`]).
+///
+/// Use [`TooManyPendingRequests::from_error`] to extract this from the error of the request.
+///
+/// [`StunUdpTransporter`]: ./struct.StunUdpTransporter.html
+/// [`StunUdpTransporterBuilder::max_pending_requests`]: ./struct.StunUdpTransporterBuilder.html#method.max_pending_requests
+/// [`TooManyPendingRequests::from_error`]: ./struct.TooManyPendingRequests.html#method.from_error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooManyPendingRequests {
+    /// The peer of the request.
+    pub peer: SocketAddr,
+
+    /// The maximum number of pending requests per peer.
+    pub max: usize,
+}
+impl TooManyPendingRequests {
+    /// Returns the `TooManyPendingRequests` that caused the given error if exists.
+    pub fn from_error(error: &crate::Error) -> Option<&Self> {
+        error.concrete_cause()
+    }
+}
+impl fmt::Display for TooManyPendingRequests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Too many pending requests: peer={}, max={}",
+            self.peer, self.max
+        )
+    }
+}
+impl std::error::Error for TooManyPendingRequests {}
+
 /// UDP transport layer that can be used for STUN.
+///
+/// This retransmits requests sent via the inner transporter `T`,
+/// which also needs to implement [`StunTransport`] (e.g., `fibers_transport::UdpTransporter` and [`StdUdpTransporter`]).
+///
+/// [`StunTransport`]: ./trait.StunTransport.html
+/// [`StdUdpTransporter`]: ./struct.StdUdpTransporter.html
 #[derive(Debug)]
 pub struct StunUdpTransporter<A, T> {
     inner: RetransmitTransporter<A, T>,
@@ -196,8 +275,17 @@ where
 impl<A, T> StunTransport<A> for StunUdpTransporter<A, T>
 where
     A: Attribute,
-    T: UdpTransport<SendItem = Message<A>, RecvItem = DecodedMessage<A>>,
+    T: UdpTransport + StunTransport<A>,
 {
+    fn start_request(
+        &mut self,
+        peer: SocketAddr,
+        request: Message<A>,
+        options: &CallOptions,
+    ) -> Result<()> {
+        track!(self.inner.start_request(peer, request, options))
+    }
+
     fn finish_transaction(
         &mut self,
         peer: &SocketAddr,
@@ -205,6 +293,36 @@ where
     ) -> Result<()> {
         track!(self.inner.finish_transaction(peer, transaction_id))
     }
+
+    fn is_transient_error(&self, error: &Error) -> bool {
+        self.inner.is_transient_error(error)
+    }
+}
+
+/// `fibers_transport::UdpTransporter` can be used as the inner transporter of [`StunUdpTransporter`].
+///
+/// The transporter cannot be used after an I/O error, thus only encoding/decoding errors are transient.
+/// Use [`StdUdpTransporter`] instead if clients have to survive I/O errors such as `ENETUNREACH`.
+///
+/// [`StunUdpTransporter`]: ./struct.StunUdpTransporter.html
+/// [`StdUdpTransporter`]: ./struct.StdUdpTransporter.html
+impl<A, E, D> StunTransport<A> for UdpTransporter<E, D>
+where
+    A: Attribute,
+    E: Encode<Item = Message<A>>,
+    D: Decode<Item = DecodedMessage<A>>,
+{
+    fn finish_transaction(
+        &mut self,
+        _peer: &SocketAddr,
+        _transaction_id: TransactionId,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_transient_error(&self, error: &Error) -> bool {
+        error.concrete_cause::<io::Error>().is_none()
+    }
 }
 
 /// An implementation of [`StunTransport`] that retransmits request messages for improving reliability.
@@ -214,11 +332,13 @@ where
 struct RetransmitTransporter<A, T> {
     inner: T,
     timeout_queue: TimeoutQueue<TimeoutEntry<A>>,
+    clock: Arc<dyn Clock>,
     peers: HashMap<SocketAddr, PeerState<A>>,
     rto: Duration,
     rto_cache_duration: Duration,
     min_transaction_interval: Duration,
     max_outstanding_transactions: usize,
+    max_pending_requests: usize,
 }
 impl<A, T> RetransmitTransporter<A, T>
 where
@@ -226,11 +346,12 @@ where
     T: UdpTransport<SendItem = Message<A>, RecvItem = DecodedMessage<A>>,
 {
     fn waiting_time(&self, peer: SocketAddr) -> Option<Duration> {
-        self.peers[&peer]
-            .last_transaction_start_time
-            .elapsed()
+        self.clock
+            .now()
+            .duration_since(self.peers[&peer].last_transaction_start_time)
             .ok()
             .and_then(|d| self.min_transaction_interval.checked_sub(d))
+            .filter(|d| *d > Duration::from_secs(0))
     }
 
     fn peer_mut(&mut self, peer: SocketAddr) -> &mut PeerState<A> {
@@ -242,36 +363,64 @@ where
         &mut self,
         peer: SocketAddr,
         request: Message<A>,
+        options: CallOptions,
         first: bool,
     ) -> Result<()> {
         if !self.peers.contains_key(&peer) {
             self.peers.insert(peer, PeerState::new(peer, self.rto));
         }
 
-        if self.peers[&peer].waiting {
-            self.peer_mut(peer).pending(request, first);
+        if options.is_no_throttle() {
+            track!(self.inner.start_send(peer, request.clone()))?;
+            let now = self.clock.now();
+            let timeout = self
+                .peer_mut(peer)
+                .start_transaction(request, &options, now);
+            self.timeout_queue.push(timeout.0, timeout.1);
+        } else if self.peers[&peer].waiting {
+            track!(self.pending(peer, request, options, first))?;
         } else if let Some(duration) = self.waiting_time(peer) {
+            track!(self.pending(peer, request, options, first))?;
             self.peer_mut(peer).waiting = true;
             self.timeout_queue
                 .push(TimeoutEntry::AllowNextRequest { peer }, duration);
-            self.peer_mut(peer).pending(request, first);
         } else if self.peers[&peer].transactions.len() >= self.max_outstanding_transactions {
-            self.peer_mut(peer).pending(request, first);
+            track!(self.pending(peer, request, options, first))?;
         } else {
             track!(self.inner.start_send(peer, request.clone()))?;
-            let timeout = self.peer_mut(peer).start_transaction(request);
+            let now = self.clock.now();
+            let timeout = self
+                .peer_mut(peer)
+                .start_transaction(request, &options, now);
             self.timeout_queue.push(timeout.0, timeout.1);
         }
         Ok(())
     }
 
+    fn pending(
+        &mut self,
+        peer: SocketAddr,
+        request: Message<A>,
+        options: CallOptions,
+        first: bool,
+    ) -> Result<()> {
+        let max = self.max_pending_requests;
+        let p = self.peer_mut(peer);
+        if first && p.pending_requests.len() >= max {
+            let e = ErrorKind::Other.cause(TooManyPendingRequests { peer, max });
+            return Err(track!(Error::from(e)));
+        }
+        p.pending(request, options, first);
+        Ok(())
+    }
+
     fn poll_timeout(&mut self) -> Option<TimeoutEntry<A>> {
         let peers = &self.peers;
         self.timeout_queue.filter_pop(|entry| {
             if let TimeoutEntry::Retransmit { peer, request, .. } = entry {
-                peers.get(peer).map_or(false, |p| {
-                    p.transactions.contains(&request.transaction_id())
-                })
+                peers
+                    .get(peer)
+                    .is_some_and(|p| p.transactions.contains(&request.transaction_id()))
             } else {
                 true
             }
@@ -282,8 +431,8 @@ where
         if !self.peers.contains_key(&peer) {
             return Ok(());
         }
-        if let Some(request) = self.peer_mut(peer).pop_pending_request() {
-            track!(self.start_transaction(peer, request, false))?;
+        if let Some((request, options, span)) = self.peer_mut(peer).pop_pending_request() {
+            span.in_scope(|| track!(self.start_transaction(peer, request, options, false)))?;
         }
         if self.peers[&peer].is_idle() {
             self.peers.remove(&peer);
@@ -296,11 +445,15 @@ where
         peer: SocketAddr,
         request: Message<A>,
         rto: Duration,
+        remaining_retransmits: Option<usize>,
+        span: Span,
     ) -> Result<()> {
         if let Some(p) = self.peers.get_mut(&peer) {
             if let Some(request) = p.retransmit(
                 request,
                 rto,
+                remaining_retransmits,
+                span,
                 self.rto_cache_duration,
                 &mut self.timeout_queue,
             ) {
@@ -321,7 +474,7 @@ where
 
     fn start_send(&mut self, peer: SocketAddr, item: Self::SendItem) -> Result<()> {
         if item.class() == MessageClass::Request {
-            track!(self.start_transaction(peer, item, true))
+            track!(self.start_transaction(peer, item, CallOptions::default(), true))
         } else {
             track!(self.inner.start_send(peer, item))
         }
@@ -334,8 +487,16 @@ where
                     peer,
                     request,
                     next_rto,
+                    remaining_retransmits,
+                    span,
                 } => {
-                    track!(self.handle_retransmit(peer, request, next_rto))?;
+                    track!(self.handle_retransmit(
+                        peer,
+                        request,
+                        next_rto,
+                        remaining_retransmits,
+                        span
+                    ))?;
                 }
                 TimeoutEntry::ExpireRtoCache { peer, cached_rto } => {
                     if let Some(p) = self.peers.get_mut(&peer) {
@@ -361,8 +522,21 @@ where
 impl<A, T> StunTransport<A> for RetransmitTransporter<A, T>
 where
     A: Attribute,
-    T: UdpTransport<SendItem = Message<A>, RecvItem = DecodedMessage<A>>,
+    T: UdpTransport + StunTransport<A>,
 {
+    fn start_request(
+        &mut self,
+        peer: SocketAddr,
+        request: Message<A>,
+        options: &CallOptions,
+    ) -> Result<()> {
+        if request.class() == MessageClass::Request {
+            track!(self.start_transaction(peer, request, options.clone(), true))
+        } else {
+            track!(self.inner.start_send(peer, request))
+        }
+    }
+
     fn finish_transaction(
         &mut self,
         peer: &SocketAddr,
@@ -373,6 +547,9 @@ where
         }
         track!(self.handle_pending_request(*peer))
     }
+    fn is_transient_error(&self, error: &Error) -> bool {
+        self.inner.is_transient_error(error)
+    }
 }
 
 #[derive(Debug)]
@@ -381,6 +558,8 @@ enum TimeoutEntry<A> {
         peer: SocketAddr,
         request: Message<A>,
         next_rto: Duration,
+        remaining_retransmits: Option<usize>,
+        span: Span,
     },
     ExpireRtoCache {
         peer: SocketAddr,
@@ -395,7 +574,7 @@ enum TimeoutEntry<A> {
 struct PeerState<A> {
     peer: SocketAddr,
     transactions: HashSet<TransactionId>,
-    pending_requests: VecDeque<Message<A>>,
+    pending_requests: VecDeque<(Message<A>, CallOptions, Span)>,
     waiting: bool,
     last_transaction_start_time: SystemTime,
     cached_rto: Duration,
@@ -412,71 +591,293 @@ impl<A: Attribute> PeerState<A> {
         }
     }
 
-    fn pending(&mut self, request: Message<A>, first: bool) {
-        if first {
-            self.pending_requests.push_back(request);
+    fn pending(&mut self, request: Message<A>, options: CallOptions, first: bool) {
+        trace_event!(
+            debug,
+            pending_requests = self.pending_requests.len(),
+            "Request queued"
+        );
+        let priority = options.get_priority();
+        let position = if first {
+            // Behind the requests having the same or higher priorities
+            self.pending_requests
+                .iter()
+                .position(|(_, o, _)| o.get_priority() > priority)
         } else {
-            self.pending_requests.push_front(request);
-        }
+            // Ahead of the requests having the same or lower priorities
+            self.pending_requests
+                .iter()
+                .position(|(_, o, _)| o.get_priority() >= priority)
+        };
+        let position = position.unwrap_or(self.pending_requests.len());
+        self.pending_requests
+            .insert(position, (request, options, Span::current()));
     }
 
     fn is_idle(&self) -> bool {
         self.transactions.is_empty() && !self.waiting
     }
 
-    fn pop_pending_request(&mut self) -> Option<Message<A>> {
-        while let Some(request) = self.pending_requests.pop_front() {
-            if self.transactions.contains(&request.transaction_id()) {
-                return Some(request);
-            }
-        }
-        None
+    fn pop_pending_request(&mut self) -> Option<(Message<A>, CallOptions, Span)> {
+        self.pending_requests.pop_front()
     }
 
     fn retransmit(
         &mut self,
         request: Message<A>,
         rto: Duration,
+        remaining_retransmits: Option<usize>,
+        span: Span,
         rto_cache_duration: Duration,
         queue: &mut TimeoutQueue<TimeoutEntry<A>>,
     ) -> Option<Message<A>> {
-        if self.transactions.contains(&request.transaction_id()) {
+        if !self.transactions.contains(&request.transaction_id()) {
+            return None;
+        }
+        if remaining_retransmits == Some(0) {
+            trace_event!(debug, parent: span, "No more retransmissions");
+            return None;
+        }
+        trace_event!(debug, parent: span, rto = ?rto, "Retransmitting request");
+        queue.push(
+            TimeoutEntry::Retransmit {
+                peer: self.peer,
+                request: request.clone(),
+                next_rto: rto * 2,
+                remaining_retransmits: remaining_retransmits.map(|n| n - 1),
+                span,
+            },
+            rto,
+        );
+        if self.cached_rto < rto {
+            self.cached_rto = rto;
             queue.push(
-                TimeoutEntry::Retransmit {
+                TimeoutEntry::ExpireRtoCache {
                     peer: self.peer,
-                    request: request.clone(),
-                    next_rto: rto * 2,
+                    cached_rto: rto,
                 },
-                rto,
+                rto_cache_duration,
             );
-            if self.cached_rto < rto {
-                self.cached_rto = rto;
-                queue.push(
-                    TimeoutEntry::ExpireRtoCache {
-                        peer: self.peer,
-                        cached_rto: rto,
-                    },
-                    rto_cache_duration,
-                );
-            }
-            Some(request)
-        } else {
-            None
         }
+        Some(request)
     }
 
-    fn start_transaction(&mut self, request: Message<A>) -> (TimeoutEntry<A>, Duration) {
+    fn start_transaction(
+        &mut self,
+        request: Message<A>,
+        options: &CallOptions,
+        now: SystemTime,
+    ) -> (TimeoutEntry<A>, Duration) {
         self.transactions.insert(request.transaction_id());
-        self.last_transaction_start_time = SystemTime::now();
+        if !options.is_no_throttle() {
+            self.last_transaction_start_time = now;
+        }
+        let rto = options.get_rto().unwrap_or(self.cached_rto);
         let entry = TimeoutEntry::Retransmit {
             peer: self.peer,
             request,
-            next_rto: self.cached_rto * 2,
+            next_rto: rto * 2,
+            remaining_retransmits: options.get_max_retransmits(),
+            span: Span::current(),
         };
-        (entry, self.cached_rto)
+        (entry, rto)
     }
 
     fn finish_transaction(&mut self, transaction_id: TransactionId) {
-        self.transactions.remove(&transaction_id);
+        if !self.transactions.remove(&transaction_id) {
+            // The request may have been cancelled before being sent
+            self.pending_requests
+                .retain(|(request, _, _)| request.transaction_id() != transaction_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Priority;
+    use crate::clock::ManualClock;
+    use crate::message::Request;
+    use crate::transport::{MemoryTransporter, SimulatedNetwork};
+    use futures::future;
+    use stun_codec::rfc5389;
+    use trackable::error::MainError;
+
+    type Transporter =
+        StunUdpTransporter<rfc5389::Attribute, MemoryTransporter<rfc5389::Attribute>>;
+
+    fn binding_request() -> Message<rfc5389::Attribute> {
+        Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING).into_message()
+    }
+
+    fn advance_and_count(
+        clock: &ManualClock,
+        network: &SimulatedNetwork,
+        transporter: &mut Transporter,
+        duration: Duration,
+    ) -> Result<u64> {
+        clock.advance(duration);
+        track!(transporter.poll_send())?;
+        Ok(network.stats().sent)
+    }
+
+    #[test]
+    fn rto_doubles_on_each_retransmission() -> std::result::Result<(), MainError> {
+        let clock = ManualClock::new();
+        let network = SimulatedNetwork::new();
+        let mut transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_millis(100))
+            .clock(clock.clone())
+            .finish(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            track!(transporter.start_send(peer, binding_request()))?;
+            track!(transporter.poll_send())?;
+            assert_eq!(network.stats().sent, 1);
+
+            let mut expected_sent = 1;
+            for rto in [100, 200, 400, 800] {
+                let t = &mut transporter;
+                let sent = track!(advance_and_count(&clock, &network, t, ms(rto - 1)))?;
+                assert_eq!(sent, expected_sent);
+
+                expected_sent += 1;
+                let sent = track!(advance_and_count(&clock, &network, t, ms(1)))?;
+                assert_eq!(sent, expected_sent);
+            }
+            Ok(())
+        }));
+        track!(result)?;
+        Ok(())
+    }
+
+    #[test]
+    fn min_transaction_interval_works() -> std::result::Result<(), MainError> {
+        let clock = ManualClock::new();
+        let network = SimulatedNetwork::new();
+        let mut transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_secs(60))
+            .min_transaction_interval(Duration::from_millis(500))
+            .clock(clock.clone())
+            .finish(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            track!(transporter.start_send(peer, binding_request()))?;
+            track!(transporter.start_send(peer, binding_request()))?;
+            track!(transporter.poll_send())?;
+            assert_eq!(network.stats().sent, 1);
+
+            let t = &mut transporter;
+            assert_eq!(track!(advance_and_count(&clock, &network, t, ms(499)))?, 1);
+            assert_eq!(track!(advance_and_count(&clock, &network, t, ms(1)))?, 2);
+            Ok(())
+        }));
+        track!(result)?;
+        Ok(())
+    }
+
+    #[test]
+    fn per_request_retransmission_options_work() -> std::result::Result<(), MainError> {
+        let clock = ManualClock::new();
+        let network = SimulatedNetwork::new();
+        let mut transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_secs(1))
+            .min_transaction_interval(Duration::from_millis(500))
+            .clock(clock.clone())
+            .finish(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let result = fibers_global::execute(future::lazy(move || -> Result<()> {
+            let options = CallOptions::new()
+                .rto(ms(50))
+                .max_retransmits(2)
+                .no_throttle(true)
+                .clone();
+            track!(transporter.start_send(peer, binding_request()))?;
+            track!(transporter.start_request(peer, binding_request(), &options))?;
+            track!(transporter.poll_send())?;
+            assert_eq!(network.stats().sent, 2); // Not throttled
+
+            let t = &mut transporter;
+            assert_eq!(track!(advance_and_count(&clock, &network, t, ms(50)))?, 3);
+            assert_eq!(track!(advance_and_count(&clock, &network, t, ms(100)))?, 4);
+            assert_eq!(track!(advance_and_count(&clock, &network, t, ms(200)))?, 4);
+            assert_eq!(track!(advance_and_count(&clock, &network, t, ms(400)))?, 4);
+
+            // The first request is retransmitted with the default RTO
+            assert_eq!(track!(advance_and_count(&clock, &network, t, ms(250)))?, 5);
+            Ok(())
+        }));
+        track!(result)?;
+        Ok(())
+    }
+
+    #[test]
+    fn pending_requests_are_ordered_by_priority() -> std::result::Result<(), MainError> {
+        let network = SimulatedNetwork::new();
+        let mut transporter: Transporter = StunUdpTransporterBuilder::new()
+            .max_outstanding_transactions(1)
+            .min_transaction_interval(Duration::from_secs(0))
+            .finish(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        let request = |priority| {
+            let options = CallOptions::new().priority(priority).clone();
+            (binding_request(), options)
+        };
+        let requests = vec![
+            request(Priority::Normal),
+            request(Priority::Background),
+            request(Priority::Normal),
+            request(Priority::Urgent),
+            request(Priority::Background),
+        ];
+        let ids = requests
+            .iter()
+            .map(|(r, _)| r.transaction_id())
+            .collect::<Vec<_>>();
+        for (request, options) in requests {
+            track!(transporter.start_request(peer, request, &options))?;
+        }
+
+        let mut sent = Vec::new();
+        for _ in 0..ids.len() {
+            let p = &transporter.inner.peers[&peer];
+            let id = *p.transactions.iter().next().expect("never fails");
+            sent.push(ids.iter().position(|x| *x == id).expect("never fails"));
+            track!(transporter.finish_transaction(&peer, id))?;
+        }
+        assert_eq!(sent, [0, 3, 2, 1, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_pending_requests_are_rejected() -> std::result::Result<(), MainError> {
+        let network = SimulatedNetwork::new();
+        let mut transporter: Transporter = StunUdpTransporterBuilder::new()
+            .max_outstanding_transactions(1)
+            .max_pending_requests(2)
+            .finish(track!(network.bind_any())?);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+
+        for _ in 0..3 {
+            track!(transporter.start_send(peer, binding_request()))?;
+        }
+        let e = crate::Error::from(track_assert_some!(
+            transporter.start_send(peer, binding_request()).err(),
+            ErrorKind::Other
+        ));
+        assert_eq!(
+            TooManyPendingRequests::from_error(&e),
+            Some(&TooManyPendingRequests { peer, max: 2 })
+        );
+        assert_eq!(transporter.inner.peers[&peer].pending_requests.len(), 2);
+        Ok(())
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
     }
 }